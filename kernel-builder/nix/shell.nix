@@ -2,14 +2,23 @@
 { compiler ? "gcc-default" }:
 
 let
+  # 解析 "gcc-12" 或 "gcc-12.2"，minor 可省略
+  parsedCompiler = builtins.match "(gcc|clang)-([0-9]+)(\\.([0-9]+))?" compiler;
+  compilerMajor = if parsedCompiler == null then "default" else builtins.elemAt parsedCompiler 1;
+  compilerMinor = if parsedCompiler == null then null else builtins.elemAt parsedCompiler 3;
+  # 去掉 minor 后的 "gcc-12"，用于选择 channel
+  compilerFamily =
+    if parsedCompiler == null then compiler
+    else "${builtins.elemAt parsedCompiler 0}-${compilerMajor}";
+
   # 对于旧版本编译器，使用相应的旧 channel
   pkgs =
-    if compiler == "gcc-8" then
+    if compilerFamily == "gcc-8" then
       # 对于 gcc 8, 使用 nixos-21.05 channel
       import (builtins.fetchTarball {
         url = "https://github.com/NixOS/nixpkgs/archive/nixos-21.05.tar.gz";
       }) { }
-    else if builtins.elem compiler [ "clang-8" "clang-9" "clang-10" "clang-11" ] then
+    else if builtins.elem compilerFamily [ "clang-8" "clang-9" "clang-10" "clang-11" ] then
       # 对于 Clang 8-11，使用 nixos-21.11 channel
       import (builtins.fetchTarball {
         url = "https://github.com/NixOS/nixpkgs/archive/nixos-21.11.tar.gz";
@@ -24,6 +33,7 @@ let
     git
     pkg-config
     bear
+    ccache
     perl
     python3
     ncurses
@@ -42,29 +52,37 @@ let
     zstd
     zlib
     zlib.dev
+    # 静态链接 reproducer（compile_reproducer 使用 -static）
+    glibc.static
   ];
 
-  # 解析 GCC 版本号
-  parseGccVersion = compiler:
-    if compiler == "gcc-default" then
-      "default"
-    else if pkgs.lib.strings.hasPrefix "gcc-" compiler then
-      let
-        versionStr = builtins.substring 4 (builtins.stringLength compiler) compiler;
-      in
-      if versionStr != "" then versionStr else "default"
-    else
-      "default";
+  # 按 major.minor 选择最匹配的属性：先尝试带 minor 的属性名（如 gcc49、llvmPackages_3_9），
+  # 不存在时退回只带 major 的属性
+  bestMatchAttr = candidates:
+    let
+      available = builtins.filter (attr: builtins.hasAttr attr pkgs) candidates;
+    in
+    if available == [ ] then null else builtins.head available;
+
+  # nixpkgs 只提供了 major 对应的版本时，提示无法固定到所需的 minor 版本
+  minorWarning = pkg:
+    if compilerMinor != null
+      && pkgs.lib.versions.majorMinor pkg.version != "${compilerMajor}.${compilerMinor}" then ''
+      echo "   WARNING: ${compiler} requested but nixpkgs only provides ${pkg.version}, the exact version could not be pinned" >&2
+    '' else "";
 
   # 工具链配置
   toolchainConfig =
     if pkgs.lib.strings.hasPrefix "clang-" compiler then
       # Clang 配置
       let
-        version = builtins.substring 6 (builtins.stringLength compiler) compiler;
-        llvmAttr = "llvmPackages_" + version;
+        version = compilerMajor;
+        llvmAttr = bestMatchAttr (
+          pkgs.lib.optional (compilerMinor != null) "llvmPackages_${version}_${compilerMinor}"
+          ++ [ "llvmPackages_${version}" ]
+        );
       in
-      if builtins.hasAttr llvmAttr pkgs then
+      if llvmAttr != null then
         let
           llvmPkgs = pkgs.${llvmAttr};
         in
@@ -76,11 +94,12 @@ let
             bintools
           ];
           hook = ''
-            echo "   Toolchain: Clang (LLVM version ${version})"
+            echo "   Toolchain: Clang (LLVM version ${llvmPkgs.clang.version})"
+            ${minorWarning llvmPkgs.clang}
             echo "   CC: $CC"
             echo "   CXX: $CXX"
             echo "   LD: ld.lld"
-            ${if builtins.elem compiler [ "clang-8" "clang-9" "clang-10" "clang-11" ] then ''
+            ${if builtins.elem compilerFamily [ "clang-8" "clang-9" "clang-10" "clang-11" ] then ''
               echo "       Channel: nixos-21.11 (for Clang ${version} support)"
               echo "       Note: Using nixos-21.11 channel for Clang ${version} with compatible dependencies"
             '' else ""}
@@ -92,7 +111,7 @@ let
     else
       # GCC 配置
       let
-        gccVersionStr = parseGccVersion compiler;
+        gccVersionStr = compilerMajor;
 
         selectedGcc =
           if gccVersionStr == "default" then
@@ -102,9 +121,12 @@ let
             pkgs.gcc8
           else
             let
-              gccAttr = "gcc${gccVersionStr}";
+              gccAttr = bestMatchAttr (
+                pkgs.lib.optional (compilerMinor != null) "gcc${gccVersionStr}${compilerMinor}"
+                ++ [ "gcc${gccVersionStr}" ]
+              );
             in
-            if builtins.hasAttr gccAttr pkgs then
+            if gccAttr != null then
               pkgs.${gccAttr}
             else
               throw "Error: GCC version '${gccVersionStr}' not found. Available versions: 8 (from nixos-24.05), 9, 10, 11, 12, 13, 14";
@@ -121,7 +143,8 @@ let
         hook = ''
           export CC=${toString selectedGcc}/bin/gcc
           export CXX=${toString selectedGcc}/bin/g++
-          echo "   Toolchain: GCC (Version: ${gccVersionStr})"
+          echo "   Toolchain: GCC (Version: ${selectedGcc.version})"
+          ${minorWarning selectedGcc}
           echo "   CC: $CC"
           echo "   CXX: $CXX"
           echo "   Binutils: ${pkgs.binutils}/bin"
@@ -176,6 +199,7 @@ toolchainConfig.stdenv.mkDerivation {
     echo ""
     echo "   Compiler Usage Examples:"
     echo "   nix-shell --arg compiler '\"gcc-8\"'        # GCC 8"
+    echo "   nix-shell --arg compiler '\"gcc-12.2\"'     # GCC 12.2, warns if only another 12.x is available"
     echo "   nix-shell --arg compiler '\"clang-8\"'      # Clang 8"
     echo "   nix-shell --arg compiler '\"clang-11\"'     # Clang 11"
     echo "   nix-shell --arg compiler '\"clang-17\"'     # Clang 17"
@@ -5,12 +5,13 @@ set -euo pipefail
 SCRIPT_DIR=$(dirname "$(realpath "$0")")
 ROOT_DIR=$(dirname "$SCRIPT_DIR")
 IMAGE_DIR="$ROOT_DIR/image"
-WORK_DIR="$ROOT_DIR/workspace"
+WORK_DIR="${WORK_DIR:-$ROOT_DIR/workspace}"
 ID=$1
 COMMIT_ID=$2
-LINUX_WORK_DIR="$WORK_DIR/$ID"
-LINUX_BUILD_DIR="$WORK_DIR/$ID/build"
-LINUX_INSTALL_DIR="$WORK_DIR/$ID/install"
+# files of the crash being built, workspace/<id>/crash-<n> for every crash but the first
+LINUX_WORK_DIR="${CRASH_DIR:-$WORK_DIR/$ID}"
+LINUX_BUILD_DIR="$LINUX_WORK_DIR/build"
+LINUX_INSTALL_DIR="$LINUX_WORK_DIR/install"
 LINUX_SRC_DIR="$WORK_DIR/$ID/linux-$COMMIT_ID"
 LINUX_IMAGE_DIR="$LINUX_WORK_DIR/image"
 IMAGE_PATH="$LINUX_IMAGE_DIR/debian.img"
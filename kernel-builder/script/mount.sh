@@ -22,14 +22,17 @@ create_dir() {
 SCRIPT_DIR=$(dirname "$(realpath "$0")")
 ROOT_DIR=$(dirname "$SCRIPT_DIR")
 IMAGE_DIR="$ROOT_DIR/image"
-WORK_DIR="$ROOT_DIR/workspace"
+WORK_DIR="${WORK_DIR:-$ROOT_DIR/workspace}"
 ID=$1
 COMMIT_ID=$2
-LINUX_WORK_DIR="$WORK_DIR/$ID"
-LINUX_BUILD_DIR="$WORK_DIR/$ID/build"
-LINUX_INSTALL_DIR="$WORK_DIR/$ID/install"
+# files of the crash being built, workspace/<id>/crash-<n> for every crash but the first
+LINUX_WORK_DIR="${CRASH_DIR:-$WORK_DIR/$ID}"
+LINUX_BUILD_DIR="$LINUX_WORK_DIR/build"
+LINUX_INSTALL_DIR="$LINUX_WORK_DIR/install"
 LINUX_SRC_DIR="$WORK_DIR/$ID/linux-$COMMIT_ID"
 LINUX_IMAGE_DIR="$LINUX_WORK_DIR/image"
+# boot image of the build, bzImage on x86_64 and Image on arm64 and riscv64
+KERNEL_IMAGE="${KERNEL_IMAGE:-$LINUX_BUILD_DIR/arch/x86_64/boot/bzImage}"
 
 if [ -z "$COMMIT_ID" ]; then
     error_exit "Commit ID is required as an argument."
@@ -37,12 +40,11 @@ fi
 
 log "INFO" "Starting process with COMMIT_ID: $COMMIT_ID"
 
-cd "$WORK_DIR" || error_exit "Failed to change directory to $WORK_DIR"
-cd "$ID" || error_exit "Failed to change directory to $WORK_DIR/$ID"
+cd "$LINUX_WORK_DIR" || error_exit "Failed to change directory to $LINUX_WORK_DIR"
 
 create_dir "image"
 
-cd "image" || error_exit "Failed to change directory to $WORK_DIR/$ID/image"
+cd "image" || error_exit "Failed to change directory to $LINUX_IMAGE_DIR"
 
 log "INFO" "Copying debian.img..."
 rsync -av "$IMAGE_DIR/debian.img" ./ || error_exit "Failed to copy debian.img"
@@ -59,14 +61,14 @@ sudo rm -rf ./linux || error_exit "Failed to remove linux directory"
 sudo cp -R "$LINUX_INSTALL_DIR/include/asm" ./ || error_exit "Failed to copy asm headers"
 sudo cp -R "$LINUX_INSTALL_DIR/include/linux" ./ || error_exit "Failed to copy linux headers"
 
-log "INFO" "Copying bug.c to root..."
+log "INFO" "Copying reproducer.c to root/bug.c..."
 cd ../../ || error_exit "Failed to return to mnt directory"
-sudo cp -R "$LINUX_WORK_DIR/bug.c" ./root || error_exit "Failed to copy bug.c"
+sudo cp "$LINUX_WORK_DIR/reproducer.c" ./root/bug.c || error_exit "Failed to copy reproducer.c"
 
 log "INFO" "Unmounting debian.img..."
 cd ..
 sudo umount mnt || error_exit "Failed to unmount debian.img"
 
-cp "$LINUX_BUILD_DIR/arch/x86_64/boot/bzImage" ./
+cp "$KERNEL_IMAGE" ./ || error_exit "Failed to copy $KERNEL_IMAGE"
 
 log "INFO" "Image has been successfully copied."
\ No newline at end of file
@@ -1,17 +1,91 @@
 use crate::kvm::ssh::SSHError;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
 use serde_with::DurationSeconds;
+use serde_with::serde_as;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
-use tracing::{error, info};
+use tracing::{info, warn};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Config {
     pub proxy: ProxyConfig,
     pub ssh: SSHConfig,
+    #[serde(default)]
+    pub download: DownloadConfig,
+    #[serde(default)]
+    pub build: BuildConfig,
+    #[serde(default)]
+    pub workspace: WorkspaceConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+}
+
+// tracing output, `--log-format` overrides the format
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub format: LogFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // multi-line, for reading on a terminal
+    #[default]
+    Pretty,
+    Compact,
+    // one JSON object per line, including the fields of the enclosing spans
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "pretty" => Ok(LogFormat::Pretty),
+            "compact" => Ok(LogFormat::Compact),
+            "json" => Ok(LogFormat::Json),
+            other => anyhow::bail!(
+                "Unknown log format {:?}, expected pretty, compact or json",
+                other
+            ),
+        }
+    }
+}
+
+// where per-report build directories and the source cache live
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct WorkspaceConfig {
+    // relative paths are resolved against the directory the builder is started from
+    pub root: PathBuf,
+    // directory holding mount.sh and get.sh, resolved like `root`
+    pub script_dir: PathBuf,
+}
+
+impl Default for WorkspaceConfig {
+    fn default() -> Self {
+        WorkspaceConfig {
+            root: PathBuf::from("workspace"),
+            script_dir: PathBuf::from("script"),
+        }
+    }
+}
+
+impl WorkspaceConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.root.as_os_str().is_empty() {
+            anyhow::bail!("workspace root must not be empty");
+        }
+        if self.script_dir.as_os_str().is_empty() {
+            anyhow::bail!("workspace script_dir must not be empty");
+        }
+        Ok(())
+    }
 }
 
 // proxy config
@@ -19,6 +93,207 @@ pub struct Config {
 pub struct ProxyConfig {
     pub host: String,
     pub port: u16,
+    #[serde(default)]
+    pub policy: ProxyPolicy,
+}
+
+impl ProxyConfig {
+    pub fn validate(&self) -> Result<()> {
+        // the auto policy reads the proxy from the environment and ignores host and port
+        if self.policy == ProxyPolicy::Auto {
+            return Ok(());
+        }
+        if self.host.trim().is_empty() {
+            anyhow::bail!("proxy host cannot be empty");
+        }
+        if self.port == 0 {
+            anyhow::bail!("proxy port must be greater than 0");
+        }
+        Ok(())
+    }
+}
+
+// which downloads go through the configured proxy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyPolicy {
+    Always,
+    Never,
+    // kernel sources come straight from GitHub, syzbot assets through the proxy
+    #[default]
+    SyzkallerOnly,
+    // ignore host/port and follow HTTP_PROXY, HTTPS_PROXY and NO_PROXY
+    Auto,
+}
+
+// kernel build config
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct BuildConfig {
+    // keep the command, environment, config and log of a failed build under workspace/<id>/failure
+    pub keep_on_failure: bool,
+    // write build output to workspace/<id>/build.log instead of the console
+    pub capture_output: bool,
+    // fixed `make -j` value, overrides reserved_cpus
+    pub jobs: Option<usize>,
+    // cpus left for the rest of the machine when jobs is not set
+    pub reserved_cpus: usize,
+    // upper bound on a single build step, the whole nix-shell process group is killed after it
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub build_timeout: Option<Duration>,
+    // reruns of a build step whose nix-shell failed on a network error while fetching from
+    // the binary cache. compile errors are never retried
+    pub nix_retries: usize,
+    // compile through ccache with a cache in workspace/.cache/ccache shared by all reports
+    pub ccache: bool,
+}
+
+impl Default for BuildConfig {
+    fn default() -> Self {
+        BuildConfig {
+            keep_on_failure: false,
+            capture_output: true,
+            jobs: None,
+            reserved_cpus: 2,
+            build_timeout: Some(Duration::from_secs(4 * 3600)),
+            nix_retries: 1,
+            ccache: false,
+        }
+    }
+}
+
+impl BuildConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.jobs == Some(0) {
+            anyhow::bail!("build jobs must be greater than 0");
+        }
+        if self.build_timeout == Some(Duration::ZERO) {
+            anyhow::bail!("build build_timeout must be greater than 0");
+        }
+        Ok(())
+    }
+
+    // `make -j` value on a machine with `cpus` cpus, never below 1
+    pub fn jobs(&self, cpus: usize) -> usize {
+        self.jobs
+            .unwrap_or_else(|| cpus.saturating_sub(self.reserved_cpus))
+            .max(1)
+    }
+}
+
+// download and extraction config
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct DownloadConfig {
+    // source tarballs extracted at the same time across all reports
+    pub max_concurrent_extractions: usize,
+    // download stages run at the same time by a batch run, see pipeline::run_batch
+    pub max_concurrent_downloads: usize,
+    // share downloaded tarballs and extracted trees between reports through workspace/.cache
+    pub cache: bool,
+    // hard bound on a single download, including all of its retries
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub timeout: Option<Duration>,
+    // attempts per download, only network errors and 5xx responses are retried
+    pub max_retries: usize,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub initial_backoff: Duration,
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub max_backoff: Duration,
+    // reproducers and kernel configs are fetched from here, e.g. a syzbot mirror
+    pub syzkaller_base: String,
+    // kernel sources are fetched as <kernel_archive_base>/<commit>.tar.gz
+    pub kernel_archive_base: String,
+    // clone commits the archive does not serve (404) with git, much slower than a tarball
+    pub git_fallback: bool,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            max_concurrent_extractions: 2,
+            max_concurrent_downloads: 4,
+            cache: true,
+            timeout: Some(Duration::from_secs(3600)),
+            max_retries: 3,
+            initial_backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(60),
+            syzkaller_base: "https://syzkaller.appspot.com/".to_string(),
+            kernel_archive_base: "https://github.com/torvalds/linux/archive/".to_string(),
+            git_fallback: false,
+        }
+    }
+}
+
+impl DownloadConfig {
+    pub fn validate(&self) -> Result<()> {
+        if self.max_concurrent_extractions == 0 {
+            anyhow::bail!("download max_concurrent_extractions must be greater than 0");
+        }
+        if self.max_concurrent_downloads == 0 {
+            anyhow::bail!("download max_concurrent_downloads must be greater than 0");
+        }
+        if self.timeout == Some(Duration::ZERO) {
+            anyhow::bail!("download timeout must be greater than 0");
+        }
+        if self.max_retries == 0 {
+            anyhow::bail!("download max_retries must be greater than 0");
+        }
+        if self.initial_backoff > self.max_backoff {
+            anyhow::bail!("download initial_backoff must not exceed max_backoff");
+        }
+        for (name, base) in [
+            ("syzkaller_base", &self.syzkaller_base),
+            ("kernel_archive_base", &self.kernel_archive_base),
+        ] {
+            if !base.starts_with("http://") && !base.starts_with("https://") {
+                anyhow::bail!("download {} must be an http(s) URL, got {:?}", name, base);
+            }
+        }
+        Ok(())
+    }
+
+    // `path` is a syzbot asset path from the report, e.g. `text?tag=ReproC&x=..`
+    pub fn syzkaller_url(&self, path: &str) -> String {
+        join_url(&self.syzkaller_base, path)
+    }
+
+    pub fn kernel_archive_url(&self, commit: &str) -> String {
+        join_url(&self.kernel_archive_base, &format!("{}.tar.gz", commit))
+    }
+}
+
+// the base may or may not end in a slash, the path may or may not start with one
+fn join_url(base: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        path.trim().trim_start_matches('/')
+    )
+}
+
+// how to log into the guest.
+// in toml: auth = "agent", auth = { key_file = "<path>" } or auth = { password = "<password>" }
+#[derive(Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthMethod {
+    KeyFile(PathBuf),
+    // whatever keys the agent behind SSH_AUTH_SOCK holds
+    Agent,
+    Password(String),
+}
+
+// keep passwords out of debug logs
+impl fmt::Debug for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthMethod::KeyFile(path) => f.debug_tuple("KeyFile").field(path).finish(),
+            AuthMethod::Agent => f.write_str("Agent"),
+            AuthMethod::Password(_) => f.write_str("Password(<redacted>)"),
+        }
+    }
 }
 
 // ssh config
@@ -28,7 +303,11 @@ pub struct SSHConfig {
     pub host: String,
     pub port: u16,
     pub user: String,
+    // the key file used when `auth` is not set
+    #[serde(default)]
     pub key_path: PathBuf,
+    #[serde(default)]
+    pub auth: Option<AuthMethod>,
 
     #[serde_as(as = "DurationSeconds<u64>")]
     pub timeout: Duration,
@@ -44,35 +323,80 @@ pub struct SSHConfig {
     pub compression: bool,
     #[serde_as(as = "Option<DurationSeconds<u64>>")]
     pub keep_alive_interval: Option<Duration>,
+    // reconnect once and retry when a command fails because the session dropped
+    #[serde(default)]
+    pub auto_reconnect: bool,
 }
 
+// the hardcoded defaults, settings.toml is not read. main loads the real config once with
+// Config::load() and passes it down
 impl Default for Config {
     fn default() -> Self {
-        load_config().unwrap_or_else(|e| {
-            error!(
-                "Failed to load config, using hardcoded default. Error: {:?}",
-                e
+        Config::fallback()
+    }
+}
+
+impl Config {
+    // settings.toml as found by find_config_file, with KB_* environment overrides applied.
+    // only a missing file falls back to the hardcoded defaults, a file that cannot be read,
+    // parsed or validated is an error, as is a $KERNEL_BUILDER_CONFIG pointing nowhere
+    pub fn load() -> Result<Config> {
+        let vars = std::env::vars_os()
+            .filter_map(|(key, value)| Some((key.into_string().ok()?, value.into_string().ok()?)));
+        apply_env(Config::load_file()?, vars)
+    }
+
+    fn load_file() -> Result<Config> {
+        let env_override = std::env::var_os(CONFIG_ENV).filter(|path| !path.is_empty());
+        let explicit = env_override.is_some();
+        let config_file = find_config_file(env_override, &std::env::current_dir()?);
+
+        if !explicit && !config_file.exists() {
+            warn!(
+                "No config file found at {:?}, using hardcoded default",
+                config_file
             );
-            Config {
-                proxy: ProxyConfig {
-                    host: "127.0.0.1".to_string(),
-                    port: 7890,
-                },
-                ssh: SSHConfig {
-                    host: "127.0.0.1".to_string(),
-                    port: 22,
-                    user: "root".to_string(),
-                    key_path: PathBuf::from("~/.ssh/debian-key"),
-                    timeout: Duration::from_secs(30),
-                    max_retries: 5,
-                    initial_backoff: Duration::from_secs(1),
-                    max_backoff: Duration::from_secs(30),
-                    compression: false,
-                    strict_host_key_checking: false,
-                    keep_alive_interval: Some(Duration::from_secs(60)),
-                },
-            }
-        })
+            return Ok(Config::fallback());
+        }
+        load_config(&config_file)
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.proxy.validate()?;
+        self.download.validate()?;
+        self.build.validate()?;
+        self.workspace.validate()?;
+        Ok(())
+    }
+
+    // used when config/settings.toml is missing or invalid
+    fn fallback() -> Config {
+        Config {
+            proxy: ProxyConfig {
+                host: "127.0.0.1".to_string(),
+                port: 7890,
+                policy: ProxyPolicy::default(),
+            },
+            ssh: SSHConfig {
+                host: "127.0.0.1".to_string(),
+                port: 22,
+                user: "root".to_string(),
+                key_path: PathBuf::from("~/.ssh/debian-key"),
+                auth: None,
+                timeout: Duration::from_secs(30),
+                max_retries: 5,
+                initial_backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(30),
+                compression: false,
+                strict_host_key_checking: false,
+                keep_alive_interval: Some(Duration::from_secs(60)),
+                auto_reconnect: false,
+            },
+            download: DownloadConfig::default(),
+            build: BuildConfig::default(),
+            workspace: WorkspaceConfig::default(),
+            log: LogConfig::default(),
+        }
     }
 }
 
@@ -88,19 +412,161 @@ impl SSHConfig {
                 "Max retries must be greater than 0".to_string(),
             ));
         }
+        match self.auth_method() {
+            AuthMethod::KeyFile(path) if path.as_os_str().is_empty() => {
+                return Err(SSHError::AuthenticationFailed(
+                    "No key file configured, set ssh.key_path or ssh.auth".to_string(),
+                ));
+            }
+            AuthMethod::KeyFile(path) => check_key_file(&path)?,
+            AuthMethod::Agent if std::env::var_os("SSH_AUTH_SOCK").is_none() => {
+                return Err(SSHError::AuthenticationFailed(
+                    "Agent authentication requires SSH_AUTH_SOCK to be set".to_string(),
+                ));
+            }
+            AuthMethod::Password(password) if password.is_empty() => {
+                return Err(SSHError::AuthenticationFailed(
+                    "Password cannot be empty".to_string(),
+                ));
+            }
+            _ => {}
+        }
         Ok(())
     }
+
+    // key file paths come back with `~` expanded
+    pub fn auth_method(&self) -> AuthMethod {
+        match self.auth.clone() {
+            Some(AuthMethod::KeyFile(path)) => AuthMethod::KeyFile(expand_home(&path)),
+            Some(auth) => auth,
+            None => AuthMethod::KeyFile(expand_home(&self.key_path)),
+        }
+    }
+}
+
+// catch a missing or too permissive key here instead of as a cryptic ssh failure at connect time
+fn check_key_file(path: &Path) -> Result<(), SSHError> {
+    let metadata = fs::metadata(path).map_err(|e| {
+        SSHError::AuthenticationFailed(format!(
+            "Key file {} is not readable: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    if !metadata.is_file() {
+        return Err(SSHError::AuthenticationFailed(format!(
+            "Key file {} is not a regular file",
+            path.display()
+        )));
+    }
+
+    // ssh refuses private keys that group or others can access
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode & 0o077 != 0 {
+            return Err(SSHError::AuthenticationFailed(format!(
+                "Key file {} has permissions {:04o}, which are too open; run chmod 600 on it",
+                path.display(),
+                mode
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+// `~` and `~/...` resolve against $HOME, anything else is returned unchanged
+pub fn expand_home(path: &Path) -> PathBuf {
+    let Ok(rest) = path.strip_prefix("~") else {
+        return path.to_path_buf();
+    };
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(rest),
+        None => path.to_path_buf(),
+    }
+}
+
+// overrides where settings.toml is read from
+const CONFIG_ENV: &str = "KERNEL_BUILDER_CONFIG";
+
+// $KERNEL_BUILDER_CONFIG if set, otherwise the first config/settings.toml found walking up
+// from `start`. without either, start/config/settings.toml so the error names a sensible path
+fn find_config_file(env_override: Option<std::ffi::OsString>, start: &Path) -> PathBuf {
+    if let Some(path) = env_override.filter(|path| !path.is_empty()) {
+        return PathBuf::from(path);
+    }
+
+    start
+        .ancestors()
+        .map(|dir| dir.join("config").join("settings.toml"))
+        .find(|candidate| candidate.is_file())
+        .unwrap_or_else(|| start.join("config").join("settings.toml"))
 }
 
-// default load config from config/settings.toml
-fn load_config() -> Result<Config> {
-    let mut config_file = PathBuf::from(std::env::current_dir()?);
-    config_file.push("config");
-    config_file.push("settings.toml");
+// environment variables named KB_<SECTION>_<FIELD> override single fields, e.g. KB_SSH_PORT=2222
+// or KB_PROXY_HOST=10.0.0.1. precedence is env > settings.toml > hardcoded default
+const ENV_PREFIX: &str = "KB_";
+
+fn apply_env(config: Config, vars: impl IntoIterator<Item = (String, String)>) -> Result<Config> {
+    let mut value = toml::Value::try_from(&config).context("Failed to serialize config")?;
+    let mut overridden = false;
+
+    for (key, raw) in vars {
+        let Some(name) = key.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let name = name.to_lowercase();
+        // section names have no underscore, so KB_SSH_KEY_PATH is ssh.key_path
+        let Some((section, field)) = name.split_once('_') else {
+            warn!("Ignoring {}, expected {}<SECTION>_<FIELD>", key, ENV_PREFIX);
+            continue;
+        };
+        let Some(table) = value.get_mut(section).and_then(|s| s.as_table_mut()) else {
+            warn!("Ignoring {}, there is no [{}] config section", key, section);
+            continue;
+        };
+
+        let parsed = parse_env_value(table.get(field), &raw)
+            .with_context(|| format!("Invalid value for {}: {:?}", key, raw))?;
+        info!("Overriding {}.{} from {}", section, field, key);
+        table.insert(field.to_string(), parsed);
+        overridden = true;
+    }
 
+    if !overridden {
+        return Ok(config);
+    }
+    let config: Config = value
+        .try_into()
+        .context("Failed to apply config overrides from the environment")?;
+    config.validate()?;
+    Ok(config)
+}
+
+// string fields take the raw value so hosts and paths need no quoting, anything else is parsed
+// as a TOML value (KB_SSH_PORT=2222, KB_DOWNLOAD_CACHE=false). an unset optional field accepts
+// either
+fn parse_env_value(current: Option<&toml::Value>, raw: &str) -> Result<toml::Value> {
+    if let Some(toml::Value::String(_)) = current {
+        return Ok(toml::Value::String(raw.to_string()));
+    }
+    let parsed = toml::from_str::<toml::Table>(&format!("value = {}", raw))
+        .ok()
+        .filter(|table| table.len() == 1)
+        .and_then(|mut table| table.remove("value"));
+    match parsed {
+        Some(value) => Ok(value),
+        None if current.is_none() => Ok(toml::Value::String(raw.to_string())),
+        None => anyhow::bail!("not a valid TOML value"),
+    }
+}
+
+fn load_config(config_file: &Path) -> Result<Config> {
     info!("Loading configuration from: {:?}", config_file);
 
-    let config_content = fs::read_to_string(&config_file)
+    let config_content = fs::read_to_string(config_file)
         .with_context(|| format!("Failed to read config file: {:?}", config_file))?;
 
     info!(
@@ -111,6 +577,8 @@ fn load_config() -> Result<Config> {
     let config: Config = toml::from_str(&config_content)
         .with_context(|| format!("Failed to parse config file: {:?}", config_file))?;
 
+    config.validate()?;
+
     info!("Loaded configuration succeeded");
 
     Ok(config)
@@ -122,9 +590,257 @@ mod tests {
 
     #[test]
     fn test_default_config() {
-        let config = Config::default();
+        let config = Config::fallback();
         assert_eq!(config.proxy.host, "127.0.0.1");
-        assert_eq!(config.proxy.port, 9870);
+        assert_eq!(config.proxy.port, 7890);
         assert_eq!(config.ssh.port, 22);
+        assert!(config.proxy.validate().is_ok());
+
+        // the shipped settings must load, otherwise every run silently uses the fallback
+        let config = Config::load().unwrap();
+        assert_eq!(config.proxy.port, 7890);
+    }
+
+    #[test]
+    fn test_proxy_validate() {
+        let proxy = |s: &str| -> ProxyConfig { toml::from_str(s).unwrap() };
+        assert!(proxy("host = \"h\"\nport = 1").validate().is_ok());
+        assert!(proxy("host = \"\"\nport = 1").validate().is_err());
+        assert!(proxy("host = \"h\"\nport = 0").validate().is_err());
+        assert!(
+            proxy("host = \"\"\nport = 0\npolicy = \"auto\"")
+                .validate()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_proxy_policy() {
+        let proxy: ProxyConfig = toml::from_str("host = \"h\"\nport = 1").unwrap();
+        assert_eq!(proxy.policy, ProxyPolicy::SyzkallerOnly);
+
+        let proxy: ProxyConfig =
+            toml::from_str("host = \"h\"\nport = 1\npolicy = \"auto\"").unwrap();
+        assert_eq!(proxy.policy, ProxyPolicy::Auto);
+    }
+
+    #[test]
+    fn test_build_jobs() {
+        let mut build = BuildConfig::default();
+        assert_eq!(build.jobs(1), 1);
+        assert_eq!(build.jobs(2), 1);
+        assert_eq!(build.jobs(64), 62);
+
+        build.jobs = Some(8);
+        assert_eq!(build.jobs(1), 8);
+        assert_eq!(build.jobs(64), 8);
+    }
+
+    #[test]
+    fn test_auth_method() {
+        let ssh = |extra: &str| -> SSHConfig {
+            toml::from_str(&format!(
+                "host = \"h\"\nport = 22\nuser = \"root\"\nkey_path = \"/k\"\ntimeout = 1\nmax_retries = 1\ninitial_backoff = 1\nmax_backoff = 1\nstrict_host_key_checking = false\ncompression = false\n{}",
+                extra
+            ))
+            .unwrap()
+        };
+
+        assert_eq!(
+            ssh("").auth_method(),
+            AuthMethod::KeyFile(PathBuf::from("/k"))
+        );
+        assert_eq!(ssh("auth = \"agent\"").auth_method(), AuthMethod::Agent);
+        assert_eq!(
+            ssh("auth = { password = \"hunter2\" }").auth_method(),
+            AuthMethod::Password("hunter2".to_string())
+        );
+        assert!(!format!("{:?}", ssh("auth = { password = \"hunter2\" }")).contains("hunter2"));
+
+        assert!(matches!(
+            ssh("auth = { password = \"\" }").validate(),
+            Err(SSHError::AuthenticationFailed(_))
+        ));
+        assert!(matches!(
+            ssh("auth = { key_file = \"\" }").validate(),
+            Err(SSHError::AuthenticationFailed(_))
+        ));
+        assert!(ssh("auth = { password = \"hunter2\" }").validate().is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_key_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let key = dir.path().join("debian-key");
+        assert!(matches!(
+            check_key_file(&key),
+            Err(SSHError::AuthenticationFailed(_))
+        ));
+
+        fs::write(&key, "key").unwrap();
+        for (mode, ok) in [(0o600, true), (0o400, true), (0o644, false), (0o640, false)] {
+            fs::set_permissions(&key, fs::Permissions::from_mode(mode)).unwrap();
+            assert_eq!(check_key_file(&key).is_ok(), ok, "mode {:o}", mode);
+        }
+
+        assert!(matches!(
+            check_key_file(dir.path()),
+            Err(SSHError::AuthenticationFailed(_))
+        ));
+    }
+
+    #[test]
+    fn test_expand_home() {
+        let home = PathBuf::from(std::env::var_os("HOME").unwrap());
+        let key = expand_home(Path::new("~/.ssh/debian-key"));
+        assert!(key.is_absolute());
+        assert_eq!(key, home.join(".ssh/debian-key"));
+        assert_eq!(expand_home(Path::new("~")), home);
+
+        // only a leading `~` component is special
+        assert_eq!(expand_home(Path::new("/k/~/x")), PathBuf::from("/k/~/x"));
+        assert_eq!(expand_home(Path::new("~user/x")), PathBuf::from("~user/x"));
+    }
+
+    #[test]
+    fn test_log_format() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("compact".parse::<LogFormat>().unwrap(), LogFormat::Compact);
+        assert!("xml".parse::<LogFormat>().is_err());
+
+        let log: LogConfig = toml::from_str("format = \"json\"").unwrap();
+        assert_eq!(log.format, LogFormat::Json);
+        let log: LogConfig = toml::from_str("").unwrap();
+        assert_eq!(log.format, LogFormat::Pretty);
+    }
+
+    #[test]
+    fn test_find_config_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let nested = dir.path().join("workspace/abc");
+        std::fs::create_dir_all(&nested).unwrap();
+        let fallback = nested.join("config/settings.toml");
+
+        // nothing anywhere up the tree
+        assert_eq!(find_config_file(None, &nested), fallback);
+
+        let settings = dir.path().join("config/settings.toml");
+        std::fs::create_dir_all(settings.parent().unwrap()).unwrap();
+        std::fs::write(&settings, "").unwrap();
+        assert_eq!(find_config_file(None, &nested), settings);
+        assert_eq!(find_config_file(Some("".into()), &nested), settings);
+
+        let custom = dir.path().join("custom.toml");
+        assert_eq!(
+            find_config_file(Some(custom.clone().into()), &nested),
+            custom
+        );
+    }
+
+    #[test]
+    fn test_load_config_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.toml");
+
+        assert!(load_config(&path).is_err());
+
+        fs::write(&path, "[proxy\nhost = ").unwrap();
+        let err = load_config(&path).unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to parse config file"));
+
+        // parses, but fails validation
+        let shipped = fs::read_to_string("config/settings.toml").unwrap();
+        fs::write(&path, shipped.replace("port = 7890", "port = 0")).unwrap();
+        assert!(load_config(&path).is_err());
+
+        fs::write(&path, shipped).unwrap();
+        assert_eq!(load_config(&path).unwrap().proxy.port, 7890);
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let config = apply_env(
+            Config::fallback(),
+            env(&[
+                ("KB_SSH_HOST", "10.0.0.2"),
+                ("KB_SSH_PORT", "2200"),
+                ("KB_SSH_KEY_PATH", "/keys/id_ed25519"),
+                ("KB_PROXY_HOST", "proxy.internal"),
+                ("KB_PROXY_POLICY", "always"),
+                ("KB_DOWNLOAD_CACHE", "false"),
+                ("KB_BUILD_JOBS", "16"),
+                ("KB_SSH_AUTH", "agent"),
+                ("PATH", "/usr/bin"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.ssh.host, "10.0.0.2");
+        assert_eq!(config.ssh.port, 2200);
+        assert_eq!(config.ssh.key_path, PathBuf::from("/keys/id_ed25519"));
+        assert!(config.ssh.auth == Some(AuthMethod::Agent));
+        assert_eq!(config.proxy.host, "proxy.internal");
+        assert_eq!(config.proxy.policy, ProxyPolicy::Always);
+        assert!(!config.download.cache);
+        assert_eq!(config.build.jobs(4), 16);
+        // untouched fields keep the file (here: fallback) value
+        assert_eq!(config.proxy.port, 7890);
+        assert_eq!(config.ssh.user, "root");
+
+        // env wins over the file
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("settings.toml");
+        fs::copy("config/settings.toml", &path).unwrap();
+        let config = apply_env(load_config(&path).unwrap(), env(&[("KB_SSH_PORT", "22")])).unwrap();
+        assert_eq!(config.ssh.port, 22);
+        assert_eq!(config.ssh.user, "root");
+
+        // unknown sections are ignored, bad values and invalid results are errors
+        assert!(apply_env(Config::fallback(), env(&[("KB_NOPE_HOST", "x")])).is_ok());
+        assert!(apply_env(Config::fallback(), env(&[("KB_SSH_PORT", "abc")])).is_err());
+        assert!(apply_env(Config::fallback(), env(&[("KB_SSH_PORT", "70000")])).is_err());
+        assert!(apply_env(Config::fallback(), env(&[("KB_PROXY_PORT", "0")])).is_err());
+    }
+
+    #[test]
+    fn test_download_urls() {
+        let default = DownloadConfig::default();
+        assert_eq!(
+            default.kernel_archive_url("abc123"),
+            "https://github.com/torvalds/linux/archive/abc123.tar.gz"
+        );
+        assert_eq!(
+            default.syzkaller_url("/text?tag=ReproC&x=1"),
+            "https://syzkaller.appspot.com/text?tag=ReproC&x=1"
+        );
+
+        let mirror = DownloadConfig {
+            syzkaller_base: "http://mirror.internal/syzbot".to_string(),
+            kernel_archive_base: "https://git.internal/linux/archive/".to_string(),
+            ..DownloadConfig::default()
+        };
+        assert!(mirror.validate().is_ok());
+        assert_eq!(
+            mirror.kernel_archive_url("abc123"),
+            "https://git.internal/linux/archive/abc123.tar.gz"
+        );
+        assert_eq!(
+            mirror.syzkaller_url("text?tag=KernelConfig&x=2"),
+            "http://mirror.internal/syzbot/text?tag=KernelConfig&x=2"
+        );
+
+        let invalid = DownloadConfig {
+            syzkaller_base: "mirror.internal".to_string(),
+            ..DownloadConfig::default()
+        };
+        assert!(invalid.validate().is_err());
     }
 }
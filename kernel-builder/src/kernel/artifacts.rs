@@ -0,0 +1,134 @@
+use crate::parse::arch::{Architecture, select_architecture};
+use crate::parse::compiler::Compiler;
+use crate::parse::layout::Layout;
+use crate::parse::report::CrashReport;
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+// what a kernel build leaves in build/, for booting and for crash/gdb on a vmcore
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildArtifacts {
+    // the boot image, bzImage on x86_64 and Image on arm64 and riscv64
+    pub bzimage: PathBuf,
+    // the unstripped kernel with debug info
+    pub vmlinux: PathBuf,
+    pub system_map: PathBuf,
+    pub config: PathBuf,
+    // the build directory when the kernel was built with modules, the .ko files are below it
+    pub modules_dir: Option<PathBuf>,
+    // bear's database of the build, see kernel::compdb. not checked by verify(), a dry run or a
+    // build outside of bear has none
+    pub compile_commands: PathBuf,
+    // the compiler the report asks for and the one nix/shell.nix provided for the build, which
+    // may differ in the patch version. None for artifacts of an earlier build
+    pub requested_compiler: Option<Compiler>,
+    pub actual_compiler: Option<Compiler>,
+}
+
+impl BuildArtifacts {
+    // where a build of `arch` puts its artifacts, whether or not they exist yet
+    pub fn expected(layout: &Layout, arch: Architecture) -> BuildArtifacts {
+        let build_dir = layout.build_out_dir();
+        BuildArtifacts {
+            bzimage: layout.kernel_image_path(arch),
+            vmlinux: build_dir.join("vmlinux"),
+            system_map: build_dir.join("System.map"),
+            config: layout.config_path(),
+            modules_dir: None,
+            compile_commands: layout.compile_commands_path(),
+            requested_compiler: None,
+            actual_compiler: None,
+        }
+    }
+
+    // the artifacts of a finished build, an error names the first one missing
+    pub async fn locate(layout: &Layout, arch: Architecture) -> Result<BuildArtifacts> {
+        let mut artifacts = BuildArtifacts::expected(layout, arch);
+        artifacts.verify().await?;
+
+        // modules.order lists every module built, it is empty for a kernel without any
+        let modules_order = layout.build_out_dir().join("modules.order");
+        if fs::metadata(&modules_order)
+            .await
+            .is_ok_and(|meta| meta.len() > 0)
+        {
+            artifacts.modules_dir = Some(layout.build_out_dir());
+        }
+
+        Ok(artifacts)
+    }
+
+    pub async fn verify(&self) -> Result<()> {
+        let required: [(&str, &Path); 4] = [
+            ("kernel image", &self.bzimage),
+            ("vmlinux", &self.vmlinux),
+            ("System.map", &self.system_map),
+            ("kernel config", &self.config),
+        ];
+        for (what, path) in required {
+            if !fs::try_exists(path).await? {
+                anyhow::bail!("No {} at {}, build the kernel first", what, path.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+// the artifacts of the kernel built for crash `crash_index` of `report`
+pub async fn locate_artifacts(report: &CrashReport, crash_index: usize) -> Result<BuildArtifacts> {
+    let layout = Layout::for_crash(report, crash_index)?;
+    BuildArtifacts::locate(&layout, select_architecture(report)?).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse::parse_file;
+
+    #[tokio::test]
+    async fn test_locate_artifacts() {
+        let report = parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
+        let layout = Layout::new(&report).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let build = dir.path().join("build");
+
+        // the same file names, below a scratch build directory
+        let mut artifacts = BuildArtifacts::expected(&layout, Architecture::Amd64);
+        assert!(
+            artifacts
+                .bzimage
+                .ends_with("build/arch/x86_64/boot/bzImage")
+        );
+        assert!(
+            BuildArtifacts::expected(&layout, Architecture::Arm64)
+                .bzimage
+                .ends_with("build/arch/arm64/boot/Image")
+        );
+        for path in [
+            &mut artifacts.bzimage,
+            &mut artifacts.vmlinux,
+            &mut artifacts.system_map,
+            &mut artifacts.config,
+        ] {
+            let relative = path
+                .strip_prefix(layout.build_out_dir())
+                .unwrap()
+                .to_path_buf();
+            *path = build.join(relative);
+        }
+
+        let error = artifacts.verify().await.unwrap_err().to_string();
+        assert!(error.contains("No kernel image"), "{}", error);
+
+        std::fs::create_dir_all(artifacts.bzimage.parent().unwrap()).unwrap();
+        for path in [&artifacts.bzimage, &artifacts.vmlinux, &artifacts.config] {
+            std::fs::write(path, "").unwrap();
+        }
+        let error = artifacts.verify().await.unwrap_err().to_string();
+        assert!(error.contains("No System.map"), "{}", error);
+
+        std::fs::write(&artifacts.system_map, "").unwrap();
+        artifacts.verify().await.unwrap();
+    }
+}
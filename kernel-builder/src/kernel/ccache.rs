@@ -0,0 +1,181 @@
+use crate::kernel::nix::NixCommand;
+use crate::parse::layout::Layout;
+use crate::parse::workspace::default_workspace;
+use crate::util::shell_quote;
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::PathBuf;
+
+// compiler cache in workspace/.cache/ccache, shared by the builds of every report
+#[derive(Debug, Clone)]
+pub struct Ccache {
+    dir: PathBuf,
+    base_dir: PathBuf,
+}
+
+impl Ccache {
+    // paths under the workspace root are hashed relative to the build directory, and the
+    // directory itself is left out of the hash (CCACHE_NOHASHDIR), so reports building the same
+    // commit can hit each other's objects. the price: an object taken from another report keeps
+    // that report's build directory as DW_AT_comp_dir, so gdb may need `set substitute-path`
+    pub fn new(layout: &Layout) -> Ccache {
+        Ccache {
+            dir: layout.ccache_dir(),
+            base_dir: default_workspace().root().to_path_buf(),
+        }
+    }
+
+    // variable assignments to put in front of a command, nix-shell --pure drops our environment
+    pub fn env(&self) -> String {
+        format!(
+            "CCACHE_DIR={} CCACHE_BASEDIR={} CCACHE_NOHASHDIR=1",
+            shell_quote(&self.dir.to_string_lossy()),
+            shell_quote(&self.base_dir.to_string_lossy())
+        )
+    }
+
+    // make's CC, with `cc` run through ccache
+    pub fn compiler(&self, cc: &str) -> String {
+        format!("CC={}", shell_quote(&format!("ccache {}", cc)))
+    }
+
+    // the cache's counters, which add up over every build that used it
+    pub async fn stats(&self, nix_cmd: &NixCommand) -> Result<CcacheStats> {
+        let output = nix_cmd
+            .output(&format!("{} ccache -s", self.env()))
+            .await
+            .context("Failed to read the ccache statistics")?;
+        CcacheStats::parse(&output)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CcacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CcacheStats {
+    // `ccache -s` of ccache 4 ("Hits: 12 / 20") and of ccache 3 ("cache hit (direct) 12")
+    pub fn parse(output: &str) -> Result<CcacheStats> {
+        let count = |value: &str| -> Option<u64> { value.split_whitespace().next()?.parse().ok() };
+
+        let mut hits = None;
+        let mut misses = None;
+        for line in output.lines().map(str::trim) {
+            // ccache 4 repeats Hits and Misses per storage backend, the first ones are the totals
+            if let Some(value) = line.strip_prefix("Hits:") {
+                hits = hits.or(count(value));
+            } else if let Some(value) = line.strip_prefix("Misses:") {
+                misses = misses.or(count(value));
+            } else if let Some(value) = line
+                .strip_prefix("cache hit (direct)")
+                .or_else(|| line.strip_prefix("cache hit (preprocessed)"))
+            {
+                *hits.get_or_insert(0) += count(value).unwrap_or(0);
+            } else if let Some(value) = line.strip_prefix("cache miss") {
+                misses = misses.or(count(value));
+            }
+        }
+
+        match (hits, misses) {
+            (Some(hits), Some(misses)) => Ok(CcacheStats { hits, misses }),
+            _ => anyhow::bail!("Unrecognized ccache -s output:\n{}", output),
+        }
+    }
+
+    // what was counted between `earlier` and self
+    pub fn since(&self, earlier: &CcacheStats) -> CcacheStats {
+        CcacheStats {
+            hits: self.hits.saturating_sub(earlier.hits),
+            misses: self.misses.saturating_sub(earlier.misses),
+        }
+    }
+
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 * 100.0 / total as f64)
+    }
+}
+
+impl fmt::Display for CcacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} hits, {} misses", self.hits, self.misses)?;
+        if let Some(rate) = self.hit_rate() {
+            write!(f, " ({:.1}% hit rate)", rate)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stats() {
+        let ccache4 = "\
+Cacheable calls:   120 / 140 (85.71%)
+  Hits:             90 / 120 (75.00%)
+    Direct:         80 /  90 (88.89%)
+    Preprocessed:   10 /  90 (11.11%)
+  Misses:           30 / 120 (25.00%)
+Uncacheable calls:  20 / 140 (14.29%)
+Local storage:
+  Cache size (GB): 1.2 / 5.0 (24.00%)
+  Hits:             90 / 120 (75.00%)
+  Misses:           30 / 120 (25.00%)
+";
+        assert_eq!(
+            CcacheStats::parse(ccache4).unwrap(),
+            CcacheStats {
+                hits: 90,
+                misses: 30
+            }
+        );
+
+        let ccache3 = "\
+cache directory                     /srv/workspace/.cache/ccache
+cache hit (direct)                    80
+cache hit (preprocessed)              10
+cache miss                            30
+cache hit rate                     75.00 %
+called for link                        4
+";
+        assert_eq!(
+            CcacheStats::parse(ccache3).unwrap(),
+            CcacheStats::parse(ccache4).unwrap()
+        );
+
+        assert!(CcacheStats::parse("ccache: command not found").is_err());
+    }
+
+    #[test]
+    fn test_stats_since() {
+        let before = CcacheStats {
+            hits: 90,
+            misses: 30,
+        };
+        let after = CcacheStats {
+            hits: 190,
+            misses: 30,
+        };
+        let build = after.since(&before);
+        assert_eq!(build.to_string(), "100 hits, 0 misses (100.0% hit rate)");
+        assert_eq!(
+            CcacheStats::default().since(&before).to_string(),
+            "0 hits, 0 misses"
+        );
+    }
+
+    #[test]
+    fn test_env_shares_across_reports() {
+        let report = crate::parse::parse::parse_file(
+            "datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json",
+        )
+        .unwrap();
+        let env = Ccache::new(&Layout::new(&report).unwrap()).env();
+        assert!(env.contains("CCACHE_BASEDIR="));
+        assert!(env.ends_with(" CCACHE_NOHASHDIR=1"));
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::info;
+
+// one entry of a clang compilation database as bear writes it. `arguments` or `command`,
+// `output` and whatever else the writer put in are kept as they are
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CompileCommand {
+    pub directory: String,
+    pub file: String,
+    #[serde(flatten)]
+    pub rest: Map<String, Value>,
+}
+
+impl CompileCommand {
+    // `file` resolved against `directory`, what identifies an entry
+    pub fn source_path(&self) -> PathBuf {
+        Path::new(&self.directory).join(&self.file)
+    }
+}
+
+// a compile_commands.json, see https://clang.llvm.org/docs/JSONCompilationDatabase.html
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompilationDatabase {
+    entries: Vec<CompileCommand>,
+}
+
+impl CompilationDatabase {
+    pub async fn load(path: &Path) -> Result<CompilationDatabase> {
+        let content = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let entries = serde_json::from_str(&content)
+            .with_context(|| format!("Malformed compilation database {}", path.display()))?;
+        Ok(CompilationDatabase { entries })
+    }
+
+    // written next to `path` first and renamed over it, clangd may be reading the old one
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+        fs::write(&partial, serde_json::to_string_pretty(&self.entries)?)
+            .await
+            .with_context(|| format!("Failed to write {}", partial.display()))?;
+        fs::rename(&partial, path)
+            .await
+            .with_context(|| format!("Failed to replace {}", path.display()))?;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[CompileCommand] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // take over the entries of `update`, typically from an incremental rebuild that only
+    // compiled what changed. an entry for the same source file replaces ours in place, new
+    // files are appended. returns how many entries were replaced and added
+    pub fn merge(&mut self, update: CompilationDatabase) -> (usize, usize) {
+        let mut index: HashMap<PathBuf, usize> = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(i, entry)| (entry.source_path(), i))
+            .collect();
+
+        let (mut replaced, mut added) = (0, 0);
+        for entry in update.entries {
+            match index.get(&entry.source_path()) {
+                Some(&i) => {
+                    self.entries[i] = entry;
+                    replaced += 1;
+                }
+                None => {
+                    index.insert(entry.source_path(), self.entries.len());
+                    self.entries.push(entry);
+                    added += 1;
+                }
+            }
+        }
+        (replaced, added)
+    }
+}
+
+// fold the database of an incremental build at `update` into `base`, which is created from it
+// when there is none yet. `update` is removed once merged, returns the path of `base`
+pub async fn merge_into(base: &Path, update: &Path) -> Result<PathBuf> {
+    let rebuilt = CompilationDatabase::load(update).await?;
+    let merged = if fs::try_exists(base).await? {
+        let mut merged = CompilationDatabase::load(base).await?;
+        let (replaced, added) = merged.merge(rebuilt);
+        info!(
+            "Updated {} and added {} entries of {} ({} in total)",
+            replaced,
+            added,
+            base.display(),
+            merged.len()
+        );
+        merged
+    } else {
+        info!(
+            "No {} yet, starting it with the {} rebuilt entries",
+            base.display(),
+            rebuilt.len()
+        );
+        rebuilt
+    };
+    merged.save(base).await?;
+    fs::remove_file(update)
+        .await
+        .with_context(|| format!("Failed to remove {}", update.display()))?;
+    Ok(base.to_path_buf())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(file: &str, flags: &str) -> Value {
+        serde_json::json!({
+            "directory": "/ws/linux",
+            "file": file,
+            "arguments": ["gcc", flags, "-c", file],
+            "output": file.replace(".c", ".o"),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_merge_into() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("compile_commands.json");
+        let update = dir.path().join("rebuild_compile_commands.json");
+
+        std::fs::write(
+            &base,
+            Value::Array(vec![entry("mm/slub.c", "-O2"), entry("fs/open.c", "-O2")]).to_string(),
+        )
+        .unwrap();
+        std::fs::write(
+            &update,
+            Value::Array(vec![entry("fs/open.c", "-O1"), entry("fs/new.c", "-O2")]).to_string(),
+        )
+        .unwrap();
+
+        assert_eq!(merge_into(&base, &update).await.unwrap(), base);
+        assert!(!update.exists());
+
+        let merged = CompilationDatabase::load(&base).await.unwrap();
+        let files: Vec<&str> = merged.entries().iter().map(|e| e.file.as_str()).collect();
+        assert_eq!(files, vec!["mm/slub.c", "fs/open.c", "fs/new.c"]);
+        assert_eq!(merged.entries()[1].rest["arguments"][1], "-O1");
+        assert_eq!(merged.entries()[1].rest["output"], "fs/open.o");
+    }
+
+    #[tokio::test]
+    async fn test_merge_without_base() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("compile_commands.json");
+        let update = dir.path().join("rebuild_compile_commands.json");
+        std::fs::write(
+            &update,
+            Value::Array(vec![entry("fs/open.c", "-O2")]).to_string(),
+        )
+        .unwrap();
+
+        merge_into(&base, &update).await.unwrap();
+        assert_eq!(CompilationDatabase::load(&base).await.unwrap().len(), 1);
+
+        std::fs::write(&update, "{").unwrap();
+        assert!(merge_into(&base, &update).await.is_err());
+    }
+}
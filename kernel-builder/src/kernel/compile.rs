@@ -1,119 +1,465 @@
-use crate::parse::compiler::{select_compiler, CompilerType};
-use crate::parse::parse::{build_path, kernel_source_path};
-use crate::parse::report::CrashReport;
+use crate::config::config::{BuildConfig, Config};
+use crate::kernel::artifacts::BuildArtifacts;
+use crate::kernel::ccache::{Ccache, CcacheStats};
+use crate::kernel::compdb;
+use crate::kernel::download::link_tree;
+use crate::kernel::nix::{NixCommand, verify_compiler_available};
+use crate::parse::arch::{Architecture, select_architecture};
+use crate::parse::compiler::{CompilerType, select_compiler};
+use crate::parse::layout::Layout;
+use crate::parse::report::{CrashReport, ExperimentMode, FixSelector};
+use crate::script::tool::require_tool;
+use crate::util::shell_quote;
 use anyhow::{Context, Result};
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::SystemTime;
+use thiserror::Error;
 use tokio::fs;
 use tokio::fs::try_exists;
 use tokio::process::Command;
-use tracing::info;
-
-struct NixCommand {
-    shell_script: PathBuf,
-    compiler: String,
-    working_dir: PathBuf,
-}
-impl NixCommand {
-    fn new(shell_script: PathBuf, compiler: &str, working_dir: PathBuf) -> Self {
-        Self {
-            shell_script,
-            compiler: compiler.to_string(),
-            working_dir,
-        }
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+// knobs for make_kernel / rebuild_kernel
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    // log the rendered commands and return without building
+    pub dry_run: bool,
+    // run headers_install in rebuild_kernel even if no uapi header changed
+    pub force_headers: bool,
+    // which of report.crashes to build, 0 is the one syzbot lists first
+    pub crash_index: usize,
+    // aborts the running build step, cancelled by the Ctrl-C handler in main
+    pub cancel: CancellationToken,
+    // settings.toml as loaded once by main, the hardcoded defaults unless set
+    pub config: Arc<Config>,
+    // build the tree the crash is reproduced on, or the one with the fix
+    pub mode: ExperimentMode,
+    // the fix commit a VerifyFix build uses
+    pub fix: FixSelector,
+}
+
+impl BuildOptions {
+    // the commit these options build, validating the report's commits
+    pub fn build_commit<'a>(&self, report: &'a CrashReport) -> Result<&'a str> {
+        Ok(report.build_commit_with_fix(self.mode, &self.fix, self.crash_index)?)
     }
 
-    async fn execute(&self, command: &str) -> Result<()> {
-        let status = Command::new("nix-shell")
-            .arg(&self.shell_script)
-            .arg("--pure")
-            .arg("--argstr")
-            .arg("compiler")
-            .arg(&self.compiler)
-            .arg("--run")
-            .arg(command)
-            .current_dir(&self.working_dir)
-            .stdout(std::process::Stdio::inherit())
-            .stderr(std::process::Stdio::inherit())
-            .status()
+    // the layout of the crash with the source tree of the commit these options build
+    pub fn layout(&self, report: &CrashReport) -> Result<Layout> {
+        let commit = report.experiment_commit(self.mode, &self.fix, self.crash_index)?;
+        Layout::for_commit(report, self.crash_index, commit)
+    }
+}
+
+// source directories whose headers end up in the headers_install output for `arch`
+fn uapi_dirs(arch: Architecture) -> [String; 2] {
+    [
+        "include/uapi".to_string(),
+        format!("arch/{}/include/uapi", arch.source_arch()),
+    ]
+}
+
+// run one build step, into build.log when `capture_output` is set, on the console otherwise
+async fn run_build_step(
+    nix_cmd: &NixCommand,
+    layout: &Layout,
+    config: &BuildConfig,
+    command: &str,
+) -> Result<()> {
+    if config.capture_output {
+        nix_cmd
+            .execute_logged(command, &layout.build_log_path())
             .await
-            .context("Failed to execute nix-shell command")?;
+    } else {
+        nix_cmd.execute(command).await
+    }
+}
 
-        if !status.success() {
-            anyhow::bail!(
-                "Command failed with exit code: {:?}\nCommand: {}",
-                status.code(),
-                command
-            );
+// start a fresh build.log for this build
+async fn reset_build_log(layout: &Layout) -> Result<()> {
+    let log_path = layout.build_log_path();
+    if try_exists(&log_path).await? {
+        fs::remove_file(&log_path).await?;
+    }
+    info!("Build output goes to {}", log_path.display());
+    Ok(())
+}
+
+async fn install_headers(
+    nix_cmd: &NixCommand,
+    layout: &Layout,
+    config: &BuildConfig,
+    command: &str,
+) -> Result<()> {
+    info!("start linux headers install");
+
+    run_build_step(nix_cmd, layout, config, command)
+        .await
+        .context("Failed to execute header install command")?;
+
+    fs::write(layout.headers_stamp(), "")
+        .await
+        .context("Failed to record the headers install")?;
+
+    Ok(())
+}
+
+// whether the installed headers are missing or older than a header under the uapi directories
+async fn headers_stale(layout: &Layout, arch: Architecture) -> Result<bool> {
+    let stamp = layout.headers_stamp();
+    let Ok(metadata) = fs::metadata(&stamp).await else {
+        return Ok(true);
+    };
+    let installed_at = metadata.modified()?;
+
+    let source_dir = layout.source_dir();
+    tokio::task::spawn_blocking(move || -> Result<bool> {
+        for dir in uapi_dirs(arch) {
+            if let Some(newest) = newest_mtime(&source_dir.join(dir))?
+                && newest > installed_at
+            {
+                return Ok(true);
+            }
         }
+        Ok(false)
+    })
+    .await?
+}
+
+// latest modification time of any file below `dir`, None if it has no files
+fn newest_mtime(dir: &Path) -> Result<Option<SystemTime>> {
+    let mut newest = None;
+    if !dir.exists() {
+        return Ok(newest);
+    }
 
-        Ok(())
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+    {
+        let entry = entry?;
+        let mtime = if entry.file_type()?.is_dir() {
+            newest_mtime(&entry.path())?
+        } else {
+            Some(entry.metadata()?.modified()?)
+        };
+        newest = newest.max(mtime);
     }
+
+    Ok(newest)
 }
-pub async fn make_kernel(report: &Arc<CrashReport>) -> Result<()> {
-    let build_dir = build_path(report);
-    let compiler = select_compiler(report)?;
-    let kernel_source_dir = kernel_source_path(report);
-    let shell_script_path = env::current_dir()?.join("nix").join("shell.nix");
 
-    info!(
-        "Starting kernel compilation with compiler: {}",
-        format!(
-            "{}-{}.{}.{}",
-            compiler.compiler_type.to_string(),
-            compiler.major,
-            compiler.minor,
-            compiler.patch
-        )
+// make invocation of a kernel build, recorded by bear into `compile_commands`. with `ccache`
+// the compiler is run through it
+fn kernel_make_command(
+    compiler_type: CompilerType,
+    compile_commands: &Path,
+    make_args: &str,
+    jobs: usize,
+    ccache: Option<&Ccache>,
+) -> String {
+    let bear = format!(
+        "bear --output {} --",
+        shell_quote(&compile_commands.to_string_lossy())
     );
-
-    let num_cpu = num_cpus::get();
-    let make_cmd = match compiler.compiler_type {
+    let env = ccache
+        .map(|ccache| format!("{} ", ccache.env()))
+        .unwrap_or_default();
+    let cc = ccache.map(|ccache| ccache.compiler(&compiler_type.to_string()));
+    match compiler_type {
         CompilerType::GCC => {
-            format!("bear -- make O=../build -j{}", num_cpu - 2)
+            let cc = cc.map(|cc| format!(" {}", cc)).unwrap_or_default();
+            format!("{}{} make {}{} -j{}", env, bear, make_args, cc, jobs)
         }
         CompilerType::CLANG => {
+            let cc = cc.unwrap_or_else(|| "CC=clang".to_string());
             format!(
-                "bear -- make O=../build LLVM=1 CC=clang LD=ld.lld AR=llvm-ar NM=llvm-nm OBJCOPY=llvm-objcopy -j{}",
-                num_cpu - 2
+                "{}{} make {} LLVM=1 {} LD=ld.lld AR=llvm-ar NM=llvm-nm OBJCOPY=llvm-objcopy -j{}",
+                env, bear, make_args, cc, jobs
             )
         }
+    }
+}
+
+// ccache's counters before a build, None without ccache or when they can't be read
+async fn ccache_stats(ccache: Option<&Ccache>, nix_cmd: &NixCommand) -> Option<CcacheStats> {
+    match ccache?.stats(nix_cmd).await {
+        Ok(stats) => Some(stats),
+        Err(e) => {
+            warn!("{:#}", e);
+            None
+        }
+    }
+}
+
+// log the hits and misses of the build that ran since `before` was read. the cache is
+// shared, so builds of other reports running at the same time are counted as well
+async fn log_ccache_stats(
+    ccache: Option<&Ccache>,
+    nix_cmd: &NixCommand,
+    before: Option<CcacheStats>,
+) {
+    let Some(before) = before else {
+        return;
     };
+    if let Some(after) = ccache_stats(ccache, nix_cmd).await {
+        info!("ccache: {}", after.since(&before));
+    }
+}
+
+// log what a build would run, used instead of executing it in dry-run mode
+fn log_dry_run(nix_cmd: &NixCommand, commands: &[&str]) {
+    info!(
+        "dry run, working directory: {}",
+        nix_cmd.working_dir().display()
+    );
+    for command in commands {
+        info!("dry run: {}", nix_cmd.render(command));
+    }
+}
+
+// preserve a failed build for post-mortem when `keep_on_failure` is set
+async fn keep_failure(
+    report: &CrashReport,
+    options: &BuildOptions,
+    nix_cmd: &NixCommand,
+    command: &str,
+    error: &anyhow::Error,
+) {
+    if !options.config.build.keep_on_failure {
+        return;
+    }
+
+    match preserve_failure(report, options.crash_index, nix_cmd, command, error).await {
+        Ok(failure_dir) => info!("Failed build preserved in {}", failure_dir.display()),
+        Err(e) => warn!("Failed to preserve the failed build: {:#}", e),
+    }
+}
 
-    let compiler_str = format!("{}-{}", compiler.compiler_type.to_string(), compiler.major);
-    let nix_cmd = NixCommand::new(shell_script_path, &compiler_str, kernel_source_dir);
+async fn preserve_failure(
+    report: &CrashReport,
+    crash_index: usize,
+    nix_cmd: &NixCommand,
+    command: &str,
+    error: &anyhow::Error,
+) -> Result<PathBuf> {
+    let layout = Layout::for_crash(report, crash_index)?;
+    let failure_dir = layout.failure_dir();
+    fs::create_dir_all(&failure_dir)
+        .await
+        .with_context(|| format!("Failed to create directory: {}", failure_dir.display()))?;
+
+    let rendered = nix_cmd.render(command);
+    fs::write(
+        failure_dir.join("command.sh"),
+        format!("#!/bin/sh\n{}\n", rendered),
+    )
+    .await?;
 
-    nix_cmd
-        .execute(&make_cmd)
+    // the environment the build saw only exists inside nix-shell, so ask it again
+    let environment = nix_cmd
+        .output("env")
         .await
-        .context("Failed to execute nix-shell command")?;
+        .unwrap_or_else(|e| format!("failed to capture the nix-shell environment: {:#}\n", e));
+    fs::write(failure_dir.join("environment.txt"), environment).await?;
+
+    let config_path = layout.config_path();
+    if try_exists(&config_path).await? {
+        fs::copy(&config_path, failure_dir.join("config")).await?;
+    }
+
+    let log_path = layout.build_log_path();
+    let log_note = if try_exists(&log_path).await? {
+        fs::copy(&log_path, failure_dir.join("build.log")).await?;
+        "- `build.log`: captured output of the failed build"
+    } else {
+        "- no build log was captured, the build output went to the console"
+    };
+
+    let readme = format!(
+        "# Failed build of report {id}\n\n\
+         Error:\n\n```\n{error:#}\n```\n\n\
+         - `command.sh`: the exact nix-shell invocation that failed\n\
+         - `environment.txt`: environment variables inside the nix-shell\n\
+         - `config`: the kernel .config used for the build\n\
+         {log_note}\n\n\
+         The build tree is left in place at `{build}`.\n\
+         To reproduce the failure by hand run:\n\n```\nsh {command_sh}\n```\n",
+        id = report.id,
+        build = layout.build_out_dir().display(),
+        command_sh = failure_dir.join("command.sh").display(),
+    );
+    fs::write(failure_dir.join("README.md"), readme).await?;
+
+    Ok(failure_dir)
+}
+
+// build the kernel under bear, returns where the image, vmlinux and the compile_commands.json
+// it recorded are. make skips what an earlier build left in place, so bear's database is merged
+// into the existing one instead of replacing it
+pub async fn make_kernel(
+    report: &Arc<CrashReport>,
+    options: &BuildOptions,
+) -> Result<BuildArtifacts> {
+    let layout = options.layout(report)?;
+    let compiler = select_compiler(report, options.crash_index)?;
+    let kernel_source_dir = layout.source_dir();
+    let shell_script_path = env::current_dir()?.join("nix").join("shell.nix");
+
+    info!("Starting kernel compilation with compiler: {}", compiler);
+
+    let arch = select_architecture(report)?;
+    let make_args = format!("{} {}", layout.make_dirs_args(), arch.make_args());
+    info!("Building for {} ({})", arch, arch.make_args());
+
+    let build = &options.config.build;
+    let jobs = build.jobs(num_cpus::get());
+    let compile_commands = layout.compile_commands_path();
+    let recorded_commands = layout.rebuild_compile_commands_path();
+    let ccache = build.ccache.then(|| Ccache::new(&layout));
+    let make_cmd = kernel_make_command(
+        compiler.compiler_type,
+        &recorded_commands,
+        &make_args,
+        jobs,
+        ccache.as_ref(),
+    );
+
+    let header_install_cmd = format!("make {} headers_install", make_args);
+    let compiler_str = compiler.nix_arg();
+    let nix_cmd = NixCommand::new(shell_script_path, &compiler_str, kernel_source_dir.clone())
+        .with_timeout(build.build_timeout)
+        .with_retries(build.nix_retries)
+        .with_cancel(options.cancel.clone());
+
+    if options.dry_run {
+        log_dry_run(&nix_cmd, &[&make_cmd, &header_install_cmd]);
+        let mut artifacts = BuildArtifacts::expected(&layout, arch);
+        artifacts.requested_compiler = Some(compiler);
+        return Ok(artifacts);
+    }
+
+    let actual_compiler = verify_compiler_available(&nix_cmd, &compiler).await?;
+
+    reset_build_log(&layout).await?;
+
+    let ccache_before = ccache_stats(ccache.as_ref(), &nix_cmd).await;
+    if let Err(e) = run_build_step(&nix_cmd, &layout, build, &make_cmd).await {
+        keep_failure(report, options, &nix_cmd, &make_cmd, &e).await;
+        return Err(e.context("Failed to execute nix-shell command"));
+    }
 
     info!("compilation succeeded");
+    log_ccache_stats(ccache.as_ref(), &nix_cmd, ccache_before).await;
+
+    let mut artifacts = BuildArtifacts::locate(&layout, arch).await?;
+    artifacts.requested_compiler = Some(compiler);
+    artifacts.actual_compiler = Some(actual_compiler);
 
-    let bz_image_path = build_dir.join("build").join("arch/x86_64/boot/bzImage");
-    if !try_exists(&bz_image_path).await? {
-        anyhow::bail!("bzImage not found in: {}", bz_image_path.display());
+    // bear writes no database when make had nothing to compile
+    if try_exists(&recorded_commands).await? {
+        compdb::merge_into(&compile_commands, &recorded_commands).await?;
     }
 
-    info!("start linux headers install");
+    install_headers(&nix_cmd, &layout, build, &header_install_cmd).await?;
+    Ok(artifacts)
+}
 
-    let header_install_cmd = "make O=../build headers_install INSTALL_HDR_PATH=../install";
+// build reproducer.c with the report's compiler against the headers make_kernel installed,
+// into a static binary that runs in the guest without a toolchain. diagnostics are kept in
+// reproducer.log, warnings are logged and errors returned
+pub async fn compile_reproducer(report: &CrashReport, options: &BuildOptions) -> Result<PathBuf> {
+    let layout = options.layout(report)?;
+    build_reproducer(report, &layout, options).await
+}
 
-    nix_cmd
-        .execute(header_install_cmd)
-        .await
-        .context("Failed to execute header install command")?;
+async fn build_reproducer(
+    report: &CrashReport,
+    layout: &Layout,
+    options: &BuildOptions,
+) -> Result<PathBuf> {
+    let compiler = select_compiler(report, options.crash_index)?;
+    let shell_script_path = env::current_dir()?.join("nix").join("shell.nix");
 
-    Ok(())
+    let source = layout.reproducer_path();
+    let binary = layout.reproducer_binary_path();
+    let log_path = layout.reproducer_log_path();
+    let include_dir = layout.install_dir().join("include");
+    let command = format!(
+        "{} -pthread -static -I {} -o {} {} 2> {}",
+        compiler.binary(),
+        shell_quote(&include_dir.to_string_lossy()),
+        shell_quote(&binary.to_string_lossy()),
+        shell_quote(&source.to_string_lossy()),
+        shell_quote(&log_path.to_string_lossy())
+    );
+    let nix_cmd = NixCommand::new(
+        shell_script_path,
+        &compiler.nix_arg(),
+        layout.root().to_path_buf(),
+    );
+
+    if options.dry_run {
+        log_dry_run(&nix_cmd, &[&command]);
+        return Ok(binary);
+    }
+
+    if !try_exists(&source).await? {
+        anyhow::bail!("No C reproducer at {}, download it first", source.display());
+    }
+    if !try_exists(&include_dir).await? {
+        anyhow::bail!(
+            "No installed headers at {}, build the kernel first",
+            include_dir.display()
+        );
+    }
+    // a stale binary must not pass for the output of this build
+    if try_exists(&binary).await? {
+        fs::remove_file(&binary).await?;
+    }
+
+    info!("Compiling {} with {}", source.display(), compiler);
+    let result = nix_cmd.output(&command).await;
+    let diagnostics = fs::read_to_string(&log_path).await.unwrap_or_default();
+    let diagnostics = diagnostics.trim();
+
+    if let Err(e) = result {
+        return Err(e.context(format!(
+            "Failed to compile {}:\n{}",
+            source.display(),
+            diagnostics
+        )));
+    }
+    if !diagnostics.is_empty() {
+        warn!(
+            "Compiler diagnostics for {}:\n{}",
+            source.display(),
+            diagnostics
+        );
+    }
+    if !try_exists(&binary).await? {
+        anyhow::bail!(
+            "Compiler succeeded but produced no binary at {}",
+            binary.display()
+        );
+    }
+
+    info!("Reproducer binary ready: {}", binary.display());
+    Ok(binary)
 }
 
-pub async fn apply_patch(report: &Arc<CrashReport>, patch: PathBuf) -> Result<()> {
+pub async fn apply_patch(
+    report: &Arc<CrashReport>,
+    crash_index: usize,
+    patch: PathBuf,
+) -> Result<()> {
     if !fs::try_exists(&patch).await? {
         anyhow::bail!("Patch file does not exist: {}", patch.display());
     }
 
-    let kernel_source_dir = kernel_source_path(report);
+    let kernel_source_dir = Layout::for_crash(report, crash_index)?.source_dir();
     let patch_contents = fs::read(&patch)
         .await
         .with_context(|| format!("Failed to read patch file: {}", patch.display()))?;
@@ -123,80 +469,602 @@ pub async fn apply_patch(report: &Arc<CrashReport>, patch: PathBuf) -> Result<()
         .await
         .with_context(|| format!("Failed to write patch file to: {}", patch_path.display()))?;
 
-    let status = Command::new("patch")
-        .arg("-p1")
-        .arg("-i")
-        .arg("patch.diff")
-        .current_dir(&kernel_source_dir)
-        .stdout(std::process::Stdio::inherit())
-        .stderr(std::process::Stdio::inherit())
-        .status()
+    apply_series(&kernel_source_dir, &[patch_path]).await
+}
+
+// write the report's inline fix to workspace/<id>/fix.diff, apply it and check that it
+// touched every file listed in patch_modified_files
+pub async fn apply_report_patch(report: &Arc<CrashReport>, crash_index: usize) -> Result<()> {
+    if report.patch.trim().is_empty() {
+        anyhow::bail!("Report {} carries no patch", report.id);
+    }
+
+    let layout = Layout::for_crash(report, crash_index)?;
+    let patch_path = layout.fix_patch_path();
+    fs::write(&patch_path, &report.patch)
         .await
-        .with_context(|| format!("Failed to apply patch: {}", patch_path.display()))?;
+        .with_context(|| format!("Failed to write patch file to: {}", patch_path.display()))?;
+
+    apply_and_verify(
+        &layout.source_dir(),
+        &patch_path,
+        &report.patch_modified_files,
+    )
+    .await
+}
+
+async fn apply_and_verify(dir: &Path, patch: &Path, modified_files: &[String]) -> Result<()> {
+    let mut before = Vec::with_capacity(modified_files.len());
+    for file in modified_files {
+        before.push(file_stamp(&dir.join(file)).await);
+    }
+
+    apply_series(dir, &[patch.to_path_buf()]).await?;
+
+    let mut untouched = Vec::new();
+    for (file, before) in modified_files.iter().zip(before) {
+        if file_stamp(&dir.join(file)).await == before {
+            untouched.push(file.as_str());
+        }
+    }
 
-    if !status.success() {
-        anyhow::bail!("Failed to apply patch, exit code: {:?}", status.code());
+    if !untouched.is_empty() {
+        anyhow::bail!(
+            "Patch {} did not modify the expected files: {}",
+            patch.display(),
+            untouched.join(", ")
+        );
     }
 
     Ok(())
 }
 
-pub async fn rebuild_kernel(report: &Arc<CrashReport>) -> Result<()> {
-    let build_dir = build_path(report);
-    let compiler = select_compiler(report)?;
-    let kernel_source_dir = kernel_source_path(report);
-    let shell_script_path = env::current_dir()?.join("nix").join("shell.nix");
+// identity and modification time of a file, None if it doesn't exist.
+// patch and git apply replace files, so either value changes when a file is rewritten
+async fn file_stamp(path: &Path) -> Option<(u64, SystemTime)> {
+    use std::os::unix::fs::MetadataExt;
 
-    info!(
-        "Starting kernel compilation with compiler: {}",
-        format!(
-            "{}-{}.{}.{}",
-            compiler.compiler_type.to_string(),
-            compiler.major,
-            compiler.minor,
-            compiler.patch
-        )
-    );
+    let metadata = fs::metadata(path).await.ok()?;
+    Some((metadata.ino(), metadata.modified().ok()?))
+}
 
-    let num_cpu = num_cpus::get();
-    let make_cmd = match compiler.compiler_type {
-        CompilerType::GCC => {
-            format!(
-                "bear --output rebuild_compile_commands.json -- make O=../build -j{}",
-                num_cpu - 2
-            )
+// tool used to apply a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PatchBackend {
+    #[default]
+    Patch,
+    // understands renames, mode changes and binary hunks of git-format diffs
+    GitApply,
+}
+
+impl PatchBackend {
+    pub fn detect(contents: &str) -> PatchBackend {
+        if contents.lines().any(|line| line.starts_with("diff --git ")) {
+            PatchBackend::GitApply
+        } else {
+            PatchBackend::Patch
         }
-        CompilerType::CLANG => {
-            format!(
-                "bear --output rebuild_compile_commands.json -- make O=../build LLVM=1 CC=clang LD=ld.lld AR=llvm-ar NM=llvm-nm OBJCOPY=llvm-objcopy -j{}",
-                num_cpu - 2
-            )
+    }
+
+    fn tool(&self) -> &'static str {
+        match self {
+            PatchBackend::Patch => "patch",
+            PatchBackend::GitApply => "git",
         }
-    };
+    }
+}
+
+// what run_patch does with a patch, the check modes leave the tree alone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PatchMode {
+    Apply,
+    Revert,
+    CheckApply,
+    CheckRevert,
+}
+
+// whether a fix is present in a source tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchState {
+    Applied,
+    NotApplied,
+    // neither applies nor reverts cleanly: partially applied or conflicting with the tree
+    Partial,
+}
+
+#[derive(Debug, Error)]
+pub enum PatchError {
+    #[error("Patch {0} is partially applied or conflicts with the source tree")]
+    PartiallyApplied(String),
+}
+
+// run the backend on one patch inside `dir`, returning the tool's combined output on failure
+async fn run_patch(dir: &Path, patch: &Path, backend: PatchBackend, mode: PatchMode) -> Result<()> {
+    require_tool(backend.tool())?;
+
+    let reverse = matches!(mode, PatchMode::Revert | PatchMode::CheckRevert);
+    let check = matches!(mode, PatchMode::CheckApply | PatchMode::CheckRevert);
+
+    let mut command = Command::new(backend.tool());
+    match backend {
+        PatchBackend::Patch => {
+            command.args(["-p1", "--no-backup-if-mismatch"]);
+            // --batch would treat a reversed patch as already applied and flip -R back,
+            // -f takes the direction literally
+            if reverse {
+                command.args(["-R", "-f"]);
+            } else {
+                command.args(["--forward", "--batch"]);
+            }
+            if check {
+                command.arg("--dry-run");
+            }
+            command.arg("-i").arg(patch);
+        }
+        PatchBackend::GitApply => {
+            command.arg("apply");
+            // source tarballs are not repositories, --index only makes sense in a checkout
+            if try_exists(dir.join(".git")).await? {
+                command.arg("--index");
+            }
+            if reverse {
+                command.arg("-R");
+            }
+            if check {
+                command.arg("--check");
+            }
+            command.arg(patch);
+        }
+    }
 
-    let compiler_str = format!("{}-{}", compiler.compiler_type.to_string(), compiler.major);
-    let nix_cmd = NixCommand::new(shell_script_path, &compiler_str, kernel_source_dir);
+    let output = command
+        .current_dir(dir)
+        .stdin(std::process::Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {} for {}", backend.tool(), patch.display()))?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(())
+}
+
+async fn read_backend(patch: &Path) -> Result<PatchBackend> {
+    let contents = fs::read_to_string(patch)
+        .await
+        .with_context(|| format!("Failed to read patch file: {}", patch.display()))?;
+    Ok(PatchBackend::detect(&contents))
+}
 
-    nix_cmd
-        .execute(&make_cmd)
+// probe `dir` with dry runs: reverting cleanly means applied, applying cleanly means not applied
+async fn patch_state(dir: &Path, patch: &Path) -> Result<PatchState> {
+    let backend = read_backend(patch).await?;
+
+    if run_patch(dir, patch, backend, PatchMode::CheckRevert)
+        .await
+        .is_ok()
+    {
+        return Ok(PatchState::Applied);
+    }
+    if run_patch(dir, patch, backend, PatchMode::CheckApply)
         .await
-        .context("Failed to execute nix-shell command")?;
+        .is_ok()
+    {
+        return Ok(PatchState::NotApplied);
+    }
+    Ok(PatchState::Partial)
+}
+
+// whether `patch` is already in the report's source tree, a partial application is an error
+pub async fn is_patch_applied(
+    report: &Arc<CrashReport>,
+    crash_index: usize,
+    patch: &Path,
+) -> Result<bool> {
+    let patch = std::path::absolute(patch)?;
+    let source_dir = Layout::for_crash(report, crash_index)?.source_dir();
+
+    match patch_state(&source_dir, &patch).await? {
+        PatchState::Applied => Ok(true),
+        PatchState::NotApplied => Ok(false),
+        PatchState::Partial => {
+            Err(PatchError::PartiallyApplied(patch.display().to_string()).into())
+        }
+    }
+}
+
+// take `patch` back out of the report's source tree, e.g. to build the buggy kernel
+pub async fn revert_patch(
+    report: &Arc<CrashReport>,
+    crash_index: usize,
+    patch: &Path,
+) -> Result<()> {
+    let patch = std::path::absolute(patch)?;
+    let backend = read_backend(&patch).await?;
+
+    run_patch(
+        &Layout::for_crash(report, crash_index)?.source_dir(),
+        &patch,
+        backend,
+        PatchMode::Revert,
+    )
+    .await
+    .with_context(|| format!("Failed to revert patch {}", patch.display()))?;
+
+    info!("Reverted patch {} with {}", patch.display(), backend.tool());
+
+    Ok(())
+}
+
+// apply `patches` in order on top of the report's source tree. with `check_first` the whole
+// series is tried on a hardlinked scratch copy before the real tree is touched; a plain
+// `patch --dry-run` can't validate a series whose patches build on each other
+pub async fn apply_patches(
+    report: &Arc<CrashReport>,
+    crash_index: usize,
+    patches: &[PathBuf],
+    check_first: bool,
+) -> Result<()> {
+    for patch in patches {
+        if !fs::try_exists(patch).await? {
+            anyhow::bail!("Patch file does not exist: {}", patch.display());
+        }
+    }
+    // patch runs inside the source tree, relative paths would resolve against it
+    let patches = patches
+        .iter()
+        .map(std::path::absolute)
+        .collect::<std::io::Result<Vec<_>>>()?;
+
+    let layout = Layout::for_crash(report, crash_index)?;
+    let source_dir = layout.source_dir();
+
+    if check_first {
+        let scratch = layout.root().join("patch-check");
+        if try_exists(&scratch).await? {
+            fs::remove_dir_all(&scratch).await?;
+        }
+
+        info!("Checking {} patches on a scratch copy", patches.len());
+        let (from, to) = (source_dir.clone(), scratch.clone());
+        tokio::task::spawn_blocking(move || link_tree(&from, &to)).await??;
+
+        let checked = apply_series(&scratch, &patches).await;
+        fs::remove_dir_all(&scratch).await?;
+        checked.context("Patch series does not apply, source tree left untouched")?;
+    }
+
+    apply_series(&source_dir, &patches).await
+}
+
+// fail fast with the position and name of the first patch that doesn't apply
+async fn apply_series(dir: &Path, patches: &[PathBuf]) -> Result<()> {
+    for (index, patch) in patches.iter().enumerate() {
+        let backend = read_backend(patch).await?;
+
+        if let Err(e) = run_patch(dir, patch, backend, PatchMode::Apply).await {
+            anyhow::bail!(
+                "Patch {} ({}) does not apply cleanly with {}:\n{}",
+                index + 1,
+                patch.display(),
+                backend.tool(),
+                e
+            );
+        }
+        info!(
+            "Applied patch {} ({}) with {}",
+            index + 1,
+            patch.display(),
+            backend.tool()
+        );
+    }
+
+    Ok(())
+}
+
+// rebuild after a patch. make only recompiles what changed, bear records just those commands
+// and they are merged into the compile_commands.json of the full build
+pub async fn rebuild_kernel(
+    report: &Arc<CrashReport>,
+    options: &BuildOptions,
+) -> Result<BuildArtifacts> {
+    let layout = options.layout(report)?;
+    let compiler = select_compiler(report, options.crash_index)?;
+    let kernel_source_dir = layout.source_dir();
+    let shell_script_path = env::current_dir()?.join("nix").join("shell.nix");
+
+    info!("Starting kernel compilation with compiler: {}", compiler);
+
+    let arch = select_architecture(report)?;
+    let make_args = format!("{} {}", layout.make_dirs_args(), arch.make_args());
+    info!("Building for {} ({})", arch, arch.make_args());
+
+    let build = &options.config.build;
+    let jobs = build.jobs(num_cpus::get());
+    let compile_commands = layout.compile_commands_path();
+    let rebuild_commands = layout.rebuild_compile_commands_path();
+    let ccache = build.ccache.then(|| Ccache::new(&layout));
+    let make_cmd = kernel_make_command(
+        compiler.compiler_type,
+        &rebuild_commands,
+        &make_args,
+        jobs,
+        ccache.as_ref(),
+    );
+
+    let header_install_cmd = format!("make {} headers_install", make_args);
+    let compiler_str = compiler.nix_arg();
+    let nix_cmd = NixCommand::new(shell_script_path, &compiler_str, kernel_source_dir)
+        .with_timeout(build.build_timeout)
+        .with_retries(build.nix_retries)
+        .with_cancel(options.cancel.clone());
+
+    if options.dry_run {
+        log_dry_run(&nix_cmd, &[&make_cmd, &header_install_cmd]);
+        let mut artifacts = BuildArtifacts::expected(&layout, arch);
+        artifacts.requested_compiler = Some(compiler);
+        return Ok(artifacts);
+    }
+
+    let actual_compiler = verify_compiler_available(&nix_cmd, &compiler).await?;
+
+    if !try_exists(&compile_commands).await? {
+        warn!(
+            "No {} from a full build, the rebuilt database only covers recompiled files",
+            compile_commands.display()
+        );
+    }
+
+    reset_build_log(&layout).await?;
+
+    let ccache_before = ccache_stats(ccache.as_ref(), &nix_cmd).await;
+    if let Err(e) = run_build_step(&nix_cmd, &layout, build, &make_cmd).await {
+        keep_failure(report, options, &nix_cmd, &make_cmd, &e).await;
+        return Err(e.context("Failed to execute nix-shell command"));
+    }
 
     info!("compilation succeeded");
+    log_ccache_stats(ccache.as_ref(), &nix_cmd, ccache_before).await;
+
+    let mut artifacts = BuildArtifacts::locate(&layout, arch).await?;
+    artifacts.requested_compiler = Some(compiler);
+    artifacts.actual_compiler = Some(actual_compiler);
 
-    let bz_image_path = build_dir.join("build").join("arch/x86_64/boot/bzImage");
-    if !try_exists(&bz_image_path).await? {
-        anyhow::bail!("bzImage not found in: {}", bz_image_path.display());
+    // bear writes no database when make had nothing to compile
+    if try_exists(&rebuild_commands).await? {
+        compdb::merge_into(&compile_commands, &rebuild_commands).await?;
     }
 
-    info!("start linux headers install");
+    if !options.force_headers && !headers_stale(&layout, arch).await? {
+        info!("uapi headers unchanged since the last install, skipping headers_install");
+        return Ok(artifacts);
+    }
+
+    install_headers(&nix_cmd, &layout, build, &header_install_cmd).await?;
+    Ok(artifacts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_newest_mtime() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(newest_mtime(&dir.path().join("missing")).unwrap(), None);
+
+        let uapi = dir.path().join("include/uapi/linux");
+        std::fs::create_dir_all(&uapi).unwrap();
+        assert_eq!(newest_mtime(dir.path()).unwrap(), None);
+
+        let old = uapi.join("old.h");
+        let new = uapi.join("new.h");
+        std::fs::write(&old, "").unwrap();
+        std::fs::write(&new, "").unwrap();
+        let earlier = SystemTime::now() - std::time::Duration::from_secs(3600);
+        std::fs::File::options()
+            .write(true)
+            .open(&old)
+            .unwrap()
+            .set_modified(earlier)
+            .unwrap();
+
+        assert_eq!(
+            newest_mtime(dir.path()).unwrap(),
+            Some(std::fs::metadata(&new).unwrap().modified().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_apply_series() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("linux");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("file.c"), "a\n").unwrap();
+
+        let first = dir.path().join("0001.patch");
+        let second = dir.path().join("0002.patch");
+        std::fs::write(
+            &first,
+            "--- a/file.c\n+++ b/file.c\n@@ -1 +1,2 @@\n a\n+b\n",
+        )
+        .unwrap();
+        // only applies on top of the first patch
+        std::fs::write(
+            &second,
+            "--- a/file.c\n+++ b/file.c\n@@ -1,2 +1,3 @@\n a\n b\n+c\n",
+        )
+        .unwrap();
+
+        let err = apply_series(&tree, std::slice::from_ref(&second))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Patch 1 ("));
+
+        apply_series(&tree, &[first, second]).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(tree.join("file.c")).unwrap(),
+            "a\nb\nc\n"
+        );
+    }
 
-    let header_install_cmd = "make O=../build headers_install INSTALL_HDR_PATH=../install";
+    #[tokio::test]
+    async fn test_git_apply_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("linux");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("old.c"), "a\n").unwrap();
 
-    nix_cmd
-        .execute(header_install_cmd)
+        let contents = "diff --git a/old.c b/new.c\nsimilarity index 100%\nrename from old.c\nrename to new.c\n";
+        assert_eq!(PatchBackend::detect(contents), PatchBackend::GitApply);
+        assert_eq!(
+            PatchBackend::detect("--- a/x\n+++ b/x\n"),
+            PatchBackend::Patch
+        );
+
+        let patch = dir.path().join("rename.patch");
+        std::fs::write(&patch, contents).unwrap();
+        apply_series(&tree, &[patch]).await.unwrap();
+        assert!(!tree.join("old.c").exists());
+        assert_eq!(std::fs::read_to_string(tree.join("new.c")).unwrap(), "a\n");
+    }
+
+    #[tokio::test]
+    async fn test_apply_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("linux");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("file.c"), "a\n").unwrap();
+        std::fs::write(tree.join("other.c"), "x\n").unwrap();
+
+        let patch = dir.path().join("fix.diff");
+        std::fs::write(
+            &patch,
+            "--- a/file.c\n+++ b/file.c\n@@ -1 +1,2 @@\n a\n+b\n",
+        )
+        .unwrap();
+
+        let err = apply_and_verify(
+            &tree,
+            &patch,
+            &["file.c".to_string(), "other.c".to_string()],
+        )
         .await
-        .context("Failed to execute header install command")?;
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("did not modify the expected files: other.c")
+        );
+        assert_eq!(
+            std::fs::read_to_string(tree.join("file.c")).unwrap(),
+            "a\nb\n"
+        );
+    }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_patch_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("linux");
+        std::fs::create_dir_all(&tree).unwrap();
+        std::fs::write(tree.join("a.c"), "a\n").unwrap();
+        std::fs::write(tree.join("b.c"), "b\n").unwrap();
+
+        let patch = dir.path().join("fix.diff");
+        std::fs::write(
+            &patch,
+            "--- a/a.c\n+++ b/a.c\n@@ -1 +1 @@\n-a\n+A\n--- a/b.c\n+++ b/b.c\n@@ -1 +1 @@\n-b\n+B\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            patch_state(&tree, &patch).await.unwrap(),
+            PatchState::NotApplied
+        );
+
+        apply_series(&tree, std::slice::from_ref(&patch))
+            .await
+            .unwrap();
+        assert_eq!(
+            patch_state(&tree, &patch).await.unwrap(),
+            PatchState::Applied
+        );
+
+        run_patch(&tree, &patch, PatchBackend::Patch, PatchMode::Revert)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(tree.join("a.c")).unwrap(), "a\n");
+
+        // only the first hunk of the fix is in the tree
+        std::fs::write(tree.join("a.c"), "A\n").unwrap();
+        assert_eq!(
+            patch_state(&tree, &patch).await.unwrap(),
+            PatchState::Partial
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compile_reproducer_preconditions() {
+        let report = Arc::new(
+            crate::parse::parse::parse_file(
+                "datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json",
+            )
+            .unwrap(),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let workspace = crate::parse::workspace::Workspace::new(dir.path()).unwrap();
+        let layout = Layout::in_workspace(&workspace, &report, 0).unwrap();
+
+        let dry_run = BuildOptions {
+            dry_run: true,
+            ..BuildOptions::default()
+        };
+        assert_eq!(
+            build_reproducer(&report, &layout, &dry_run).await.unwrap(),
+            layout.reproducer_binary_path()
+        );
+
+        let options = BuildOptions::default();
+        let err = build_reproducer(&report, &layout, &options)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No C reproducer"));
+
+        std::fs::create_dir_all(layout.crash_dir()).unwrap();
+        std::fs::write(layout.reproducer_path(), "int main() {}").unwrap();
+        let err = build_reproducer(&report, &layout, &options)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No installed headers"));
+    }
+
+    #[test]
+    fn test_kernel_make_command() {
+        let report = crate::parse::parse::parse_file(
+            "datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json",
+        )
+        .unwrap();
+        let layout = Layout::new(&report).unwrap();
+        let db = Path::new("/w/id/linux-abc/compile_commands.json");
+        assert_eq!(
+            kernel_make_command(CompilerType::GCC, db, "O=build", 8, None),
+            "bear --output '/w/id/linux-abc/compile_commands.json' -- make O=build -j8"
+        );
+
+        let ccache = Ccache::new(&layout);
+        let gcc = kernel_make_command(CompilerType::GCC, db, "O=build", 8, Some(&ccache));
+        assert!(gcc.starts_with(&format!("{} bear --output", ccache.env())));
+        assert!(gcc.ends_with("make O=build CC='ccache gcc' -j8"));
+        let clang = kernel_make_command(CompilerType::CLANG, db, "O=build", 8, Some(&ccache));
+        assert!(clang.contains("LLVM=1 CC='ccache clang' LD=ld.lld"));
+        assert!(
+            kernel_make_command(CompilerType::CLANG, db, "O=build", 8, None)
+                .contains("LLVM=1 CC=clang LD=ld.lld")
+        );
+    }
 }
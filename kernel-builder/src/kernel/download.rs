@@ -1,67 +1,344 @@
-use crate::config::config::Config;
-use crate::parse::parse::{build_path, kernel_source_path};
-use crate::parse::report::{CrashReport};
+use crate::config::config::{Config, DownloadConfig, ProxyPolicy};
+use crate::kernel::compile::BuildOptions;
+use crate::kernel::repo::KernelRepo;
+use crate::parse::crash_log::CrashSignature;
+use crate::parse::layout::Layout;
+use crate::parse::parse::cache_path;
+use crate::parse::report::CrashReport;
 use anyhow::{Context, Result};
+use once_cell::sync::{Lazy, OnceCell};
+use rand::Rng;
 use reqwest::Client;
-use std::path::Path;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use thiserror::Error;
 use tokio::fs;
-use tokio::fs::File;
 use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
 use tracing::{error, info, warn};
 
-const KERNEL_DOWNLOAD_URL: &str = "https://github.com/torvalds/linux/archive/";
-const SYZKALLER_URL: &str = "https://syzkaller.appspot.com/";
+// rough size of an extracted kernel tree relative to its gzip tarball
+const EXTRACTED_SIZE_RATIO: u64 = 6;
+
+// how many extracted bytes between two progress reports
+const PROGRESS_INTERVAL: u64 = 64 * 1024 * 1024;
+
+// sized by the download.max_concurrent_extractions of the first extraction
+static EXTRACTION_SLOTS: OnceCell<Semaphore> = OnceCell::new();
+static RESERVED_EXTRACTION_BYTES: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Error, Debug)]
 pub enum DownloadError {
     #[error("File already exists: {0}")]
     FileExists(String),
 
+    #[error("Download of {url} timed out after {elapsed:?}")]
+    Timeout { url: String, elapsed: Duration },
+
+    #[error("HTTP {code} while downloading {url}")]
+    HttpStatus { code: u16, url: String },
+
+    #[error("Checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch { expected: String, actual: String },
+
+    #[error("Cancelled: {0}")]
+    Cancelled(String),
+
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
 
-async fn download_file(url: &str, target: &Path, use_proxy: bool) -> Result<()> {
+// what the downloads do when their file (or download_kernel's source tree) is already there
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverwritePolicy {
+    // keep the file, re-runs are idempotent
+    #[default]
+    Skip,
+    // download it again
+    Overwrite,
+    // fail with DownloadError::FileExists
+    Error,
+}
+
+// apply `overwrite` to an existing `target`, returns whether it still has to be downloaded.
+// an empty file is left over from a broken run and always downloaded again
+async fn prepare_target(target: &Path, overwrite: OverwritePolicy) -> Result<bool> {
+    let metadata = match fs::metadata(target).await {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e).with_context(|| format!("Failed to stat {}", target.display())),
+    };
+
+    if metadata.len() > 0 {
+        match overwrite {
+            OverwritePolicy::Skip => {
+                let age = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok());
+                match age {
+                    Some(age) => info!(
+                        "{} was downloaded {}s ago, skipping",
+                        target.display(),
+                        age.as_secs()
+                    ),
+                    None => info!("{} is already downloaded, skipping", target.display()),
+                }
+                return Ok(false);
+            }
+            OverwritePolicy::Error => {
+                return Err(DownloadError::FileExists(target.display().to_string()).into());
+            }
+            OverwritePolicy::Overwrite => {
+                info!("Downloading {} again", target.display());
+            }
+        }
+    }
+
+    fs::remove_file(target)
+        .await
+        .with_context(|| format!("Failed to remove {}", target.display()))?;
+    Ok(true)
+}
+
+// where a download comes from, decides whether the proxy is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DownloadSource {
+    Kernel,
+    Syzkaller,
+}
+
+async fn download_file(
+    url: &str,
+    target: &Path,
+    source: DownloadSource,
+    cancel: &CancellationToken,
+) -> Result<()> {
     info!("Downloading file from: {}", url);
     info!("Saving to: {}", target.display());
 
+    // only a completed download counts, a leftover `.part` file is resumed below
     if Path::exists(target) {
         return Err(DownloadError::FileExists(target.display().to_string()).into());
     }
 
-    let client = if use_proxy {
-        let config: Config = Config::default();
-        let proxy_url = format!("http://{}:{}", config.proxy.host, config.proxy.port);
+    let config = Config::load()?.download;
+    let download = async {
+        let Some(deadline) = config.timeout else {
+            return fetch_with_retry(url, target, source, &config).await;
+        };
 
-        let proxy = reqwest::Proxy::all(&proxy_url)
-            .with_context(|| format!("Failed to create HTTP proxy with URL {}", proxy_url))?;
+        let started = Instant::now();
+        match tokio::time::timeout(deadline, fetch_with_retry(url, target, source, &config)).await {
+            Ok(result) => result,
+            Err(_) => {
+                // the partial data stays in the `.part` file so the next attempt can resume it
+                Err(DownloadError::Timeout {
+                    url: url.to_string(),
+                    elapsed: started.elapsed(),
+                }
+                .into())
+            }
+        }
+    };
 
-        Client::builder()
-            .proxy(proxy)
-            .build()
-            .with_context(|| "Failed to create HTTP client")?
-    } else {
-        Client::builder()
-            .no_proxy()
-            .build()
-            .with_context(|| "Failed to create HTTP client")?
+    tokio::select! {
+        result = download => result,
+        _ = cancel.cancelled() => {
+            // unlike a timeout, a cancelled download is not meant to be resumed
+            let _ = fs::remove_file(partial_path(target)).await;
+            Err(DownloadError::Cancelled(format!("download of {}", url)).into())
+        }
+    }
+}
+
+// network errors and server side failures may go away, client errors and local IO errors won't
+fn is_transient(e: &anyhow::Error) -> bool {
+    if let Some(DownloadError::HttpStatus { code, .. }) = e.downcast_ref::<DownloadError>() {
+        return *code >= 500;
+    }
+
+    e.chain()
+        .find_map(|cause| cause.downcast_ref::<reqwest::Error>())
+        .is_some_and(|e| e.is_connect() || e.is_timeout() || e.is_request() || e.is_body())
+}
+
+// same backoff scheme as `SSHManager::connect`, every retry resumes from the `.part` file
+async fn fetch_with_retry(
+    url: &str,
+    target: &Path,
+    source: DownloadSource,
+    config: &DownloadConfig,
+) -> Result<()> {
+    let mut backoff = config.initial_backoff;
+
+    for attempt in 0..config.max_retries {
+        match fetch_file(url, target, source).await {
+            Ok(()) => return Ok(()),
+            Err(e) if !is_transient(&e) || attempt + 1 == config.max_retries => return Err(e),
+            Err(e) => {
+                warn!("Download attempt {} failed: {:#}", attempt + 1, e);
+
+                // the thread local rng is not Send, so it must not live across the sleep below
+                let jitter = rand::rng().random_range(0..backoff.as_millis().max(1) as u64);
+                let sleep_duration = backoff + Duration::from_millis(jitter);
+
+                info!(
+                    "Retrying download in {:?} (attempt {}/{})",
+                    sleep_duration,
+                    attempt + 2,
+                    config.max_retries
+                );
+                tokio::time::sleep(sleep_duration).await;
+
+                backoff = std::cmp::min(backoff * 2, config.max_backoff);
+            }
+        }
+    }
+
+    unreachable!("max_retries is validated to be greater than 0")
+}
+
+// in-progress downloads are written next to the target and renamed once complete
+fn partial_path(target: &Path) -> PathBuf {
+    let mut name = target.as_os_str().to_owned();
+    name.push(".part");
+    PathBuf::from(name)
+}
+
+// start offset of a `Content-Range: bytes <start>-<end>/<total>` header
+fn content_range_start(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes ")?
+        .split('-')
+        .next()?
+        .parse()
+        .ok()
+}
+
+// the complete length from `Content-Range: bytes */<length>`, sent with a 416
+fn content_range_total(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes */")?
+        .parse()
+        .ok()
+}
+
+// build a client for `source` according to the configured proxy policy
+fn http_client(source: DownloadSource) -> Result<Client> {
+    let config = Config::load()?;
+
+    let builder = match (config.proxy.policy, source) {
+        // reqwest picks up the proxy environment variables by default
+        (ProxyPolicy::Auto, _) => Client::builder(),
+        (ProxyPolicy::Never, _) | (ProxyPolicy::SyzkallerOnly, DownloadSource::Kernel) => {
+            Client::builder().no_proxy()
+        }
+        (ProxyPolicy::Always, _) | (ProxyPolicy::SyzkallerOnly, DownloadSource::Syzkaller) => {
+            let proxy_url = format!("http://{}:{}", config.proxy.host, config.proxy.port);
+            let proxy = reqwest::Proxy::all(&proxy_url)
+                .with_context(|| format!("Failed to create HTTP proxy with URL {}", proxy_url))?;
+            Client::builder().proxy(proxy)
+        }
     };
 
-    let mut response = client
-        .get(url)
-        .send()
-        .await
-        .with_context(|| format!("Failed to download from {}", url))?
-        .error_for_status()
-        .with_context(|| format!("HTTP error while downloading from {}", url))?;
+    builder
+        .build()
+        .with_context(|| "Failed to create HTTP client")
+}
+
+async fn fetch_file(url: &str, target: &Path, source: DownloadSource) -> Result<()> {
+    let client = http_client(source)?;
+
+    let part = partial_path(target);
+    let (mut response, resume_from) = loop {
+        let resume_from = match fs::metadata(&part).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+
+        let mut request = client.get(url);
+        if resume_from > 0 {
+            info!(
+                "Resuming download of {} from byte {}",
+                target.display(),
+                resume_from
+            );
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", resume_from));
+        }
 
-    let mut file = BufWriter::new(
-        File::create(&target)
+        let response = request
+            .send()
             .await
-            .with_context(|| format!("Failed to create file: {}", target.display()))?,
-    );
+            .with_context(|| format!("Failed to download from {}", url))?;
+
+        // the range starts at or past the end: either the partial file is already complete or
+        // it is longer than the file on the server and has to be fetched again
+        if resume_from > 0 && response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            if content_range_total(&response) == Some(resume_from) {
+                info!("Partial download of {} is already complete", url);
+                fs::rename(&part, target)
+                    .await
+                    .with_context(|| format!("Failed to move {} into place", part.display()))?;
+                return Ok(());
+            }
+            warn!(
+                "Partial download of {} does not match the server, restarting download",
+                url
+            );
+            fs::remove_file(&part)
+                .await
+                .with_context(|| format!("Failed to remove {}", part.display()))?;
+            continue;
+        }
+
+        break (response, resume_from);
+    };
+
+    if !response.status().is_success() {
+        return Err(DownloadError::HttpStatus {
+            code: response.status().as_u16(),
+            url: url.to_string(),
+        }
+        .into());
+    }
+
+    let append = resume_from > 0 && response.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+    if append && content_range_start(&response) != Some(resume_from) {
+        let _ = fs::remove_file(&part).await;
+        anyhow::bail!(
+            "Server returned an unexpected range for {}, discarded the partial download",
+            url
+        );
+    }
+    if resume_from > 0 && !append {
+        warn!(
+            "Server does not support range requests for {}, restarting download",
+            url
+        );
+    }
+
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(&part)
+        .await
+        .with_context(|| format!("Failed to create file: {}", part.display()))?;
+    let mut file = BufWriter::new(file);
 
     while let Some(chunk) = response
         .chunk()
@@ -70,19 +347,203 @@ async fn download_file(url: &str, target: &Path, use_proxy: bool) -> Result<()>
     {
         file.write_all(&chunk)
             .await
-            .with_context(|| format!("Failed to write chunk to file: {}", target.display()))?;
+            .with_context(|| format!("Failed to write chunk to file: {}", part.display()))?;
     }
 
     file.flush()
         .await
-        .with_context(|| format!("Failed to flush file: {}", target.display()))?;
+        .with_context(|| format!("Failed to flush file: {}", part.display()))?;
+
+    fs::rename(&part, target)
+        .await
+        .with_context(|| format!("Failed to move {} into place", part.display()))?;
 
     info!("Download completed successfully");
 
     Ok(())
 }
 
-async fn decompress_file(source: &Path, target: &Path) -> Result<()> {
+// hex encoded sha256 of the file at `path`
+async fn sha256_file(path: &Path) -> Result<String> {
+    let path = path.to_owned();
+
+    tokio::task::spawn_blocking(move || -> Result<String> {
+        use std::io::Read;
+
+        let mut file = std::fs::File::open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        let mut buf = vec![0u8; 1 << 20];
+        loop {
+            let n = file
+                .read(&mut buf)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            if n == 0 {
+                break;
+            }
+            context.update(&buf[..n]);
+        }
+
+        Ok(hex::encode(context.finish()))
+    })
+    .await?
+}
+
+// compare the tarball against the expected hash, or log it so it can be recorded in the report
+async fn verify_checksum(path: &Path, expected: Option<&str>) -> Result<()> {
+    let actual = sha256_file(path).await?;
+
+    match expected {
+        Some(expected) if !expected.trim().eq_ignore_ascii_case(&actual) => {
+            Err(DownloadError::ChecksumMismatch {
+                expected: expected.trim().to_string(),
+                actual,
+            }
+            .into())
+        }
+        Some(_) => {
+            info!("Checksum of {} verified", path.display());
+            Ok(())
+        }
+        None => {
+            info!("sha256 of {}: {}", path.display(), actual);
+            Ok(())
+        }
+    }
+}
+
+// bytes free for unprivileged users on the filesystem holding `path`
+fn available_space(path: &Path) -> Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid path: {}", path.display()))?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to stat filesystem of {}", path.display()));
+    }
+
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// disk space promised to an in-flight extraction, released on drop
+struct SpaceReservation(u64);
+
+impl SpaceReservation {
+    // reserve `bytes` on the filesystem of `target`, accounting for every extraction still running
+    fn acquire(target: &Path, bytes: u64) -> Result<Self> {
+        let reserved = RESERVED_EXTRACTION_BYTES.fetch_add(bytes, Ordering::SeqCst) + bytes;
+        let reservation = SpaceReservation(bytes);
+
+        let available = available_space(target)?;
+        if available < reserved {
+            anyhow::bail!(
+                "Not enough disk space to extract into {}: {} MB available, {} MB needed by running extractions",
+                target.display(),
+                available / 1024 / 1024,
+                reserved / 1024 / 1024
+            );
+        }
+
+        Ok(reservation)
+    }
+}
+
+impl Drop for SpaceReservation {
+    fn drop(&mut self) {
+        RESERVED_EXTRACTION_BYTES.fetch_sub(self.0, Ordering::SeqCst);
+    }
+}
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+// stdout of an external decompressor, its exit status is checked once the stream ends
+struct ChildReader {
+    name: &'static str,
+    child: std::process::Child,
+    stdout: std::process::ChildStdout,
+}
+
+impl std::io::Read for ChildReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(std::io::Error::other(format!(
+                    "{} exited with {}",
+                    self.name, status
+                )));
+            }
+        }
+        Ok(n)
+    }
+}
+
+// don't leave the decoder running when unpacking bails out early
+impl Drop for ChildReader {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// there is no xz or zstd crate in the build, so those formats go through the system binaries
+fn spawn_decoder(name: &'static str, file: std::fs::File) -> Result<Box<dyn std::io::Read>> {
+    let tool = crate::script::tool::require_tool(name)?;
+    let mut child = std::process::Command::new(tool)
+        .args(["-d", "-c"])
+        .stdin(file)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to spawn {}", name))?;
+    let stdout = child.stdout.take().context("decoder stdout not captured")?;
+
+    Ok(Box::new(ChildReader {
+        name,
+        child,
+        stdout,
+    }))
+}
+
+// choose a decoder from the magic bytes, falling back to the extension and finally gzip
+fn pick_decoder(path: &Path) -> Result<Box<dyn std::io::Read>> {
+    use std::io::{BufReader, Read, Seek};
+
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open source file: {}", path.display()))?;
+    let mut magic = [0u8; 6];
+    let n = file.read(&mut magic)?;
+    file.rewind()?;
+    let magic = &magic[..n];
+
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+
+    if magic.starts_with(XZ_MAGIC) || (!magic.starts_with(GZIP_MAGIC) && extension == "xz") {
+        spawn_decoder("xz", file)
+    } else if magic.starts_with(ZSTD_MAGIC)
+        || (!magic.starts_with(GZIP_MAGIC) && extension == "zst")
+    {
+        spawn_decoder("zstd", file)
+    } else {
+        Ok(Box::new(flate2::read::GzDecoder::new(BufReader::new(file))))
+    }
+}
+
+// the codecs only decode serially, so each tarball is extracted on one blocking thread and
+// several tarballs can be extracted at once.
+// `progress` receives the bytes extracted so far and the total when it is known
+async fn decompress_file(
+    source: &Path,
+    target: &Path,
+    config: &DownloadConfig,
+    progress: Option<&(dyn Fn(u64, Option<u64>) + Send + Sync)>,
+    cancel: &CancellationToken,
+) -> Result<()> {
     info!("Decompressing file from: {}", source.display());
     info!("Saving decompressed content to: {}", target.display());
 
@@ -96,82 +557,447 @@ async fn decompress_file(source: &Path, target: &Path) -> Result<()> {
             .with_context(|| format!("Failed to create target directory: {}", target.display()))?;
     }
 
+    let _permit = EXTRACTION_SLOTS
+        .get_or_init(|| Semaphore::new(config.max_concurrent_extractions))
+        .acquire()
+        .await
+        .context("Extraction semaphore closed")?;
+
+    let compressed_size = fs::metadata(source).await?.len();
+    let _reservation = SpaceReservation::acquire(target, compressed_size * EXTRACTED_SIZE_RATIO)?;
+
     let source = source.to_owned();
     let target = target.to_owned();
 
-    tokio::task::spawn_blocking(move || -> Result<()> {
-        use std::fs::File;
-        use std::io::BufReader;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let cancel = cancel.clone();
+
+    let extraction = tokio::task::spawn_blocking(move || -> Result<()> {
+        let decoder = pick_decoder(&source)?;
+        extract_archive(decoder, &source, &target, &cancel, |extracted| {
+            // the receiver is only gone if the caller stopped waiting
+            let _ = tx.send(extracted);
+        })
+    });
+
+    // the channel closes when the blocking task finishes
+    while let Some(extracted) = rx.recv().await {
+        if let Some(progress) = progress {
+            progress(extracted, None);
+        }
+    }
+    extraction.await??;
+
+    info!("Decompression completed successfully");
+
+    Ok(())
+}
+
+// unpack the tar stream `decoder` into the existing directory `target`. on cancellation the
+// top level entries written so far are removed again. `on_progress` gets the extracted bytes
+// every PROGRESS_INTERVAL
+fn extract_archive(
+    decoder: Box<dyn std::io::Read>,
+    source: &Path,
+    target: &Path,
+    cancel: &CancellationToken,
+    mut on_progress: impl FnMut(u64),
+) -> Result<()> {
+    let mut archive = tar::Archive::new(decoder);
+
+    // top level entries written so far, removed again if the extraction is cancelled
+    let mut created = HashSet::new();
 
-        let file = File::open(&source)
-            .with_context(|| format!("Failed to open source file: {}", source.display()))?;
-        let buf_reader = BufReader::new(file);
+    let result = (|| -> Result<()> {
+        let mut extracted = 0u64;
+        let mut reported = 0u64;
+        for entry in archive
+            .entries()
+            .with_context(|| format!("Failed to read archive: {}", source.display()))?
+        {
+            if cancel.is_cancelled() {
+                return Err(DownloadError::Cancelled(format!(
+                    "extraction of {}",
+                    source.display()
+                ))
+                .into());
+            }
 
-        let decoder = flate2::read::GzDecoder::new(buf_reader);
-        let mut archive = tar::Archive::new(decoder);
-        archive
-            .unpack(&target)
-            .with_context(|| format!("Failed to unpack archive to: {}", target.display()))?;
+            let mut entry = entry.context("Failed to read archive entry")?;
+            if let Some(first) = entry.path()?.components().next() {
+                created.insert(first.as_os_str().to_owned());
+            }
+
+            let size = entry.size();
+            entry
+                .unpack_in(target)
+                .with_context(|| format!("Failed to unpack archive to: {}", target.display()))?;
+
+            extracted += size;
+            if extracted - reported >= PROGRESS_INTERVAL {
+                reported = extracted;
+                on_progress(extracted);
+            }
+        }
 
         Ok(())
-    })
-    .await??;
+    })();
 
-    info!("Decompression completed successfully");
+    if let Err(e) = &result
+        && matches!(
+            e.downcast_ref::<DownloadError>(),
+            Some(DownloadError::Cancelled(_))
+        )
+    {
+        remove_extracted(target, &created);
+    }
+    result
+}
+
+// remove the top level `entries` of a partial extraction into `target`
+fn remove_extracted(target: &Path, entries: &HashSet<std::ffi::OsString>) {
+    for entry in entries {
+        let path = target.join(entry);
+        let removed = if path.is_dir() {
+            std::fs::remove_dir_all(&path)
+        } else {
+            std::fs::remove_file(&path)
+        };
+        if let Err(e) = removed
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warn!(
+                "Failed to remove partial extraction {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+// check that `commit` can be fetched from the kernel archive, i.e. it is reachable from a branch
+pub async fn check_commit_available(
+    repo: &KernelRepo,
+    commit: &str,
+    config: &DownloadConfig,
+) -> Result<()> {
+    let url = repo.archive_url(commit, config);
+
+    let response = http_client(DownloadSource::Kernel)?
+        .head(&url)
+        .send()
+        .await
+        .with_context(|| format!("Failed to query {}", url))?;
+
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "Commit {} is not reachable from {} ({} returned {})",
+            commit,
+            repo,
+            url,
+            response.status()
+        );
+    }
 
     Ok(())
 }
 
-pub async fn download_kernel(report: &CrashReport) -> Result<()> {
+// fetch and extract the kernel source of the commit the options build (the parent of the
+// fix, or a fix commit) into workspace/<id>/linux-<commit>. with Overwrite the tree and
+// its tarball are fetched again, in the shared cache as well
+pub async fn download_kernel(
+    report: &CrashReport,
+    options: &BuildOptions,
+    overwrite: OverwritePolicy,
+) -> Result<()> {
     if report.crashes.is_empty() {
         anyhow::bail!("No crashes found in the report, cannot download kernel.");
     }
 
-    let commit = report.crashes.first().unwrap().kernel_source_commit.clone();
-    let download_url = format!("{}{}.tar.gz", KERNEL_DOWNLOAD_URL, commit);
+    let config = &options.config.download;
+    let cancel = &options.cancel;
+    let crash = report.crash(options.crash_index)?;
+    let commit = options.build_commit(report)?.to_string();
+    let repo = KernelRepo::parse(&crash.kernel_source_git)?;
+    let download_url = repo.archive_url(&commit, config);
 
-    let file_name = format!("linux-{}.tar.gz", commit);
-    let save_dir = build_path(report);
+    let layout = options.layout(report)?;
+    let save_dir = layout.root().to_path_buf();
 
-    info!("Preparing to download kernel source from: {}", download_url);
+    info!(
+        "Preparing to download kernel source of {} from: {}",
+        repo, download_url
+    );
 
     fs::create_dir_all(&save_dir)
         .await
         .with_context(|| format!("Failed to create directory: {}", save_dir.display()))?;
+    layout.migrate().await?;
 
-    let target_path = save_dir.join(file_name);
-    let source_dir = kernel_source_path(report);
+    let source_dir = layout.source_dir();
 
     if fs::try_exists(&source_dir).await? {
-        warn!(
-            "Kernel source directory already exists: {}. Skipping download.",
-            source_dir.display()
-        );
+        match overwrite {
+            OverwritePolicy::Skip => {
+                warn!(
+                    "Kernel source directory already exists: {}. Skipping download.",
+                    source_dir.display()
+                );
+                return Ok(());
+            }
+            OverwritePolicy::Error => {
+                return Err(DownloadError::FileExists(source_dir.display().to_string()).into());
+            }
+            OverwritePolicy::Overwrite => {
+                info!("Downloading kernel source {} again", source_dir.display());
+                remove_existing(&source_dir).await?;
+            }
+        }
+    }
+
+    // the report's checksum is that of the crash's own tree
+    let expected = crash
+        .sha256
+        .as_deref()
+        .filter(|_| commit == crash.kernel_source_commit);
+
+    // a fix commit or parent that was force-pushed away fails here rather than after the
+    // directories are set up. with git_fallback the clone below may still find it
+    let archive = if config.cache {
+        layout.cached_archive()
+    } else {
+        layout.source_archive()
+    };
+    let fetched = overwrite != OverwritePolicy::Overwrite
+        && (fs::try_exists(&archive).await? || fs::try_exists(&layout.cached_source_dir()).await?);
+    if !fetched && let Err(e) = check_commit_available(&repo, &commit, config).await {
+        if !config.git_fallback {
+            return Err(e);
+        }
+        warn!("{:#}", e);
+    }
+
+    if !config.cache {
+        if overwrite == OverwritePolicy::Overwrite {
+            remove_existing(&layout.source_archive()).await?;
+        }
+        fetch_source(
+            &repo,
+            &commit,
+            &layout.source_archive(),
+            &save_dir,
+            expected,
+            config,
+            cancel,
+        )
+        .await?;
+        info!("Kernel source download and extraction completed successfully");
         return Ok(());
     }
 
-    match download_file(&download_url, &target_path, false).await {
+    let cached = layout.cached_source_dir();
+    let cache_dir = layout.cache_dir();
+    fs::create_dir_all(cache_dir)
+        .await
+        .with_context(|| format!("Failed to create directory: {}", cache_dir.display()))?;
+
+    // reports on the same commit would resume the same .part and extract into the same
+    // staging directory. whoever gets the lock second finds the cached tree in place
+    let lock = lock_commit(cache_dir, &commit, cancel).await?;
+    // trees of other reports linked from the cache keep their files
+    if overwrite == OverwritePolicy::Overwrite {
+        remove_existing(&cached).await?;
+        remove_existing(&layout.cached_archive()).await?;
+    }
+    if fs::try_exists(&cached).await? {
+        info!("Using cached kernel source {}", cached.display());
+    } else {
+        // extract next to the cache and move the tree in once complete, so an interrupted
+        // extraction is never mistaken for a cached tree
+        let staging = cache_dir.join(format!(".extract-{}", commit));
+        if fs::try_exists(&staging).await? {
+            fs::remove_dir_all(&staging).await?;
+        }
+        fetch_source(
+            &repo,
+            &commit,
+            &layout.cached_archive(),
+            &staging,
+            expected,
+            config,
+            cancel,
+        )
+        .await?;
+        fs::rename(staging.join(source_dir.file_name().unwrap()), &cached)
+            .await
+            .with_context(|| format!("Failed to move extracted source to {}", cached.display()))?;
+        fs::remove_dir_all(&staging).await?;
+    }
+    drop(lock);
+
+    info!(
+        "Linking cached kernel source {} into {}",
+        cached.display(),
+        source_dir.display()
+    );
+    let (from, to) = (cached.clone(), source_dir.clone());
+    if let Err(e) = tokio::task::spawn_blocking(move || link_tree(&from, &to)).await? {
+        let _ = fs::remove_dir_all(&source_dir).await;
+        return Err(e);
+    }
+
+    info!("Kernel source is ready in {}", source_dir.display());
+
+    Ok(())
+}
+
+// remove the file or directory tree at `path`, if there is one
+async fn remove_existing(path: &Path) -> Result<()> {
+    let removed = match fs::symlink_metadata(path).await {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path).await,
+        Ok(_) => fs::remove_file(path).await,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => Err(e),
+    };
+    removed.with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+// one mutex per commit, for the reports of this process
+static COMMIT_LOCKS: Lazy<std::sync::Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>> =
+    Lazy::new(Default::default);
+
+// held while a commit is fetched and extracted into the cache, released on drop
+struct CommitLock {
+    _guard: tokio::sync::OwnedMutexGuard<()>,
+    _file: std::fs::File,
+}
+
+// serialize fetching `commit` into `cache_dir`: within this process through COMMIT_LOCKS,
+// against other processes sharing the workspace through an flock on linux-<commit>.lock
+async fn lock_commit(
+    cache_dir: &Path,
+    commit: &str,
+    cancel: &CancellationToken,
+) -> Result<CommitLock> {
+    let mutex = COMMIT_LOCKS
+        .lock()
+        .unwrap()
+        .entry(commit.to_string())
+        .or_default()
+        .clone();
+    let guard = tokio::select! {
+        guard = mutex.lock_owned() => guard,
+        _ = cancel.cancelled() => {
+            return Err(DownloadError::Cancelled(format!("waiting for commit {}", commit)).into());
+        }
+    };
+
+    let path = cache_dir.join(format!("linux-{}.lock", commit));
+    let file = tokio::task::spawn_blocking(move || -> Result<std::fs::File> {
+        use std::os::fd::AsRawFd;
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Failed to lock {}", path.display()));
+        }
+        Ok(file)
+    })
+    .await??;
+
+    Ok(CommitLock {
+        _guard: guard,
+        _file: file,
+    })
+}
+
+// download the tarball to `target_path` unless it is already there, verify it and extract it
+// into `save_dir` as linux-<commit>, whatever the top-level directory of the tarball is
+async fn fetch_source(
+    repo: &KernelRepo,
+    commit: &str,
+    target_path: &Path,
+    save_dir: &Path,
+    expected: Option<&str>,
+    config: &DownloadConfig,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    let download_url = repo.archive_url(commit, config);
+    match download_file(&download_url, target_path, DownloadSource::Kernel, cancel).await {
         Ok(_) => info!(
             "Kernel source downloaded successfully to: {}",
             target_path.display()
         ),
-        Err(e) => {
-            if let Some(DownloadError::FileExists(_)) = e.downcast_ref::<DownloadError>() {
+        Err(e) => match e.downcast_ref::<DownloadError>() {
+            Some(DownloadError::FileExists(_)) => {
                 warn!(
                     "Kernel source file already exists: {}. Skipping download.",
                     target_path.display()
                 );
-            } else {
+            }
+            Some(DownloadError::HttpStatus { code: 404, .. }) => {
+                if config.git_fallback {
+                    warn!(
+                        "Kernel commit {} is not in the archive of {}, cloning it with git",
+                        commit, repo
+                    );
+                    if expected.is_some() {
+                        warn!(
+                            "The kernel-source-sha256 of the report cannot be checked for a git checkout"
+                        );
+                    }
+                    let target = save_dir.join(format!("linux-{}", commit));
+                    return clone_source(&repo.git_url(), commit, &target, git_proxy()?, cancel)
+                        .await
+                        .with_context(|| format!("Commit {} not found in {}", commit, repo));
+                }
+                error!(
+                    "Kernel commit {} is not available from {}. It may have been \
+                     garbage-collected after a force push; try the commit from a stable tree or \
+                     set download.git_fallback to fetch it with git instead.",
+                    commit, repo
+                );
+                return Err(e.context(format!("Commit {} not found in {}", commit, repo)));
+            }
+            _ => {
                 error!("Failed to download kernel source: {}", e);
                 return Err(e);
             }
-        }
+        },
+    }
+
+    if let Err(e) = verify_checksum(target_path, expected).await {
+        error!("Kernel source {} is corrupt: {}", target_path.display(), e);
+        // remove it so the next run downloads it again instead of failing forever
+        let _ = fs::remove_file(target_path).await;
+        return Err(e);
     }
 
-    match decompress_file(&target_path, &save_dir).await {
+    let progress = |extracted: u64, total: Option<u64>| match total {
+        Some(total) => info!(
+            "Extracted {} / {} MB of kernel source",
+            extracted / 1024 / 1024,
+            total / 1024 / 1024
+        ),
+        None => info!("Extracted {} MB of kernel source", extracted / 1024 / 1024),
+    };
+
+    let source_name = format!("linux-{}", commit);
+    let archive_dir = repo.archive_dir(commit);
+    let extract_dir = match archive_dir {
+        Some(_) => save_dir.to_path_buf(),
+        None => save_dir.join(&source_name),
+    };
+
+    match decompress_file(target_path, &extract_dir, config, Some(&progress), cancel).await {
         Ok(_) => info!(
             "Kernel source decompressed successfully to: {}",
-            save_dir.display()
+            extract_dir.display()
         ),
         Err(e) => {
             error!("Failed to decompress kernel source: {}", e);
@@ -179,40 +1005,191 @@ pub async fn download_kernel(report: &CrashReport) -> Result<()> {
         }
     }
 
-    info!("Kernel source download and extraction completed successfully");
+    if let Some(dir) = archive_dir
+        && dir != source_name
+    {
+        let (from, to) = (save_dir.join(&dir), save_dir.join(&source_name));
+        fs::rename(&from, &to)
+            .await
+            .with_context(|| format!("Failed to rename {} to {}", from.display(), to.display()))?;
+    }
+
+    Ok(())
+}
+
+// the http.proxy git should use for kernel sources, following the proxy policy like http_client.
+// None leaves git to the proxy environment variables
+fn git_proxy() -> Result<Option<String>> {
+    let proxy = Config::load()?.proxy;
+    Ok(match proxy.policy {
+        ProxyPolicy::Auto => None,
+        // an empty http.proxy disables the proxy, including the environment
+        ProxyPolicy::Never | ProxyPolicy::SyzkallerOnly => Some(String::new()),
+        ProxyPolicy::Always => Some(format!("http://{}:{}", proxy.host, proxy.port)),
+    })
+}
+
+// check `commit` out of the repository at `url` into `target` without history: a shallow
+// clone, then a fetch of just that commit. the .git directory is removed afterwards so the
+// tree looks like an extracted tarball (and the kernel version gets no git suffix)
+async fn clone_source(
+    url: &str,
+    commit: &str,
+    target: &Path,
+    proxy: Option<String>,
+    cancel: &CancellationToken,
+) -> Result<()> {
+    info!("Cloning {} at {} into {}", url, commit, target.display());
+
+    if fs::try_exists(target).await? {
+        fs::remove_dir_all(target).await?;
+    }
+    let target_str = target.to_string_lossy();
+    let steps: [&[&str]; 3] = [
+        &["clone", "--depth", "1", "--no-checkout", url, &target_str],
+        &["-C", &target_str, "fetch", "--depth", "1", "origin", commit],
+        &["-C", &target_str, "checkout", "--detach", commit],
+    ];
+
+    for args in steps {
+        if let Err(e) = run_git(args, proxy.as_deref(), cancel).await {
+            let _ = fs::remove_dir_all(target).await;
+            return Err(e);
+        }
+    }
 
+    fs::remove_dir_all(target.join(".git"))
+        .await
+        .with_context(|| format!("Failed to remove {}/.git", target.display()))?;
+    info!("Kernel source checked out to: {}", target.display());
+    Ok(())
+}
+
+async fn run_git(args: &[&str], proxy: Option<&str>, cancel: &CancellationToken) -> Result<()> {
+    let mut command = tokio::process::Command::new("git");
+    if let Some(proxy) = proxy {
+        command.arg("-c").arg(format!("http.proxy={}", proxy));
+    }
+    let child = command
+        .args(args)
+        .stdin(std::process::Stdio::null())
+        .stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("Failed to run git")?;
+
+    let output = tokio::select! {
+        output = child.wait_with_output() => output.context("Failed to run git")?,
+        _ = cancel.cancelled() => {
+            return Err(DownloadError::Cancelled(format!("git {}", args.join(" "))).into());
+        }
+    };
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
     Ok(())
 }
 
-pub async fn download_bug(report: &Arc<CrashReport>) -> Result<()> {
+// mirror the tree `from` at `to` with hardlinks, copying when they cross filesystems.
+// patch(1) and the O= build never write to source files in place, so the cache stays pristine
+pub(crate) fn link_tree(from: &Path, to: &Path) -> Result<()> {
+    std::fs::create_dir_all(to)
+        .with_context(|| format!("Failed to create directory: {}", to.display()))?;
+
+    for entry in std::fs::read_dir(from)
+        .with_context(|| format!("Failed to read directory: {}", from.display()))?
+    {
+        let entry = entry?;
+        let (source, target) = (entry.path(), to.join(entry.file_name()));
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            link_tree(&source, &target)?;
+        } else if file_type.is_symlink() {
+            std::os::unix::fs::symlink(std::fs::read_link(&source)?, &target)
+                .with_context(|| format!("Failed to create symlink: {}", target.display()))?;
+        } else if std::fs::hard_link(&source, &target).is_err() {
+            std::fs::copy(&source, &target)
+                .with_context(|| format!("Failed to copy {}", source.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+// drop every cached kernel tarball and source tree
+pub async fn clear_cache() -> Result<()> {
+    let cache_dir = cache_path();
+
+    if fs::try_exists(&cache_dir).await? {
+        info!("Removing download cache {}", cache_dir.display());
+        fs::remove_dir_all(&cache_dir)
+            .await
+            .with_context(|| format!("Failed to remove {}", cache_dir.display()))?;
+    }
+
+    Ok(())
+}
+
+// the directory of the crash's own files, inside the workspace download_kernel created
+async fn create_crash_dir(layout: &Layout) -> Result<()> {
+    if !fs::try_exists(layout.root()).await? {
+        anyhow::bail!(
+            "Build directory does not exist or is not a directory: {}",
+            layout.root().display()
+        );
+    }
+    fs::create_dir_all(layout.crash_dir())
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to create directory: {}",
+                layout.crash_dir().display()
+            )
+        })
+}
+
+pub async fn download_bug(
+    report: &Arc<CrashReport>,
+    crash_index: usize,
+    overwrite: OverwritePolicy,
+    cancel: &CancellationToken,
+) -> Result<()> {
     if report.crashes.is_empty() {
         anyhow::bail!("No crashes found in the report, cannot download bug.");
     }
 
-    let c_reproducer = report.crashes.first().unwrap().c_reproducer.clone();
-    let c_reproducer = c_reproducer.trim().trim_start_matches('/');
-    let download_url = format!("{}{}", SYZKALLER_URL, c_reproducer);
+    let c_reproducer = &report.crash(crash_index)?.c_reproducer;
+    let download_url = Config::load()?.download.syzkaller_url(c_reproducer);
 
     info!(
         "Preparing to download bug reproducer from: {}",
         download_url
     );
 
-    let build_dir = build_path(report);
-    let reproducer_path = build_dir.join("bug.c");
+    let layout = Layout::for_crash(report, crash_index)?;
+    let reproducer_path = layout.reproducer_path();
 
     info!("Saving bug reproducer to: {}", reproducer_path.display());
 
-    if !fs::try_exists(&build_dir).await? {
-        anyhow::bail!(
-            "Build directory does not exist or is not a directory: {}",
-            build_dir.display()
-        );
+    create_crash_dir(&layout).await?;
+    if !prepare_target(&reproducer_path, overwrite).await? {
+        return Ok(());
     }
 
-    download_file(&download_url, &reproducer_path, true)
-        .await
-        .with_context(|| format!("Failed to download bug reproducer from {}", download_url))?;
+    download_file(
+        &download_url,
+        &reproducer_path,
+        DownloadSource::Syzkaller,
+        cancel,
+    )
+    .await
+    .with_context(|| format!("Failed to download bug reproducer from {}", download_url))?;
 
     info!(
         "Bug reproducer downloaded successfully to: {}",
@@ -222,27 +1199,120 @@ pub async fn download_bug(report: &Arc<CrashReport>) -> Result<()> {
     Ok(())
 }
 
-pub async fn download_config(report: &Arc<CrashReport>) -> Result<()> {
+// the syzkaller program of the crash, for running it with syz-execprog. returns None when the
+// report has no syz reproducer
+pub async fn download_syz_reproducer(
+    report: &Arc<CrashReport>,
+    crash_index: usize,
+    cancel: &CancellationToken,
+) -> Result<Option<PathBuf>> {
+    let syz_reproducer = report.crash(crash_index)?.syz_reproducer.trim();
+    if syz_reproducer.is_empty() {
+        info!("Report {} has no syz reproducer", report.id);
+        return Ok(None);
+    }
+    let download_url = Config::load()?.download.syzkaller_url(syz_reproducer);
+
+    let layout = Layout::for_crash(report, crash_index)?;
+    let reproducer_path = layout.syz_reproducer_path();
+
+    if fs::try_exists(&reproducer_path).await? {
+        info!(
+            "Syz reproducer already downloaded: {}",
+            reproducer_path.display()
+        );
+        return Ok(Some(reproducer_path));
+    }
+    create_crash_dir(&layout).await?;
+
+    info!(
+        "Downloading syz reproducer from {} to {}",
+        download_url,
+        reproducer_path.display()
+    );
+    download_file(
+        &download_url,
+        &reproducer_path,
+        DownloadSource::Syzkaller,
+        cancel,
+    )
+    .await
+    .with_context(|| format!("Failed to download syz reproducer from {}", download_url))?;
+
+    Ok(Some(reproducer_path))
+}
+
+// syzbot's crash report of the crash, parsed into the signature the guest console is matched
+// against
+pub async fn download_crash_log(
+    report: &Arc<CrashReport>,
+    crash_index: usize,
+    cancel: &CancellationToken,
+) -> Result<CrashSignature> {
+    let crash = report.crash(crash_index)?;
+    let link = crash.crash_report_link.trim();
+    if link.is_empty() {
+        anyhow::bail!("Report {} has no crash report link", report.id);
+    }
+
+    let layout = Layout::for_crash(report, crash_index)?;
+    let log_path = layout.crash_log_path();
+    if fs::try_exists(&log_path).await? {
+        info!("Crash log already downloaded: {}", log_path.display());
+    } else {
+        create_crash_dir(&layout).await?;
+        let download_url = Config::load()?.download.syzkaller_url(link);
+        info!(
+            "Downloading crash log from {} to {}",
+            download_url,
+            log_path.display()
+        );
+        download_file(&download_url, &log_path, DownloadSource::Syzkaller, cancel)
+            .await
+            .with_context(|| format!("Failed to download crash log from {}", download_url))?;
+    }
+
+    let log = fs::read_to_string(&log_path)
+        .await
+        .with_context(|| format!("Failed to read {}", log_path.display()))?;
+    CrashSignature::parse(&log, &crash.title)
+        .with_context(|| format!("Failed to parse crash log {}", log_path.display()))
+}
+
+pub async fn download_config(
+    report: &Arc<CrashReport>,
+    crash_index: usize,
+    overwrite: OverwritePolicy,
+    cancel: &CancellationToken,
+) -> Result<()> {
     if report.crashes.is_empty() {
         anyhow::bail!("No crashes found in the report, cannot download config.");
     }
 
-    let config = report.crashes.first().unwrap().kernel_config.clone();
-    let config = config.trim().trim_start_matches('/');
-    let download_url = format!("{}{}", SYZKALLER_URL, config);
+    let config = &report.crash(crash_index)?.kernel_config;
+    let download_url = Config::load()?.download.syzkaller_url(config);
 
-    let build_dir = build_path(report).join("build");
-    let config_path = build_dir.join(".config");
+    let layout = Layout::for_crash(report, crash_index)?;
+    let build_dir = layout.build_out_dir();
+    let config_path = layout.config_path();
 
     info!("Preparing to download kernel config from: {}", download_url);
 
     fs::create_dir_all(&build_dir)
         .await
         .with_context(|| format!("Failed to create directory: {}", build_dir.display()))?;
+    if !prepare_target(&config_path, overwrite).await? {
+        return Ok(());
+    }
 
-    download_file(&download_url, &config_path, true)
-        .await
-        .with_context(|| format!("Failed to download kernel config from {}", download_url))?;
+    download_file(
+        &download_url,
+        &config_path,
+        DownloadSource::Syzkaller,
+        cancel,
+    )
+    .await
+    .with_context(|| format!("Failed to download kernel config from {}", download_url))?;
 
     info!(
         "Kernel config downloaded successfully to: {}",
@@ -251,3 +1321,513 @@ pub async fn download_config(report: &Arc<CrashReport>) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancelled_extraction_is_removed() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, "linux/a.c", &b"a"[..])
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("keep"), "").unwrap();
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let result = extract_archive(
+            Box::new(std::io::Cursor::new(archive)),
+            Path::new("linux.tar"),
+            dir.path(),
+            &cancel,
+            |_| {},
+        );
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<DownloadError>(),
+            Some(DownloadError::Cancelled(_))
+        ));
+        assert!(dir.path().join("keep").exists());
+    }
+
+    #[tokio::test]
+    async fn test_resume_partial_download() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/linux.tar.gz", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request = String::from_utf8(request).unwrap().to_lowercase();
+            assert!(request.contains("range: bytes=6-"));
+
+            stream
+                .write_all(
+                    b"HTTP/1.1 206 Partial Content\r\n\
+                      Content-Range: bytes 6-10/11\r\n\
+                      Content-Length: 5\r\n\
+                      Connection: close\r\n\r\n\
+                      world",
+                )
+                .await
+                .unwrap();
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("linux.tar.gz");
+        std::fs::write(partial_path(&target), "hello ").unwrap();
+
+        download_file(
+            &url,
+            &target,
+            DownloadSource::Kernel,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        server.await.unwrap();
+
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "hello world");
+        assert!(!partial_path(&target).exists());
+
+        let err = download_file(
+            &url,
+            &target,
+            DownloadSource::Kernel,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DownloadError>(),
+            Some(DownloadError::FileExists(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resume_past_end() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/linux.tar.gz", listener.local_addr().unwrap());
+
+        // the file on the server is 5 bytes long
+        let server = tokio::spawn(async move {
+            let mut ranges = Vec::new();
+            for _ in 0..3 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap().to_lowercase();
+                let range = request.contains("range: bytes=");
+                ranges.push(range);
+
+                let response: &[u8] = if range {
+                    b"HTTP/1.1 416 Range Not Satisfiable\r\n\
+                      Content-Range: bytes */5\r\n\
+                      Content-Length: 0\r\n\
+                      Connection: close\r\n\r\n"
+                } else {
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello"
+                };
+                stream.write_all(response).await.unwrap();
+            }
+            ranges
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let cancel = CancellationToken::new();
+
+        // longer than the file: discarded and fetched again
+        let target = dir.path().join("linux.tar.gz");
+        std::fs::write(partial_path(&target), "hello world").unwrap();
+        download_file(&url, &target, DownloadSource::Kernel, &cancel)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "hello");
+        assert!(!partial_path(&target).exists());
+
+        // already complete: moved into place
+        let target = dir.path().join("complete.tar.gz");
+        std::fs::write(partial_path(&target), "hello").unwrap();
+        download_file(&url, &target, DownloadSource::Kernel, &cancel)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "hello");
+        assert!(!partial_path(&target).exists());
+
+        assert_eq!(server.await.unwrap(), [true, false, true]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("linux.tar.gz");
+        std::fs::write(&path, "abc").unwrap();
+
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert!(verify_checksum(&path, None).await.is_ok());
+        assert!(verify_checksum(&path, Some(digest)).await.is_ok());
+        assert!(
+            verify_checksum(&path, Some(&digest.to_uppercase()))
+                .await
+                .is_ok()
+        );
+
+        let err = verify_checksum(&path, Some("00")).await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DownloadError>(),
+            Some(DownloadError::ChecksumMismatch { actual, .. }) if actual == digest
+        ));
+    }
+
+    // a tarball holding linux/Makefile, to be compressed by each codec
+    fn codec_tarball() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(6);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "linux/Makefile", &b"kernel"[..])
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    async fn assert_decompresses(archive: &Path, target: &Path) {
+        decompress_file(
+            archive,
+            target,
+            &DownloadConfig::default(),
+            None,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(target.join("linux/Makefile")).unwrap(),
+            "kernel"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_decompress_gzip() {
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("linux.tar.gz");
+        let mut encoder = flate2::write::GzEncoder::new(
+            std::fs::File::create(&archive).unwrap(),
+            flate2::Compression::fast(),
+        );
+        encoder.write_all(&codec_tarball()).unwrap();
+        encoder.finish().unwrap();
+
+        assert_decompresses(&archive, &dir.path().join("gzip")).await;
+    }
+
+    // xz and zstd are decoded by their command line tools, which the test needs as well
+    #[tokio::test]
+    #[ignore = "needs the xz and zstd tools on PATH"]
+    async fn test_decompress_xz_zstd() {
+        let dir = tempfile::tempdir().unwrap();
+        let tar_path = dir.path().join("linux.tar");
+        std::fs::write(&tar_path, codec_tarball()).unwrap();
+
+        for (codec, extension) in [("xz", "xz"), ("zstd", "zst")] {
+            let tool = crate::script::tool::require_tool(codec).unwrap();
+            let output = std::process::Command::new(tool)
+                .arg("-c")
+                .arg(&tar_path)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            let archive = dir.path().join(format!("linux.tar.{}", extension));
+            std::fs::write(&archive, output.stdout).unwrap();
+
+            assert_decompresses(&archive, &dir.path().join(codec)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_status_error() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/missing.tar.gz", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = stream.read(&mut buf).await.unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let err = download_file(
+            &url,
+            &dir.path().join("missing.tar.gz"),
+            DownloadSource::Kernel,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DownloadError>(),
+            Some(DownloadError::HttpStatus { code: 404, .. })
+        ));
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let status = |code| {
+            anyhow::Error::from(DownloadError::HttpStatus {
+                code,
+                url: "https://example.com".to_string(),
+            })
+        };
+        assert!(is_transient(&status(503)));
+        assert!(!is_transient(&status(404)));
+        assert!(!is_transient(&anyhow::anyhow!("disk full")));
+    }
+
+    #[test]
+    fn test_link_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("cache/linux-abc");
+        std::fs::create_dir_all(from.join("include")).unwrap();
+        std::fs::write(from.join("Makefile"), "all:").unwrap();
+        std::os::unix::fs::symlink("../Makefile", from.join("include/Makefile")).unwrap();
+
+        let to = dir.path().join("report/linux-abc");
+        link_tree(&from, &to).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(to.join("Makefile")).unwrap(),
+            "all:"
+        );
+        assert_eq!(
+            std::fs::read_link(to.join("include/Makefile")).unwrap(),
+            Path::new("../Makefile")
+        );
+        use std::os::unix::fs::MetadataExt;
+        assert_eq!(
+            std::fs::metadata(from.join("Makefile")).unwrap().ino(),
+            std::fs::metadata(to.join("Makefile")).unwrap().ino()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_download() {
+        // accepts the connection but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/linux.tar.gz", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("linux.tar.gz");
+        std::fs::write(partial_path(&target), "hello ").unwrap();
+
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.cancel();
+        });
+
+        let err = download_file(&url, &target, DownloadSource::Kernel, &cancel)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DownloadError>(),
+            Some(DownloadError::Cancelled(_))
+        ));
+        assert!(!partial_path(&target).exists());
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn test_remove_extracted() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("linux-abc/kernel")).unwrap();
+        std::fs::write(dir.path().join("pax_global_header"), "").unwrap();
+        std::fs::write(dir.path().join("bug.c"), "").unwrap();
+
+        let entries = ["linux-abc", "pax_global_header", "missing"]
+            .into_iter()
+            .map(std::ffi::OsString::from)
+            .collect();
+        remove_extracted(dir.path(), &entries);
+
+        assert!(!dir.path().join("linux-abc").exists());
+        assert!(!dir.path().join("pax_global_header").exists());
+        assert!(dir.path().join("bug.c").exists());
+    }
+
+    #[tokio::test]
+    async fn test_clone_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = dir.path().join("origin");
+        let git = |args: &[&str]| {
+            let status = std::process::Command::new("git")
+                .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+                .arg("-C")
+                .arg(&origin)
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(status.status.success(), "{:?}", status);
+            String::from_utf8(status.stdout).unwrap().trim().to_string()
+        };
+        std::fs::create_dir_all(&origin).unwrap();
+        git(&["init", "-q"]);
+        std::fs::write(origin.join("Makefile"), "VERSION = 1\n").unwrap();
+        git(&["add", "Makefile"]);
+        git(&["commit", "-q", "-m", "first"]);
+        let first = git(&["rev-parse", "HEAD"]);
+        // the wanted commit is no longer the tip of any branch
+        std::fs::write(origin.join("Makefile"), "VERSION = 2\n").unwrap();
+        git(&["commit", "-q", "-am", "second"]);
+
+        let url = format!("file://{}", origin.display());
+        let target = dir.path().join(format!("linux-{}", first));
+        let cancel = CancellationToken::new();
+        clone_source(&url, &first, &target, None, &cancel)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(target.join("Makefile")).unwrap(),
+            "VERSION = 1\n"
+        );
+        assert!(!target.join(".git").exists());
+
+        let missing = "0123456789abcdef0123456789abcdef01234567";
+        let target = dir.path().join("linux-missing");
+        assert!(
+            clone_source(&url, missing, &target, None, &cancel)
+                .await
+                .is_err()
+        );
+        assert!(!target.exists());
+    }
+
+    #[tokio::test]
+    async fn test_prepare_target() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join(".config");
+        for overwrite in [
+            OverwritePolicy::Skip,
+            OverwritePolicy::Overwrite,
+            OverwritePolicy::Error,
+        ] {
+            assert!(prepare_target(&target, overwrite).await.unwrap());
+        }
+
+        // an empty leftover is downloaded again whatever the policy
+        std::fs::write(&target, "").unwrap();
+        assert!(
+            prepare_target(&target, OverwritePolicy::Error)
+                .await
+                .unwrap()
+        );
+        assert!(!target.exists());
+
+        std::fs::write(&target, "CONFIG_KASAN=y\n").unwrap();
+        assert!(
+            !prepare_target(&target, OverwritePolicy::Skip)
+                .await
+                .unwrap()
+        );
+        assert!(target.exists());
+        let err = prepare_target(&target, OverwritePolicy::Error)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DownloadError>(),
+            Some(DownloadError::FileExists(_))
+        ));
+        assert!(
+            prepare_target(&target, OverwritePolicy::Overwrite)
+                .await
+                .unwrap()
+        );
+        assert!(!target.exists());
+    }
+
+    #[tokio::test]
+    async fn test_lock_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let cancel = CancellationToken::new();
+
+        let first = lock_commit(dir.path(), "abc", &cancel).await.unwrap();
+        assert!(dir.path().join("linux-abc.lock").exists());
+        // another commit is not held up
+        lock_commit(dir.path(), "def", &cancel).await.unwrap();
+        assert!(
+            tokio::time::timeout(
+                Duration::from_millis(100),
+                lock_commit(dir.path(), "abc", &cancel)
+            )
+            .await
+            .is_err()
+        );
+
+        let waiter = CancellationToken::new();
+        waiter.cancel();
+        let err = lock_commit(dir.path(), "abc", &waiter).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<DownloadError>(),
+            Some(DownloadError::Cancelled(_))
+        ));
+
+        drop(first);
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            lock_commit(dir.path(), "abc", &cancel),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remove_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let tree = dir.path().join("linux-abc");
+        std::fs::create_dir_all(tree.join("kernel")).unwrap();
+        std::fs::write(tree.join("kernel/fork.c"), "").unwrap();
+        let archive = dir.path().join("linux-abc.tar.gz");
+        std::fs::write(&archive, "gz").unwrap();
+
+        for path in [&tree, &archive] {
+            remove_existing(path).await.unwrap();
+            assert!(!path.exists());
+            // nothing left to remove is not an error
+            remove_existing(path).await.unwrap();
+        }
+    }
+}
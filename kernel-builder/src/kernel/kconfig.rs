@@ -0,0 +1,384 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tristate {
+    Yes,
+    Module,
+    No,
+}
+
+// the value of one symbol in a .config. the file does not say which Kconfig type a symbol
+// has, so `y`/`n` are read as Bool and only `m` as Tristate. values compare by their
+// .config spelling, Bool(true) equals Tristate(Yes) and 0x0100 equals 0x100
+#[derive(Debug, Clone)]
+pub enum ConfigValue {
+    Bool(bool),
+    Tristate(Tristate),
+    String(String),
+    Int(i64),
+    Hex(u64),
+}
+
+impl ConfigValue {
+    // a value in .config syntax: y/m/n, a quoted string, a decimal or a 0x number
+    pub fn parse(value: &str) -> Result<ConfigValue> {
+        let value = value.trim();
+        match value {
+            "y" => return Ok(ConfigValue::Bool(true)),
+            "n" => return Ok(ConfigValue::Bool(false)),
+            "m" => return Ok(ConfigValue::Tristate(Tristate::Module)),
+            _ => {}
+        }
+        if let Some(inner) = value.strip_prefix('"') {
+            return parse_string(inner)
+                .map(ConfigValue::String)
+                .with_context(|| format!("Unterminated string value {}", value));
+        }
+        if let Some(hex) = value
+            .strip_prefix("0x")
+            .or_else(|| value.strip_prefix("0X"))
+        {
+            return u64::from_str_radix(hex, 16)
+                .map(ConfigValue::Hex)
+                .with_context(|| format!("Invalid hex value {}", value));
+        }
+        value
+            .parse::<i64>()
+            .map(ConfigValue::Int)
+            .with_context(|| format!("Invalid config value {}", value))
+    }
+
+    // a value as written in config/kernel.toml, where strings need not be quoted
+    pub fn from_toml(value: &str) -> ConfigValue {
+        ConfigValue::parse(value).unwrap_or_else(|_| ConfigValue::String(value.trim().to_string()))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(
+            self,
+            ConfigValue::Bool(false) | ConfigValue::Tristate(Tristate::No)
+        )
+    }
+
+    // the value as `make` writes it after the `=`, "n" for a disabled symbol
+    pub fn to_kconfig_string(&self) -> String {
+        match self {
+            ConfigValue::Bool(true) | ConfigValue::Tristate(Tristate::Yes) => "y".to_string(),
+            ConfigValue::Bool(false) | ConfigValue::Tristate(Tristate::No) => "n".to_string(),
+            ConfigValue::Tristate(Tristate::Module) => "m".to_string(),
+            ConfigValue::String(s) => {
+                format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            ConfigValue::Int(n) => n.to_string(),
+            ConfigValue::Hex(n) => format!("0x{:x}", n),
+        }
+    }
+}
+
+impl PartialEq for ConfigValue {
+    fn eq(&self, other: &ConfigValue) -> bool {
+        self.to_kconfig_string() == other.to_kconfig_string()
+    }
+}
+
+impl Eq for ConfigValue {}
+
+impl fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_kconfig_string())
+    }
+}
+
+// the body of a quoted value after the opening quote, which must close at its last character
+fn parse_string(inner: &str) -> Option<String> {
+    let mut unescaped = String::new();
+    let mut escaped = false;
+    for (i, c) in inner.char_indices() {
+        match c {
+            _ if escaped => {
+                unescaped.push(c);
+                escaped = false;
+            }
+            '\\' => escaped = true,
+            '"' => return (i == inner.len() - 1).then_some(unescaped),
+            _ => unescaped.push(c),
+        }
+    }
+    None
+}
+
+// a parsed .config. the lines are kept as read so that rewriting the file only touches the
+// symbols that were set
+#[derive(Debug, Clone, Default)]
+pub struct KernelConfig {
+    lines: Vec<String>,
+    // symbol -> (index into `lines`, value)
+    entries: HashMap<String, (usize, ConfigValue)>,
+}
+
+impl KernelConfig {
+    // lines we would silently misread are rejected, so the file is safe to rewrite
+    pub fn parse(content: &str) -> Result<KernelConfig> {
+        let mut config = KernelConfig::default();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            let index = config.lines.len();
+            config.lines.push(line.to_string());
+
+            match parse_config_line(line) {
+                Some((key, value)) => {
+                    let valid_key = key.starts_with("CONFIG_")
+                        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                    let value = ConfigValue::parse(&value).ok().filter(|_| valid_key);
+                    let Some(value) = value else {
+                        anyhow::bail!("Malformed kernel config line {}: {}", number + 1, line);
+                    };
+                    config.entries.insert(key, (index, value));
+                }
+                None if line.is_empty() || line.starts_with('#') => {}
+                None => anyhow::bail!("Malformed kernel config line {}: {}", number + 1, line),
+            }
+        }
+        Ok(config)
+    }
+
+    pub async fn from_file(path: &Path) -> Result<KernelConfig> {
+        let content = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to open config file at {}", path.display()))?;
+        KernelConfig::parse(&content).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    // `# CONFIG_X is not set` reads as Bool(false), an absent symbol as None
+    pub fn get(&self, key: &str) -> Option<&ConfigValue> {
+        self.entries.get(key).map(|(_, value)| value)
+    }
+
+    // set `key`, rewriting its line in place (keeping a trailing comment) or appending one.
+    // returns the previous value
+    pub fn set(&mut self, key: &str, value: ConfigValue) -> Option<ConfigValue> {
+        match self.entries.get_mut(key) {
+            Some((index, current)) => {
+                if *current == value {
+                    return Some(value);
+                }
+                let line = &self.lines[*index];
+                self.lines[*index] = match split_comment(line) {
+                    // `# X is not set` is itself a comment, there is nothing to keep
+                    (_, Some(comment)) if !line.starts_with('#') => {
+                        format!("{} {}", format_config_line(key, &value), comment)
+                    }
+                    _ => format_config_line(key, &value),
+                };
+                Some(std::mem::replace(current, value))
+            }
+            None => {
+                self.lines.push(format_config_line(key, &value));
+                self.entries
+                    .insert(key.to_string(), (self.lines.len() - 1, value));
+                None
+            }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &ConfigValue)> {
+        self.entries
+            .iter()
+            .map(|(key, (_, value))| (key.as_str(), value))
+    }
+
+    // the whole file, ready to be written back
+    pub fn to_kconfig_string(&self) -> String {
+        self.lines.join("\n") + "\n"
+    }
+}
+
+// split a line into its content and a trailing `#` comment, ignoring `#` inside quoted strings
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return (line[..i].trim_end(), Some(&line[i..])),
+            _ => {}
+        }
+    }
+
+    (line, None)
+}
+
+// `# CONFIG_X is not set` is reported as "n", string values keep their quotes
+fn parse_config_line(line: &str) -> Option<(String, String)> {
+    if let Some(key) = line
+        .strip_prefix("# CONFIG_")
+        .and_then(|s| s.strip_suffix(" is not set"))
+    {
+        return Some((format!("CONFIG_{}", key.trim()), "n".to_string()));
+    }
+
+    if line.starts_with('#') {
+        return None;
+    }
+
+    let (content, _) = split_comment(line);
+    content
+        .split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+}
+
+fn format_config_line(key: &str, value: &ConfigValue) -> String {
+    if value.is_enabled() {
+        format!("{}={}", key, value.to_kconfig_string())
+    } else {
+        format!("# {} is not set", key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config_value() {
+        let parse = |value| ConfigValue::parse(value).unwrap();
+
+        assert_eq!(parse("y"), ConfigValue::Bool(true));
+        assert_eq!(parse("y"), ConfigValue::Tristate(Tristate::Yes));
+        assert!(matches!(
+            parse("m"),
+            ConfigValue::Tristate(Tristate::Module)
+        ));
+        assert!(matches!(parse("17"), ConfigValue::Int(17)));
+        assert!(matches!(parse("-1"), ConfigValue::Int(-1)));
+        assert!(matches!(parse("0x1000000"), ConfigValue::Hex(0x1000000)));
+        assert_eq!(parse("0X0100"), ConfigValue::Hex(0x100));
+        assert_eq!(
+            parse("\"root=\\\"/dev/sda\\\"\""),
+            ConfigValue::String("root=\"/dev/sda\"".to_string())
+        );
+
+        assert!(ConfigValue::parse("\"unterminated").is_err());
+        assert!(ConfigValue::parse("maybe").is_err());
+        assert!(ConfigValue::parse("0xzz").is_err());
+
+        assert_eq!(
+            ConfigValue::from_toml("quiet"),
+            ConfigValue::String("quiet".to_string())
+        );
+        assert_eq!(
+            ConfigValue::from_toml("\"quiet\""),
+            ConfigValue::String("quiet".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_config_line() {
+        let parse = |line| parse_config_line(line).map(|(_, value)| value);
+
+        assert_eq!(
+            parse("CONFIG_CMDLINE=\"console=ttyS0 # not a comment\""),
+            Some("\"console=ttyS0 # not a comment\"".to_string())
+        );
+        assert_eq!(
+            parse("CONFIG_KCOV=m # needed for fuzzing"),
+            Some("m".to_string())
+        );
+        assert_eq!(parse("CONFIG_LOG_BUF_SHIFT=17"), Some("17".to_string()));
+        assert_eq!(
+            parse("CONFIG_PHYSICAL_START=0x1000000"),
+            Some("0x1000000".to_string())
+        );
+        assert_eq!(parse("# CONFIG_KASAN is not set"), Some("n".to_string()));
+        assert_eq!(parse("# a plain comment"), None);
+    }
+
+    #[test]
+    fn test_format_config_line() {
+        let format = |key, value| format_config_line(key, &ConfigValue::from_toml(value));
+
+        assert_eq!(format("CONFIG_KCOV", "y"), "CONFIG_KCOV=y");
+        assert_eq!(format("CONFIG_KCOV", "m"), "CONFIG_KCOV=m");
+        assert_eq!(format("CONFIG_KCOV", "n"), "# CONFIG_KCOV is not set");
+        assert_eq!(format("CONFIG_SHIFT", "17"), "CONFIG_SHIFT=17");
+        assert_eq!(format("CONFIG_START", "0x100"), "CONFIG_START=0x100");
+        assert_eq!(
+            format("CONFIG_CMDLINE", "root=\"/dev/sda\""),
+            "CONFIG_CMDLINE=\"root=\\\"/dev/sda\\\"\""
+        );
+        assert_eq!(
+            format("CONFIG_CMDLINE", "\"quiet\""),
+            "CONFIG_CMDLINE=\"quiet\""
+        );
+
+        for value in ["y", "m", "n", "17", "0x100", "a=b"] {
+            let line = format("CONFIG_X", value);
+            assert!(KernelConfig::parse(&line).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_parse_kernel_config() {
+        let config = KernelConfig::parse(
+            "#\n\
+             # Automatically generated file; DO NOT EDIT.\n\
+             \n\
+             CONFIG_CC_VERSION_TEXT=\"gcc (GCC) 10.2.1 20210110\"\n\
+             CONFIG_KCOV=y\n\
+             # CONFIG_KASAN is not set\n",
+        )
+        .unwrap();
+        assert_eq!(config.get("CONFIG_KCOV"), Some(&ConfigValue::Bool(true)));
+        assert_eq!(config.get("CONFIG_KASAN"), Some(&ConfigValue::Bool(false)));
+        assert_eq!(config.get("CONFIG_UBSAN"), None);
+        assert_eq!(config.iter().count(), 3);
+
+        assert!(KernelConfig::parse("CONFIG_CMDLINE=\"unterminated").is_err());
+        assert!(KernelConfig::parse("KCOV=y").is_err());
+        assert!(KernelConfig::parse("CONFIG_KCOV=maybe").is_err());
+        assert!(KernelConfig::parse("not a config line").is_err());
+    }
+
+    #[test]
+    fn test_set_config_value() {
+        let mut config = KernelConfig::parse(
+            "CONFIG_KASAN=y\n\
+             CONFIG_KCOV=m # fuzzing\n\
+             CONFIG_START=0X0100\n\
+             # CONFIG_DEBUG_INFO is not set\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.set("CONFIG_KCOV", ConfigValue::Bool(true)),
+            Some(ConfigValue::Tristate(Tristate::Module))
+        );
+        config.set("CONFIG_DEBUG_INFO", ConfigValue::Bool(true));
+        config.set("CONFIG_KASAN", ConfigValue::Bool(false));
+        // the same value, the line is left as written
+        config.set("CONFIG_START", ConfigValue::Hex(0x100));
+        assert_eq!(
+            config.set("CONFIG_CMDLINE", ConfigValue::String("quiet".to_string())),
+            None
+        );
+
+        assert_eq!(
+            config.to_kconfig_string(),
+            "# CONFIG_KASAN is not set\n\
+             CONFIG_KCOV=y # fuzzing\n\
+             CONFIG_START=0X0100\n\
+             CONFIG_DEBUG_INFO=y\n\
+             CONFIG_CMDLINE=\"quiet\"\n"
+        );
+        assert_eq!(
+            config.get("CONFIG_CMDLINE"),
+            Some(&ConfigValue::String("quiet".to_string()))
+        );
+    }
+}
@@ -1,3 +1,10 @@
+pub mod artifacts;
+pub mod ccache;
+pub mod compdb;
+pub mod compile;
 pub mod download;
+pub mod kconfig;
 pub mod modify;
-pub mod compile;
\ No newline at end of file
+pub mod nix;
+pub mod policy;
+pub mod repo;
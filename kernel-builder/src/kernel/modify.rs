@@ -1,170 +1,376 @@
+use crate::kernel::compile::BuildOptions;
+use crate::kernel::kconfig::{ConfigValue, KernelConfig};
+use crate::kernel::nix::NixCommand;
+use crate::kernel::policy::{ConfigPolicy, PolicyViolations};
+use crate::parse::arch::select_architecture;
 use crate::parse::compiler::select_compiler;
-use crate::parse::parse::{build_path, kernel_source_path};
 use crate::parse::report::CrashReport;
 use anyhow::{Context, Result};
-use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
 use tokio::fs;
-use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, BufReader};
-use tokio::process::Command;
-use tracing::info;
-
-async fn load_kernel_config() -> Result<HashMap<String, String>> {
-    let mut kernel_config_path = PathBuf::from(env::current_dir()?);
-    kernel_config_path.push("config");
-    kernel_config_path.push("kernel.toml");
-
-    info!(
-        "Loading kernel configuration from: {}",
-        kernel_config_path.display()
-    );
-
-    let kernel_config_content = tokio::fs::read_to_string(&kernel_config_path)
-        .await
-        .with_context(|| {
-            format!(
-                "Failed to read kernel configuration file from {}",
-                kernel_config_path.display()
-            )
-        })?;
-
-    info!("Kernel configuration loaded successfully");
-
-    let config: HashMap<String, String> =
-        toml::from_str(&kernel_config_content).with_context(|| {
-            format!(
-                "Failed to parse kernel configuration from {}",
-                kernel_config_path.display()
-            )
-        })?;
-
-    Ok(config)
+use tracing::{debug, info, warn};
+
+const OLDDEFCONFIG_TIMEOUT: Duration = Duration::from_secs(600);
+
+// config/kernel.toml with config/overrides/<report-id>.toml layered on top,
+// also returns the keys that were taken from the override
+async fn load_kernel_config(report_id: &str) -> Result<(ConfigPolicy, Vec<String>)> {
+    let config_dir = env::current_dir()?.join("config");
+    let mut policy = ConfigPolicy::from_file(&config_dir.join("kernel.toml")).await?;
+
+    let override_path = config_dir
+        .join("overrides")
+        .join(format!("{}.toml", report_id));
+    if !fs::try_exists(&override_path).await? {
+        return Ok((policy, Vec::new()));
+    }
+
+    let overrides = ConfigPolicy::from_file(&override_path).await?;
+    let overridden = policy.merge(overrides);
+
+    Ok((policy, overridden))
 }
 
-pub async fn check_fix_config(report: &Arc<CrashReport>) -> Result<()> {
-    let root_dir = build_path(report);
-    let kernel_source_dir = kernel_source_path(report);
+// symbols changed between two parsed .config files
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigDiff {
+    pub added: Vec<(String, String)>,
+    pub removed: Vec<(String, String)>,
+    // (key, before, after)
+    pub changed: Vec<(String, String, String)>,
+}
 
-    let config_path = root_dir.join("build").join(".config");
-    let shell_script_path = env::current_dir()?.join("nix").join("shell.nix");
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
 
-    let kernel_config = load_kernel_config().await?; // configuration to be modified
+// requirements `make olddefconfig` would not keep, usually because of unmet dependencies
+#[derive(Debug, Error)]
+#[error("kernel config does not satisfy kernel.toml: {0}")]
+pub struct ConfigUnsatisfied(pub PolicyViolations);
 
-    let file = File::open(&config_path)
-        .await
-        .with_context(|| format!("Failed to open config file at {}", config_path.display()))?;
-    let reader = BufReader::new(file);
-    let mut lines_stream = reader.lines();
-
-    let mut lines = Vec::new();
-    let mut config = HashMap::new();
-
-    while let Some(line) = lines_stream.next_line().await? {
-        let trimmed_line = line.trim();
-        lines.push(trimmed_line.to_string());
-
-        if let Some(key) = trimmed_line
-            .strip_prefix("# CONFIG_")
-            .and_then(|s| s.strip_suffix(" is not set"))
-        {
-            let full = format!("CONFIG_{}", key.trim());
-            config.insert(full, "n".to_string());
-            continue;
-        }
+// what check_fix_config did to the downloaded .config
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ConfigFixReport {
+    // requested keys that were missing from .config
+    pub added: Vec<String>,
+    // (key, before, after)
+    pub changed: Vec<(String, String, String)>,
+    // requirements the downloaded .config already met
+    pub unchanged: usize,
+    // what the downloaded .config did not meet, by category
+    pub violations: PolicyViolations,
+    // symbols `make olddefconfig` changed on top of the requested config
+    pub olddefconfig: ConfigDiff,
+}
+
+impl ConfigFixReport {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty()
+    }
 
-        if trimmed_line.starts_with('#') || !trimmed_line.contains('=') {
-            continue;
+    pub fn print(&self) {
+        self.violations.print();
+        println!("[✔] {} configs already satisfied", self.unchanged);
+    }
+}
+
+pub fn diff_configs(before: &KernelConfig, after: &KernelConfig) -> ConfigDiff {
+    let mut diff = ConfigDiff::default();
+
+    for (key, after_value) in after.iter() {
+        match before.get(key) {
+            None => diff
+                .added
+                .push((key.to_string(), after_value.to_kconfig_string())),
+            Some(before_value) if before_value != after_value => diff.changed.push((
+                key.to_string(),
+                before_value.to_kconfig_string(),
+                after_value.to_kconfig_string(),
+            )),
+            _ => {}
         }
+    }
 
-        if let Some((key, value)) = trimmed_line.split_once('=') {
-            let key = key.trim().to_string();
-            let value = value.trim().to_string();
-            config.insert(key, value);
+    for (key, before_value) in before.iter() {
+        if after.get(key).is_none() {
+            diff.removed
+                .push((key.to_string(), before_value.to_kconfig_string()));
         }
     }
 
-    info!("Checking and modifying kernel config...");
+    diff.added.sort();
+    diff.removed.sort();
+    diff.changed.sort();
+    diff
+}
+
+// set what `config` needs to meet `policy`, reporting which symbols were added or changed.
+// an unmet one_of group gets its first symbol enabled
+fn fix_config(config: &mut KernelConfig, policy: &ConfigPolicy) -> ConfigFixReport {
+    let violations = policy.evaluate(config);
+    let mut fix_report = ConfigFixReport {
+        unchanged: policy.requirements() - violations.len(),
+        ..ConfigFixReport::default()
+    };
+
+    let mut fixes: Vec<(&str, ConfigValue)> = Vec::new();
+    for key in &violations.not_enabled {
+        fixes.push((key, ConfigValue::Bool(true)));
+    }
+    for (key, _) in &violations.not_disabled {
+        fixes.push((key, ConfigValue::Bool(false)));
+    }
+    for (key, _, _) in &violations.wrong_value {
+        fixes.push((key, policy.values[key].clone()));
+    }
+    for group in &violations.none_of {
+        fixes.push((&group[0], ConfigValue::Bool(true)));
+    }
 
-    let mut update = false;
-    let mut original = lines.clone();
-    let mut found_keys = std::collections::HashSet::new();
-
-    for (i, line) in lines.iter().enumerate() {
-        for (key, expected) in &kernel_config {
-            if line.starts_with(&format!("{}=", key)) || *line == format!("# {} is not set", key) {
-                found_keys.insert(key.clone());
-                let actual_value = config.get(key).map_or("n", |v| v.as_str());
-                if actual_value != expected {
-                    println!(
-                        "[✘] error config: {} (expected: {}, actually: {})",
-                        key, expected, actual_value
-                    );
-
-                    if expected == "n" {
-                        original[i] = format!("# {} is not set", key);
-                    } else {
-                        original[i] = format!("{}={}", key, expected);
-                    }
-                    update = true;
-                } else {
-                    println!("[✔] {}={}", key, expected);
-                }
+    for (key, value) in fixes {
+        let after = value.to_kconfig_string();
+        match config.set(key, value) {
+            None => fix_report.added.push(key.to_string()),
+            Some(before) => {
+                fix_report
+                    .changed
+                    .push((key.to_string(), before.to_kconfig_string(), after))
             }
         }
     }
+    fix_report.added.sort();
+    fix_report.changed.sort();
+    fix_report.violations = violations;
 
-    for (key, expected) in kernel_config {
-        if !found_keys.contains(&key) {
-            println!("[✘] lack config: {} (expected: {})", key, expected);
+    fix_report
+}
 
-            if expected == "n" {
-                original.push(format!("# {} is not set", key));
-            } else {
-                original.push(format!("{}={}", key, expected));
-            }
-            update = true;
-        }
+// bring .config in line with kernel.toml, returning what was fixed and what olddefconfig changed
+pub async fn check_fix_config(
+    report: &Arc<CrashReport>,
+    options: &BuildOptions,
+) -> Result<ConfigFixReport> {
+    let crash_index = options.crash_index;
+    let layout = options.layout(report)?;
+    let kernel_source_dir = layout.source_dir();
+
+    let config_path = layout.config_path();
+    let shell_script_path = env::current_dir()?.join("nix").join("shell.nix");
+
+    // configuration to be modified
+    let (policy, overridden) = load_kernel_config(&report.id).await?;
+    for key in &overridden {
+        info!(
+            "{} {} comes from the overrides for report {}",
+            key,
+            policy.describe(key).unwrap_or_default(),
+            report.id
+        );
     }
 
-    if update {
+    let mut config = KernelConfig::from_file(&config_path)
+        .await
+        .with_context(|| format!("Refusing to rewrite {}", config_path.display()))?;
+
+    info!("Checking and modifying kernel config...");
+
+    let mut fix_report = fix_config(&mut config, &policy);
+
+    fix_report.print();
+
+    if !fix_report.is_empty() {
         info!("updating config file");
 
-        let content = original.join("\n") + "\n";
-        fs::write(&config_path, content).await?;
-
-        info!("config file updated successfully. running \"make O=../build olddefconfig\"");
-
-        let make_cmd = "make O=../build olddefconfig";
-
-        let compiler = select_compiler(&report)?;
-        let compiler_str = format!("{}-{}", compiler.compiler_type.to_string(), compiler.major);
-
-        let status = Command::new("nix-shell")
-            .arg(shell_script_path)
-            .arg("--pure")
-            .arg("--argstr")
-            .arg("compiler")
-            .arg(compiler_str)
-            .arg("--run")
-            .arg(make_cmd)
-            .current_dir(kernel_source_dir)
-            .stdout(std::process::Stdio::inherit())
-            .stderr(std::process::Stdio::inherit())
-            .status()
-            .await?;
-
-        if !status.success() {
-            anyhow::bail!(
-                "error running make old defconfig, exit code: {:?}",
-                status.code()
+        fs::write(&config_path, config.to_kconfig_string()).await?;
+        let requested = config;
+
+        info!("config file updated successfully. running \"make olddefconfig\"");
+
+        // without ARCH, olddefconfig would re-evaluate a foreign .config as x86
+        let arch = select_architecture(report)?;
+        let make_cmd = format!(
+            "make O={} {} olddefconfig",
+            layout.build_out_dir().display(),
+            arch.make_args()
+        );
+
+        let compiler = select_compiler(report, crash_index)?;
+        let compiler_str = compiler.nix_arg();
+
+        // NixCommand closes stdin, so a symbol without a default can't block on a prompt
+        let stdout = NixCommand::new(shell_script_path, &compiler_str, kernel_source_dir)
+            .with_timeout(Some(OLDDEFCONFIG_TIMEOUT))
+            .with_cancel(options.cancel.clone())
+            .output(&make_cmd)
+            .await
+            .context("make olddefconfig failed")?;
+        debug!("make olddefconfig output: {}", stdout);
+
+        let final_config = KernelConfig::from_file(&config_path).await?;
+        let diff = diff_configs(&requested, &final_config);
+
+        for (key, value) in &diff.added {
+            info!("olddefconfig added {}={}", key, value);
+        }
+        for (key, value) in &diff.removed {
+            info!("olddefconfig dropped {}={}", key, value);
+        }
+        for (key, before, after) in &diff.changed {
+            info!("olddefconfig changed {}: {} -> {}", key, before, after);
+        }
+        info!(
+            "olddefconfig added {}, dropped {}, changed {} symbols",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
+        );
+        fix_report.olddefconfig = diff;
+
+        let unsatisfied = policy.evaluate(&final_config);
+        if !unsatisfied.is_empty() {
+            warn!(
+                "olddefconfig did not keep what kernel.toml asks for ({}), check the dependencies",
+                unsatisfied
             );
+            return Err(ConfigUnsatisfied(unsatisfied).into());
         }
     } else {
         println!("all needed config are satisfied");
     }
 
-    Ok(())
+    Ok(fix_report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_configs() {
+        let before = KernelConfig::parse("CONFIG_KASAN=y\nCONFIG_KCOV=y\nCONFIG_BUG=y\n").unwrap();
+        let after = KernelConfig::parse(
+            "CONFIG_KASAN=y\n# CONFIG_KCOV is not set\nCONFIG_KASAN_GENERIC=y\n",
+        )
+        .unwrap();
+
+        let diff = diff_configs(&before, &after);
+        assert_eq!(
+            diff.added,
+            vec![("CONFIG_KASAN_GENERIC".to_string(), "y".to_string())]
+        );
+        assert_eq!(
+            diff.removed,
+            vec![("CONFIG_BUG".to_string(), "y".to_string())]
+        );
+        assert_eq!(
+            diff.changed,
+            vec![("CONFIG_KCOV".to_string(), "y".to_string(), "n".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_fix_config() {
+        let mut config = KernelConfig::parse(
+            "CONFIG_KASAN=y\nCONFIG_KCOV=m # fuzzing\n# CONFIG_DEBUG_INFO is not set\n",
+        )
+        .unwrap();
+        let policy = ConfigPolicy::parse(
+            "CONFIG_KASAN = \"y\"\n\
+             CONFIG_KCOV = \"y\"\n\
+             CONFIG_DEBUG_INFO = \"y\"\n\
+             CONFIG_CMDLINE = \"quiet\"\n",
+        )
+        .unwrap();
+
+        let report = fix_config(&mut config, &policy);
+        assert_eq!(
+            config.to_kconfig_string(),
+            "CONFIG_KASAN=y\n\
+             CONFIG_KCOV=y # fuzzing\n\
+             CONFIG_DEBUG_INFO=y\n\
+             CONFIG_CMDLINE=\"quiet\"\n"
+        );
+        assert_eq!(report.added, vec!["CONFIG_CMDLINE"]);
+        assert_eq!(
+            report.changed,
+            vec![
+                (
+                    "CONFIG_DEBUG_INFO".to_string(),
+                    "n".to_string(),
+                    "y".to_string()
+                ),
+                ("CONFIG_KCOV".to_string(), "m".to_string(), "y".to_string()),
+            ]
+        );
+        assert_eq!(report.unchanged, 1);
+        assert_eq!(report.violations.wrong_value.len(), 3);
+    }
+
+    #[test]
+    fn test_fix_config_categories() {
+        let mut config =
+            KernelConfig::parse("CONFIG_KCOV=m\nCONFIG_RANDOMIZE_BASE=y\nCONFIG_KFENCE=n\n")
+                .unwrap();
+        let policy = ConfigPolicy::parse(
+            "enabled = [\"CONFIG_KCOV\", \"CONFIG_KASAN\"]\n\
+             disabled = [\"CONFIG_RANDOMIZE_BASE\", \"CONFIG_UBSAN\"]\n\
+             one_of = [[\"CONFIG_KFENCE\", \"CONFIG_SLUB_DEBUG\"]]\n",
+        )
+        .unwrap();
+
+        let report = fix_config(&mut config, &policy);
+        assert_eq!(report.added, vec!["CONFIG_KASAN"]);
+        assert_eq!(
+            report.changed,
+            vec![
+                (
+                    "CONFIG_KFENCE".to_string(),
+                    "n".to_string(),
+                    "y".to_string()
+                ),
+                (
+                    "CONFIG_RANDOMIZE_BASE".to_string(),
+                    "y".to_string(),
+                    "n".to_string()
+                ),
+            ]
+        );
+        // KCOV=m is enabled, UBSAN is absent
+        assert_eq!(report.unchanged, 2);
+        assert!(policy.evaluate(&config).is_empty());
+    }
+
+    #[test]
+    fn test_config_unsatisfied() {
+        let policy = ConfigPolicy::parse(
+            "CONFIG_KASAN = \"y\"\n\
+             CONFIG_KCOV = \"n\"\n\
+             CONFIG_CMDLINE = \"quiet\"\n\
+             CONFIG_KASAN_INLINE = \"y\"\n",
+        )
+        .unwrap();
+        let final_config =
+            KernelConfig::parse("CONFIG_KASAN=y\nCONFIG_CMDLINE=\"quiet\"\n").unwrap();
+
+        let unsatisfied = policy.evaluate(&final_config);
+        assert_eq!(
+            unsatisfied.wrong_value,
+            vec![(
+                "CONFIG_KASAN_INLINE".to_string(),
+                "y".to_string(),
+                "n".to_string()
+            )]
+        );
+        assert_eq!(unsatisfied.len(), 1);
+        assert_eq!(
+            ConfigUnsatisfied(unsatisfied).to_string(),
+            "kernel config does not satisfy kernel.toml: wrong value: CONFIG_KASAN_INLINE (wanted y, got n)"
+        );
+    }
 }
@@ -0,0 +1,671 @@
+use crate::parse::compiler::Compiler;
+use crate::util::shell_quote;
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command};
+use tokio::time::{Instant, timeout};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+// lines of build output quoted in the error of a failed build
+const LOG_TAIL_LINES: usize = 50;
+
+// stderr kept by tee_stderr for the error, enough for LOG_TAIL_LINES lines of a build
+const STDERR_TAIL_BYTES: usize = 64 * 1024;
+
+// what nix's own `error:` lines say when fetching from a binary cache or a source mirror
+// failed on the network, as opposed to a failing build. curl's messages end up in them
+const TRANSIENT_NIX_ERRORS: &[&str] = &[
+    "unable to download",
+    "SSL connection",
+    "SSL connect error",
+    "SSL peer certificate",
+    "Connection reset by peer",
+    "Could not resolve host",
+    "Couldn't resolve host",
+    "Timeout was reached",
+    "HTTP error 50",
+];
+
+#[derive(Debug, Error)]
+pub enum BuildError {
+    #[error("Build step timed out after {elapsed:?}: {command}")]
+    BuildTimeout { command: String, elapsed: Duration },
+    #[error("Build step cancelled: {0}")]
+    Cancelled(String),
+}
+
+// a command run inside the nix-shell environment of nix/shell.nix for one compiler
+pub struct NixCommand {
+    shell_script: PathBuf,
+    compiler: String,
+    working_dir: PathBuf,
+    timeout: Option<Duration>,
+    cancel: CancellationToken,
+    retries: usize,
+}
+
+impl NixCommand {
+    pub fn new(shell_script: PathBuf, compiler: &str, working_dir: PathBuf) -> Self {
+        Self {
+            shell_script,
+            compiler: compiler.to_string(),
+            working_dir,
+            timeout: None,
+            cancel: CancellationToken::new(),
+            retries: 0,
+        }
+    }
+
+    pub fn working_dir(&self) -> &Path {
+        &self.working_dir
+    }
+
+    // `nix-shell <script> --pure --argstr compiler <compiler> --run <command>` in the working
+    // directory, with stdin closed
+    fn command(&self, command: &str) -> Command {
+        let mut cmd = Command::new("nix-shell");
+        cmd.arg(&self.shell_script)
+            .arg("--pure")
+            .arg("--argstr")
+            .arg("compiler")
+            .arg(&self.compiler)
+            .arg("--run")
+            .arg(command)
+            .current_dir(&self.working_dir)
+            .stdin(std::process::Stdio::null());
+        cmd
+    }
+
+    // rerun `execute` and `execute_logged` up to `retries` times after a transient nix failure
+    pub fn with_retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    async fn retrying<F, Fut>(&self, command: &str, mut run: F) -> Result<()>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match run().await {
+                Err(e) if attempt < self.retries && is_transient_failure(&e) => {
+                    attempt += 1;
+                    warn!(
+                        "nix-shell failed on a network error, retrying ({}/{}): {}",
+                        attempt, self.retries, command
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // bound `execute`, `execute_logged` and `output`, see `wait_bounded`
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // abort `execute`, `execute_logged` and `output` once `cancel` is cancelled
+    pub fn with_cancel(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    // run `work`, which waits for the nix-shell child `pgid`, until it finishes, the timeout
+    // passes or the command is cancelled. nix-shell is started as its own process group, in the
+    // latter cases the whole group is killed so no make or cc outlives it
+    async fn wait_bounded<T>(
+        &self,
+        pgid: Option<u32>,
+        command: &str,
+        work: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let started = Instant::now();
+        let bounded = async {
+            let Some(limit) = self.timeout else {
+                return Ok(work.await);
+            };
+            timeout(limit, work).await.map_err(|_| {
+                anyhow::Error::from(BuildError::BuildTimeout {
+                    command: command.to_string(),
+                    elapsed: started.elapsed(),
+                })
+            })
+        };
+
+        let result = tokio::select! {
+            result = bounded => result,
+            _ = self.cancel.cancelled() => Err(BuildError::Cancelled(command.to_string()).into()),
+        };
+        match result {
+            Ok(result) => result,
+            Err(e) => {
+                if let Some(pgid) = pgid {
+                    kill_process_group(pgid);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // run `command` with captured output, returning stdout. bounded like `execute`, but never
+    // retried
+    pub async fn output(&self, command: &str) -> Result<String> {
+        let child = self
+            .command(command)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to execute nix-shell command")?;
+        let output = self
+            .wait_bounded(child.id(), command, async {
+                child
+                    .wait_with_output()
+                    .await
+                    .context("Failed to wait for nix-shell command")
+            })
+            .await?;
+
+        if !output.status.success() {
+            anyhow::bail!(
+                "Command failed with exit code: {:?}\nCommand: {}\nstderr: {}",
+                output.status.code(),
+                command,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    // shell command line equivalent to `execute(command)`
+    pub fn render(&self, command: &str) -> String {
+        format!(
+            "cd {} && nix-shell {} --pure --argstr compiler {} --run {}",
+            shell_quote(&self.working_dir.to_string_lossy()),
+            shell_quote(&self.shell_script.to_string_lossy()),
+            shell_quote(&self.compiler),
+            shell_quote(command)
+        )
+    }
+
+    // like `execute`, but append stdout and stderr to `log_path` and mirror them at debug level.
+    // on failure the error carries the last lines of output
+    pub async fn execute_logged(&self, command: &str, log_path: &Path) -> Result<()> {
+        self.retrying(command, || self.execute_logged_once(command, log_path))
+            .await
+    }
+
+    async fn execute_logged_once(&self, command: &str, log_path: &Path) -> Result<()> {
+        let mut log = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path)
+            .await
+            .with_context(|| format!("Failed to open build log {}", log_path.display()))?;
+
+        let mut child = self
+            .command(command)
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to execute nix-shell command")?;
+
+        let pgid = child.id();
+        let (tail, status) = self
+            .wait_bounded(pgid, command, async {
+                let tail = tee_output(&mut child, &mut log).await?;
+                let status = child
+                    .wait()
+                    .await
+                    .context("Failed to wait for nix-shell command")?;
+                Ok((tail, status))
+            })
+            .await?;
+        if !status.success() {
+            anyhow::bail!(
+                "Command failed with exit code: {:?}\nCommand: {}\nLast {} lines of {}:\n{}",
+                status.code(),
+                command,
+                tail.len(),
+                log_path.display(),
+                Vec::from(tail).join("\n")
+            );
+        }
+
+        Ok(())
+    }
+
+    pub async fn execute(&self, command: &str) -> Result<()> {
+        self.retrying(command, || self.execute_once(command)).await
+    }
+
+    // stderr is passed through to ours as it comes, keeping its end for the error
+    async fn execute_once(&self, command: &str) -> Result<()> {
+        let mut child = self
+            .command(command)
+            .stdout(std::process::Stdio::inherit())
+            .stderr(std::process::Stdio::piped())
+            .process_group(0)
+            .kill_on_drop(true)
+            .spawn()
+            .context("Failed to execute nix-shell command")?;
+
+        let (tail, status) = self
+            .wait_bounded(child.id(), command, async {
+                let tail = tee_stderr(&mut child).await?;
+                let status = child
+                    .wait()
+                    .await
+                    .context("Failed to wait for nix-shell command")?;
+                Ok((tail, status))
+            })
+            .await?;
+
+        if !status.success() {
+            anyhow::bail!(
+                "Command failed with exit code: {:?}\nCommand: {}\nLast {} lines of stderr:\n{}",
+                status.code(),
+                command,
+                tail.len(),
+                Vec::from(tail).join("\n")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+// whether a failed build step is worth running again: nix hit a network error. only nix's
+// `error:` lines count, a compiler complaining about OpenSSL in the build output must not
+// trigger a retry. timeouts and cancellations are never retried
+fn is_transient_failure(error: &anyhow::Error) -> bool {
+    if error.downcast_ref::<BuildError>().is_some() {
+        return false;
+    }
+    format!("{:#}", error)
+        .lines()
+        .map(str::trim_start)
+        .filter(|line| line.starts_with("error:") || line.starts_with("warning: error:"))
+        .any(|line| {
+            TRANSIENT_NIX_ERRORS
+                .iter()
+                .any(|pattern| line.contains(pattern))
+        })
+}
+
+// copy a child's stderr to ours unchanged, so nix's \r progress lines keep working, returning
+// its last LOG_TAIL_LINES lines
+async fn tee_stderr(child: &mut Child) -> Result<VecDeque<String>> {
+    let mut stderr = child.stderr.take().unwrap();
+    let mut console = tokio::io::stderr();
+    let mut buf = vec![0; 8192];
+    let mut tail = Vec::new();
+
+    loop {
+        let n = stderr.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        console.write_all(&buf[..n]).await?;
+        tail.extend_from_slice(&buf[..n]);
+        if tail.len() > STDERR_TAIL_BYTES {
+            tail.drain(..tail.len() - STDERR_TAIL_BYTES);
+        }
+    }
+    console.flush().await?;
+
+    Ok(tail_lines(&String::from_utf8_lossy(&tail)))
+}
+
+// the last LOG_TAIL_LINES lines of `output`, a line rewritten with \r only keeps its last state
+fn tail_lines(output: &str) -> VecDeque<String> {
+    let mut tail: VecDeque<String> = output
+        .lines()
+        .map(|line| line.rsplit('\r').next().unwrap_or_default().to_string())
+        .collect();
+    while tail.len() > LOG_TAIL_LINES {
+        tail.pop_front();
+    }
+    tail
+}
+
+// SIGKILL every process in the group led by `pgid`
+fn kill_process_group(pgid: u32) {
+    // a negative pid addresses the process group
+    if unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) } != 0 {
+        warn!(
+            "Failed to kill process group {}: {}",
+            pgid,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+// copy a child's stdout and stderr line by line into `log` and the debug log,
+// returning the last LOG_TAIL_LINES lines
+async fn tee_output(child: &mut Child, log: &mut fs::File) -> Result<VecDeque<String>> {
+    let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+    let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+    let mut tail = VecDeque::with_capacity(LOG_TAIL_LINES);
+    let (mut stdout_open, mut stderr_open) = (true, true);
+
+    while stdout_open || stderr_open {
+        let (line, from_stdout) = tokio::select! {
+            line = stdout.next_line(), if stdout_open => (line?, true),
+            line = stderr.next_line(), if stderr_open => (line?, false),
+        };
+        let Some(line) = line else {
+            if from_stdout {
+                stdout_open = false;
+            } else {
+                stderr_open = false;
+            }
+            continue;
+        };
+
+        debug!("{}", line);
+        log.write_all(line.as_bytes()).await?;
+        log.write_all(b"\n").await?;
+        if tail.len() == LOG_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+    }
+    log.flush().await?;
+
+    Ok(tail)
+}
+
+// the nix-shell cannot provide the compiler a report needs
+#[derive(Debug, Error)]
+pub enum ToolchainError {
+    #[error("toolchain {requested} is not available from nix/shell.nix: {reason}")]
+    Unavailable { requested: String, reason: String },
+    #[error("nix-shell for {requested} provides {actual} instead")]
+    VersionMismatch { requested: String, actual: String },
+}
+
+// make sure the nix-shell of `nix_cmd`, set up for `compiler`, provides it before starting a
+// long build, returns the compiler it actually provides. bounded by the timeout and
+// cancellation of `nix_cmd`
+pub async fn verify_compiler_available(
+    nix_cmd: &NixCommand,
+    compiler: &Compiler,
+) -> Result<Compiler> {
+    let output = nix_cmd
+        .output(&format!("{} --version", compiler.binary()))
+        .await
+        .map_err(|e| ToolchainError::Unavailable {
+            requested: compiler.nix_arg(),
+            reason: format!("{:#}", e),
+        })?;
+
+    let actual = check_toolchain_output(compiler, &output)?;
+    if actual.matches(compiler) {
+        info!("nix-shell provides {} (requested {})", actual, compiler);
+    } else {
+        // only the major version is available from nixpkgs
+        warn!(
+            "nix-shell provides {} but the report was built with {}, the exact version could not be pinned",
+            actual, compiler
+        );
+    }
+
+    Ok(actual)
+}
+
+// find the version line in `--version` output and check that the major version is the requested one
+fn check_toolchain_output(requested: &Compiler, output: &str) -> Result<Compiler, ToolchainError> {
+    let requested_str = requested.nix_arg();
+
+    let actual = output
+        .lines()
+        .find_map(|line| Compiler::parse(line.trim()).ok())
+        .ok_or_else(|| ToolchainError::Unavailable {
+            requested: requested_str.clone(),
+            reason: format!("no compiler version in output: {}", output.trim()),
+        })?;
+
+    if actual.compiler_type != requested.compiler_type || actual.major != requested.major {
+        return Err(ToolchainError::VersionMismatch {
+            requested: requested_str,
+            actual: actual.to_string(),
+        });
+    }
+
+    Ok(actual)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_quotes_arguments() {
+        let nix_cmd = NixCommand::new(
+            PathBuf::from("/repo/nix/shell.nix"),
+            "gcc-10",
+            PathBuf::from("/repo/workspace/id/linux-abc"),
+        );
+        assert_eq!(
+            nix_cmd.render("echo 'hi'"),
+            "cd '/repo/workspace/id/linux-abc' && nix-shell '/repo/nix/shell.nix' --pure --argstr compiler 'gcc-10' --run 'echo '\\''hi'\\'''"
+        );
+    }
+
+    #[test]
+    fn test_tail_lines() {
+        let output: String = (1..=60).map(|i| format!("line {}\n", i)).collect();
+        let tail = tail_lines(&format!("{}copying path 1/3\rcopying path 3/3\n", output));
+        assert_eq!(tail.len(), LOG_TAIL_LINES);
+        assert_eq!(tail.front().unwrap(), "line 12");
+        assert_eq!(tail.back().unwrap(), "copying path 3/3");
+    }
+
+    #[tokio::test]
+    async fn test_tee_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("build.log");
+        let mut log = fs::File::create(&log_path).await.unwrap();
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg("for i in $(seq 1 60); do echo line $i; done; echo oops >&2")
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        let tail = tee_output(&mut child, &mut log).await.unwrap();
+        child.wait().await.unwrap();
+
+        assert_eq!(tail.len(), LOG_TAIL_LINES);
+        assert!(!tail.contains(&"line 1".to_string()));
+
+        // stdout and stderr may interleave in any order, but nothing is lost
+        let contents = std::fs::read_to_string(&log_path).unwrap();
+        assert_eq!(contents.lines().count(), 61);
+        assert!(contents.lines().any(|line| line == "oops"));
+    }
+
+    #[tokio::test]
+    async fn test_execute_timeout_kills_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let nix_cmd = NixCommand::new(PathBuf::new(), "gcc-10", dir.path().to_path_buf())
+            .with_timeout(Some(Duration::from_millis(200)));
+
+        // stand-in for nix-shell: a shell whose background child would outlive it
+        let marker = dir.path().join("pid");
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(format!("sleep 30 & echo $! > {}; wait", marker.display()))
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let started = Instant::now();
+        let err = nix_cmd
+            .wait_bounded(child.id(), "sleep", async {
+                child.wait().await.map_err(anyhow::Error::from)
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BuildError>(),
+            Some(BuildError::BuildTimeout { .. })
+        ));
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // the grandchild went down with the group
+        let pid: i32 = std::fs::read_to_string(&marker)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        child.wait().await.unwrap();
+        let alive = std::path::Path::new(&format!("/proc/{}", pid)).exists()
+            && !std::fs::read_to_string(format!("/proc/{}/stat", pid))
+                .unwrap_or_default()
+                .contains(") Z ");
+        assert!(!alive);
+    }
+
+    #[tokio::test]
+    async fn test_execute_cancel_kills_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let cancel = CancellationToken::new();
+        let nix_cmd = NixCommand::new(PathBuf::new(), "gcc-10", dir.path().to_path_buf())
+            .with_cancel(cancel.clone());
+
+        let mut child = Command::new("sleep")
+            .arg("30")
+            .process_group(0)
+            .spawn()
+            .unwrap();
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.cancel();
+        });
+
+        let err = nix_cmd
+            .wait_bounded(child.id(), "sleep", async {
+                child.wait().await.map_err(anyhow::Error::from)
+            })
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<BuildError>(),
+            Some(BuildError::Cancelled(_))
+        ));
+        // killed rather than left running for 30s
+        let status = tokio::time::timeout(Duration::from_secs(5), child.wait())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!status.success());
+    }
+
+    #[tokio::test]
+    async fn test_retry_transient_failures() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let nix_cmd = NixCommand::new(PathBuf::new(), "gcc-10", PathBuf::from("/")).with_retries(1);
+        let nix_cmd = &nix_cmd;
+        let failing = |stderr: &'static str| {
+            let attempts = AtomicUsize::new(0);
+            async move {
+                let result = nix_cmd
+                    .retrying("make", || {
+                        attempts.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            anyhow::bail!(
+                                "Command failed with exit code: Some(1)\nCommand: make SSL=1\n{}",
+                                stderr
+                            )
+                        }
+                    })
+                    .await;
+                (result.is_err(), attempts.load(Ordering::SeqCst))
+            }
+        };
+
+        // retried once, then the failure stands
+        assert_eq!(
+            failing("error: unable to download 'https://cache.nixos.org/abc.narinfo'").await,
+            (true, 2)
+        );
+        assert_eq!(
+            failing("error: unable to download 'https://cache.nixos.org/abc.narinfo': SSL connect error (35)").await,
+            (true, 2)
+        );
+        // a compile error, and a pattern only in the command line
+        assert_eq!(
+            failing("mm/slub.c:12: error: expected ';'").await,
+            (true, 1)
+        );
+        // the build log tail of an old kernel against OpenSSL 3
+        assert_eq!(
+            failing("scripts/sign-file.c:25:10: error: 'ERR_get_error_line' is deprecated: Since OpenSSL 3.0 [-Werror]\nmake[1]: *** [scripts/Makefile.host:95: scripts/sign-file] Error 1").await,
+            (true, 1)
+        );
+
+        let attempts = AtomicUsize::new(0);
+        nix_cmd
+            .retrying("make", || {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    if attempt == 0 {
+                        anyhow::bail!(
+                            "error: unable to download 'https://example.org/x.tar.gz': Connection reset by peer (56)"
+                        );
+                    }
+                    Ok(())
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        let timeout = anyhow::Error::from(BuildError::BuildTimeout {
+            command: "unable to download".to_string(),
+            elapsed: Duration::from_secs(1),
+        });
+        assert!(!is_transient_failure(&timeout));
+    }
+
+    #[test]
+    fn test_check_toolchain_output() {
+        let requested = Compiler::parse("gcc (GCC) 10.2.1 20210110").unwrap();
+
+        let output = "shell hook banner\ngcc (GCC) 10.3.0\nCopyright (C) 2020\n";
+        assert_eq!(
+            check_toolchain_output(&requested, output)
+                .unwrap()
+                .to_string(),
+            "gcc-10.3.0"
+        );
+
+        assert!(matches!(
+            check_toolchain_output(&requested, "gcc (GCC) 12.2.0"),
+            Err(ToolchainError::VersionMismatch { .. })
+        ));
+        assert!(matches!(
+            check_toolchain_output(&requested, "command not found: gcc"),
+            Err(ToolchainError::Unavailable { .. })
+        ));
+    }
+}
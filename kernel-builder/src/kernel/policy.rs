@@ -0,0 +1,373 @@
+use crate::kernel::kconfig::{ConfigValue, KernelConfig};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+use tracing::info;
+
+// config/kernel.toml as written. the flat `CONFIG_X = "y"` form is still accepted next to the
+// sections: "n" means disabled, anything else an exact value
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct PolicyFile {
+    enabled: Vec<String>,
+    disabled: Vec<String>,
+    values: BTreeMap<String, toml::Value>,
+    one_of: Vec<Vec<String>>,
+    #[serde(flatten)]
+    flat: BTreeMap<String, String>,
+}
+
+// what config/kernel.toml asks of a .config
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigPolicy {
+    // must be y or m
+    pub enabled: BTreeSet<String>,
+    // must be n or absent
+    pub disabled: BTreeSet<String>,
+    // must have exactly this value
+    pub values: BTreeMap<String, ConfigValue>,
+    // at least one symbol of each group must be enabled, e.g. one of several debug allocators
+    pub one_of: Vec<Vec<String>>,
+}
+
+impl ConfigPolicy {
+    pub fn parse(content: &str) -> Result<ConfigPolicy> {
+        let file: PolicyFile = toml::from_str(content)?;
+
+        let mut policy = ConfigPolicy {
+            enabled: file.enabled.into_iter().collect(),
+            disabled: file.disabled.into_iter().collect(),
+            one_of: file.one_of,
+            ..ConfigPolicy::default()
+        };
+        for (key, value) in file.values {
+            let value = match value {
+                toml::Value::Boolean(enabled) => ConfigValue::Bool(enabled),
+                toml::Value::Integer(n) => ConfigValue::Int(n),
+                toml::Value::String(s) => ConfigValue::from_toml(&s),
+                other => anyhow::bail!("Unsupported value for {}: {}", key, other),
+            };
+            policy.values.insert(key, value);
+        }
+        for (key, value) in file.flat {
+            if value.trim() == "n" {
+                policy.disabled.insert(key);
+            } else {
+                policy.values.insert(key, ConfigValue::from_toml(&value));
+            }
+        }
+
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    pub async fn from_file(path: &Path) -> Result<ConfigPolicy> {
+        info!("Loading kernel configuration from: {}", path.display());
+        let content = tokio::fs::read_to_string(path).await.with_context(|| {
+            format!(
+                "Failed to read kernel configuration file from {}",
+                path.display()
+            )
+        })?;
+        ConfigPolicy::parse(&content).with_context(|| {
+            format!(
+                "Failed to parse kernel configuration from {}",
+                path.display()
+            )
+        })
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut seen = BTreeSet::new();
+        for key in self.keys() {
+            if !key.starts_with("CONFIG_") {
+                anyhow::bail!("{} is not a kernel config symbol", key);
+            }
+            if !seen.insert(key) {
+                anyhow::bail!("{} is required more than once", key);
+            }
+        }
+        for group in &self.one_of {
+            if group.is_empty() {
+                anyhow::bail!("one_of groups must not be empty");
+            }
+            if let Some(key) = group.iter().find(|key| !key.starts_with("CONFIG_")) {
+                anyhow::bail!("{} is not a kernel config symbol", key);
+            }
+        }
+        Ok(())
+    }
+
+    // symbols with an enabled, disabled or value requirement
+    fn keys(&self) -> impl Iterator<Item = &String> {
+        self.enabled
+            .iter()
+            .chain(&self.disabled)
+            .chain(self.values.keys())
+    }
+
+    // layer `other` on top: a symbol it mentions loses its requirement here, its one_of
+    // groups are added. returns the symbols `other` sets, sorted
+    pub fn merge(&mut self, other: ConfigPolicy) -> Vec<String> {
+        let mut keys: Vec<String> = other.keys().cloned().collect();
+        keys.sort();
+        for key in &keys {
+            self.enabled.remove(key);
+            self.disabled.remove(key);
+            self.values.remove(key);
+        }
+        self.enabled.extend(other.enabled);
+        self.disabled.extend(other.disabled);
+        self.values.extend(other.values);
+        self.one_of.extend(other.one_of);
+        keys
+    }
+
+    // how `key` is required, for logging
+    pub fn describe(&self, key: &str) -> Option<String> {
+        if self.enabled.contains(key) {
+            Some("enabled".to_string())
+        } else if self.disabled.contains(key) {
+            Some("disabled".to_string())
+        } else {
+            self.values.get(key).map(|value| format!("= {}", value))
+        }
+    }
+
+    // every requirement `config` does not meet, absent symbols count as "n"
+    pub fn evaluate(&self, config: &KernelConfig) -> PolicyViolations {
+        let disabled = ConfigValue::Bool(false);
+        let actual = |key: &str| config.get(key).unwrap_or(&disabled);
+
+        let mut violations = PolicyViolations::default();
+        for key in &self.enabled {
+            if !actual(key).is_enabled() {
+                violations.not_enabled.push(key.clone());
+            }
+        }
+        for key in &self.disabled {
+            if actual(key).is_enabled() {
+                violations
+                    .not_disabled
+                    .push((key.clone(), actual(key).to_kconfig_string()));
+            }
+        }
+        for (key, wanted) in &self.values {
+            if actual(key) != wanted {
+                violations.wrong_value.push((
+                    key.clone(),
+                    wanted.to_kconfig_string(),
+                    actual(key).to_kconfig_string(),
+                ));
+            }
+        }
+        for group in &self.one_of {
+            if !group.iter().any(|key| actual(key).is_enabled()) {
+                violations.none_of.push(group.clone());
+            }
+        }
+        violations
+    }
+
+    // how many requirements there are, a one_of group counts once
+    pub fn requirements(&self) -> usize {
+        self.enabled.len() + self.disabled.len() + self.values.len() + self.one_of.len()
+    }
+}
+
+// requirements of a ConfigPolicy a .config does not meet, by category
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PolicyViolations {
+    pub not_enabled: Vec<String>,
+    // (key, actual)
+    pub not_disabled: Vec<(String, String)>,
+    // (key, wanted, actual)
+    pub wrong_value: Vec<(String, String, String)>,
+    pub none_of: Vec<Vec<String>>,
+}
+
+impl PolicyViolations {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.not_enabled.len()
+            + self.not_disabled.len()
+            + self.wrong_value.len()
+            + self.none_of.len()
+    }
+
+    pub fn print(&self) {
+        for key in &self.not_enabled {
+            println!("[✘] not enabled: {}", key);
+        }
+        for (key, actual) in &self.not_disabled {
+            println!("[✘] not disabled: {} (actually: {})", key, actual);
+        }
+        for (key, wanted, actual) in &self.wrong_value {
+            println!(
+                "[✘] error config: {} (expected: {}, actually: {})",
+                key, wanted, actual
+            );
+        }
+        for group in &self.none_of {
+            println!("[✘] none enabled of: {}", group.join(", "));
+        }
+    }
+}
+
+impl fmt::Display for PolicyViolations {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut categories = Vec::new();
+        if !self.not_enabled.is_empty() {
+            categories.push(format!("not enabled: {}", self.not_enabled.join(", ")));
+        }
+        if !self.not_disabled.is_empty() {
+            let keys: Vec<String> = self
+                .not_disabled
+                .iter()
+                .map(|(key, actual)| format!("{} (got {})", key, actual))
+                .collect();
+            categories.push(format!("not disabled: {}", keys.join(", ")));
+        }
+        if !self.wrong_value.is_empty() {
+            let keys: Vec<String> = self
+                .wrong_value
+                .iter()
+                .map(|(key, wanted, actual)| format!("{} (wanted {}, got {})", key, wanted, actual))
+                .collect();
+            categories.push(format!("wrong value: {}", keys.join(", ")));
+        }
+        for group in &self.none_of {
+            categories.push(format!("none enabled of: {}", group.join(", ")));
+        }
+        write!(f, "{}", categories.join("; "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: &str = r#"
+enabled = ["CONFIG_KASAN", "CONFIG_KCOV"]
+disabled = ["CONFIG_RANDOMIZE_BASE"]
+one_of = [["CONFIG_SLUB_DEBUG", "CONFIG_KFENCE"]]
+CONFIG_DEBUG_INFO = "y"
+
+[values]
+CONFIG_LOG_BUF_SHIFT = 17
+CONFIG_CMDLINE = "console=ttyS0"
+CONFIG_PANIC_ON_OOPS = true
+"#;
+
+    #[test]
+    fn test_parse_policy() {
+        let policy = ConfigPolicy::parse(POLICY).unwrap();
+        assert_eq!(
+            policy.enabled.iter().collect::<Vec<_>>(),
+            vec!["CONFIG_KASAN", "CONFIG_KCOV"]
+        );
+        assert!(policy.disabled.contains("CONFIG_RANDOMIZE_BASE"));
+        assert_eq!(policy.values["CONFIG_LOG_BUF_SHIFT"], ConfigValue::Int(17));
+        assert_eq!(
+            policy.values["CONFIG_CMDLINE"],
+            ConfigValue::String("console=ttyS0".to_string())
+        );
+        assert_eq!(
+            policy.values["CONFIG_PANIC_ON_OOPS"],
+            ConfigValue::Bool(true)
+        );
+        assert_eq!(policy.values["CONFIG_DEBUG_INFO"], ConfigValue::Bool(true));
+        assert_eq!(policy.requirements(), 8);
+
+        // the flat form on its own
+        let flat = ConfigPolicy::parse("CONFIG_KASAN = \"y\"\nCONFIG_KCOV = \"n\"\n").unwrap();
+        assert_eq!(flat.values["CONFIG_KASAN"], ConfigValue::Bool(true));
+        assert!(flat.disabled.contains("CONFIG_KCOV"));
+
+        assert!(ConfigPolicy::parse("enabled = [\"KASAN\"]").is_err());
+        assert!(
+            ConfigPolicy::parse("enabled = [\"CONFIG_A\"]\ndisabled = [\"CONFIG_A\"]").is_err()
+        );
+        assert!(ConfigPolicy::parse("one_of = [[]]").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_policy() {
+        let policy = ConfigPolicy::parse(POLICY).unwrap();
+        let config = KernelConfig::parse(
+            "CONFIG_KASAN=y\n\
+             CONFIG_KCOV=m\n\
+             CONFIG_RANDOMIZE_BASE=y\n\
+             CONFIG_LOG_BUF_SHIFT=18\n\
+             CONFIG_CMDLINE=\"console=ttyS0\"\n\
+             CONFIG_PANIC_ON_OOPS=y\n\
+             # CONFIG_SLUB_DEBUG is not set\n",
+        )
+        .unwrap();
+
+        let violations = policy.evaluate(&config);
+        assert_eq!(violations.not_enabled, Vec::<String>::new());
+        assert_eq!(
+            violations.not_disabled,
+            vec![("CONFIG_RANDOMIZE_BASE".to_string(), "y".to_string())]
+        );
+        assert_eq!(
+            violations.wrong_value,
+            vec![
+                (
+                    "CONFIG_DEBUG_INFO".to_string(),
+                    "y".to_string(),
+                    "n".to_string()
+                ),
+                (
+                    "CONFIG_LOG_BUF_SHIFT".to_string(),
+                    "17".to_string(),
+                    "18".to_string()
+                ),
+            ]
+        );
+        assert_eq!(
+            violations.none_of,
+            vec![vec!["CONFIG_SLUB_DEBUG", "CONFIG_KFENCE"]]
+        );
+        assert_eq!(violations.len(), 4);
+        assert_eq!(
+            violations.to_string(),
+            "not disabled: CONFIG_RANDOMIZE_BASE (got y); \
+             wrong value: CONFIG_DEBUG_INFO (wanted y, got n), CONFIG_LOG_BUF_SHIFT (wanted 17, got 18); \
+             none enabled of: CONFIG_SLUB_DEBUG, CONFIG_KFENCE"
+        );
+    }
+
+    #[test]
+    fn test_merge_policy() {
+        let mut policy = ConfigPolicy::parse(POLICY).unwrap();
+        let overrides = ConfigPolicy::parse(
+            "disabled = [\"CONFIG_KASAN\"]\none_of = [[\"CONFIG_UBSAN\"]]\nCONFIG_LOG_BUF_SHIFT = \"20\"\n",
+        )
+        .unwrap();
+
+        let keys = policy.merge(overrides);
+        assert_eq!(keys, vec!["CONFIG_KASAN", "CONFIG_LOG_BUF_SHIFT"]);
+        assert!(!policy.enabled.contains("CONFIG_KASAN"));
+        assert_eq!(policy.describe("CONFIG_KASAN").as_deref(), Some("disabled"));
+        assert_eq!(
+            policy.describe("CONFIG_LOG_BUF_SHIFT").as_deref(),
+            Some("= 20")
+        );
+        assert_eq!(policy.describe("CONFIG_KCOV").as_deref(), Some("enabled"));
+        assert_eq!(policy.one_of.len(), 2);
+    }
+
+    #[test]
+    fn test_shipped_kernel_toml() {
+        let content = std::fs::read_to_string("config/kernel.toml").unwrap();
+        let policy = ConfigPolicy::parse(&content).unwrap();
+        assert!(policy.enabled.contains("CONFIG_KASAN"));
+    }
+}
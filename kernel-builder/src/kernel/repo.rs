@@ -0,0 +1,209 @@
+use crate::config::config::DownloadConfig;
+use anyhow::Result;
+use reqwest::Url;
+use std::fmt;
+
+// the tree a crash was found on, derived from the report's kernel-source-git. syzbot links a
+// cgit/gitiles/GitHub page for the commit, e.g.
+// https://git.kernel.org/pub/scm/linux/kernel/git/next/linux-next.git/log/?id=<commit>
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KernelRepo {
+    // torvalds/linux on any host, fetched from download.kernel_archive_base
+    Mainline,
+    GitHub { owner: String, repo: String },
+    // git.kernel.org, `url` is the repository URL ending in .git
+    Cgit { url: String, name: String },
+    // *.googlesource.com, `url` is the repository URL
+    Gitiles { url: String },
+}
+
+impl KernelRepo {
+    // an empty kernel-source-git is taken as mainline
+    pub fn parse(kernel_source_git: &str) -> Result<KernelRepo> {
+        let source = kernel_source_git.trim();
+        if source.is_empty() {
+            return Ok(KernelRepo::Mainline);
+        }
+
+        let url = Url::parse(source)
+            .map_err(|e| anyhow::anyhow!("Invalid kernel-source-git {:?}: {}", source, e))?;
+        let host = url.host_str().unwrap_or_default();
+        let segments: Vec<&str> = url
+            .path_segments()
+            .map(|segments| segments.filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+
+        // the repository path, without the /log/, /commit/ or /+log/<commit> page part
+        let repo_path: Vec<&str> = match segments.iter().position(|s| s.ends_with(".git")) {
+            Some(end) => segments[..=end].to_vec(),
+            None => segments
+                .iter()
+                .take_while(|s| !matches!(**s, "log" | "commit" | "tree") && !s.starts_with('+'))
+                .copied()
+                .collect(),
+        };
+        let name = repo_path
+            .last()
+            .map(|s| s.trim_end_matches(".git"))
+            .unwrap_or_default();
+        let owner = repo_path
+            .len()
+            .checked_sub(2)
+            .map(|i| repo_path[i])
+            .unwrap_or_default();
+
+        if owner == "torvalds" && name == "linux" {
+            return Ok(KernelRepo::Mainline);
+        }
+
+        let repo_url = format!("{}://{}/{}", url.scheme(), host, repo_path.join("/"));
+        match host {
+            "github.com" if repo_path.len() == 2 => Ok(KernelRepo::GitHub {
+                owner: owner.to_string(),
+                repo: name.to_string(),
+            }),
+            "git.kernel.org" if !name.is_empty() => Ok(KernelRepo::Cgit {
+                url: repo_url,
+                name: name.to_string(),
+            }),
+            host if host.ends_with(".googlesource.com") && !name.is_empty() => {
+                Ok(KernelRepo::Gitiles { url: repo_url })
+            }
+            _ => anyhow::bail!(
+                "Cannot derive a source archive URL from kernel-source-git {:?}, only GitHub, \
+                 git.kernel.org and *.googlesource.com repositories are supported",
+                source
+            ),
+        }
+    }
+
+    // the tarball of `commit`
+    pub fn archive_url(&self, commit: &str, config: &DownloadConfig) -> String {
+        match self {
+            KernelRepo::Mainline => config.kernel_archive_url(commit),
+            KernelRepo::GitHub { owner, repo } => format!(
+                "https://github.com/{}/{}/archive/{}.tar.gz",
+                owner, repo, commit
+            ),
+            KernelRepo::Cgit { url, name } => {
+                format!("{}/snapshot/{}-{}.tar.gz", url, name, commit)
+            }
+            KernelRepo::Gitiles { url } => format!("{}/+archive/{}.tar.gz", url, commit),
+        }
+    }
+
+    // the repository to clone with git when the archive does not have the commit
+    pub fn git_url(&self) -> String {
+        match self {
+            KernelRepo::Mainline => {
+                "https://git.kernel.org/pub/scm/linux/kernel/git/torvalds/linux.git".to_string()
+            }
+            KernelRepo::GitHub { owner, repo } => {
+                format!("https://github.com/{}/{}.git", owner, repo)
+            }
+            KernelRepo::Cgit { url, .. } | KernelRepo::Gitiles { url } => url.clone(),
+        }
+    }
+
+    // the top-level directory of the tarball, None when the files are at its root
+    pub fn archive_dir(&self, commit: &str) -> Option<String> {
+        match self {
+            KernelRepo::Mainline => Some(format!("linux-{}", commit)),
+            KernelRepo::GitHub { repo, .. } => Some(format!("{}-{}", repo, commit)),
+            KernelRepo::Cgit { name, .. } => Some(format!("{}-{}", name, commit)),
+            KernelRepo::Gitiles { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for KernelRepo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KernelRepo::Mainline => write!(f, "torvalds/linux"),
+            KernelRepo::GitHub { owner, repo } => write!(f, "github.com/{}/{}", owner, repo),
+            KernelRepo::Cgit { url, .. } | KernelRepo::Gitiles { url } => write!(f, "{}", url),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_kernel_repo() {
+        let mainline = [
+            "https://git.kernel.org/pub/scm/linux/kernel/git/torvalds/linux.git/log/?id=cf76c364",
+            "https://github.com/torvalds/linux.git",
+            "https://kernel.googlesource.com/pub/scm/linux/kernel/git/torvalds/linux/+log/cf76c364",
+            "",
+        ];
+        for source in mainline {
+            assert_eq!(
+                KernelRepo::parse(source).unwrap(),
+                KernelRepo::Mainline,
+                "{}",
+                source
+            );
+        }
+
+        assert_eq!(
+            KernelRepo::parse(
+                "https://git.kernel.org/pub/scm/linux/kernel/git/next/linux-next.git/log/?id=abc"
+            )
+            .unwrap(),
+            KernelRepo::Cgit {
+                url: "https://git.kernel.org/pub/scm/linux/kernel/git/next/linux-next.git"
+                    .to_string(),
+                name: "linux-next".to_string(),
+            }
+        );
+        assert_eq!(
+            KernelRepo::parse("https://github.com/google/kmsan.git/commit/?id=abc").unwrap(),
+            KernelRepo::GitHub {
+                owner: "google".to_string(),
+                repo: "kmsan".to_string(),
+            }
+        );
+        assert_eq!(
+            KernelRepo::parse("https://android.googlesource.com/kernel/common/+log/abc").unwrap(),
+            KernelRepo::Gitiles {
+                url: "https://android.googlesource.com/kernel/common".to_string(),
+            }
+        );
+
+        assert!(KernelRepo::parse("https://example.com/some/linux.git").is_err());
+        assert!(KernelRepo::parse("not a url").is_err());
+    }
+
+    #[test]
+    fn test_kernel_archive_url() {
+        let config = DownloadConfig::default();
+        let next = KernelRepo::parse(
+            "https://git.kernel.org/pub/scm/linux/kernel/git/next/linux-next.git/log/?id=abc",
+        )
+        .unwrap();
+        assert_eq!(
+            next.archive_url("abc", &config),
+            "https://git.kernel.org/pub/scm/linux/kernel/git/next/linux-next.git/snapshot/linux-next-abc.tar.gz"
+        );
+        assert_eq!(next.archive_dir("abc").as_deref(), Some("linux-next-abc"));
+
+        let kmsan = KernelRepo::parse("https://github.com/google/kmsan.git").unwrap();
+        assert_eq!(
+            kmsan.archive_url("abc", &config),
+            "https://github.com/google/kmsan/archive/abc.tar.gz"
+        );
+        assert_eq!(kmsan.archive_dir("abc").as_deref(), Some("kmsan-abc"));
+        assert_eq!(kmsan.git_url(), "https://github.com/google/kmsan.git");
+
+        assert_eq!(
+            KernelRepo::Mainline.archive_url("abc", &config),
+            "https://github.com/torvalds/linux/archive/abc.tar.gz"
+        );
+        assert_eq!(
+            KernelRepo::Mainline.archive_dir("abc").as_deref(),
+            Some("linux-abc")
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use crate::config::config::SSHConfig;
+use crate::kvm::qemu::{QEMUManager, VMConfig};
+use crate::kvm::ssh::SSHManager;
+use anyhow::{Context, Result};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::time::{Instant, sleep, timeout};
+use tracing::{debug, info, warn};
+
+// how long a guest gets from qemu start until sshd answers
+const BOOT_TIMEOUT: Duration = Duration::from_secs(300);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// qemu's user networking accepts connections on the forwarded port as soon as it starts, long
+// before the guest is up, so an open port means nothing. wait for sshd's version banner instead.
+async fn ssh_banner_ready(port: u16) -> bool {
+    let probe = async {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let mut banner = [0u8; 4];
+        stream.read_exact(&mut banner).await?;
+        Ok::<_, std::io::Error>(&banner == b"SSH-")
+    };
+
+    match timeout(PROBE_TIMEOUT, probe).await {
+        Ok(Ok(ready)) => ready,
+        Ok(Err(e)) => {
+            debug!("SSH port {} not ready: {}", port, e);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+async fn wait_for_ssh(vm: &QEMUManager, port: u16, deadline: Duration) -> Result<()> {
+    let started = Instant::now();
+    loop {
+        if ssh_banner_ready(port).await {
+            info!(
+                "Guest {} answered on SSH port {} after {:?}",
+                vm.config().name,
+                port,
+                started.elapsed()
+            );
+            return Ok(());
+        }
+        if !vm.is_running().await {
+            anyhow::bail!("VM {} exited while booting", vm.config().name);
+        }
+        if started.elapsed() >= deadline {
+            anyhow::bail!(
+                "VM {} did not answer on SSH port {} within {:?}",
+                vm.config().name,
+                port,
+                deadline
+            );
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn connect_guest(vm: &QEMUManager, ssh: SSHConfig) -> Result<SSHManager> {
+    wait_for_ssh(vm, ssh.port, BOOT_TIMEOUT).await?;
+
+    let mut manager = SSHManager::new(ssh)?;
+    manager
+        .connect()
+        .await
+        .context("Failed to connect to the guest")?;
+    Ok(manager)
+}
+
+// start the VM, wait until its sshd is reachable through the forwarded port and log in.
+// the VM is shut down again if any step fails. an ssh_port of 0 in `vm` is picked at start and
+// replaces the port of `ssh`
+pub async fn boot_and_connect(
+    vm: VMConfig,
+    mut ssh: SSHConfig,
+) -> Result<(QEMUManager, SSHManager)> {
+    if vm.ssh_port != 0 && vm.ssh_port != ssh.port {
+        anyhow::bail!(
+            "VM {} forwards SSH on port {} but the SSH config connects to port {}",
+            vm.name,
+            vm.ssh_port,
+            ssh.port
+        );
+    }
+
+    let mut vm = QEMUManager::new(vm);
+    vm.start().await?;
+    ssh.port = vm.ssh_port();
+
+    match connect_guest(&vm, ssh).await {
+        Ok(manager) => Ok((vm, manager)),
+        Err(e) => {
+            if let Err(shutdown) = vm.shutdown().await {
+                warn!("Failed to shut down VM {}: {}", vm.config().name, shutdown);
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_ssh_banner_ready() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            // first a guest that isn't up yet: accepted by slirp and closed right away
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(stream);
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"SSH-2.0-OpenSSH_9.2\r\n").await.unwrap();
+        });
+
+        assert!(!ssh_banner_ready(port).await);
+        assert!(ssh_banner_ready(port).await);
+        server.await.unwrap();
+
+        // nothing listening at all
+        assert!(!ssh_banner_ready(port).await);
+    }
+}
@@ -0,0 +1,168 @@
+use crate::kernel::compile::{BuildOptions, compile_reproducer};
+use crate::kvm::boot::boot_and_connect;
+use crate::kvm::reproduce::{GUEST_REPRODUCER, KERNEL_APPEND, guest_vm, upload_reproducer};
+use crate::kvm::ssh::SSHManager;
+use crate::kvm::vmcore::analyze_crash_vmcore;
+use crate::parse::layout::Layout;
+use crate::parse::report::CrashReport;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::{Instant, sleep};
+use tracing::{info, warn};
+
+// crash kernel shipped in the guest image, its initramfs saves /proc/vmcore to VMCORE_PATH and
+// reboots into the regular kernel
+const CRASH_KERNEL: &str = "/boot/crash-bzImage";
+const CRASH_INITRD: &str = "/boot/crash-initramfs.cpio.gz";
+const CRASH_APPEND: &str = "root=/dev/ram0 console=ttyS0";
+const VMCORE_PATH: &str = "/var/crash/vmcore";
+// memory the guest kernel sets aside for the crash kernel
+const CRASH_KERNEL_MEMORY: &str = "256M";
+
+// reproducers usually fire within seconds, some race for minutes
+const PANIC_TIMEOUT: Duration = Duration::from_secs(600);
+// capture kernel boot, dump and reboot into the regular kernel
+const DUMP_TIMEOUT: Duration = Duration::from_secs(900);
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+// boot the built kernel of crash `options.crash_index` with memory reserved for the crash
+// kernel, capture the vmcore of its reproducer and analyze it when crash is installed
+pub async fn dump_vmcore(report: &CrashReport, options: &BuildOptions) -> Result<PathBuf> {
+    let crash_index = options.crash_index;
+    let vm_config = guest_vm(report, crash_index)
+        .await?
+        .kernel_append(format!(
+            "{} crashkernel={}",
+            KERNEL_APPEND, CRASH_KERNEL_MEMORY
+        ))
+        // the capture kernel reboots the guest once the dump is written
+        .allow_reboot(true)
+        .build()?;
+    let ssh_config = SSHManager::builder()
+        .defaults(options.config.ssh.clone())
+        .build()?;
+    let (mut vm, mut ssh) = boot_and_connect(vm_config, ssh_config).await?;
+
+    let captured = capture_vmcore(&mut ssh, report, options).await;
+    if let Err(e) = vm.shutdown().await {
+        warn!("Failed to shut down VM for report {}: {}", report.id, e);
+    }
+    let vmcore = captured?;
+
+    analyze_crash_vmcore(report, crash_index).await?;
+    Ok(vmcore)
+}
+
+// load the crash kernel, trigger the bug and fetch the dump it leaves behind.
+// the session goes down with the guest, `ssh` is reconnected once the dump has been written.
+pub async fn capture_vmcore(
+    ssh: &mut SSHManager,
+    report: &CrashReport,
+    options: &BuildOptions,
+) -> Result<PathBuf> {
+    let crash_index = options.crash_index;
+    let local = Layout::for_crash(report, crash_index)?.vmcore_path();
+    let binary = compile_reproducer(report, options)
+        .await
+        .with_context(|| format!("Failed to build the reproducer of report {}", report.id))?;
+
+    ssh.execute(&format!(
+        "kexec -p {} --initrd={} --append=\"{}\"",
+        CRASH_KERNEL, CRASH_INITRD, CRASH_APPEND
+    ))
+    .await
+    .context("Failed to load the crash kernel")?;
+
+    let loaded = ssh.execute("cat /sys/kernel/kexec_crash_loaded").await?;
+    if loaded.trim() != "1" {
+        anyhow::bail!("Crash kernel is not loaded after kexec -p, is crashkernel= reserved?");
+    }
+
+    // a dump left over from an earlier run would be mistaken for this one
+    ssh.execute(&format!("rm -f {}", VMCORE_PATH)).await?;
+
+    upload_reproducer(ssh, &binary).await?;
+
+    info!(
+        "Running reproducer for report {} to trigger the panic",
+        report.id
+    );
+    // detached so that the command returns while the reproducer keeps running
+    ssh.execute(&format!("nohup {} >/dev/null 2>&1 &", GUEST_REPRODUCER))
+        .await?;
+
+    wait_for_panic(ssh, PANIC_TIMEOUT).await?;
+    info!("Guest went down, waiting for the capture kernel to write the dump");
+
+    wait_for_dump(ssh, DUMP_TIMEOUT).await?;
+
+    if let Some(dir) = local.parent() {
+        tokio::fs::create_dir_all(dir)
+            .await
+            .with_context(|| format!("Failed to create directory: {}", dir.display()))?;
+    }
+    ssh.download(Path::new(VMCORE_PATH), &local)
+        .await
+        .context("Failed to copy the vmcore from the guest")?;
+
+    info!(
+        "vmcore for report {} saved to {}",
+        report.id,
+        local.display()
+    );
+    Ok(local)
+}
+
+async fn wait_for_panic(ssh: &SSHManager, deadline: Duration) -> Result<()> {
+    let started = Instant::now();
+    while ssh.is_connected().await {
+        if started.elapsed() >= deadline {
+            anyhow::bail!(
+                "Guest still alive {:?} after starting the reproducer, no panic to dump",
+                deadline
+            );
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+    Ok(())
+}
+
+// the guest may answer while the capture kernel is still saving the dump, or go down again
+// on the way back into the regular kernel, so the dump is polled for until the deadline
+async fn wait_for_dump(ssh: &mut SSHManager, deadline: Duration) -> Result<()> {
+    let started = Instant::now();
+    let mut connected = false;
+    loop {
+        sleep(POLL_INTERVAL).await;
+
+        // the guest is rebooting, failed attempts are expected until it is back
+        if !connected {
+            match ssh.reconnect().await {
+                Ok(()) => connected = true,
+                Err(e) => info!("Guest not reachable yet: {}", e),
+            }
+        }
+        if connected {
+            match ssh
+                .execute_with_status(&format!("test -s {}", VMCORE_PATH))
+                .await
+            {
+                Ok(check) if check.success() => return Ok(()),
+                Ok(_) => info!("No vmcore at {} yet", VMCORE_PATH),
+                Err(e) => {
+                    info!("Guest went away again: {}", e);
+                    connected = false;
+                }
+            }
+        }
+
+        if started.elapsed() >= deadline {
+            anyhow::bail!(
+                "No vmcore appeared at {} within {:?}, check the console log",
+                VMCORE_PATH,
+                deadline
+            );
+        }
+    }
+}
@@ -0,0 +1,299 @@
+use crate::config::config::SSHConfig;
+use crate::kvm::ssh::{CommandOutput, SSHError};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, Session};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+// the openssh crate drives the ssh binary in batch mode, which disables password
+// authentication, so password logins go through libssh2 instead.
+// everything here blocks, SSHManager runs it on the blocking pool.
+
+// libssh2 error codes meaning the connection itself is gone
+const LIBSSH2_ERROR_SOCKET_SEND: i32 = -7;
+const LIBSSH2_ERROR_SOCKET_DISCONNECT: i32 = -13;
+const LIBSSH2_ERROR_SOCKET_RECV: i32 = -43;
+// a blocking call ran past session.set_timeout
+const LIBSSH2_ERROR_TIMEOUT: i32 = -9;
+
+// how long exec sleeps when neither stdout nor stderr had data
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn map_err(e: ssh2::Error) -> SSHError {
+    match e.code() {
+        ErrorCode::Session(
+            LIBSSH2_ERROR_SOCKET_SEND | LIBSSH2_ERROR_SOCKET_DISCONNECT | LIBSSH2_ERROR_SOCKET_RECV,
+        ) => SSHError::UnexpectedEof,
+        ErrorCode::Session(LIBSSH2_ERROR_TIMEOUT) => SSHError::TimeoutError(e.to_string()),
+        _ => SSHError::SessionFailed(e.to_string()),
+    }
+}
+
+pub(crate) fn connect(config: &SSHConfig, password: &str) -> Result<Session, SSHError> {
+    let addr = (config.host.as_str(), config.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| SSHError::ConnectionFailed(format!("Cannot resolve {}", config.host)))?;
+    let tcp = TcpStream::connect_timeout(&addr, config.timeout)?;
+
+    let mut session = Session::new().map_err(map_err)?;
+    session.set_tcp_stream(tcp);
+    session.set_compress(config.compression);
+    session.set_timeout(config.timeout.as_millis() as u32);
+    session.handshake().map_err(map_err)?;
+
+    if config.strict_host_key_checking {
+        check_known_host(&session, config)?;
+    }
+
+    session
+        .userauth_password(&config.user, password)
+        .map_err(|e| SSHError::AuthenticationFailed(e.message().to_string()))?;
+    if !session.authenticated() {
+        return Err(SSHError::AuthenticationFailed(format!(
+            "Password rejected for {}@{}",
+            config.user, config.host
+        )));
+    }
+
+    if let Some(interval) = config.keep_alive_interval {
+        session.set_keepalive(true, interval.as_secs() as u32);
+    }
+    // commands may legitimately run for a long time, exec sets a timeout per command
+    session.set_timeout(0);
+
+    Ok(session)
+}
+
+fn check_known_host(session: &Session, config: &SSHConfig) -> Result<(), SSHError> {
+    let file = std::env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".ssh/known_hosts"))
+        .ok_or(SSHError::HostKeyVerificationFailed)?;
+
+    let mut known_hosts = session.known_hosts().map_err(map_err)?;
+    known_hosts
+        .read_file(&file, KnownHostFileKind::OpenSSH)
+        .map_err(|_| SSHError::HostKeyVerificationFailed)?;
+
+    let (key, _) = session
+        .host_key()
+        .ok_or(SSHError::HostKeyVerificationFailed)?;
+    match known_hosts.check_port(&config.host, config.port, key) {
+        CheckResult::Match => Ok(()),
+        _ => Err(SSHError::HostKeyVerificationFailed),
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+// same shape as the openssh side, which runs every command through `bash -lc`
+fn login_shell(cmd: &str) -> String {
+    format!("bash -lc {}", quote(cmd))
+}
+
+fn exit_code(channel: &ssh2::Channel) -> Result<Option<i32>, SSHError> {
+    // a command killed by a signal has no exit code
+    if channel
+        .exit_signal()
+        .map_err(map_err)?
+        .exit_signal
+        .is_some()
+    {
+        return Ok(None);
+    }
+    Ok(Some(channel.exit_status().map_err(map_err)?))
+}
+
+// run `cmd`, giving up after `timeout`. the blocking calls are bounded by the session's
+// timeout, so a hung command releases the blocking thread and the session instead of
+// holding them forever. SSHManager never runs two libssh2 calls at once, the session's
+// blocking mode and timeout are changed for the duration
+pub(crate) fn exec(
+    session: &Session,
+    cmd: &str,
+    timeout: Duration,
+) -> Result<CommandOutput, SSHError> {
+    session.set_timeout(timeout.as_millis().clamp(1, u32::MAX as u128) as u32);
+    let output = exec_until(session, cmd, Instant::now() + timeout, timeout);
+    session.set_blocking(true);
+    session.set_timeout(0);
+    output
+}
+
+fn exec_until(
+    session: &Session,
+    cmd: &str,
+    deadline: Instant,
+    timeout: Duration,
+) -> Result<CommandOutput, SSHError> {
+    let mut channel = session.channel_session().map_err(map_err)?;
+    channel.exec(&login_shell(cmd)).map_err(map_err)?;
+
+    // stdout and stderr share the channel's window, reading one to the end while the command
+    // fills the window with the other deadlocks. both are drained as data arrives
+    session.set_blocking(false);
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut buf = vec![0; 16 * 1024];
+    loop {
+        let mut progressed = read_available(&mut channel.stream(0), &mut stdout, &mut buf)?;
+        progressed |= read_available(&mut channel.stderr(), &mut stderr, &mut buf)?;
+        if progressed {
+            continue;
+        }
+        if channel.eof() {
+            break;
+        }
+        if Instant::now() >= deadline {
+            let _ = channel.close();
+            return Err(SSHError::TimeoutError(format!(
+                "Command execution timed out after {:?}",
+                timeout
+            )));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    session.set_blocking(true);
+    channel.wait_close().map_err(map_err)?;
+
+    Ok(CommandOutput {
+        stdout: String::from_utf8_lossy(&stdout).into_owned(),
+        stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        exit_code: exit_code(&channel)?,
+    })
+}
+
+// append what a non-blocking `stream` has buffered to `out`, returns whether anything came
+fn read_available(
+    stream: &mut impl Read,
+    out: &mut Vec<u8>,
+    buf: &mut [u8],
+) -> Result<bool, SSHError> {
+    let mut progressed = false;
+    loop {
+        match stream.read(buf) {
+            Ok(0) => return Ok(progressed),
+            Ok(n) => {
+                out.extend_from_slice(&buf[..n]);
+                progressed = true;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(progressed),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// sends stdout line by line through `lines`, the returned output has an empty stdout
+pub(crate) fn exec_streaming(
+    session: &Session,
+    cmd: &str,
+    lines: UnboundedSender<String>,
+) -> Result<CommandOutput, SSHError> {
+    let mut channel = session.channel_session().map_err(map_err)?;
+    channel.exec(&login_shell(cmd)).map_err(map_err)?;
+
+    for line in BufReader::new(&mut channel).lines() {
+        // the receiver only goes away when the caller gave up on the command
+        if lines.send(line?).is_err() {
+            break;
+        }
+    }
+
+    let mut stderr = String::new();
+    channel.stderr().read_to_string(&mut stderr)?;
+    channel.wait_close().map_err(map_err)?;
+
+    Ok(CommandOutput {
+        stdout: String::new(),
+        stderr,
+        exit_code: exit_code(&channel)?,
+    })
+}
+
+// stream `local` into a remote `cat`, like the openssh side
+pub(crate) fn upload(session: &Session, local: &Path, remote: &str) -> Result<u64, SSHError> {
+    let mut file = std::fs::File::open(local)?;
+    let mut channel = session.channel_session().map_err(map_err)?;
+    channel
+        .exec(&format!("cat > {}", quote(remote)))
+        .map_err(map_err)?;
+
+    let copied = std::io::copy(&mut file, &mut channel)?;
+    channel.flush()?;
+    channel.send_eof().map_err(map_err)?;
+
+    let mut stderr = String::new();
+    channel.stderr().read_to_string(&mut stderr)?;
+    channel.wait_close().map_err(map_err)?;
+
+    if exit_code(&channel)? != Some(0) {
+        return Err(SSHError::from_transfer_stderr(&stderr));
+    }
+    Ok(copied)
+}
+
+pub(crate) fn download(session: &Session, remote: &str, local: &Path) -> Result<u64, SSHError> {
+    let mut file = std::fs::File::create(local)?;
+    let mut channel = session.channel_session().map_err(map_err)?;
+    channel
+        .exec(&format!("cat {}", quote(remote)))
+        .map_err(map_err)?;
+
+    let copied = std::io::copy(&mut channel, &mut file)?;
+    file.flush()?;
+
+    let mut stderr = String::new();
+    channel.stderr().read_to_string(&mut stderr)?;
+    channel.wait_close().map_err(map_err)?;
+
+    if exit_code(&channel)? != Some(0) {
+        return Err(SSHError::from_transfer_stderr(&stderr));
+    }
+    Ok(copied)
+}
+
+pub(crate) fn close(session: &Session) -> Result<(), SSHError> {
+    session
+        .disconnect(None, "closed by kernel-builder", None)
+        .map_err(map_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_login_shell() {
+        assert_eq!(login_shell("uname -r"), "bash -lc 'uname -r'");
+        assert_eq!(login_shell("echo 'hi'"), r"bash -lc 'echo '\''hi'\'''");
+    }
+
+    #[test]
+    fn test_read_available() {
+        // two chunks, then nothing buffered yet
+        struct Chunks(Vec<&'static [u8]>);
+        impl Read for Chunks {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                match self.0.pop() {
+                    Some(chunk) => {
+                        buf[..chunk.len()].copy_from_slice(chunk);
+                        Ok(chunk.len())
+                    }
+                    None => Err(std::io::ErrorKind::WouldBlock.into()),
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        let mut buf = vec![0; 16];
+        let mut stream = Chunks(vec![b"world", b"hello "]);
+        assert!(read_available(&mut stream, &mut out, &mut buf).unwrap());
+        assert_eq!(out, b"hello world");
+        assert!(!read_available(&mut stream, &mut out, &mut buf).unwrap());
+        assert!(!read_available(&mut std::io::empty(), &mut out, &mut buf).unwrap());
+    }
+}
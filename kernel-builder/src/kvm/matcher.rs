@@ -0,0 +1,194 @@
+use crate::parse::crash_log::{CrashSignature, is_crash_header, strip_printk_prefix};
+use futures::{Stream, StreamExt};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+// a report that never reaches one of its end markers is judged after this many lines
+const MAX_REPORT_LINES: usize = 200;
+
+// what the guest console showed while the reproducer ran
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MatchOutcome {
+    // the crash the report is about
+    Matched(CrashSignature),
+    // a crash, but not the expected one
+    DifferentCrash(CrashSignature),
+    // no crash before the timeout or the end of the output
+    NoCrash,
+}
+
+// watches console lines for a kernel crash and compares it with the one syzbot reported.
+// lines are collected from the first oops header until the report ends, then parsed with
+// CrashSignature::parse
+#[derive(Debug)]
+pub struct CrashMatcher {
+    expected: CrashSignature,
+    timeout: Duration,
+    report: Option<Vec<String>>,
+}
+
+impl CrashMatcher {
+    pub fn new(expected: CrashSignature, timeout: Duration) -> CrashMatcher {
+        CrashMatcher {
+            expected,
+            timeout,
+            report: None,
+        }
+    }
+
+    // hand over one console line, returns the outcome once a complete report has been seen
+    pub fn feed(&mut self, line: &str) -> Option<MatchOutcome> {
+        let stripped = strip_printk_prefix(line);
+        match &mut self.report {
+            None => {
+                if is_crash_header(stripped) {
+                    debug!("Crash report started: {}", stripped.trim());
+                    self.report = Some(vec![line.to_string()]);
+                }
+                None
+            }
+            Some(report) => {
+                report.push(line.to_string());
+                if is_report_end(stripped) || report.len() >= MAX_REPORT_LINES {
+                    Some(self.judge())
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    // the outcome once no more lines will come. a report cut short, e.g. by the guest
+    // rebooting, is judged on what arrived
+    pub fn finish(&mut self) -> MatchOutcome {
+        match self.report {
+            Some(_) => self.judge(),
+            None => MatchOutcome::NoCrash,
+        }
+    }
+
+    // feed `lines` until a report is complete, the stream ends or the timeout expires. the
+    // serial console from QEMUManager::console_lines is the usual input
+    pub async fn scan(mut self, lines: impl Stream<Item = String>) -> MatchOutcome {
+        let deadline = Instant::now() + self.timeout;
+        tokio::pin!(lines);
+        loop {
+            match tokio::time::timeout_at(deadline, lines.next()).await {
+                Ok(Some(line)) => {
+                    if let Some(outcome) = self.feed(&line) {
+                        return outcome;
+                    }
+                }
+                Ok(None) => return self.finish(),
+                Err(_) => {
+                    info!("No complete crash report within {:?}", self.timeout);
+                    return self.finish();
+                }
+            }
+        }
+    }
+
+    fn judge(&mut self) -> MatchOutcome {
+        let Some(report) = self.report.take() else {
+            return MatchOutcome::NoCrash;
+        };
+        match CrashSignature::parse(&report.join("\n"), &self.expected.title) {
+            Ok(observed) if self.expected.matches(&observed) => MatchOutcome::Matched(observed),
+            Ok(observed) => MatchOutcome::DifferentCrash(observed),
+            Err(_) => MatchOutcome::NoCrash,
+        }
+    }
+}
+
+// the lines the kernel closes a report with: the end of the first call trace, the end of a
+// WARNING, the KASAN separator or the panic footer
+fn is_report_end(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("</TASK>")
+        || line.contains("---[ end trace")
+        || line.starts_with("Kernel Offset:")
+        || (line.len() > 10 && line.chars().all(|c| c == '='))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &str = "\
+[   35.126377] BUG: KASAN: use-after-free in hci_conn_drop+0x2a/0x2c0
+[   35.135028] Read of size 8 at addr ffff88801d6b4808 by task bug/3601
+[   35.153000] Call Trace:
+[   35.156294]  <TASK>
+[   35.159246]  dump_stack_lvl+0xcd/0x134 lib/dump_stack.c:106
+[   35.170000]  print_report+0x16a/0x5b0 mm/kasan/report.c:433
+[   35.175000]  kasan_report+0xbf/0x1f0 mm/kasan/report.c:495
+[   35.180000]  hci_conn_drop+0x2a/0x2c0 include/net/bluetooth/hci_core.h:1230
+[   35.185000]  </TASK>
+[   35.190000] Allocated by task 3599:";
+
+    fn matcher() -> CrashMatcher {
+        let expected =
+            CrashSignature::parse(REPORT, "KASAN: use-after-free Read in hci_conn_drop").unwrap();
+        CrashMatcher::new(expected, Duration::from_secs(5))
+    }
+
+    fn lines(log: &str) -> impl Stream<Item = String> {
+        futures::stream::iter(log.lines().map(str::to_string).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_feed_lines() {
+        let mut matcher = matcher();
+        assert_eq!(matcher.feed("[   30.000000] random: crng init done"), None);
+
+        let mut outcome = None;
+        for line in REPORT.lines() {
+            if let Some(found) = matcher.feed(line) {
+                outcome = Some(found);
+                break;
+            }
+        }
+        match outcome {
+            Some(MatchOutcome::Matched(observed)) => {
+                assert_eq!(observed.function.as_deref(), Some("hci_conn_drop"))
+            }
+            other => panic!("unexpected outcome {:?}", other),
+        }
+        assert_eq!(matcher.finish(), MatchOutcome::NoCrash);
+    }
+
+    #[tokio::test]
+    async fn test_scan_outcomes() {
+        assert!(matches!(
+            matcher().scan(lines(REPORT)).await,
+            MatchOutcome::Matched(_)
+        ));
+
+        let other = REPORT.replace("hci_conn_drop", "hci_conn_put");
+        assert!(matches!(
+            matcher().scan(lines(&other)).await,
+            MatchOutcome::DifferentCrash(_)
+        ));
+
+        // cut short by the guest going down before the trace ended
+        let truncated: Vec<&str> = REPORT.lines().take(6).collect();
+        assert!(matches!(
+            matcher().scan(lines(&truncated.join("\n"))).await,
+            MatchOutcome::Matched(_)
+        ));
+
+        assert_eq!(
+            matcher().scan(lines("[    1.0] all good\n")).await,
+            MatchOutcome::NoCrash
+        );
+    }
+
+    #[tokio::test]
+    async fn test_scan_timeout() {
+        let mut matcher = matcher();
+        matcher.timeout = Duration::from_millis(50);
+        let outcome = matcher.scan(futures::stream::pending()).await;
+        assert_eq!(outcome, MatchOutcome::NoCrash);
+    }
+}
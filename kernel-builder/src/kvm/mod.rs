@@ -1,2 +1,8 @@
+pub mod boot;
+pub mod kdump;
+mod libssh2;
+pub mod matcher;
+pub mod qemu;
+pub mod reproduce;
 pub mod ssh;
-mod qemu;
\ No newline at end of file
+pub mod vmcore;
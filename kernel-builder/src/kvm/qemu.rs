@@ -1,5 +1,18 @@
+use crate::config::config::Config;
+use crate::parse::workspace::default_workspace;
+use futures::{Stream, stream};
 use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::Duration;
 use thiserror::Error;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::process::{Child, Command};
+use tracing::{debug, info, warn};
 
 #[derive(Error, Debug)]
 pub enum QEMUError {
@@ -41,13 +54,1319 @@ pub struct VMConfig {
     pub log_file: Option<String>,
     pub cpu_count: Option<u8>,
     pub disk_format: DiskFormat,
+    // guest root device and console, passed as root= and console= when booting a kernel directly
+    #[serde(default = "default_root_device")]
+    pub root_device: String,
+    #[serde(default = "default_console")]
+    pub console: String,
+    // let a guest reboot, e.g. from a kdump capture kernel. otherwise qemu runs with -no-reboot
+    // and the kernel with panic=-1, so a panic ends the VM instead of leaving it stuck
+    #[serde(default)]
+    pub allow_reboot: bool,
+    // boot from a qcow2 overlay on top of image_path, created in the workspace at start and
+    // deleted at shutdown, so the guest never writes to the image itself
+    #[serde(default)]
+    pub overlay: bool,
+}
+
+fn default_root_device() -> String {
+    "/dev/sda".to_string()
+}
+
+fn default_console() -> String {
+    "ttyS0".to_string()
+}
+
+impl VMConfig {
+    pub fn validate(&self) -> Result<(), QEMUError> {
+        if parse_memory_size(&self.memory).is_none() {
+            return Err(QEMUError::ConfigError(format!(
+                "Invalid memory size {:?}, expected e.g. 2G, 512M or a number of MiB",
+                self.memory
+            )));
+        }
+
+        // a port of 0 is picked when the VM starts
+        if self.monitor_port != 0 && self.monitor_port == self.ssh_port {
+            return Err(QEMUError::ConfigError(format!(
+                "monitor_port and ssh_port are both {}",
+                self.ssh_port
+            )));
+        }
+
+        let root_device_valid = ["/dev/", "UUID=", "PARTUUID=", "LABEL="]
+            .iter()
+            .any(|prefix| {
+                self.root_device.starts_with(prefix) && self.root_device.len() > prefix.len()
+            });
+        if !root_device_valid || self.root_device.contains(char::is_whitespace) {
+            return Err(QEMUError::ConfigError(format!(
+                "Invalid root device {:?}, expected /dev/<name>, UUID=, PARTUUID= or LABEL=",
+                self.root_device
+            )));
+        }
+
+        // e.g. ttyS0, hvc0 or ttyS0,115200n8
+        let device = self.console.split(',').next().unwrap_or_default();
+        if device.is_empty()
+            || !device.chars().all(|c| c.is_ascii_alphanumeric())
+            || !device.ends_with(|c: char| c.is_ascii_digit())
+        {
+            return Err(QEMUError::ConfigError(format!(
+                "Invalid console {:?}, expected a device like ttyS0 or ttyS0,115200",
+                self.console
+            )));
+        }
+
+        Ok(())
+    }
+
+    // a single VM from a TOML file whose top level is a VMConfig
+    pub fn from_toml(path: &Path) -> Result<VMConfig, QEMUError> {
+        let config: VMConfig = toml::from_str(&read_vm_file(path)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // every `[[vm]]` table of a profile file, validated, names must be unique
+    pub fn profiles_from_toml(path: &Path) -> Result<Vec<VMConfig>, QEMUError> {
+        #[derive(Deserialize)]
+        struct Profiles {
+            #[serde(default)]
+            vm: Vec<VMConfig>,
+        }
+
+        let profiles: Profiles = toml::from_str(&read_vm_file(path)?)?;
+        for (i, config) in profiles.vm.iter().enumerate() {
+            config
+                .validate()
+                .map_err(|e| QEMUError::ConfigError(format!("vm {:?}: {}", config.name, e)))?;
+            if profiles.vm[..i]
+                .iter()
+                .any(|other| other.name == config.name)
+            {
+                return Err(QEMUError::ConfigError(format!(
+                    "vm {:?} is defined more than once in {}",
+                    config.name,
+                    path.display()
+                )));
+            }
+        }
+        info!(
+            "Loaded {} VM profiles from {}",
+            profiles.vm.len(),
+            path.display()
+        );
+        Ok(profiles.vm)
+    }
+
+    // the `[[vm]]` profile called `name`
+    pub fn profile(path: &Path, name: &str) -> Result<VMConfig, QEMUError> {
+        VMConfig::profiles_from_toml(path)?
+            .into_iter()
+            .find(|config| config.name == name)
+            .ok_or_else(|| {
+                QEMUError::ConfigError(format!("No vm {:?} in {}", name, path.display()))
+            })
+    }
+
+    // full kernel command line: root device and console followed by any extra arguments, and
+    // panic=-1 unless the guest may reboot or kernel_append sets its own panic=
+    pub fn kernel_cmdline(&self) -> String {
+        let mut cmdline = format!("root={} console={}", self.root_device, self.console);
+        if let Some(kernel_append) = &self.kernel_append {
+            cmdline.push(' ');
+            cmdline.push_str(kernel_append);
+        }
+        let has_panic = self
+            .kernel_append
+            .iter()
+            .flat_map(|append| append.split_whitespace())
+            .any(|arg| arg.starts_with("panic="));
+        if !self.allow_reboot && !has_panic {
+            cmdline.push_str(" panic=-1");
+        }
+        cmdline
+    }
+}
+
+// a qemu -m size in bytes: a number with an optional K/M/G/T suffix, MiB when there is none
+pub fn parse_memory_size(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, suffix) = size.split_at(split);
+    let number: u64 = number.parse().ok()?;
+    let shift = match suffix.to_ascii_uppercase().as_str() {
+        "" | "M" | "MB" | "MIB" => 20,
+        "K" | "KB" | "KIB" => 10,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return None,
+    };
+    number.checked_mul(1 << shift).filter(|bytes| *bytes > 0)
+}
+
+fn read_vm_file(path: &Path) -> Result<String, QEMUError> {
+    info!("Loading VM configuration from: {}", path.display());
+    std::fs::read_to_string(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => QEMUError::FileNotFound(path.display().to_string()),
+        _ => QEMUError::Io(e),
+    })
+}
+
+#[derive(Default)]
+pub struct VMConfigBuilder {
+    name: Option<String>,
+    image_path: Option<String>,
+    kernel_path: Option<String>,
+    memory: Option<String>,
+    monitor_port: Option<u16>,
+    ssh_port: Option<u16>,
+    kernel_append: Option<String>,
+    log_file: Option<String>,
+    cpu_count: Option<u8>,
+    disk_format: Option<DiskFormat>,
+    root_device: Option<String>,
+    console: Option<String>,
+    allow_reboot: bool,
+    overlay: bool,
+}
+
+impl VMConfigBuilder {
+    pub fn name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+    pub fn image_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.image_path = Some(path.into());
+        self
+    }
+    pub fn kernel_path<S: Into<String>>(mut self, path: S) -> Self {
+        self.kernel_path = Some(path.into());
+        self
+    }
+    pub fn memory<S: Into<String>>(mut self, memory: S) -> Self {
+        self.memory = Some(memory.into());
+        self
+    }
+    pub fn monitor_port(mut self, port: u16) -> Self {
+        self.monitor_port = Some(port);
+        self
+    }
+    pub fn ssh_port(mut self, port: u16) -> Self {
+        self.ssh_port = Some(port);
+        self
+    }
+    pub fn kernel_append<S: Into<String>>(mut self, append: S) -> Self {
+        self.kernel_append = Some(append.into());
+        self
+    }
+    pub fn log_file<S: Into<String>>(mut self, path: S) -> Self {
+        self.log_file = Some(path.into());
+        self
+    }
+    pub fn cpu_count(mut self, count: u8) -> Self {
+        self.cpu_count = Some(count);
+        self
+    }
+    pub fn disk_format(mut self, format: DiskFormat) -> Self {
+        self.disk_format = Some(format);
+        self
+    }
+    pub fn root_device<S: Into<String>>(mut self, device: S) -> Self {
+        self.root_device = Some(device.into());
+        self
+    }
+    pub fn console<S: Into<String>>(mut self, console: S) -> Self {
+        self.console = Some(console.into());
+        self
+    }
+    pub fn allow_reboot(mut self, allow: bool) -> Self {
+        self.allow_reboot = allow;
+        self
+    }
+    pub fn overlay(mut self, overlay: bool) -> Self {
+        self.overlay = overlay;
+        self
+    }
+
+    // image_path is required and must exist, as must kernel_path when set. the monitor port
+    // defaults to 0 (picked at start), the ssh port to the hardcoded default of ssh.port
+    pub fn build(self) -> Result<VMConfig, QEMUError> {
+        let image_path = self
+            .image_path
+            .ok_or_else(|| QEMUError::ConfigError("image_path is required".to_string()))?;
+        for path in std::iter::once(&image_path).chain(&self.kernel_path) {
+            if !Path::new(path).is_file() {
+                return Err(QEMUError::FileNotFound(path.clone()));
+            }
+        }
+
+        let config = VMConfig {
+            name: self.name.unwrap_or_else(|| "vm".to_string()),
+            image_path,
+            kernel_path: self.kernel_path,
+            memory: self.memory.unwrap_or_else(|| "2G".to_string()),
+            monitor_port: self.monitor_port.unwrap_or(0),
+            ssh_port: self.ssh_port.unwrap_or(Config::default().ssh.port),
+            kernel_append: self.kernel_append,
+            log_file: self.log_file,
+            cpu_count: self.cpu_count,
+            disk_format: self.disk_format.unwrap_or(DiskFormat::Raw),
+            root_device: self.root_device.unwrap_or_else(default_root_device),
+            console: self.console.unwrap_or_else(default_console),
+            allow_reboot: self.allow_reboot,
+            overlay: self.overlay,
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum DiskFormat{
+pub enum DiskFormat {
     Raw,
     Qcow2,
     Vmdk,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+impl DiskFormat {
+    // only qcow2 can hold the internal snapshots savevm writes
+    pub fn supports_snapshots(&self) -> bool {
+        matches!(self, DiskFormat::Qcow2)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskFormat::Raw => "raw",
+            DiskFormat::Qcow2 => "qcow2",
+            DiskFormat::Vmdk => "vmdk",
+        }
+    }
+}
+
+const QEMU_BINARY: &str = "qemu-system-x86_64";
+const QEMU_IMG_BINARY: &str = "qemu-img";
+
+// how long a freshly spawned qemu must survive to count as started
+const STARTUP_GRACE: Duration = Duration::from_millis(500);
+
+const QMP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// savevm/loadvm write or read the whole guest memory
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(300);
+
+// the line the kernel prints once it has given up, followed by the reason
+const PANIC_MARKER: &str = "Kernel panic - not syncing";
+
+// how often console_lines() looks for new output
+const CONSOLE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// tries at finding a free port, and at starting qemu when a picked port was taken meanwhile
+const PORT_ATTEMPTS: usize = 3;
+
+// a local port nobody listens on right now, other than `taken`. the port is released again
+// before qemu binds it, so it can still be lost to another process in between
+fn free_port(taken: &[u16]) -> Result<u16, QEMUError> {
+    let mut last_error = None;
+    for _ in 0..PORT_ATTEMPTS {
+        match std::net::TcpListener::bind(("127.0.0.1", 0)).and_then(|l| l.local_addr()) {
+            Ok(addr) if !taken.contains(&addr.port()) => return Ok(addr.port()),
+            Ok(_) => {}
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(QEMUError::VMStartupFailed(format!(
+        "No free local port found: {}",
+        last_error.map_or_else(|| "every port was taken".to_string(), |e| e.to_string())
+    )))
+}
+
+// how a VM run ended, as seen by QEMUManager::watch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmOutcome {
+    // the kernel panicked, with the panic line from the console
+    Panicked(String),
+    // still running, but the console stayed silent for the whole no-output timeout
+    Hung,
+    // qemu exited without a panic on the console
+    Clean,
+}
+
+pub struct QEMUManager {
+    config: VMConfig,
+    // behind a mutex so that `is_running(&self)` can reap an exited qemu
+    child: Mutex<Option<Child>>,
+    // the ports qemu listens on, the configured ones or the ones picked for a 0
+    ssh_port: u16,
+    monitor_port: u16,
+    // the overlay the running VM boots from, see VMConfig::overlay
+    overlay_path: Option<PathBuf>,
+}
+
+impl QEMUManager {
+    pub fn new(config: VMConfig) -> Self {
+        QEMUManager {
+            ssh_port: config.ssh_port,
+            monitor_port: config.monitor_port,
+            config,
+            child: Mutex::new(None),
+            overlay_path: None,
+        }
+    }
+
+    // the forwarded ssh port, 0 until a configured 0 was replaced by start()
+    pub fn ssh_port(&self) -> u16 {
+        self.ssh_port
+    }
+
+    // the QMP monitor port, 0 until a configured 0 was replaced by start()
+    pub fn monitor_port(&self) -> u16 {
+        self.monitor_port
+    }
+
+    fn assign_ports(&mut self) -> Result<(), QEMUError> {
+        self.ssh_port = match self.config.ssh_port {
+            0 => free_port(&[])?,
+            port => port,
+        };
+        self.monitor_port = match self.config.monitor_port {
+            0 => free_port(&[self.ssh_port])?,
+            port => port,
+        };
+        Ok(())
+    }
+
+    pub async fn is_running(&self) -> bool {
+        let mut child = self.child.lock().unwrap();
+        match child.as_mut().map(|child| child.try_wait()) {
+            Some(Ok(None)) => true,
+            Some(Ok(Some(status))) => {
+                warn!("VM {} exited with {}", self.config.name, status);
+                *child = None;
+                false
+            }
+            Some(Err(e)) => {
+                warn!("Failed to query VM {} state: {}", self.config.name, e);
+                false
+            }
+            None => false,
+        }
+    }
+
+    pub fn pid(&self) -> Option<u32> {
+        self.child
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|child| child.id())
+    }
+
+    pub fn config(&self) -> &VMConfig {
+        &self.config
+    }
+
+    pub fn builder() -> VMConfigBuilder {
+        VMConfigBuilder::default()
+    }
+
+    pub async fn monitor(&self) -> Result<QMPClient, QEMUError> {
+        if self.monitor_port == 0 {
+            return Err(QEMUError::MonitorNotConnected);
+        }
+        QMPClient::connect(("127.0.0.1", self.monitor_port)).await
+    }
+
+    // the serial console from the start of the log file on, following it like `tail -f` until
+    // the VM has exited and everything it wrote has been read. needs a log_file
+    pub async fn console_lines(&self) -> Result<impl Stream<Item = String> + '_, QEMUError> {
+        let log_file = self.config.log_file.as_ref().ok_or_else(|| {
+            QEMUError::ConfigError(format!("VM {} has no log_file", self.config.name))
+        })?;
+        let file = tokio::fs::File::open(log_file)
+            .await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => QEMUError::FileNotFound(log_file.clone()),
+                _ => QEMUError::Io(e),
+            })?;
+
+        let lines = stream::unfold(
+            (BufReader::new(file), Vec::new()),
+            move |(mut reader, mut line)| async move {
+                loop {
+                    // checked before reading, so nothing written before the exit is missed
+                    let running = self.is_running().await;
+                    match reader.read_until(b'\n', &mut line).await {
+                        Ok(0) if running => tokio::time::sleep(CONSOLE_POLL_INTERVAL).await,
+                        // qemu is gone, hand out a last line without newline
+                        Ok(0) if line.is_empty() => return None,
+                        Ok(0) => break,
+                        Ok(_) if line.ends_with(b"\n") => break,
+                        // the rest of the line is still being written
+                        Ok(_) => {}
+                        Err(e) => {
+                            warn!("Failed to read the console of {}: {}", self.config.name, e);
+                            return None;
+                        }
+                    }
+                }
+                let text = String::from_utf8_lossy(&line)
+                    .trim_end_matches(['\r', '\n'])
+                    .to_string();
+                line.clear();
+                Some((text, (reader, line)))
+            },
+        );
+        Ok(lines)
+    }
+
+    // save the running guest as internal snapshot `name` of its qcow2 image, replacing an
+    // older snapshot of that name
+    pub async fn snapshot(&mut self, name: &str) -> Result<(), QEMUError> {
+        self.snapshot_command("savevm", name).await?;
+        info!("Saved snapshot {} of VM {}", name, self.config.name);
+        Ok(())
+    }
+
+    // bring the guest back to snapshot `name`. connections into the guest, ssh included, are
+    // stale afterwards and have to be opened again
+    pub async fn restore(&mut self, name: &str) -> Result<(), QEMUError> {
+        self.snapshot_command("loadvm", name).await?;
+        info!("Restored VM {} to snapshot {}", self.config.name, name);
+        Ok(())
+    }
+
+    async fn snapshot_command(&mut self, command: &str, name: &str) -> Result<(), QEMUError> {
+        if self.overlay_path.is_none() && !self.config.disk_format.supports_snapshots() {
+            return Err(QEMUError::ConfigError(format!(
+                "VM {} uses a {} image, snapshots need qcow2",
+                self.config.name,
+                self.config.disk_format.as_str()
+            )));
+        }
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        {
+            return Err(QEMUError::ConfigError(format!(
+                "Invalid snapshot name {:?}",
+                name
+            )));
+        }
+        if !self.is_running().await {
+            return Err(QEMUError::VMNotRunning);
+        }
+
+        // savevm and loadvm print nothing unless they failed
+        let output = self
+            .monitor()
+            .await?
+            .human_monitor_command(&format!("{} {}", command, name), SNAPSHOT_TIMEOUT)
+            .await?;
+        if !output.trim().is_empty() {
+            return Err(QEMUError::MonitorCommandExecutionFailed(format!(
+                "{} {}: {}",
+                command,
+                name,
+                output.trim()
+            )));
+        }
+        Ok(())
+    }
+
+    // follow the serial console until the kernel panics, qemu exits or nothing was printed for
+    // `no_output_timeout`. a quiet but healthy guest is reported as hung as well, so the timeout
+    // must cover the longest silence the workload can have. needs a log_file
+    pub async fn watch(&self, no_output_timeout: Duration) -> Result<VmOutcome, QEMUError> {
+        use futures::StreamExt;
+
+        let lines = self.console_lines().await?;
+        tokio::pin!(lines);
+        loop {
+            match tokio::time::timeout(no_output_timeout, lines.next()).await {
+                Ok(Some(line)) => {
+                    if let Some(at) = line.find(PANIC_MARKER) {
+                        let message = line[at..].trim().to_string();
+                        warn!("VM {} panicked: {}", self.config.name, message);
+                        return Ok(VmOutcome::Panicked(message));
+                    }
+                }
+                Ok(None) => {
+                    info!("VM {} exited without a panic", self.config.name);
+                    return Ok(VmOutcome::Clean);
+                }
+                Err(_) => {
+                    warn!(
+                        "VM {} printed nothing for {:?}, considering it hung",
+                        self.config.name, no_output_timeout
+                    );
+                    return Ok(VmOutcome::Hung);
+                }
+            }
+        }
+    }
+
+    fn build_args(&self) -> Vec<String> {
+        let config = &self.config;
+        let mut args = vec![
+            "-name".to_string(),
+            config.name.clone(),
+            "-m".to_string(),
+            config.memory.clone(),
+            "-smp".to_string(),
+            config.cpu_count.unwrap_or(2).to_string(),
+            "-drive".to_string(),
+            match &self.overlay_path {
+                Some(overlay) => format!("file={},format=qcow2", overlay.display()),
+                None => format!(
+                    "file={},format={}",
+                    config.image_path,
+                    config.disk_format.as_str()
+                ),
+            },
+            "-net".to_string(),
+            format!(
+                "user,host=10.0.2.10,hostfwd=tcp:127.0.0.1:{}-:22",
+                self.ssh_port
+            ),
+            "-net".to_string(),
+            "nic,model=e1000".to_string(),
+            "-enable-kvm".to_string(),
+            "-nographic".to_string(),
+            "-qmp".to_string(),
+            format!("tcp:127.0.0.1:{},server,nowait", self.monitor_port),
+        ];
+
+        // with panic=-1 the kernel reboots right after a panic, which makes qemu exit
+        if !config.allow_reboot {
+            args.push("-no-reboot".to_string());
+        }
+
+        // the guest's serial console, read back by console_lines()
+        if let Some(log_file) = &config.log_file {
+            args.push("-serial".to_string());
+            args.push(format!("file:{}", log_file));
+        }
+
+        if let Some(kernel_path) = &config.kernel_path {
+            args.push("-kernel".to_string());
+            args.push(kernel_path.clone());
+            args.push("-append".to_string());
+            args.push(config.kernel_cmdline());
+        }
+
+        args
+    }
+
+    pub async fn start(&mut self) -> Result<(), QEMUError> {
+        if self.is_running().await {
+            warn!("VM {} is already started", self.config.name);
+            return Ok(());
+        }
+
+        self.config.validate()?;
+
+        if !Path::new(&self.config.image_path).exists() {
+            return Err(QEMUError::FileNotFound(self.config.image_path.clone()));
+        }
+
+        if self.config.overlay {
+            let overlay = self.create_overlay().await?;
+            self.overlay_path = Some(overlay);
+        }
+
+        // a picked port may be taken by someone else before qemu binds it, qemu then exits
+        // right away and the start is retried with new ports
+        let auto_ports = self.config.ssh_port == 0 || self.config.monitor_port == 0;
+        let attempts = if auto_ports { PORT_ATTEMPTS } else { 1 };
+        let mut attempt = 1;
+        let child = loop {
+            self.assign_ports()?;
+            match self.spawn().await {
+                Ok(child) => break child,
+                Err(QEMUError::VMStartupFailed(e)) if attempt < attempts => {
+                    warn!(
+                        "VM {} failed to start on ssh port {} / monitor port {}, retrying: {}",
+                        self.config.name, self.ssh_port, self.monitor_port, e
+                    );
+                    attempt += 1;
+                }
+                Err(e) => {
+                    self.discard_overlay();
+                    return Err(e);
+                }
+            }
+        };
+
+        info!(
+            "VM {} started with pid {:?}, ssh on port {}, monitor on port {}",
+            self.config.name,
+            child.id(),
+            self.ssh_port,
+            self.monitor_port
+        );
+        *self.child.get_mut().unwrap() = Some(child);
+
+        Ok(())
+    }
+
+    // a fresh qcow2 overlay backed by image_path, in <workspace>/overlays
+    async fn create_overlay(&self) -> Result<PathBuf, QEMUError> {
+        // qemu-img resolves a relative backing file against the overlay's directory
+        let base = std::fs::canonicalize(&self.config.image_path).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => QEMUError::FileNotFound(self.config.image_path.clone()),
+            _ => QEMUError::Io(e),
+        })?;
+        let dir = default_workspace().root().join("overlays");
+        std::fs::create_dir_all(&dir)?;
+        let overlay = dir.join(format!(
+            "{}-{:08x}.qcow2",
+            self.config.name,
+            rand::random::<u32>()
+        ));
+
+        let output = Command::new(QEMU_IMG_BINARY)
+            .arg("create")
+            .args(["-f", "qcow2", "-F", self.config.disk_format.as_str(), "-b"])
+            .arg(&base)
+            .arg(&overlay)
+            .stdin(Stdio::null())
+            .output()
+            .await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => QEMUError::ConfigError(format!(
+                    "{} not found in PATH, it is needed for overlay = true",
+                    QEMU_IMG_BINARY
+                )),
+                _ => QEMUError::ProcessError(format!("Failed to run {}: {}", QEMU_IMG_BINARY, e)),
+            })?;
+        if !output.status.success() {
+            return Err(QEMUError::ProcessError(format!(
+                "{} create failed with {}: {}",
+                QEMU_IMG_BINARY,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        info!(
+            "Created overlay {} on top of {}",
+            overlay.display(),
+            base.display()
+        );
+        Ok(overlay)
+    }
+
+    fn discard_overlay(&mut self) {
+        if let Some(overlay) = self.overlay_path.take() {
+            match std::fs::remove_file(&overlay) {
+                Ok(()) => debug!("Removed overlay {}", overlay.display()),
+                Err(e) => warn!("Failed to remove overlay {}: {}", overlay.display(), e),
+            }
+        }
+    }
+
+    async fn spawn(&self) -> Result<Child, QEMUError> {
+        // created up front so that console_lines() can open it before qemu does
+        if let Some(log_file) = &self.config.log_file {
+            std::fs::File::create(log_file)?;
+        }
+
+        let args = self.build_args();
+        info!(
+            "Starting VM {}: {} {}",
+            self.config.name,
+            QEMU_BINARY,
+            args.join(" ")
+        );
+
+        let mut child = Command::new(QEMU_BINARY)
+            .args(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| QEMUError::VMStartupFailed(format!("Failed to spawn qemu: {}", e)))?;
+
+        // qemu exits right away on bad arguments, a missing kvm device or a busy port
+        if let Ok(status) = tokio::time::timeout(STARTUP_GRACE, child.wait()).await {
+            return Err(QEMUError::VMStartupFailed(format!(
+                "qemu exited during startup with {}",
+                status?
+            )));
+        }
+
+        Ok(child)
+    }
+
+    pub async fn shutdown(&mut self) -> Result<(), QEMUError> {
+        let child = self.child.get_mut().unwrap().take();
+        let result = match child {
+            None => Err(QEMUError::VMNotRunning),
+            Some(child) => self.stop(child).await,
+        };
+        // also when qemu was already gone, the overlay is of no use without it
+        self.discard_overlay();
+        result
+    }
+
+    async fn stop(&self, mut child: Child) -> Result<(), QEMUError> {
+        if let Some(status) = child.try_wait()? {
+            warn!("VM {} had already exited with {}", self.config.name, status);
+            return Ok(());
+        }
+
+        info!("Shutting down VM {}", self.config.name);
+
+        child
+            .kill()
+            .await
+            .map_err(|e| QEMUError::ProcessError(format!("Failed to kill qemu: {}", e)))?;
+
+        Ok(())
+    }
+}
+
+// qemu itself is killed on drop, see kill_on_drop in spawn()
+impl Drop for QEMUManager {
+    fn drop(&mut self) {
+        self.discard_overlay();
+    }
+}
+
+// minimal QMP client, see docs/interop/qmp-spec in the qemu tree
+pub struct QMPClient {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl QMPClient {
+    pub async fn connect(addr: impl tokio::net::ToSocketAddrs) -> Result<Self, QEMUError> {
+        let stream = tokio::time::timeout(QMP_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| QEMUError::TimeoutError("connecting to QMP monitor".to_string()))?
+            .map_err(|e| QEMUError::MonitorConnectionFailed(e.to_string()))?;
+        let (reader, writer) = stream.into_split();
+        let mut client = QMPClient {
+            reader: BufReader::new(reader),
+            writer,
+        };
+
+        let greeting = client.read_message(QMP_TIMEOUT).await?;
+        if greeting.get("QMP").is_none() {
+            return Err(QEMUError::MonitorConnectionFailed(format!(
+                "unexpected greeting: {}",
+                greeting
+            )));
+        }
+
+        // the monitor rejects every other command until capabilities are negotiated
+        client.execute_qmp("qmp_capabilities", None).await?;
+
+        Ok(client)
+    }
+
+    pub async fn execute_qmp(
+        &mut self,
+        command: &str,
+        args: Option<Value>,
+    ) -> Result<Value, QEMUError> {
+        self.execute_qmp_within(command, args, QMP_TIMEOUT).await
+    }
+
+    // an HMP command through human-monitor-command, returns what it printed
+    pub async fn human_monitor_command(
+        &mut self,
+        command_line: &str,
+        timeout: Duration,
+    ) -> Result<String, QEMUError> {
+        let output = self
+            .execute_qmp_within(
+                "human-monitor-command",
+                Some(json!({ "command-line": command_line })),
+                timeout,
+            )
+            .await?;
+        Ok(output.as_str().unwrap_or_default().to_string())
+    }
+
+    // like execute_qmp, for commands that take longer than QMP_TIMEOUT
+    async fn execute_qmp_within(
+        &mut self,
+        command: &str,
+        args: Option<Value>,
+        timeout: Duration,
+    ) -> Result<Value, QEMUError> {
+        let mut request = json!({ "execute": command });
+        if let Some(args) = args {
+            request["arguments"] = args;
+        }
+
+        debug!("QMP -> {}", request);
+        let mut line = request.to_string();
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await?;
+
+        loop {
+            let mut message = self.read_message(timeout).await?;
+            if let Some(result) = message.get_mut("return") {
+                return Ok(result.take());
+            }
+            if let Some(error) = message.get("error") {
+                return Err(QEMUError::MonitorCommandExecutionFailed(format!(
+                    "{}: {}",
+                    command,
+                    error
+                        .get("desc")
+                        .and_then(Value::as_str)
+                        .unwrap_or(&error.to_string())
+                )));
+            }
+            // asynchronous events can arrive between a command and its reply
+            if let Some(event) = message.get("event") {
+                debug!("QMP event {}", event);
+            }
+        }
+    }
+
+    async fn read_message(&mut self, timeout: Duration) -> Result<Value, QEMUError> {
+        let mut line = String::new();
+        let read = tokio::time::timeout(timeout, self.reader.read_line(&mut line))
+            .await
+            .map_err(|_| QEMUError::TimeoutError("waiting for QMP reply".to_string()))??;
+        if read == 0 {
+            return Err(QEMUError::MonitorConnectionFailed(
+                "monitor closed the connection".to_string(),
+            ));
+        }
+
+        debug!("QMP <- {}", line.trim_end());
+        serde_json::from_str(&line)
+            .map_err(|e| QEMUError::MonitorConnectionFailed(format!("invalid QMP message: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vm_config() -> VMConfig {
+        VMConfig {
+            name: "test".to_string(),
+            image_path: "debian.img".to_string(),
+            kernel_path: Some("bzImage".to_string()),
+            memory: "2G".to_string(),
+            monitor_port: 4444,
+            ssh_port: 2222,
+            kernel_append: Some("nokaslr".to_string()),
+            log_file: None,
+            cpu_count: None,
+            disk_format: DiskFormat::Raw,
+            root_device: default_root_device(),
+            console: default_console(),
+            allow_reboot: false,
+            overlay: false,
+        }
+    }
+
+    #[test]
+    fn test_kernel_cmdline() {
+        let mut config = vm_config();
+        assert_eq!(
+            config.kernel_cmdline(),
+            "root=/dev/sda console=ttyS0 nokaslr panic=-1"
+        );
+
+        config.root_device = "/dev/vda".to_string();
+        config.console = "hvc0".to_string();
+        config.kernel_append = None;
+        config.allow_reboot = true;
+        assert_eq!(config.kernel_cmdline(), "root=/dev/vda console=hvc0");
+
+        config.allow_reboot = false;
+        config.kernel_append = Some("panic=10".to_string());
+        assert_eq!(
+            config.kernel_cmdline(),
+            "root=/dev/vda console=hvc0 panic=10"
+        );
+    }
+
+    #[test]
+    fn test_validate_root_and_console() {
+        let mut config = vm_config();
+        assert!(config.validate().is_ok());
+
+        config.console = "ttyS0,115200n8".to_string();
+        assert!(config.validate().is_ok());
+
+        config.root_device = "sda".to_string();
+        assert!(config.validate().is_err());
+
+        config.root_device = "/dev/vda".to_string();
+        config.console = "tty S0".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_before_start() {
+        let mut vm = QEMUManager::new(vm_config());
+        assert!(!vm.is_running().await);
+        assert!(matches!(vm.shutdown().await, Err(QEMUError::VMNotRunning)));
+    }
+
+    #[tokio::test]
+    async fn test_execute_qmp() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer
+                .write_all(b"{\"QMP\": {\"version\": {}, \"capabilities\": []}}\n")
+                .await
+                .unwrap();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let request: Value = serde_json::from_str(&line).unwrap();
+                let reply = match request["execute"].as_str().unwrap() {
+                    "qmp_capabilities" => json!({ "return": {} }),
+                    "query-status" => {
+                        writer
+                            .write_all(b"{\"event\": \"RESUME\"}\n")
+                            .await
+                            .unwrap();
+                        json!({ "return": { "status": "running", "running": true } })
+                    }
+                    _ => {
+                        json!({ "error": { "class": "CommandNotFound", "desc": "no such command" } })
+                    }
+                };
+                writer
+                    .write_all(format!("{}\n", reply).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let mut client = QMPClient::connect(addr).await.unwrap();
+        let status = client.execute_qmp("query-status", None).await.unwrap();
+        assert_eq!(status["status"], "running");
+
+        let err = client
+            .execute_qmp("bogus", Some(json!({ "x": 1 })))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, QEMUError::MonitorCommandExecutionFailed(msg) if msg.contains("no such command"))
+        );
+
+        drop(client);
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(parse_memory_size("2G"), Some(2 << 30));
+        assert_eq!(parse_memory_size("512m"), Some(512 << 20));
+        assert_eq!(parse_memory_size("2048"), Some(2048 << 20));
+        assert_eq!(parse_memory_size("4GiB"), Some(4 << 30));
+        assert_eq!(parse_memory_size("0"), None);
+        assert_eq!(parse_memory_size("2X"), None);
+        assert_eq!(parse_memory_size("G"), None);
+    }
+
+    #[test]
+    fn test_vm_config_builder() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("debian.img");
+        std::fs::write(&image, "").unwrap();
+        let image = image.to_string_lossy().into_owned();
+
+        let config = QEMUManager::builder()
+            .name("repro")
+            .image_path(&image)
+            .memory("4G")
+            .monitor_port(4444)
+            .ssh_port(2222)
+            .build()
+            .unwrap();
+        assert_eq!(config.name, "repro");
+        assert_eq!(config.memory, "4G");
+        assert_eq!(config.root_device, "/dev/sda");
+
+        assert!(matches!(
+            QEMUManager::builder().build(),
+            Err(QEMUError::ConfigError(_))
+        ));
+        assert!(matches!(
+            QEMUManager::builder()
+                .image_path("/nonexistent/debian.img")
+                .build(),
+            Err(QEMUError::FileNotFound(_))
+        ));
+        assert!(matches!(
+            QEMUManager::builder()
+                .image_path(&image)
+                .memory("lots")
+                .build(),
+            Err(QEMUError::ConfigError(_))
+        ));
+        assert!(matches!(
+            QEMUManager::builder()
+                .image_path(&image)
+                .monitor_port(2222)
+                .ssh_port(2222)
+                .build(),
+            Err(QEMUError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_vm_profiles_from_toml() {
+        let profiles = VMConfig::profiles_from_toml(Path::new("config/vms.toml")).unwrap();
+        let names: Vec<&str> = profiles.iter().map(|vm| vm.name.as_str()).collect();
+        assert_eq!(names, vec!["boot-test", "kdump"]);
+        let kdump = VMConfig::profile(Path::new("config/vms.toml"), "kdump").unwrap();
+        assert!(kdump.kernel_cmdline().contains("crashkernel="));
+        assert!(kdump.allow_reboot && !kdump.kernel_cmdline().contains("panic=-1"));
+        assert!(VMConfig::profile(Path::new("config/vms.toml"), "missing").is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vm.toml");
+        assert!(matches!(
+            VMConfig::from_toml(&path),
+            Err(QEMUError::FileNotFound(_))
+        ));
+
+        let single = toml::to_string(&vm_config()).unwrap();
+        std::fs::write(&path, &single).unwrap();
+        assert_eq!(VMConfig::from_toml(&path).unwrap().name, "test");
+
+        std::fs::write(&path, "name = ").unwrap();
+        assert!(matches!(
+            VMConfig::from_toml(&path),
+            Err(QEMUError::Serialization(_))
+        ));
+
+        let twice = format!("[[vm]]\n{}\n[[vm]]\n{}", single, single);
+        std::fs::write(&path, twice).unwrap();
+        assert!(matches!(
+            VMConfig::profiles_from_toml(&path),
+            Err(QEMUError::ConfigError(_))
+        ));
+
+        let invalid = single.replace("memory = \"2G\"", "memory = \"lots\"");
+        std::fs::write(&path, format!("[[vm]]\n{}", invalid)).unwrap();
+        assert!(matches!(
+            VMConfig::profiles_from_toml(&path),
+            Err(QEMUError::ConfigError(_))
+        ));
+    }
+
+    #[test]
+    fn test_assign_ports() {
+        let mut config = vm_config();
+        config.ssh_port = 0;
+        config.monitor_port = 0;
+        let mut vm = QEMUManager::new(config);
+        assert_eq!(vm.ssh_port(), 0);
+
+        vm.assign_ports().unwrap();
+        assert_ne!(vm.ssh_port(), 0);
+        assert_ne!(vm.monitor_port(), 0);
+        assert_ne!(vm.ssh_port(), vm.monitor_port());
+        let args = vm.build_args().join(" ");
+        assert!(args.contains(&format!("hostfwd=tcp:127.0.0.1:{}-:22", vm.ssh_port())));
+        assert!(args.contains(&format!(
+            "tcp:127.0.0.1:{},server,nowait",
+            vm.monitor_port()
+        )));
+
+        // configured ports are kept as they are
+        let mut vm = QEMUManager::new(vm_config());
+        vm.assign_ports().unwrap();
+        assert_eq!((vm.ssh_port(), vm.monitor_port()), (2222, 4444));
+    }
+
+    #[tokio::test]
+    async fn test_console_lines() {
+        use futures::StreamExt;
+
+        let mut config = vm_config();
+        assert!(matches!(
+            QEMUManager::new(config.clone()).console_lines().await,
+            Err(QEMUError::ConfigError(_))
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("console.log");
+        config.log_file = Some(log.to_string_lossy().into_owned());
+        let vm = QEMUManager::new(config);
+        assert!(matches!(
+            vm.console_lines().await,
+            Err(QEMUError::FileNotFound(_))
+        ));
+
+        std::fs::write(
+            &log,
+            b"[    0.000000] Linux version\r\nKernel panic \xff\nno newline",
+        )
+        .unwrap();
+        let lines: Vec<String> = vm.console_lines().await.unwrap().collect().await;
+        assert_eq!(
+            lines,
+            vec![
+                "[    0.000000] Linux version",
+                "Kernel panic \u{fffd}",
+                "no newline"
+            ]
+        );
+
+        let args = vm.build_args().join(" ");
+        assert!(args.contains(&format!("-serial file:{}", log.display())));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restore() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            // one monitor connection per command
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = BufReader::new(reader).lines();
+                writer
+                    .write_all(b"{\"QMP\": {\"version\": {}, \"capabilities\": []}}\n")
+                    .await
+                    .unwrap();
+                while let Some(line) = lines.next_line().await.unwrap() {
+                    let request: Value = serde_json::from_str(&line).unwrap();
+                    let reply = match request["arguments"]["command-line"].as_str() {
+                        Some("savevm clean") => json!({ "return": "" }),
+                        Some(other) => json!({ "return": format!("Error: {} failed\r\n", other) }),
+                        None => json!({ "return": {} }),
+                    };
+                    writer
+                        .write_all(format!("{}\n", reply).as_bytes())
+                        .await
+                        .unwrap();
+                }
+            }
+        });
+
+        let mut config = vm_config();
+        config.monitor_port = port;
+        let mut vm = QEMUManager::new(config.clone());
+        assert!(matches!(
+            vm.snapshot("clean").await,
+            Err(QEMUError::ConfigError(msg)) if msg.contains("qcow2")
+        ));
+
+        config.disk_format = DiskFormat::Qcow2;
+        let mut vm = QEMUManager::new(config);
+        assert!(matches!(
+            vm.snapshot("clean").await,
+            Err(QEMUError::VMNotRunning)
+        ));
+        assert!(matches!(
+            vm.snapshot("bad name").await,
+            Err(QEMUError::ConfigError(_))
+        ));
+
+        // a stand-in for qemu so that the VM counts as running
+        let child = Command::new("sleep")
+            .arg("30")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        *vm.child.get_mut().unwrap() = Some(child);
+
+        vm.snapshot("clean").await.unwrap();
+        assert!(matches!(
+            vm.restore("missing").await,
+            Err(QEMUError::MonitorCommandExecutionFailed(msg)) if msg.contains("loadvm missing failed")
+        ));
+
+        vm.shutdown().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_watch_outcomes() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("console.log");
+        let mut config = vm_config();
+        config.log_file = Some(log.to_string_lossy().into_owned());
+        let mut vm = QEMUManager::new(config);
+        assert!(vm.build_args().contains(&"-no-reboot".to_string()));
+
+        // qemu stand-ins: gone right away, and alive but silent
+        let exited = Command::new("true").spawn().unwrap();
+        *vm.child.get_mut().unwrap() = Some(exited);
+        std::fs::write(&log, "[    1.000000] Linux version\n").unwrap();
+        assert_eq!(
+            vm.watch(Duration::from_secs(5)).await.unwrap(),
+            VmOutcome::Clean
+        );
+
+        std::fs::write(
+            &log,
+            "[    9.000000] Kernel panic - not syncing: Fatal exception\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vm.watch(Duration::from_secs(5)).await.unwrap(),
+            VmOutcome::Panicked("Kernel panic - not syncing: Fatal exception".to_string())
+        );
+
+        let silent = Command::new("sleep")
+            .arg("30")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        *vm.child.get_mut().unwrap() = Some(silent);
+        std::fs::write(&log, "").unwrap();
+        assert_eq!(
+            vm.watch(Duration::from_millis(300)).await.unwrap(),
+            VmOutcome::Hung
+        );
+        vm.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_overlay() {
+        let mut config = vm_config();
+        config.image_path = "/nonexistent/debian.img".to_string();
+        config.overlay = true;
+        let mut vm = QEMUManager::new(config);
+        assert!(matches!(
+            vm.create_overlay().await,
+            Err(QEMUError::FileNotFound(_))
+        ));
+
+        // as if start() had created it
+        let dir = tempfile::tempdir().unwrap();
+        let overlay = dir.path().join("test-0001.qcow2");
+        std::fs::write(&overlay, "").unwrap();
+        vm.overlay_path = Some(overlay.clone());
+        let args = vm.build_args().join(" ");
+        assert!(args.contains(&format!("-drive file={},format=qcow2", overlay.display())));
+
+        // the overlay turns a raw image into one that takes snapshots
+        assert!(matches!(
+            vm.snapshot("clean").await,
+            Err(QEMUError::VMNotRunning)
+        ));
+
+        assert!(matches!(vm.shutdown().await, Err(QEMUError::VMNotRunning)));
+        assert!(!overlay.exists());
+        assert!(vm.overlay_path.is_none());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::kernel::artifacts::locate_artifacts;
+use crate::kernel::compile::{BuildOptions, compile_reproducer};
+use crate::kvm::boot::boot_and_connect;
+use crate::kvm::qemu::{DiskFormat, QEMUManager, VMConfigBuilder, VmOutcome};
+use crate::kvm::ssh::SSHManager;
+use crate::parse::layout::Layout;
+use crate::parse::report::CrashReport;
+use anyhow::{Context, Result};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tracing::{info, warn};
+
+// reproducers usually fire within seconds, racy ones need minutes. far above ssh.timeout,
+// which is meant for quick commands
+const REPRO_TIMEOUT: Duration = Duration::from_secs(600);
+// the console is watched a bit longer than the reproducer runs, so that a reproducer timing
+// out in a quiet but healthy guest is not taken for a hang
+const WATCH_MARGIN: Duration = Duration::from_secs(60);
+// how long the console may stay silent once ssh lost the guest, before it counts as hung
+const VERDICT_TIMEOUT: Duration = Duration::from_secs(30);
+// command line of the guest kernel
+pub const KERNEL_APPEND: &str = "earlyprintk=serial net.ifnames=0 nokaslr";
+// where the host-built reproducer is run from inside the guest
+pub const GUEST_REPRODUCER: &str = "/root/bug";
+
+// result of running a reproducer inside the guest
+#[derive(Debug)]
+pub enum ReproOutcome {
+    // the kernel panicked, with the panic line from the console, or qemu exited
+    Crashed(String),
+    // the guest stopped answering and its console went silent
+    Hung(String),
+    // the reproducer finished (or timed out) and the guest is still alive
+    NoCrash(String),
+}
+
+impl fmt::Display for ReproOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReproOutcome::Crashed(detail) => write!(f, "crashed: {}", detail),
+            ReproOutcome::Hung(detail) => write!(f, "hung: {}", detail),
+            ReproOutcome::NoCrash(detail) => write!(f, "no crash: {}", detail),
+        }
+    }
+}
+
+// boot the already built kernel of crash `options.crash_index` and run its reproducer, no
+// build stage is touched
+pub async fn reproduce(report: &Arc<CrashReport>, options: &BuildOptions) -> Result<ReproOutcome> {
+    let vm_config = guest_vm(report, options.crash_index).await?.build()?;
+
+    // built on the host against the kernel's headers, the guest image has no toolchain to rely on
+    let binary = compile_reproducer(report, options)
+        .await
+        .with_context(|| format!("Failed to build the reproducer of report {}", report.id))?;
+
+    let ssh_config = SSHManager::builder()
+        .defaults(options.config.ssh.clone())
+        .build()?;
+    let (mut vm, ssh) = boot_and_connect(vm_config, ssh_config).await?;
+
+    let outcome = run_reproducer(&vm, ssh, &binary).await;
+
+    if let Err(e) = vm.shutdown().await {
+        warn!("Failed to shut down VM for report {}: {}", report.id, e);
+    }
+
+    outcome
+}
+
+// the VM of crash `crash_index`: its built kernel on the guest image of the mount stage
+pub async fn guest_vm(report: &CrashReport, crash_index: usize) -> Result<VMConfigBuilder> {
+    let layout = Layout::for_crash(report, crash_index)?;
+    let bz_image_path = locate_artifacts(report, crash_index)
+        .await
+        .with_context(|| format!("No built kernel for report {}", report.id))?
+        .bzimage;
+    let image_path = layout.image_dir().join("debian.img");
+
+    if !fs::try_exists(&image_path).await? {
+        anyhow::bail!(
+            "No guest image for report {} at {}, run the mount stage first",
+            report.id,
+            image_path.display()
+        );
+    }
+
+    Ok(QEMUManager::builder()
+        .name(report.id.clone())
+        .image_path(image_path.to_string_lossy())
+        .kernel_path(bz_image_path.to_string_lossy())
+        .memory("2G")
+        // picked when the VM starts, so that several reports can boot at once
+        .ssh_port(0)
+        .kernel_append(KERNEL_APPEND)
+        .log_file(
+            layout
+                .image_dir()
+                .join(format!("{}.log", report.id))
+                .to_string_lossy(),
+        )
+        .cpu_count(2)
+        .disk_format(DiskFormat::Raw)
+        .root_device("/dev/sda")
+        .console("ttyS0"))
+}
+
+// copy the static reproducer binary to GUEST_REPRODUCER, upload does not keep the mode
+pub async fn upload_reproducer(ssh: &mut SSHManager, binary: &Path) -> Result<()> {
+    ssh.upload(binary, Path::new(GUEST_REPRODUCER))
+        .await
+        .context("Failed to copy the reproducer into the guest")?;
+    ssh.execute(&format!("chmod +x {}", GUEST_REPRODUCER))
+        .await
+        .context("Failed to make the reproducer executable")?;
+    Ok(())
+}
+
+// run the reproducer while following the console, a panic there is the verdict even if the
+// ssh command has not noticed yet
+async fn run_reproducer(
+    vm: &QEMUManager,
+    mut ssh: SSHManager,
+    binary: &Path,
+) -> Result<ReproOutcome> {
+    upload_reproducer(&mut ssh, binary).await?;
+
+    info!("Running reproducer inside the guest");
+
+    let outcome = tokio::select! {
+        watched = vm.watch(REPRO_TIMEOUT + WATCH_MARGIN) => vm_outcome(watched?),
+        result = ssh.execute_timeout(GUEST_REPRODUCER, REPRO_TIMEOUT) => match result {
+            Ok(output) => {
+                ReproOutcome::NoCrash(format!("reproducer exited, output: {}", output))
+            }
+            Err(e) if ssh.is_connected().await => {
+                ReproOutcome::NoCrash(format!("reproducer failed, guest still alive: {}", e))
+            }
+            // the console tells whether the guest panicked, exited or is stuck
+            Err(e) => match vm_outcome(vm.watch(VERDICT_TIMEOUT).await?) {
+                ReproOutcome::Hung(_) => {
+                    ReproOutcome::Hung(format!("guest stopped responding: {}", e))
+                }
+                outcome => outcome,
+            },
+        },
+    };
+
+    if let ReproOutcome::NoCrash(_) = outcome {
+        ssh.disconnect().await?;
+    }
+
+    Ok(outcome)
+}
+
+fn vm_outcome(outcome: VmOutcome) -> ReproOutcome {
+    match outcome {
+        VmOutcome::Panicked(message) => ReproOutcome::Crashed(message),
+        VmOutcome::Clean => ReproOutcome::Crashed("qemu exited without a panic".to_string()),
+        VmOutcome::Hung => ReproOutcome::Hung("the console went silent".to_string()),
+    }
+}
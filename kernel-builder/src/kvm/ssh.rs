@@ -1,12 +1,20 @@
-use crate::config::config::{Config, SSHConfig};
-use openssh::KnownHosts::Strict;
-use openssh::{KnownHosts, Session, SessionBuilder};
+use crate::config::config::{AuthMethod, Config, SSHConfig};
+use crate::kvm::libssh2;
+use crate::kvm::matcher::{CrashMatcher, MatchOutcome};
+use crate::util::shell_quote;
+use openssh::{KnownHosts, Session, SessionBuilder, Stdio};
 use rand::Rng;
-use std::path::PathBuf;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{Mutex, mpsc};
+use tokio::task::JoinHandle;
 use tokio::time::sleep;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 #[derive(Error, Debug)]
 pub enum SSHError {
@@ -30,11 +38,64 @@ pub enum SSHError {
     TimeoutError(String),
     #[error("Unexpected EOF or connection closed")]
     UnexpectedEof,
+    #[error("File transfer failed: {0}")]
+    TransferFailed(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("No space left on device: {0}")]
+    NoSpace(String),
+}
+
+impl SSHError {
+    // whether retrying the same operation can succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SSHError::IO(e) => !matches!(
+                e.kind(),
+                std::io::ErrorKind::PermissionDenied | std::io::ErrorKind::NotFound
+            ),
+            SSHError::OpenSSH(_)
+            | SSHError::TimeoutError(_)
+            | SSHError::UnexpectedEof
+            | SSHError::TransferFailed(_) => true,
+            _ => false,
+        }
+    }
+
+    // map the stderr of a failed remote transfer command to an error
+    pub fn from_transfer_stderr(stderr: &str) -> SSHError {
+        if stderr.contains("Permission denied") || stderr.contains("Read-only file system") {
+            SSHError::PermissionDenied(stderr.trim().to_string())
+        } else if stderr.contains("No space left on device") {
+            SSHError::NoSpace(stderr.trim().to_string())
+        } else {
+            SSHError::TransferFailed(stderr.trim().to_string())
+        }
+    }
+}
+
+// key file and agent logins go through openssh, password logins through libssh2
+enum Transport {
+    OpenSSH(Session),
+    Libssh2(ssh2::Session),
+}
+
+// run a blocking libssh2 call on the blocking pool
+async fn blocking<T, F>(f: F) -> Result<T, SSHError>
+where
+    F: FnOnce() -> Result<T, SSHError> + Send + 'static,
+    T: Send + 'static,
+{
+    // keep the caller's span, e.g. the report being reproduced
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(f))
+        .await
+        .map_err(|e| SSHError::SessionFailed(format!("libssh2 task failed: {}", e)))?
 }
 
 pub struct SSHManager {
     config: SSHConfig,
-    session: Option<Session>,
+    session: Option<Transport>,
     connected_at: Option<Instant>,
 }
 
@@ -102,9 +163,71 @@ impl SSHManager {
         ))
     }
 
+    // run a file transfer, retrying transient failures with the same backoff as `connect`.
+    // `op` must restart the transfer from scratch, permanent errors are returned immediately.
+    pub async fn retry_transfer<T, F, Fut>(&self, what: &str, mut op: F) -> Result<T, SSHError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, SSHError>>,
+    {
+        let mut rng = rand::rng();
+        let mut backoff = self.config.initial_backoff;
+
+        for attempt in 0..self.config.max_retries {
+            match op().await {
+                Ok(value) => return Ok(value),
+                Err(e) if !e.is_transient() => {
+                    error!("{} failed permanently: {}", what, e);
+                    return Err(e);
+                }
+                Err(e) => {
+                    error!("{} attempt {} failed: {}", what, attempt + 1, e);
+
+                    if attempt + 1 == self.config.max_retries {
+                        return Err(SSHError::TransferFailed(format!(
+                            "{} failed after {} attempts: {}",
+                            what, self.config.max_retries, e
+                        )));
+                    }
+
+                    let jitter = rng.random_range(0..backoff.as_millis().max(1) as u64);
+                    let sleep_duration = backoff + Duration::from_millis(jitter);
+
+                    info!(
+                        "Retrying {} in {:?} (attempt {}/{})",
+                        what,
+                        sleep_duration,
+                        attempt + 2,
+                        self.config.max_retries
+                    );
+                    sleep(sleep_duration).await;
+
+                    backoff = std::cmp::min(backoff * 2, self.config.max_backoff);
+                }
+            }
+        }
+
+        Err(SSHError::TransferFailed(format!(
+            "{} failed: max retries reached",
+            what
+        )))
+    }
+
     async fn try_connect(&mut self) -> Result<(), SSHError> {
         let dest = format!("{}@{}", self.config.user, self.config.host);
 
+        if let AuthMethod::Password(password) = self.config.auth_method() {
+            let config = self.config.clone();
+            let session = tokio::time::timeout(
+                self.config.timeout,
+                blocking(move || libssh2::connect(&config, &password)),
+            )
+            .await
+            .map_err(|_| SSHError::TimeoutError("Connection timed out".to_string()))??;
+            self.session = Some(Transport::Libssh2(session));
+            return Ok(());
+        }
+
         let mut builder = SessionBuilder::default();
         builder
             .connect_timeout(self.config.timeout)
@@ -126,7 +249,17 @@ impl SSHManager {
 
         builder.port(self.config.port);
 
-        builder.keyfile(std::fs::canonicalize(&self.config.key_path)?);
+        match self.config.auth_method() {
+            AuthMethod::KeyFile(path) => {
+                builder.keyfile(std::fs::canonicalize(path)?);
+            }
+            AuthMethod::Agent => {
+                if let Some(sock) = std::env::var_os("SSH_AUTH_SOCK") {
+                    builder.ssh_auth_sock(sock);
+                }
+            }
+            AuthMethod::Password(_) => unreachable!("password logins use libssh2"),
+        }
 
         let session = tokio::time::timeout(self.config.timeout, builder.connect(&dest))
             .await
@@ -135,12 +268,63 @@ impl SSHManager {
                 SSHError::ConnectionFailed(format!("Failed to connect to {}: {:#?}", dest, e))
             })?;
 
-        self.session = Some(session);
+        self.session = Some(Transport::OpenSSH(session));
 
         Ok(())
     }
 
-    pub async fn execute(&self, cmd: &str) -> Result<String, SSHError> {
+    // close the current session, if any, and connect again with the same config
+    pub async fn reconnect(&mut self) -> Result<(), SSHError> {
+        if let Err(e) = self.disconnect().await {
+            warn!("Failed to close the old session before reconnecting: {}", e);
+        }
+        self.connect().await
+    }
+
+    // run `cmd` and return its stdout, a nonzero exit status is an error.
+    // with ssh.auto_reconnect a dropped session is re-established and the command retried once
+    pub async fn execute(&mut self, cmd: &str) -> Result<String, SSHError> {
+        self.execute_timeout(cmd, self.config.timeout).await
+    }
+
+    // like `execute`, with `timeout` instead of ssh.timeout, e.g. for a reproducer that runs for
+    // minutes. a retry after a reconnect gets the full timeout again
+    pub async fn execute_timeout(
+        &mut self,
+        cmd: &str,
+        timeout: Duration,
+    ) -> Result<String, SSHError> {
+        let output = match self.execute_with_status_timeout(cmd, timeout).await {
+            Err(SSHError::UnexpectedEof) if self.config.auto_reconnect => {
+                warn!("SSH session dropped while running {:?}, reconnecting", cmd);
+                self.reconnect().await?;
+                self.execute_with_status_timeout(cmd, timeout).await?
+            }
+            result => result?,
+        };
+
+        if !output.success() {
+            return Err(SSHError::CommandExecutionFailed(format!(
+                "Command failed with exit code: {:?}, stderr: {}",
+                output.exit_code, output.stderr
+            )));
+        }
+
+        Ok(output.stdout)
+    }
+
+    // run `cmd` and return its output whatever the exit status, for commands such as grep
+    // where a nonzero status is an answer rather than a failure
+    pub async fn execute_with_status(&self, cmd: &str) -> Result<CommandOutput, SSHError> {
+        self.execute_with_status_timeout(cmd, self.config.timeout)
+            .await
+    }
+
+    pub async fn execute_with_status_timeout(
+        &self,
+        cmd: &str,
+        timeout: Duration,
+    ) -> Result<CommandOutput, SSHError> {
         let session = self
             .session
             .as_ref()
@@ -148,37 +332,255 @@ impl SSHManager {
 
         debug!("Executing command: {}", cmd);
 
-        let output = tokio::time::timeout(
-            self.config.timeout,
-            session.command("bash").arg("-lc").arg(cmd).output(),
-        )
-        .await
-        .map_err(|_| SSHError::TimeoutError("Command execution timed out".to_string()))?
-        .map_err(|e| {
-            SSHError::CommandExecutionFailed(format!("Failed to execute command: {:#?}", e))
-        })?;
+        let output = match session {
+            Transport::OpenSSH(session) => {
+                let output = tokio::time::timeout(
+                    timeout,
+                    session.command("bash").arg("-lc").arg(cmd).output(),
+                )
+                .await
+                .map_err(|_| timed_out(timeout))?
+                .map_err(|e| match e {
+                    openssh::Error::Disconnected | openssh::Error::RemoteProcessTerminated => {
+                        SSHError::UnexpectedEof
+                    }
+                    e => SSHError::CommandExecutionFailed(format!(
+                        "Failed to execute command: {:#?}",
+                        e
+                    )),
+                })?;
+
+                CommandOutput {
+                    stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+                    stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+                    exit_code: output.status.code(),
+                }
+            }
+            Transport::Libssh2(session) => {
+                let session = session.clone();
+                let cmd = cmd.to_string();
+                blocking(move || libssh2::exec(&session, &cmd, timeout)).await?
+            }
+        };
+
+        info!("Command executed. Exit status: {:?}", output.exit_code);
+        debug!("Command output: {}", output.stdout);
+
+        if !output.stderr.is_empty() {
+            debug!("Command error output: {}", output.stderr);
+        }
+
+        Ok(output)
+    }
+
+    // like `execute`, but hands every stdout line to `on_line` as soon as it arrives.
+    // there is no timeout, the command runs until it exits or the connection drops.
+    pub async fn execute_streaming(
+        &self,
+        cmd: &str,
+        mut on_line: impl FnMut(&str),
+    ) -> Result<(), SSHError> {
+        let session = self
+            .session
+            .as_ref()
+            .ok_or(SSHError::ClientNotInitialized)?;
+
+        debug!("Executing streaming command: {}", cmd);
 
-        let stdout = String::from_utf8_lossy(&output.stdout).to_string();
-        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        let (exit_code, stderr) = match session {
+            Transport::OpenSSH(session) => stream_openssh(session, cmd, &mut on_line).await?,
+            Transport::Libssh2(session) => {
+                let (tx, mut rx) = mpsc::unbounded_channel();
+                let session = session.clone();
+                let cmd = cmd.to_string();
+                let task = blocking(move || libssh2::exec_streaming(&session, &cmd, tx));
+                tokio::pin!(task);
+
+                // lines are handed over as they arrive, the task finishes once the channel closes
+                let output = loop {
+                    tokio::select! {
+                        Some(line) = rx.recv() => on_line(&line),
+                        output = &mut task => break output?,
+                    }
+                };
+                while let Ok(line) = rx.try_recv() {
+                    on_line(&line);
+                }
+                (output.exit_code, output.stderr)
+            }
+        };
 
-        info!("Command executed. Exit status: {:?}", output.status.code());
-        debug!("Command output: {}", stdout);
+        info!("Command executed. Exit status: {:?}", exit_code);
 
         if !stderr.is_empty() {
             error!("Command error output: {}", stderr);
         }
 
-        if !output.status.success() {
+        if exit_code != Some(0) {
             return Err(SSHError::CommandExecutionFailed(format!(
-                "Command failed with status: {:?}, stderr: {}",
-                output.status, stderr
+                "Command failed with exit code: {:?}, stderr: {}",
+                exit_code, stderr
             )));
         }
 
-        Ok(stdout)
+        Ok(())
+    }
+
+    // run `cmd` in the background on the guest and follow the kernel log until `matcher`
+    // recognises a crash or its timeout expires. only messages printed after the start are
+    // looked at. a connection that drops without a readable report is returned as the error,
+    // the guest may have died before the oops made it out
+    pub async fn execute_matching(
+        &self,
+        cmd: &str,
+        matcher: CrashMatcher,
+    ) -> Result<MatchOutcome, SSHError> {
+        let command = format!(
+            "n=$(dmesg | wc -l); ({}) </dev/null >/dev/null 2>&1 & dmesg -w | tail -n +$((n + 1))",
+            cmd
+        );
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let lines = futures::stream::poll_fn(move |cx| rx.poll_recv(cx));
+
+        let scan = matcher.scan(lines);
+        let run = self.execute_streaming(&command, move |line| {
+            let _ = tx.send(line.to_string());
+        });
+        tokio::pin!(scan);
+        tokio::pin!(run);
+
+        // the sender goes away with `run`, so the scan sees the end of the output
+        let result = tokio::select! {
+            outcome = &mut scan => return Ok(outcome),
+            result = &mut run => result,
+        };
+        match (scan.await, result) {
+            (MatchOutcome::NoCrash, Err(e)) => Err(e),
+            (outcome, _) => Ok(outcome),
+        }
+    }
+
+    // stream a local file to the guest through `cat`, without reading it into memory
+    pub async fn upload(&self, local: &Path, remote: &Path) -> Result<(), SSHError> {
+        let session = self
+            .session
+            .as_ref()
+            .ok_or(SSHError::ClientNotInitialized)?;
+        let remote = remote_path(remote)?;
+
+        info!("Uploading {} to {}", local.display(), remote);
+
+        self.retry_transfer("upload", || async {
+            let copied = match session {
+                Transport::OpenSSH(session) => upload_openssh(session, local, remote).await?,
+                Transport::Libssh2(session) => {
+                    let session = session.clone();
+                    let local = local.to_path_buf();
+                    let remote = remote.to_string();
+                    blocking(move || libssh2::upload(&session, &local, &remote)).await?
+                }
+            };
+            debug!("Uploaded {} bytes to {}", copied, remote);
+            Ok(())
+        })
+        .await
+    }
+
+    // stream a guest file to `local`, which is only replaced once the whole file arrived
+    pub async fn download(&self, remote: &Path, local: &Path) -> Result<(), SSHError> {
+        let session = self
+            .session
+            .as_ref()
+            .ok_or(SSHError::ClientNotInitialized)?;
+        let remote = remote_path(remote)?;
+        let mut partial = local.as_os_str().to_owned();
+        partial.push(".part");
+        let partial = PathBuf::from(partial);
+
+        info!("Downloading {} to {}", remote, local.display());
+
+        self.retry_transfer("download", || async {
+            let copied = match session {
+                Transport::OpenSSH(session) => download_openssh(session, remote, &partial).await?,
+                Transport::Libssh2(session) => {
+                    let session = session.clone();
+                    let remote = remote.to_string();
+                    let partial = partial.clone();
+                    blocking(move || libssh2::download(&session, &remote, &partial)).await?
+                }
+            };
+            tokio::fs::rename(&partial, local).await?;
+            debug!("Downloaded {} bytes from {}", copied, remote);
+            Ok(())
+        })
+        .await
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&partial);
+        })
     }
 
-    pub async fn execute_batch(&self, commands: &[&str]) -> Result<Vec<String>, SSHError> {
+    // copy a local script to a temporary file on the guest and run it with `args` for at most
+    // `timeout`, returning its output whatever the exit status. the copy is removed again, also
+    // when the run fails
+    pub async fn execute_script(
+        &self,
+        local_script: &Path,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<CommandOutput, SSHError> {
+        if !local_script.is_file() {
+            return Err(SSHError::IO(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("script {} not found", local_script.display()),
+            )));
+        }
+
+        let temp = self
+            .execute_with_status("mktemp /tmp/kb-script.XXXXXX")
+            .await?;
+        if !temp.success() {
+            return Err(SSHError::CommandExecutionFailed(format!(
+                "Failed to create a temporary file on the guest: {}",
+                temp.stderr.trim()
+            )));
+        }
+        let remote = PathBuf::from(temp.stdout.trim());
+
+        info!(
+            "Running script {} as {}",
+            local_script.display(),
+            remote.display()
+        );
+        let result = match self.upload(local_script, &remote).await {
+            Ok(()) => {
+                self.execute_with_status_timeout(
+                    &script_command(&remote.to_string_lossy(), args),
+                    timeout,
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+
+        let cleanup = format!("rm -f {}", shell_quote(&remote.to_string_lossy()));
+        match self.execute_with_status(&cleanup).await {
+            Ok(output) if output.success() => {}
+            Ok(output) => warn!(
+                "Failed to remove {} from the guest: {}",
+                remote.display(),
+                output.stderr.trim()
+            ),
+            Err(e) => warn!(
+                "Failed to remove {} from the guest: {}",
+                remote.display(),
+                e
+            ),
+        }
+
+        result
+    }
+
+    pub async fn execute_batch(&mut self, commands: &[&str]) -> Result<Vec<String>, SSHError> {
         let mut results = Vec::new();
 
         for (i, cmd) in commands.iter().enumerate() {
@@ -196,18 +598,24 @@ impl SSHManager {
     }
 
     pub async fn is_connected(&self) -> bool {
-        if let Some(session) = &self.session {
-            match tokio::time::timeout(
-                Duration::from_secs(5),
-                session.command("echo test").output(),
-            )
-            .await
-            {
-                Ok(Ok(output)) => output.status.success(),
-                _ => false,
+        match &self.session {
+            Some(Transport::OpenSSH(session)) => matches!(
+                tokio::time::timeout(
+                    Duration::from_secs(5),
+                    session.command("echo test").output(),
+                )
+                .await,
+                Ok(Ok(output)) if output.status.success()
+            ),
+            Some(Transport::Libssh2(session)) => {
+                let session = session.clone();
+                matches!(
+                    blocking(move || libssh2::exec(&session, "echo test", Duration::from_secs(5)))
+                        .await,
+                    Ok(output) if output.success()
+                )
             }
-        } else {
-            false
+            None => false,
         }
     }
 
@@ -220,18 +628,152 @@ impl SSHManager {
         })
     }
     pub async fn disconnect(&mut self) -> Result<(), SSHError> {
-        if let Some(session) = self.session.take() {
-            info!("Disconnecting SSH session");
-            session
-                .close()
-                .await
-                .map_err(|e| SSHError::SessionFailed(format!("Failed to close session: {}", e)))?;
+        match self.session.take() {
+            Some(Transport::OpenSSH(session)) => {
+                info!("Disconnecting SSH session");
+                session.close().await.map_err(|e| {
+                    SSHError::SessionFailed(format!("Failed to close session: {}", e))
+                })?;
+            }
+            Some(Transport::Libssh2(session)) => {
+                info!("Disconnecting SSH session");
+                blocking(move || libssh2::close(&session)).await?;
+            }
+            None => {}
         }
         self.connected_at = None;
         Ok(())
     }
 }
 
+// stderr is drained alongside stdout so a chatty command can't block on a full pipe
+async fn stream_openssh(
+    session: &Session,
+    cmd: &str,
+    on_line: &mut impl FnMut(&str),
+) -> Result<(Option<i32>, String), SSHError> {
+    let mut child = session
+        .command("bash")
+        .arg("-lc")
+        .arg(cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .await
+        .map_err(|e| {
+            SSHError::CommandExecutionFailed(format!("Failed to execute command: {:#?}", e))
+        })?;
+
+    let stdout = child.stdout().take().ok_or(SSHError::UnexpectedEof)?;
+    let mut stderr = child.stderr().take().ok_or(SSHError::UnexpectedEof)?;
+
+    let read_stdout = async {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Some(line) = lines.next_line().await? {
+            on_line(&line);
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    let read_stderr = async {
+        let mut buf = String::new();
+        stderr.read_to_string(&mut buf).await.map(|_| buf)
+    };
+    let (stdout_result, stderr_result) = tokio::join!(read_stdout, read_stderr);
+    stdout_result?;
+    let stderr = stderr_result?;
+
+    let status = child.wait().await?;
+    Ok((status.code(), stderr))
+}
+
+async fn upload_openssh(session: &Session, local: &Path, remote: &str) -> Result<u64, SSHError> {
+    let mut file = File::open(local).await?;
+    let mut child = session
+        .command("cat")
+        .raw_arg(">")
+        .arg(remote)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .await?;
+
+    let mut stdin = child.stdin().take().ok_or(SSHError::UnexpectedEof)?;
+    let copied = tokio::io::copy(&mut file, &mut stdin).await;
+    // closing stdin is what lets the remote cat exit
+    drop(stdin);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(SSHError::from_transfer_stderr(&String::from_utf8_lossy(
+            &output.stderr,
+        )));
+    }
+    Ok(copied?)
+}
+
+async fn download_openssh(session: &Session, remote: &str, local: &Path) -> Result<u64, SSHError> {
+    let mut file = File::create(local).await?;
+    let mut child = session
+        .command("cat")
+        .arg(remote)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .await?;
+
+    let mut stdout = child.stdout().take().ok_or(SSHError::UnexpectedEof)?;
+    let copied = tokio::io::copy(&mut stdout, &mut file).await;
+    drop(stdout);
+
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(SSHError::from_transfer_stderr(&String::from_utf8_lossy(
+            &output.stderr,
+        )));
+    }
+    let copied = copied?;
+    file.flush().await?;
+    Ok(copied)
+}
+
+fn timed_out(timeout: Duration) -> SSHError {
+    SSHError::TimeoutError(format!("Command execution timed out after {:?}", timeout))
+}
+
+// make the uploaded script executable and run it, every argument quoted
+fn script_command(remote: &str, args: &[&str]) -> String {
+    let mut command = format!("chmod +x {0} && {0}", shell_quote(remote));
+    for arg in args {
+        command.push(' ');
+        command.push_str(&shell_quote(arg));
+    }
+    command
+}
+
+// openssh escapes arguments as str, so remote paths have to be valid utf-8
+fn remote_path(path: &Path) -> Result<&str, SSHError> {
+    path.to_str().ok_or_else(|| {
+        SSHError::TransferFailed(format!("remote path {} is not valid UTF-8", path.display()))
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub stdout: String,
+    pub stderr: String,
+    // None when the remote command was killed by a signal
+    pub exit_code: Option<i32>,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ConnectionInfo {
     pub host: String,
@@ -246,6 +788,7 @@ pub struct SSHConfigBuilder {
     port: Option<u16>,
     user: Option<String>,
     key_path: Option<PathBuf>,
+    auth: Option<AuthMethod>,
     timeout: Option<Duration>,
     max_retries: Option<usize>,
     initial_backoff: Option<Duration>,
@@ -253,9 +796,16 @@ pub struct SSHConfigBuilder {
     compression: Option<bool>,
     strict_host_key_checking: Option<bool>,
     keep_alive_interval: Option<Duration>,
+    auto_reconnect: Option<bool>,
+    defaults: Option<SSHConfig>,
 }
 
 impl SSHConfigBuilder {
+    // where the fields that are not set come from, the hardcoded defaults otherwise
+    pub fn defaults(mut self, config: SSHConfig) -> Self {
+        self.defaults = Some(config);
+        self
+    }
     pub fn host<S: Into<String>>(mut self, host: S) -> Self {
         self.host = Some(host.into());
         self
@@ -272,6 +822,10 @@ impl SSHConfigBuilder {
         self.key_path = Some(path.into());
         self
     }
+    pub fn auth(mut self, auth: AuthMethod) -> Self {
+        self.auth = Some(auth);
+        self
+    }
     pub fn timeout(mut self, timeout: Duration) -> Self {
         self.timeout = Some(timeout);
         self
@@ -297,13 +851,18 @@ impl SSHConfigBuilder {
         self.keep_alive_interval = Some(interval);
         self
     }
+    pub fn auto_reconnect(mut self, enable: bool) -> Self {
+        self.auto_reconnect = Some(enable);
+        self
+    }
     pub fn build(self) -> Result<SSHConfig, SSHError> {
-        let default = Config::default().ssh.clone();
+        let default = self.defaults.unwrap_or_else(|| Config::default().ssh);
         let config = SSHConfig {
             host: self.host.unwrap_or(default.host),
             port: self.port.unwrap_or(default.port),
             user: self.user.unwrap_or(default.user),
             key_path: self.key_path.unwrap_or(default.key_path),
+            auth: self.auth.or(default.auth),
             timeout: self.timeout.unwrap_or(default.timeout),
             max_retries: self.max_retries.unwrap_or(default.max_retries),
             initial_backoff: self.initial_backoff.unwrap_or(default.initial_backoff),
@@ -313,6 +872,7 @@ impl SSHConfigBuilder {
                 .strict_host_key_checking
                 .unwrap_or(default.strict_host_key_checking),
             keep_alive_interval: self.keep_alive_interval.or(default.keep_alive_interval),
+            auto_reconnect: self.auto_reconnect.unwrap_or(default.auto_reconnect),
         };
 
         config.validate()?;
@@ -320,40 +880,131 @@ impl SSHConfigBuilder {
     }
 }
 
+// what the pool does when it is full and a new key is requested
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    // fail the request
+    Reject,
+    // disconnect the connection that was handed out longest ago
+    #[default]
+    Lru,
+}
+
 pub struct SSHConnectionPool {
     connections: std::collections::HashMap<String, SSHManager>,
+    last_used: std::collections::HashMap<String, Instant>,
     max_connections: usize,
+    eviction_policy: EvictionPolicy,
 }
 
 impl SSHConnectionPool {
     pub fn new(max_connections: usize) -> Self {
         SSHConnectionPool {
             connections: std::collections::HashMap::new(),
+            last_used: std::collections::HashMap::new(),
             max_connections,
+            eviction_policy: EvictionPolicy::default(),
         }
     }
 
+    pub fn set_eviction_policy(&mut self, policy: EvictionPolicy) {
+        self.eviction_policy = policy;
+    }
+
     pub async fn get_or_create_connection(
         &mut self,
         key: String,
         config: SSHConfig,
     ) -> Result<&mut SSHManager, SSHError> {
+        // a VM reboot or a dropped network leaves a session that looks fine until used
+        if let Some(connection) = self.connections.get(&key)
+            && !connection.is_connected().await
+        {
+            warn!("Connection {} failed the liveness probe, reconnecting", key);
+            self.remove_connection(&key).await.ok();
+        }
+
         if !self.connections.contains_key(&key) {
-            if self.connections.len() >= self.max_connections {
-                return Err(SSHError::ConnectionFailed(
-                    "Maximum number of connections reached".to_string(),
-                ));
-            }
+            self.make_room().await?;
 
             let mut manager = SSHManager::new(config)?;
             manager.connect().await?;
             self.connections.insert(key.clone(), manager);
         }
 
+        self.last_used.insert(key.clone(), Instant::now());
         Ok(self.connections.get_mut(&key).unwrap())
     }
 
+    // free a slot for a new connection according to the eviction policy
+    async fn make_room(&mut self) -> Result<(), SSHError> {
+        while self.connections.len() >= self.max_connections {
+            let victim = match self.eviction_policy {
+                EvictionPolicy::Reject => None,
+                EvictionPolicy::Lru => self.least_recently_used(),
+            };
+            let Some(key) = victim else {
+                return Err(SSHError::ConnectionFailed(
+                    "Maximum number of connections reached".to_string(),
+                ));
+            };
+
+            info!("Evicting least recently used SSH connection {}", key);
+            if let Err(e) = self.remove_connection(&key).await {
+                debug!("Error closing evicted connection {}: {}", key, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn least_recently_used(&self) -> Option<String> {
+        self.connections
+            .keys()
+            .min_by_key(|key| self.last_used.get(*key))
+            .cloned()
+    }
+
+    // drop every connection that no longer answers, returning how many were removed
+    pub async fn prune_dead(&mut self) -> usize {
+        let mut dead = Vec::new();
+        for (key, connection) in &self.connections {
+            if !connection.is_connected().await {
+                dead.push(key.clone());
+            }
+        }
+
+        for key in &dead {
+            info!("Pruning dead SSH connection {}", key);
+            if let Err(e) = self.remove_connection(key).await {
+                debug!("Error closing dead connection {}: {}", key, e);
+            }
+        }
+
+        dead.len()
+    }
+
+    // run prune_dead every `interval` (usually ssh.keep_alive_interval) until the pool is dropped
+    pub fn spawn_health_check(pool: &Arc<Mutex<Self>>, interval: Duration) -> JoinHandle<()> {
+        let pool = Arc::downgrade(pool);
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // the first tick completes immediately
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let Some(pool) = pool.upgrade() else {
+                    break;
+                };
+                let pruned = pool.lock().await.prune_dead().await;
+                if pruned > 0 {
+                    info!("Health check removed {} dead SSH connections", pruned);
+                }
+            }
+        })
+    }
+
     pub async fn remove_connection(&mut self, key: &str) -> Result<(), SSHError> {
+        self.last_used.remove(key);
         if let Some(mut connection) = self.connections.remove(key) {
             connection.disconnect().await?;
         }
@@ -361,6 +1012,7 @@ impl SSHConnectionPool {
     }
 
     pub async fn close_all(&mut self) -> Result<(), SSHError> {
+        self.last_used.clear();
         for (_, mut connection) in self.connections.drain() {
             if let Err(e) = connection.disconnect().await {
                 error!("Error closing connection: {}", e);
@@ -369,3 +1021,193 @@ impl SSHConnectionPool {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use once_cell::sync::Lazy;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // validate insists on an existing private key, tempfile creates it with mode 0600
+    static KEY: Lazy<tempfile::NamedTempFile> =
+        Lazy::new(|| tempfile::NamedTempFile::new().unwrap());
+
+    fn manager() -> SSHManager {
+        let config = SSHManager::builder()
+            .host("127.0.0.1")
+            .key_path(KEY.path())
+            .max_retries(3)
+            .backoff(Duration::from_millis(1), Duration::from_millis(2))
+            .build()
+            .unwrap();
+        SSHManager::new(config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retry_transfer_retries_transient_errors() {
+        let ssh = manager();
+        let attempts = AtomicUsize::new(0);
+
+        let result = ssh
+            .retry_transfer("upload", || async {
+                if attempts.fetch_add(1, Ordering::SeqCst) < 2 {
+                    Err(SSHError::UnexpectedEof)
+                } else {
+                    Ok(())
+                }
+            })
+            .await;
+
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retry_transfer_stops_on_permanent_errors() {
+        let ssh = manager();
+        let attempts = AtomicUsize::new(0);
+
+        let result: Result<(), SSHError> = ssh
+            .retry_transfer("upload", || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(SSHError::from_transfer_stderr(
+                    "cat: /root/bug: No space left on device",
+                ))
+            })
+            .await;
+
+        assert!(matches!(result, Err(SSHError::NoSpace(_))));
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_requires_connection() {
+        let ssh = manager();
+        let local = Path::new("/tmp/bzImage");
+        let remote = Path::new("/root/bzImage");
+
+        assert!(matches!(
+            ssh.upload(local, remote).await,
+            Err(SSHError::ClientNotInitialized)
+        ));
+        assert!(matches!(
+            ssh.download(remote, local).await,
+            Err(SSHError::ClientNotInitialized)
+        ));
+        assert!(matches!(
+            ssh.execute_streaming("dmesg -w", |_| {}).await,
+            Err(SSHError::ClientNotInitialized)
+        ));
+        assert!(matches!(
+            ssh.execute_with_status_timeout("uname -r", Duration::from_secs(1))
+                .await,
+            Err(SSHError::ClientNotInitialized)
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("repro.sh");
+        assert!(matches!(
+            ssh.execute_script(&script, &[], Duration::from_secs(1))
+                .await,
+            Err(SSHError::IO(_))
+        ));
+        std::fs::write(&script, "#!/bin/sh\n").unwrap();
+        assert!(matches!(
+            ssh.execute_script(&script, &[], Duration::from_secs(1))
+                .await,
+            Err(SSHError::ClientNotInitialized)
+        ));
+    }
+
+    #[test]
+    fn test_script_command() {
+        assert_eq!(
+            script_command("/tmp/kb-script.abc123", &["-n", "3", "it's"]),
+            "chmod +x '/tmp/kb-script.abc123' && '/tmp/kb-script.abc123' '-n' '3' 'it'\\''s'"
+        );
+    }
+
+    #[test]
+    fn test_command_output_success() {
+        let output = |exit_code| CommandOutput {
+            stdout: String::new(),
+            stderr: String::new(),
+            exit_code,
+        };
+        assert!(output(Some(0)).success());
+        assert!(!output(Some(1)).success());
+        assert!(!output(None).success());
+    }
+
+    #[tokio::test]
+    async fn test_prune_dead() {
+        let mut pool = SSHConnectionPool::new(2);
+        pool.connections.insert("vm".to_string(), manager());
+
+        assert_eq!(pool.prune_dead().await, 1);
+        assert!(pool.connections.is_empty());
+        assert_eq!(pool.prune_dead().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_health_check_stops_with_pool() {
+        let pool = Arc::new(Mutex::new(SSHConnectionPool::new(2)));
+        pool.lock()
+            .await
+            .connections
+            .insert("vm".to_string(), manager());
+
+        let handle = SSHConnectionPool::spawn_health_check(&pool, Duration::from_millis(5));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(pool.lock().await.connections.is_empty());
+
+        drop(pool);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lru_eviction() {
+        let mut pool = SSHConnectionPool::new(2);
+        let now = Instant::now();
+        for (key, age) in [("old", 10), ("new", 1)] {
+            pool.connections.insert(key.to_string(), manager());
+            pool.last_used
+                .insert(key.to_string(), now - Duration::from_secs(age));
+        }
+
+        pool.set_eviction_policy(EvictionPolicy::Reject);
+        assert!(matches!(
+            pool.make_room().await,
+            Err(SSHError::ConnectionFailed(_))
+        ));
+        assert_eq!(pool.connections.len(), 2);
+
+        pool.set_eviction_policy(EvictionPolicy::Lru);
+        pool.make_room().await.unwrap();
+        assert!(pool.connections.contains_key("new"));
+        assert!(!pool.connections.contains_key("old"));
+        assert!(!pool.last_used.contains_key("old"));
+    }
+
+    #[tokio::test]
+    async fn test_execute_without_session() {
+        let mut ssh = SSHManager::new(
+            SSHManager::builder()
+                .host("127.0.0.1")
+                .key_path(KEY.path())
+                .auto_reconnect(true)
+                .build()
+                .unwrap(),
+        )
+        .unwrap();
+
+        // only a dropped session triggers a reconnect, a missing one is a caller error
+        assert!(matches!(
+            ssh.execute("true").await,
+            Err(SSHError::ClientNotInitialized)
+        ));
+    }
+}
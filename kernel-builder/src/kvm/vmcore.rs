@@ -0,0 +1,297 @@
+use crate::kernel::artifacts::locate_artifacts;
+use crate::parse::layout::Layout;
+use crate::parse::report::CrashReport;
+use crate::script::tool::{find_tool, require_tool};
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::fs;
+use tokio::process::Command;
+use tracing::{info, warn};
+
+pub const CRASH_TOOL: &str = "crash";
+
+// crash reads the whole debug info of vmlinux first, which takes a while for a kasan kernel
+const ANALYSIS_TIMEOUT: Duration = Duration::from_secs(600);
+
+// what the crash utility makes of a vmcore
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmcoreAnalysis {
+    // the panic message from `sys`, e.g. `Kernel panic - not syncing: Fatal exception`
+    pub panic: Option<String>,
+    // functions of the panicking task's stack from `bt`, innermost first
+    pub backtrace: Vec<String>,
+    // the instruction pointer of the exception frame, e.g. `hci_conn_drop+42`. None when the
+    // kernel panicked without an exception, as after a KASAN report
+    pub faulting_ip: Option<String>,
+    // `dis -l` of faulting_ip: the source line and the instruction
+    pub faulting_instruction: Option<String>,
+    // the kernel log buffer
+    pub dmesg: String,
+    // where the raw command output was left, next to the vmcore
+    pub output_dir: PathBuf,
+}
+
+// run `crash` in batch mode on `vmcore` and the `vmlinux` it was taken from, the raw output of
+// every command is kept in <vmcore>-analysis/
+pub async fn analyze_vmcore(vmcore: &Path, vmlinux: &Path) -> Result<VmcoreAnalysis> {
+    for (what, path) in [("vmcore", vmcore), ("vmlinux", vmlinux)] {
+        if !fs::try_exists(path).await? {
+            anyhow::bail!("No {} at {}", what, path.display());
+        }
+    }
+    let tool = require_tool(CRASH_TOOL)
+        .context("vmcore analysis needs the crash utility, e.g. `apt install crash`")?;
+
+    let mut output_dir = vmcore.as_os_str().to_owned();
+    output_dir.push("-analysis");
+    let output_dir = PathBuf::from(output_dir);
+    fs::create_dir_all(&output_dir)
+        .await
+        .with_context(|| format!("Failed to create {}", output_dir.display()))?;
+
+    info!(
+        "Analyzing {} with {} and {}",
+        vmcore.display(),
+        CRASH_TOOL,
+        vmlinux.display()
+    );
+    let crash = CrashSession {
+        tool,
+        vmcore,
+        vmlinux,
+        output_dir: &output_dir,
+    };
+    crash
+        .run(&[("sys", "sys.txt"), ("bt", "bt.txt"), ("log", "dmesg.txt")])
+        .await?;
+
+    let sys = crash.read("sys.txt").await?;
+    let bt = crash.read("bt.txt").await?;
+    let (backtrace, faulting_ip) = parse_backtrace(&bt);
+
+    // a second session, the address is only known from the first one's bt
+    let faulting_instruction = match &faulting_ip {
+        Some(ip) => {
+            crash
+                .run(&[(&format!("dis -l {} 1", ip), "dis.txt")])
+                .await?;
+            parse_disassembly(&crash.read("dis.txt").await?)
+        }
+        None => None,
+    };
+
+    let analysis = VmcoreAnalysis {
+        panic: parse_panic(&sys),
+        backtrace,
+        faulting_ip,
+        faulting_instruction,
+        dmesg: crash.read("dmesg.txt").await?,
+        output_dir: output_dir.clone(),
+    };
+    info!(
+        "vmcore analysis: panic {:?}, {} frames, faulting at {:?}",
+        analysis.panic,
+        analysis.backtrace.len(),
+        analysis.faulting_ip
+    );
+    Ok(analysis)
+}
+
+// analyze the vmcore fetched for crash `crash_index` against the vmlinux of its build. crash is
+// an optional tool, without it the dump is kept but None is returned
+pub async fn analyze_crash_vmcore(
+    report: &CrashReport,
+    crash_index: usize,
+) -> Result<Option<VmcoreAnalysis>> {
+    if find_tool(CRASH_TOOL).is_none() {
+        warn!(
+            "{} is not installed, skipping the analysis of the vmcore of report {}",
+            CRASH_TOOL, report.id
+        );
+        return Ok(None);
+    }
+    let vmcore = Layout::for_crash(report, crash_index)?.vmcore_path();
+    let vmlinux = locate_artifacts(report, crash_index)
+        .await
+        .with_context(|| format!("No built kernel for report {}", report.id))?
+        .vmlinux;
+    analyze_vmcore(&vmcore, &vmlinux).await.map(Some)
+}
+
+struct CrashSession<'a> {
+    tool: PathBuf,
+    vmcore: &'a Path,
+    vmlinux: &'a Path,
+    output_dir: &'a Path,
+}
+
+impl CrashSession<'_> {
+    // each command's output goes to its file in output_dir through crash's own `>` redirection,
+    // so that the banner and prompts stay out of it
+    async fn run(&self, commands: &[(&str, &str)]) -> Result<()> {
+        let mut script = String::new();
+        for (command, file) in commands {
+            script.push_str(&format!(
+                "{} > {}\n",
+                command,
+                self.output_dir.join(file).display()
+            ));
+        }
+        script.push_str("quit\n");
+        let script_path = self.output_dir.join("commands");
+        fs::write(&script_path, script).await?;
+
+        let output = tokio::time::timeout(
+            ANALYSIS_TIMEOUT,
+            Command::new(&self.tool)
+                .arg("-s")
+                .arg(self.vmlinux)
+                .arg(self.vmcore)
+                .arg("-i")
+                .arg(&script_path)
+                .stdin(Stdio::null())
+                .kill_on_drop(true)
+                .output(),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("{} timed out after {:?}", CRASH_TOOL, ANALYSIS_TIMEOUT))?
+        .with_context(|| format!("Failed to run {}", self.tool.display()))?;
+
+        if !output.status.success() {
+            anyhow::bail!(
+                "{} failed with {}: {}",
+                CRASH_TOOL,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    async fn read(&self, file: &str) -> Result<String> {
+        let path = self.output_dir.join(file);
+        fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("{} left no {}", CRASH_TOOL, path.display()))
+    }
+}
+
+// `       PANIC: "Kernel panic - not syncing: Fatal exception"` of `sys`
+fn parse_panic(sys: &str) -> Option<String> {
+    sys.lines().find_map(|line| {
+        let value = line.trim().strip_prefix("PANIC:")?.trim().trim_matches('"');
+        (!value.is_empty()).then(|| value.to_string())
+    })
+}
+
+// frames ` #3 [ffffc90000a3fb58] oops_end at ffffffff81029c36` and the exception frame's
+// `[exception RIP: hci_conn_drop+42]` of `bt`
+fn parse_backtrace(bt: &str) -> (Vec<String>, Option<String>) {
+    static FRAME: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"^\s*#\d+\s+\[[0-9a-f]+\]\s+(?P<func>\S+)").unwrap());
+    static EXCEPTION: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"\[exception RIP: (?P<ip>[^\]\s]+)\]").unwrap());
+
+    let frames = bt
+        .lines()
+        .filter_map(|line| FRAME.captures(line))
+        .map(|captures| captures["func"].to_string())
+        .collect();
+    let faulting_ip = bt
+        .lines()
+        .find_map(|line| EXCEPTION.captures(line))
+        .map(|captures| captures["ip"].to_string());
+    (frames, faulting_ip)
+}
+
+// `dis -l` prints the source line followed by the instruction, kept as one line
+fn parse_disassembly(dis: &str) -> Option<String> {
+    let lines: Vec<&str> = dis
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect();
+    (!lines.is_empty()).then(|| lines.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BT: &str = "\
+PID: 3601     TASK: ffff88801c8a2000  CPU: 0    COMMAND: \"bug\"
+ #0 [ffffc90000a3f8d8] machine_kexec at ffffffff8107d2c1
+ #1 [ffffc90000a3f930] __crash_kexec at ffffffff8120a3b5
+ #2 [ffffc90000a3f9f8] crash_kexec at ffffffff8120b46e
+ #3 [ffffc90000a3fa10] oops_end at ffffffff81029c36
+ #4 [ffffc90000a3fa38] exc_general_protection at ffffffff89e0c8d1
+ #5 [ffffc90000a3fad0] asm_exc_general_protection at ffffffff8a000ae2
+    [exception RIP: hci_conn_drop+42]
+    RIP: ffffffff88e3a1ca  RSP: ffffc90000a3fb88  RFLAGS: 00010202
+ #6 [ffffc90000a3fbb0] hci_conn_drop at ffffffff88e3a1ca
+";
+
+    #[test]
+    fn test_parse_backtrace() {
+        let (frames, faulting_ip) = parse_backtrace(BT);
+        assert_eq!(
+            frames,
+            vec![
+                "machine_kexec",
+                "__crash_kexec",
+                "crash_kexec",
+                "oops_end",
+                "exc_general_protection",
+                "asm_exc_general_protection",
+                "hci_conn_drop"
+            ]
+        );
+        assert_eq!(faulting_ip.as_deref(), Some("hci_conn_drop+42"));
+
+        let (frames, faulting_ip) =
+            parse_backtrace(" #0 [ffffc90000a3f8d8] panic at ffffffff8108\n");
+        assert_eq!(frames, vec!["panic"]);
+        assert_eq!(faulting_ip, None);
+    }
+
+    #[test]
+    fn test_parse_sys_and_dis() {
+        let sys = "\
+      KERNEL: vmlinux
+    DUMPFILE: vmcore  [PARTIAL DUMP]
+       PANIC: \"Kernel panic - not syncing: Fatal exception\"
+";
+        assert_eq!(
+            parse_panic(sys).as_deref(),
+            Some("Kernel panic - not syncing: Fatal exception")
+        );
+        assert_eq!(parse_panic("       PANIC: \"\"\n"), None);
+
+        let dis = "\
+/usr/src/linux/include/net/bluetooth/hci_core.h: 1230
+0xffffffff88e3a1ca <hci_conn_drop+42>:\tmov    0x10(%rbx),%rax
+";
+        assert_eq!(
+            parse_disassembly(dis).as_deref(),
+            Some(
+                "/usr/src/linux/include/net/bluetooth/hci_core.h: 1230 0xffffffff88e3a1ca <hci_conn_drop+42>:\tmov    0x10(%rbx),%rax"
+            )
+        );
+        assert_eq!(parse_disassembly("\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_analyze_requires_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let vmcore = dir.path().join("vmcore");
+        let error = analyze_vmcore(&vmcore, &dir.path().join("vmlinux"))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("No vmcore at"), "{}", error);
+    }
+}
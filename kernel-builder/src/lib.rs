@@ -1,5 +1,10 @@
+#![allow(clippy::module_inception)]
+
 pub mod config;
 pub mod kernel;
 pub mod kvm;
+pub mod logging;
 pub mod parse;
+pub mod pipeline;
 pub mod script;
+pub mod util;
@@ -0,0 +1,205 @@
+use crate::config::config::{LogConfig, LogFormat};
+use serde_json::{Map, Value};
+use std::fmt;
+use tracing::field::{Field, Visit};
+use tracing::span::Record;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::registry::LookupSpan;
+
+// install the global subscriber in the format of `config`, or in `format_override` (the
+// --log-format value). must run before anything logs
+pub fn init(config: &LogConfig, format_override: Option<&str>) -> anyhow::Result<()> {
+    let format = match format_override {
+        Some(format) => format.parse()?,
+        None => config.format,
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_target(true)
+        .with_thread_ids(true)
+        .with_thread_names(true)
+        .with_line_number(true)
+        .with_file(true);
+
+    match format {
+        LogFormat::Pretty => builder.pretty().init(),
+        LogFormat::Compact => builder.compact().init(),
+        LogFormat::Json => builder
+            .with_ansi(false)
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .init(),
+    }
+    Ok(())
+}
+
+// collects event and span fields into a JSON object
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0.insert(
+            field.name().to_string(),
+            Value::from(format!("{:?}", value)),
+        );
+    }
+}
+
+// the fmt layer stores every span's fields as a string, keep them as a JSON object so
+// JsonFormat can put them back into the event
+pub struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut map = Map::new();
+        fields.record(&mut JsonVisitor(&mut map));
+        write!(writer, "{}", Value::Object(map))
+    }
+
+    // fields recorded after the span was created, e.g. `span.record("commit", ..)`
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &Record<'_>,
+    ) -> fmt::Result {
+        let mut map = match serde_json::from_str(&current.fields) {
+            Ok(Value::Object(map)) => map,
+            _ => Map::new(),
+        };
+        fields.record(&mut JsonVisitor(&mut map));
+        current.fields = Value::Object(map).to_string();
+        Ok(())
+    }
+}
+
+// one JSON object per event:
+// {"timestamp", "level", "target", "file", "line", "thread", "fields", "spans": [{"name", ..fields}]}
+pub struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+
+        // outermost span first, like the pretty format's "in" lines read bottom up
+        let mut spans = Vec::new();
+        if let Some(scope) = ctx.event_scope() {
+            for span in scope.from_root() {
+                let mut entry = Map::new();
+                entry.insert("name".to_string(), Value::from(span.name()));
+                if let Some(formatted) = span.extensions().get::<FormattedFields<JsonFields>>()
+                    && let Ok(Value::Object(span_fields)) = serde_json::from_str(&formatted.fields)
+                {
+                    entry.extend(span_fields);
+                }
+                spans.push(Value::Object(entry));
+            }
+        }
+
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), Value::from(timestamp));
+        line.insert("level".to_string(), Value::from(metadata.level().as_str()));
+        line.insert("target".to_string(), Value::from(metadata.target()));
+        line.insert("file".to_string(), Value::from(metadata.file()));
+        line.insert("line".to_string(), Value::from(metadata.line()));
+        line.insert(
+            "thread".to_string(),
+            Value::from(std::thread::current().name()),
+        );
+        line.insert("fields".to_string(), Value::Object(fields));
+        line.insert("spans".to_string(), Value::Array(spans));
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_format() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let report = tracing::info_span!("report", id = "abc", commit = tracing::field::Empty);
+            let _report = report.enter();
+            report.record("commit", "deadbeef");
+            let crash = tracing::info_span!("crash", index = 1u64);
+            let _crash = crash.enter();
+            tracing::warn!(jobs = 8u64, "building \"kernel\"");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["fields"]["message"], "building \"kernel\"");
+        assert_eq!(line["fields"]["jobs"], 8);
+        assert_eq!(line["spans"][0]["name"], "report");
+        assert_eq!(line["spans"][0]["id"], "abc");
+        assert_eq!(line["spans"][0]["commit"], "deadbeef");
+        assert_eq!(line["spans"][1]["index"], 1);
+    }
+}
@@ -1,128 +1,546 @@
-use kernel_builder::kernel::compile::{apply_patch, make_kernel, rebuild_kernel};
-use kernel_builder::kernel::download::{
-    download_bug, download_config, download_kernel, DownloadError,
-};
-use kernel_builder::kernel::modify::check_fix_config;
-use kernel_builder::kvm::ssh::SSHManager;
-use kernel_builder::parse::parse::{build_path, parse_file};
-use kernel_builder::script::script::mount;
+use anyhow::Context;
+use kernel_builder::config::config::Config;
+use kernel_builder::kernel::compile::BuildOptions;
+use kernel_builder::kvm::kdump::dump_vmcore;
+use kernel_builder::kvm::reproduce::reproduce;
+use kernel_builder::logging;
+use kernel_builder::parse::compiler::select_compiler;
+use kernel_builder::parse::parse::{parse_file_async, parse_report_list};
+use kernel_builder::parse::report::{CrashReport, ExperimentMode, FixSelector};
+use kernel_builder::parse::workspace::{Workspace, set_default_workspace};
+use kernel_builder::pipeline::{Pipeline, Stage, report_span};
+use kernel_builder::script::preflight::{check_compiler, preflight_check};
+use kernel_builder::script::tool::check_tools;
+use std::path::Path;
 use std::sync::Arc;
-use tracing::{error, info, warn};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, error, info, warn};
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt()
-        .with_target(true)
-        .with_thread_ids(true)
-        .with_thread_names(true)
-        .with_line_number(true)
-        .with_file(true)
-        .pretty()
-        .init();
-
-    // let config = SSHManager::builder().build().unwrap();
-    //
-    // let mut ssh = SSHManager::new(config).unwrap();
-    // ssh.connect().await.unwrap();
-    //
-    // match ssh.execute("kexec -p /boot/crash-bzImage --initrd=/boot/crash-initramfs.cpio.gz --append=\"root=/dev/ram0 console=ttyS0\"").await {
-    //     Ok(output) => {
-    //         println!("命令输出: {}", output);
-    //     }
-    //     Err(e) => {
-    //         eprintln!("SSH 命令执行失败: {}", e);
-    //     }
-    // }
-    //
-    // match ssh.execute("./bug").await {
-    //     Ok(output) => {
-    //         println!("命令输出: {}", output);
-    //     }
-    //     Err(e) => {
-    //         eprintln!("SSH 命令执行失败: {}", e);
-    //     }
-    // }
-
-    let mut handles = vec![];
-
-    let path = "datasets/0be4824a86385f022a4f6f5104bcb9246032fdd9.json";
-    let report = Arc::new(parse_file(path).unwrap());
-    // let build_dir = build_path(&report);
-    // let patch_path = build_dir.join("patch.diff");
-
-    // apply_patch(&report, patch_path)
-    //     .await
-    //     .unwrap_or_else(|err| {
-    //         error!("Failed to apply patch: {}", err);
-    //     });
-
-    // rebuild_kernel(&report).await.expect("TODO: panic message");
-
-    match download_kernel(&report).await {
-        Ok(()) => {}
+    let mut args: Vec<String> = std::env::args().collect();
+    // nothing can be logged before the subscriber is set up
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
         Err(err) => {
-            error!("{}", err);
+            eprintln!("{:#}", err);
+            std::process::exit(1);
         }
+    };
+    if let Err(err) = take_option(&mut args, "--log-format")
+        .and_then(|format| logging::init(&config.log, format.as_deref()))
+    {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
     }
 
-    let handle = {
-        let report = Arc::clone(&report);
-        tokio::spawn(async move { download_bug(&report).await })
+    let (crash_index, workspace) = match take_global_options(&mut args) {
+        Ok(options) => options,
+        Err(err) => {
+            error!("{:#}", err);
+            std::process::exit(1);
+        }
+    };
+    let workspace = match workspace {
+        Some(workspace) => workspace,
+        None => match Workspace::from_config(&config.workspace) {
+            Ok(workspace) => workspace,
+            Err(err) => {
+                error!("{:#}", err);
+                std::process::exit(1);
+            }
+        },
+    };
+    info!("Using workspace {}", workspace.root().display());
+    set_default_workspace(workspace).expect("workspace is set before any path is resolved");
+    // --fix builds the selected fix commit instead of its parent
+    let fix = match take_option(&mut args, "--fix")
+        .and_then(|value| value.map(|value| value.parse::<FixSelector>()).transpose())
+    {
+        Ok(fix) => fix,
+        Err(err) => {
+            error!("{:#}", err);
+            std::process::exit(1);
+        }
+    };
+    let options = BuildOptions {
+        dry_run: args.iter().any(|arg| arg == "--dry-run"),
+        force_headers: args.iter().any(|arg| arg == "--force-headers"),
+        crash_index: crash_index.unwrap_or(0),
+        cancel: cancel_on_ctrl_c(),
+        config,
+        mode: if fix.is_some() {
+            ExperimentMode::VerifyFix
+        } else {
+            ExperimentMode::Reproduce
+        },
+        fix: fix.unwrap_or_default(),
+    };
+    let parallel = match take_option(&mut args, "--parallel").and_then(|value| {
+        value
+            .map(|value| {
+                value
+                    .parse::<usize>()
+                    .with_context(|| format!("Invalid --parallel value {:?}", value))
+            })
+            .transpose()
+    }) {
+        Ok(parallel) => parallel,
+        Err(err) => {
+            error!("{:#}", err);
+            std::process::exit(1);
+        }
+    };
+    let force = match take_forced_stages(&mut args) {
+        Ok(force) => force,
+        Err(err) => {
+            error!("{:#}", err);
+            std::process::exit(1);
+        }
     };
-    handles.push(handle);
+    // without --crash, --all-crashes builds every crash of the report in turn
+    let all_crashes = crash_index.is_none() && args.iter().any(|arg| arg == "--all-crashes");
+    args.retain(|arg| arg != "--dry-run" && arg != "--force-headers" && arg != "--all-crashes");
 
-    let handle = {
-        let report = Arc::clone(&report);
-        tokio::spawn(async move { download_config(&report).await })
+    let command = match args.get(1).map(|command| command.parse::<Command>()) {
+        Some(Ok(command)) => command,
+        Some(Err(err)) => {
+            error!("{:#}", err);
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match command {
+        Command::Help => {
+            println!("{}", USAGE);
+            return;
+        }
+        Command::Doctor => {
+            if !doctor() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Command::Verify => {
+            if !verify(&args[2..], options.crash_index).await {
+                std::process::exit(1);
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let inputs = match report_inputs(&args[2..]) {
+        Ok(inputs) => inputs,
+        Err(err) => {
+            error!("{:#}", err);
+            std::process::exit(1);
+        }
     };
-    handles.push(handle);
 
-    for handle in handles {
-        match handle.await {
-            Err(join_err) => {
-                error!("任务 panic 或被取消: {:?}", join_err);
+    let Some(last) = command.last_stage() else {
+        if all_crashes {
+            error!("{} runs a single crash, pick it with --crash <n>", args[1]);
+            std::process::exit(2);
+        }
+        let mut failed = false;
+        for input in inputs {
+            if options.cancel.is_cancelled() {
+                break;
             }
-            Ok(Err(err)) => {
-                if let Some(download_error) = err.downcast_ref::<DownloadError>() {
-                    match download_error {
-                        DownloadError::FileExists(path) => {
-                            warn!("文件已存在，跳过错误: {}", path);
-                            continue;
-                        }
-                        _ => {
-                            error!("任务失败: {:?}", err);
-                        }
-                    }
-                } else {
-                    error!("任务失败: {:?}", err);
+            let outcome = match command {
+                Command::Vmcore => vmcore_report(&input, &options).await,
+                _ => reproduce_report(&input, &options).await,
+            };
+            if let Err(err) = outcome {
+                error!("{:#}", err);
+                failed = true;
+            }
+        }
+        if failed {
+            std::process::exit(1);
+        }
+        return;
+    };
+
+    // run_batch builds one crash per report
+    if all_crashes && parallel.is_some() {
+        error!("--all-crashes cannot be combined with --parallel, pick a crash with --crash <n>");
+        std::process::exit(2);
+    }
+
+    let run = RunArgs {
+        options,
+        force,
+        last,
+        all_crashes,
+    };
+
+    let succeeded = match parallel {
+        Some(max_parallel) => run_parallel(&inputs, &run, max_parallel).await,
+        None => {
+            let mut succeeded = true;
+            for input in inputs {
+                if run.options.cancel.is_cancelled() {
+                    break;
                 }
+                succeeded &= run_report(&report_path(&input), &run).await;
             }
-            Ok(Ok(())) => {
-                info!("任务成功");
+            succeeded
+        }
+    };
+    if !succeeded {
+        std::process::exit(1);
+    }
+}
+
+const USAGE: &str = "\
+usage: kernel-builder <command> [options] <report>...
+
+commands:
+  download     download the kernel source, reproducer and kernel config
+  config-fix   download, then fix up the kernel config
+  build        download, fix the config and build the kernel
+  run-all      every stage, up to installing the kernel into the guest image
+  boot         boot the built kernel and run the reproducer (alias: reproduce)
+  vmcore       boot the built kernel, capture the vmcore of its reproducer and analyze it
+  verify       check tools, toolchain, proxy and ssh key; with reports, also their compilers
+  doctor       check that the external tools are installed
+  help         print this message
+
+a report is an id looked up in datasets/, a path to a .json report, `-` to read a list
+from stdin or `--from-file <list>`
+
+options:
+  --crash <n>           crash of the report to build (default 0)
+  --fix <n|repo:branch> build the selected fix commit instead of its parent
+  --all-crashes         build every crash of the report in turn, not with --parallel
+  --force <stage>       re-run <stage> and everything after it even if already done
+  --dry-run             only print what would be built
+  --force-headers       reinstall the kernel headers
+  --parallel <n>        build up to <n> reports at once
+  --workspace <dir>     workspace root, overrides settings.toml
+  --log-format <fmt>    pretty, compact or json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Download,
+    ConfigFix,
+    Build,
+    RunAll,
+    Boot,
+    Vmcore,
+    Verify,
+    Doctor,
+    Help,
+}
+
+impl std::str::FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "download" => Ok(Command::Download),
+            "config-fix" => Ok(Command::ConfigFix),
+            "build" => Ok(Command::Build),
+            "run-all" => Ok(Command::RunAll),
+            "boot" | "reproduce" => Ok(Command::Boot),
+            "vmcore" => Ok(Command::Vmcore),
+            "verify" => Ok(Command::Verify),
+            "doctor" => Ok(Command::Doctor),
+            "help" | "--help" | "-h" => Ok(Command::Help),
+            _ => anyhow::bail!("Unknown command {:?}", s),
+        }
+    }
+}
+
+impl Command {
+    // the pipeline runs up to this stage, None for commands that do not run the pipeline
+    fn last_stage(self) -> Option<Stage> {
+        match self {
+            Command::Download => Some(Stage::DownloadConfig),
+            Command::ConfigFix => Some(Stage::FixConfig),
+            Command::Build => Some(Stage::Build),
+            Command::RunAll => Some(Stage::Mount),
+            Command::Boot | Command::Vmcore | Command::Verify | Command::Doctor | Command::Help => {
+                None
             }
         }
     }
+}
 
-    println!("All tasks completed");
+// what a pipeline command runs with, the same for every report it is given
+struct RunArgs {
+    options: BuildOptions,
+    force: Vec<Stage>,
+    // the command's final stage, earlier ones already done are skipped by the checkpoint
+    last: Stage,
+    all_crashes: bool,
+}
 
-    match check_fix_config(&report).await {
-        Ok(()) => {}
+impl RunArgs {
+    fn pipeline(&self, options: BuildOptions) -> Pipeline {
+        self.force
+            .iter()
+            .fold(Pipeline::builder(), |builder, stage| builder.force(*stage))
+            .until(self.last)
+            .options(options)
+            .build()
+    }
+}
+
+// build every report at once, at most `max_parallel` kernels at a time. false if any
+// report could not be read or did not complete
+async fn run_parallel(inputs: &[String], run: &RunArgs, max_parallel: usize) -> bool {
+    let mut succeeded = true;
+    let mut reports = Vec::new();
+    for input in inputs {
+        match parse_file_async(Path::new(&report_path(input))).await {
+            Ok(report) => reports.push(Arc::new(report)),
+            Err(err) => {
+                error!("{:#}", err);
+                succeeded = false;
+            }
+        }
+    }
+
+    let pipeline = run.pipeline(run.options.clone());
+    let summary = pipeline.run_batch(reports, max_parallel).await;
+    for id in summary.failed() {
+        warn!("Report {} did not complete", id);
+        succeeded = false;
+    }
+    succeeded
+}
+
+// the first Ctrl-C cancels the running downloads and builds so they can clean up,
+// a second one exits right away
+fn cancel_on_ctrl_c() -> CancellationToken {
+    let cancel = CancellationToken::new();
+    let token = cancel.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return;
+        }
+        warn!("Interrupted, cancelling running tasks (press Ctrl-C again to exit immediately)");
+        token.cancel();
+
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
+    cancel
+}
+
+// removes `--crash <n>` and `--workspace <dir>` from args
+fn take_global_options(
+    args: &mut Vec<String>,
+) -> anyhow::Result<(Option<usize>, Option<Workspace>)> {
+    let crash_index = take_option(args, "--crash")?
+        .map(|value| {
+            value
+                .parse()
+                .with_context(|| format!("Invalid crash index {:?}", value))
+        })
+        .transpose()?;
+    let workspace = take_option(args, "--workspace")?
+        .map(Workspace::new)
+        .transpose()?;
+    Ok((crash_index, workspace))
+}
+
+// removes every `--force <stage>` from args
+fn take_forced_stages(args: &mut Vec<String>) -> anyhow::Result<Vec<Stage>> {
+    let mut stages = Vec::new();
+    while let Some(stage) = take_option(args, "--force")? {
+        stages.push(stage.parse()?);
+    }
+    Ok(stages)
+}
+
+// removes `<name> <value>` from args, returning the value
+fn take_option(args: &mut Vec<String>, name: &str) -> anyhow::Result<Option<String>> {
+    let Some(pos) = args.iter().position(|arg| arg == name) else {
+        return Ok(None);
+    };
+    let value = args
+        .get(pos + 1)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("{} requires a value", name))?;
+    args.drain(pos..=pos + 1);
+    Ok(Some(value))
+}
+
+// false if the report could not be read or one of its crashes did not complete
+async fn run_report(path: &str, run: &RunArgs) -> bool {
+    let report = match parse_file_async(Path::new(path)).await {
+        Ok(report) => Arc::new(report),
         Err(err) => {
-            error!("{}", err);
+            error!("{:#}", err);
+            return false;
+        }
+    };
+
+    if !run.all_crashes {
+        return run_crash(&report, run, run.options.clone()).await;
+    }
+
+    let mut succeeded = true;
+    for crash_index in 0..report.crashes.len() {
+        if run.options.cancel.is_cancelled() {
+            break;
         }
+        info!(
+            "Building crash {}/{} of report {}",
+            crash_index + 1,
+            report.crashes.len(),
+            report.id
+        );
+        let options = BuildOptions {
+            crash_index,
+            ..run.options.clone()
+        };
+        succeeded &= run_crash(&report, run, options).await;
     }
+    succeeded
+}
 
-    match make_kernel(&report).await {
-        Ok(()) => {}
+async fn run_crash(report: &Arc<CrashReport>, run: &RunArgs, options: BuildOptions) -> bool {
+    let crash_index = options.crash_index;
+    match run
+        .pipeline(options)
+        .run(report)
+        .instrument(report_span(report, crash_index))
+        .await
+    {
+        Ok(result) if result.succeeded() => {
+            info!("Report {} done", report.id);
+            true
+        }
+        Ok(_) => false,
         Err(err) => {
-            error!("{}", err);
+            error!("{:#}", err);
+            false
         }
     }
+}
 
-    match mount(&report).await {
-        Ok(()) => {}
+// reports to process: explicit ids/paths, `-` to read a list from stdin, or `--from-file <list>`
+fn report_inputs(args: &[String]) -> anyhow::Result<Vec<String>> {
+    if args.is_empty() {
+        anyhow::bail!("No report given, see `kernel-builder help`");
+    }
+    report_inputs_or_none(args)
+}
+
+// like report_inputs, for commands where the reports are optional
+fn report_inputs_or_none(args: &[String]) -> anyhow::Result<Vec<String>> {
+    let mut inputs = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-" => {
+                let content = std::io::read_to_string(std::io::stdin())
+                    .context("Failed to read report list from stdin")?;
+                inputs.extend(parse_report_list(&content));
+            }
+            "--from-file" => {
+                let list = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--from-file requires a path"))?;
+                let content = std::fs::read_to_string(list)
+                    .with_context(|| format!("Failed to read report list {}", list))?;
+                inputs.extend(parse_report_list(&content));
+            }
+            _ => inputs.push(arg.clone()),
+        }
+    }
+
+    Ok(inputs)
+}
+
+// a report can be given by id (looked up in datasets/) or by path
+fn report_path(id: &str) -> String {
+    if id.ends_with(".json") {
+        id.to_string()
+    } else {
+        format!("datasets/{}.json", id)
+    }
+}
+
+// boot the kernel built for crash `options.crash_index` of the report and run its reproducer
+async fn reproduce_report(id: &str, options: &BuildOptions) -> anyhow::Result<()> {
+    let report = Arc::new(parse_file_async(Path::new(&report_path(id))).await?);
+    let outcome = reproduce(&report, options)
+        .instrument(report_span(&report, options.crash_index))
+        .await?;
+    info!("Report {} reproduction outcome: {}", report.id, outcome);
+    Ok(())
+}
+
+// boot the kernel built for crash `options.crash_index` of the report and capture a vmcore of
+// its crash
+async fn vmcore_report(id: &str, options: &BuildOptions) -> anyhow::Result<()> {
+    let report = Arc::new(parse_file_async(Path::new(&report_path(id))).await?);
+    let vmcore = dump_vmcore(&report, options)
+        .instrument(report_span(&report, options.crash_index))
+        .await?;
+    info!("Report {} vmcore saved to {}", report.id, vmcore.display());
+    Ok(())
+}
+
+// preflight checks before a long run, plus the compiler of each given report
+async fn verify(inputs: &[String], crash_index: usize) -> bool {
+    let mut results = match preflight_check().await {
+        Ok(results) => results,
         Err(err) => {
-            error!("{}", err);
+            error!("{:#}", err);
+            return false;
+        }
+    };
+
+    // a report that cannot be read fails verify, the checks gathered so far are still printed
+    let mut reports_ok = true;
+    let inputs = report_inputs_or_none(inputs).unwrap_or_else(|err| {
+        error!("{:#}", err);
+        reports_ok = false;
+        Vec::new()
+    });
+    for input in inputs {
+        let compiler = parse_file_async(Path::new(&report_path(&input)))
+            .await
+            .and_then(|report| select_compiler(&report, crash_index));
+        match compiler {
+            Ok(compiler) => results.push(check_compiler(&compiler).await),
+            Err(err) => {
+                error!("{}: {:#}", input, err);
+                reports_ok = false;
+            }
+        }
+    }
+
+    for result in &results {
+        println!("{}", result);
+    }
+    reports_ok && results.iter().all(|result| result.passed)
+}
+
+// check that every external tool the pipeline needs is installed
+fn doctor() -> bool {
+    let mut ok = true;
+    for (name, result) in check_tools() {
+        match result {
+            Ok(path) => println!("[✔] {} ({})", name, path.display()),
+            Err(err) => {
+                println!("[✘] {}", err);
+                ok = false;
+            }
         }
     }
+    ok
 }
@@ -0,0 +1,138 @@
+use crate::parse::report::CrashReport;
+use std::fmt;
+use thiserror::Error;
+
+// target architectures we know how to build, named as in syzbot reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    Amd64,
+    Arm64,
+    Riscv64,
+}
+
+#[derive(Debug, Error)]
+pub enum ArchError {
+    #[error("No crash data found in the report")]
+    NoCrashData,
+    #[error("Unsupported architecture: {0}")]
+    UnsupportedArchitecture(String),
+}
+
+impl fmt::Display for Architecture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Architecture::Amd64 => write!(f, "amd64"),
+            Architecture::Arm64 => write!(f, "arm64"),
+            Architecture::Riscv64 => write!(f, "riscv64"),
+        }
+    }
+}
+
+impl Architecture {
+    pub fn parse(arch: &str) -> Result<Architecture, ArchError> {
+        match arch.trim() {
+            "amd64" | "x86_64" => Ok(Architecture::Amd64),
+            "arm64" | "aarch64" => Ok(Architecture::Arm64),
+            "riscv64" => Ok(Architecture::Riscv64),
+            other => Err(ArchError::UnsupportedArchitecture(other.to_string())),
+        }
+    }
+
+    // value of the kernel's ARCH= make variable
+    pub fn kernel_arch(&self) -> &'static str {
+        match self {
+            Architecture::Amd64 => "x86_64",
+            Architecture::Arm64 => "arm64",
+            Architecture::Riscv64 => "riscv",
+        }
+    }
+
+    // directory under arch/ in the kernel source, which is not always ARCH=
+    pub fn source_arch(&self) -> &'static str {
+        match self {
+            Architecture::Amd64 => "x86",
+            Architecture::Arm64 => "arm64",
+            Architecture::Riscv64 => "riscv",
+        }
+    }
+
+    // target as reported by std::env::consts::ARCH, to tell native from cross builds
+    fn rust_arch(&self) -> &'static str {
+        match self {
+            Architecture::Amd64 => "x86_64",
+            Architecture::Arm64 => "aarch64",
+            Architecture::Riscv64 => "riscv64",
+        }
+    }
+
+    // CROSS_COMPILE prefix when building on a different host architecture
+    pub fn cross_compile(&self) -> Option<&'static str> {
+        if self.rust_arch() == std::env::consts::ARCH {
+            return None;
+        }
+
+        match self {
+            Architecture::Amd64 => Some("x86_64-linux-gnu-"),
+            Architecture::Arm64 => Some("aarch64-linux-gnu-"),
+            Architecture::Riscv64 => Some("riscv64-linux-gnu-"),
+        }
+    }
+
+    // bootable image relative to the build output directory
+    pub fn image_path(&self) -> &'static str {
+        match self {
+            Architecture::Amd64 => "arch/x86_64/boot/bzImage",
+            Architecture::Arm64 => "arch/arm64/boot/Image",
+            Architecture::Riscv64 => "arch/riscv/boot/Image",
+        }
+    }
+
+    // ARCH= and, for cross builds, CROSS_COMPILE= arguments for make
+    pub fn make_args(&self) -> String {
+        match self.cross_compile() {
+            Some(prefix) => format!("ARCH={} CROSS_COMPILE={}", self.kernel_arch(), prefix),
+            None => format!("ARCH={}", self.kernel_arch()),
+        }
+    }
+}
+
+pub fn select_architecture(report: &CrashReport) -> Result<Architecture, ArchError> {
+    let crash = report.crashes.first().ok_or(ArchError::NoCrashData)?;
+    Architecture::parse(&crash.architecture)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse::parse_file;
+
+    #[test]
+    fn test_select_architecture() {
+        let crash_report =
+            parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
+        let arch = select_architecture(&crash_report).unwrap();
+        assert_eq!(arch, Architecture::Amd64);
+        assert_eq!(arch.image_path(), "arch/x86_64/boot/bzImage");
+    }
+
+    #[test]
+    fn test_parse_architecture() {
+        let arm64 = Architecture::parse("arm64").unwrap();
+        assert_eq!(arm64.kernel_arch(), "arm64");
+        assert_eq!(arm64.image_path(), "arch/arm64/boot/Image");
+        assert_eq!(Architecture::Amd64.source_arch(), "x86");
+        assert_eq!(Architecture::Riscv64.source_arch(), "riscv");
+        if std::env::consts::ARCH == "x86_64" {
+            assert_eq!(
+                arm64.make_args(),
+                "ARCH=arm64 CROSS_COMPILE=aarch64-linux-gnu-"
+            );
+            assert_eq!(Architecture::Amd64.make_args(), "ARCH=x86_64");
+        }
+
+        assert!(matches!(
+            Architecture::parse("s390x"),
+            Err(ArchError::UnsupportedArchitecture(arch)) if arch == "s390x"
+        ));
+    }
+}
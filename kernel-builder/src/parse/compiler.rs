@@ -5,7 +5,7 @@ use regex::Regex;
 use std::fmt;
 use thiserror::Error;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CompilerType {
     GCC,
     CLANG,
@@ -21,7 +21,7 @@ impl fmt::Display for CompilerType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Compiler {
     pub compiler_type: CompilerType,
     pub major: usize,
@@ -36,57 +36,97 @@ pub enum ParseCompilerError {
     NoCrashData,
     #[error("Compiler description string does not match the expected format")]
     FormatNotMatched,
-    #[error("Version string '{0}' is not in major.minor.patch format")]
+    #[error("Version string '{0}' is not a valid major[.minor[.patch]] version")]
     VersionFormat(String),
     #[error("Unknown compiler type found: {0}")]
     UnknownCompiler(String),
 }
 
-pub fn select_compiler(report: &CrashReport) -> Result<Compiler> {
-    let compiler_str = report.crashes.first().unwrap().compiler_description.clone();
-    static RE: Lazy<Regex> =
-        Lazy::new(|| Regex::new(r"^(?P<name>gcc|clang) \(.*?\) (?P<version>[\d.-]+)").unwrap());
+impl fmt::Display for Compiler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}.{}.{}",
+            self.compiler_type, self.major, self.minor, self.patch
+        )
+    }
+}
+
+impl Compiler {
+    // parse a compiler description such as `gcc (GCC) 10.2.1 20210110`
+    pub fn parse(compiler_str: &str) -> Result<Compiler> {
+        parse_compiler(compiler_str)
+    }
+
+    // the `compiler` argument for nix/shell.nix, e.g. `gcc-12.2`. the shell picks the nixpkgs
+    // attribute closest to major.minor, nixpkgs never distinguishes patch releases
+    pub fn nix_arg(&self) -> String {
+        format!("{}-{}.{}", self.compiler_type, self.major, self.minor)
+    }
+
+    // the compiler binary the nix-shell should put on PATH
+    pub(crate) fn binary(&self) -> &'static str {
+        match self.compiler_type {
+            CompilerType::GCC => "gcc",
+            CompilerType::CLANG => "clang",
+        }
+    }
+
+    // same toolchain family and version as far as the kernel build is concerned
+    pub fn matches(&self, other: &Compiler) -> bool {
+        self.compiler_type == other.compiler_type
+            && self.major == other.major
+            && self.minor == other.minor
+    }
+}
+
+pub fn select_compiler(report: &CrashReport, crash_index: usize) -> Result<Compiler> {
+    let compiler_str = report.crash(crash_index)?.compiler_description.clone();
+    parse_compiler(&compiler_str)
+}
+
+// accepts `gcc (<vendor>) X[.Y[.Z]]...` and `[<vendor> ]clang version X[.Y[.Z]]...`,
+// missing minor/patch components default to 0 and suffixes such as `-syz` are ignored
+fn parse_compiler(compiler_str: &str) -> Result<Compiler> {
+    static RE: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(
+            r"^(?:(?P<name>gcc|clang) \(.*?\)|(?:\S+ )?(?P<clang>clang) version) (?P<version>\d+(?:\.\d+)*)",
+        )
+        .unwrap()
+    });
 
     let captures = RE
-        .captures(&compiler_str)
+        .captures(compiler_str)
         .ok_or(ParseCompilerError::FormatNotMatched)?;
 
-    let compiler_type = match captures.name("name").unwrap().as_str() {
+    let name = captures
+        .name("name")
+        .or_else(|| captures.name("clang"))
+        .unwrap()
+        .as_str();
+    let compiler_type = match name {
         "gcc" => CompilerType::GCC,
         "clang" => CompilerType::CLANG,
         other => anyhow::bail!(ParseCompilerError::UnknownCompiler(other.to_string())),
     };
 
-    // Parse the version string into major, minor, and patch
     let version_str = captures.name("version").unwrap().as_str();
-    let mut parts = version_str.split('.');
-
-    let major_str = parts
-        .next()
-        .ok_or_else(|| ParseCompilerError::VersionFormat(version_str.to_string()))?;
-    let minor_str = parts
-        .next()
-        .ok_or_else(|| ParseCompilerError::VersionFormat(version_str.to_string()))?;
-    let patch_str = parts
-        .next()
-        .ok_or_else(|| ParseCompilerError::VersionFormat(version_str.to_string()))?;
-
-    // Note the change to parse into `usize`
-    let major = major_str
-        .parse::<usize>()
-        .map_err(|_| ParseCompilerError::VersionFormat(version_str.to_string()))?;
-    let minor = minor_str
-        .parse::<usize>()
-        .map_err(|_| ParseCompilerError::VersionFormat(version_str.to_string()))?;
-    let patch = patch_str
-        .parse::<usize>()
-        .map_err(|_| ParseCompilerError::VersionFormat(version_str.to_string()))?;
+    let mut parts = version_str
+        .split('.')
+        .map(|part| part.parse::<usize>())
+        .chain(std::iter::repeat(Ok(0)));
+    let mut next = || {
+        parts
+            .next()
+            .unwrap()
+            .map_err(|_| ParseCompilerError::VersionFormat(version_str.to_string()))
+    };
 
     let compiler = Compiler {
         compiler_type,
-        major,
-        minor,
-        patch,
+        major: next()?,
+        minor: next()?,
+        patch: next()?,
     };
 
     Ok(compiler)
@@ -114,10 +154,69 @@ mod tests {
     fn test_select_compiler() {
         let crash_report =
             parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
-        let compiler = select_compiler(&crash_report).unwrap();
+        let compiler = select_compiler(&crash_report, 0).unwrap();
         assert_eq!(compiler.compiler_type.to_string(), "gcc".to_string());
         assert_eq!(compiler.major, 10);
         assert_eq!(compiler.minor, 2);
         assert_eq!(compiler.patch, 1);
     }
+
+    #[test]
+    fn test_parse_version_output() {
+        let compiler = Compiler::parse("gcc (GCC) 12.2.0").unwrap();
+        assert_eq!(compiler.to_string(), "gcc-12.2.0");
+
+        let requested = Compiler::parse("gcc (Debian 12.2.0-14) 12.2.0").unwrap();
+        assert!(requested.matches(&compiler));
+    }
+
+    #[test]
+    fn test_parse_dataset_descriptions() {
+        let cases = [
+            (
+                "gcc (Debian 10.2.1-6) 10.2.1 20210110, GNU ld (GNU Binutils for Debian) 2.35.2",
+                "gcc-10.2.1",
+            ),
+            ("gcc (GCC) 10.1.0-syz 20200507", "gcc-10.1.0"),
+            ("gcc (GCC) 9.0.0 20181231 (experimental)", "gcc-9.0.0"),
+            (
+                "Debian clang version 13.0.1-++20220126092033+75e33f71c2da-1~exp1~20220126212112.63, GNU ld (GNU Binutils for Debian) 2.35.2",
+                "clang-13.0.1",
+            ),
+            (
+                "Debian clang version 15.0.7, GNU ld (GNU Binutils for Debian) 2.35.2",
+                "clang-15.0.7",
+            ),
+            (
+                "clang version 10.0.0 (https://github.com/llvm/llvm-project/ c2443155a0fb245c8f17f2c1c72b6ea391e86e81)",
+                "clang-10.0.0",
+            ),
+            (
+                "clang version 15 (https://github.com/llvm/llvm-project/)",
+                "clang-15.0.0",
+            ),
+            ("gcc (GCC) 12.2", "gcc-12.2.0"),
+        ];
+
+        for (description, expected) in cases {
+            assert_eq!(
+                Compiler::parse(description).unwrap().to_string(),
+                expected,
+                "{}",
+                description
+            );
+        }
+
+        assert!(Compiler::parse("icc version 19.0").is_err());
+    }
+
+    #[test]
+    fn test_nix_arg() {
+        let compiler = Compiler::parse("gcc (Debian 12.2.0-14) 12.2.0").unwrap();
+        assert_eq!(compiler.nix_arg(), "gcc-12.2");
+
+        let compiler =
+            Compiler::parse("clang version 15 (https://github.com/llvm/llvm-project/)").unwrap();
+        assert_eq!(compiler.nix_arg(), "clang-15.0");
+    }
 }
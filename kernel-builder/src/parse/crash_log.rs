@@ -0,0 +1,257 @@
+use anyhow::Result;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+// how many leading stack frames are compared when neither side names a culprit function
+const MATCH_FRAMES: usize = 3;
+
+// what identifies a kernel crash in a console log: the oops header, the function it blames and
+// the first call trace. parsed from syzbot's CrashReport text or from a guest's serial output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrashSignature {
+    // the syzbot bug title, e.g. `KASAN: use-after-free Read in foo`
+    pub title: String,
+    // the kind of report, e.g. `KASAN: use-after-free`, `WARNING`, `general protection fault`
+    pub kind: String,
+    // the first oops line, without the printk prefix
+    pub header: String,
+    // the function the report blames, from `... in <func>` or the RIP line
+    pub function: Option<String>,
+    // function names of the first call trace, innermost first
+    pub stack: Vec<String>,
+}
+
+impl CrashSignature {
+    // `title` is carried along as is, the rest comes from the first oops found in `log`
+    pub fn parse(log: &str, title: &str) -> Result<CrashSignature> {
+        let lines: Vec<&str> = log.lines().map(strip_printk_prefix).collect();
+
+        let (start, header) = lines
+            .iter()
+            .enumerate()
+            .find(|(_, line)| HEADER.is_match(line))
+            .map(|(i, line)| (i, line.trim().to_string()))
+            .ok_or_else(|| anyhow::anyhow!("No kernel crash report found in the log"))?;
+        let rest = &lines[start..];
+
+        let kind = crash_kind(&header);
+        let function = IN_FUNCTION
+            .captures(&header)
+            .or_else(|| rest.iter().find_map(|line| RIP.captures(line)))
+            .map(|captures| captures["func"].to_string());
+
+        Ok(CrashSignature {
+            title: title.to_string(),
+            kind,
+            header,
+            function,
+            stack: call_trace(rest),
+        })
+    }
+
+    // whether `other`, typically parsed from the guest console, is the same crash: the same
+    // kind, and the same blamed function or the same innermost frames
+    pub fn matches(&self, other: &CrashSignature) -> bool {
+        if self.kind != other.kind {
+            return false;
+        }
+        if let (Some(ours), Some(theirs)) = (&self.function, &other.function) {
+            return ours == theirs;
+        }
+        let frames = MATCH_FRAMES.min(self.stack.len());
+        frames > 0 && other.stack.len() >= frames && self.stack[..frames] == other.stack[..frames]
+    }
+}
+
+static HEADER: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(
+        r"^(?:BUG: |WARNING: |WARNING in |UBSAN: |KMSAN: |KCSAN: |KFENCE: |INFO: task .* blocked|general protection fault|kernel BUG at |Kernel panic - |BUG kmalloc|unregister_netdevice: )",
+    )
+    .unwrap()
+});
+
+// `in foo+0x12/0x34`, as in KASAN and KFENCE headers
+static IN_FUNCTION: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r" in (?P<func>[A-Za-z0-9_.]+)(?:\+0x[0-9a-f]+/0x[0-9a-f]+)?").unwrap()
+});
+
+static RIP: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^RIP: [0-9a-f]{4}:(?P<func>[A-Za-z0-9_.]+)\+0x[0-9a-f]+/0x[0-9a-f]+").unwrap()
+});
+
+// ` foo+0x12/0x34 mm/foo.c:12` or ` __dump_stack lib/dump_stack.c:88 [inline]`
+static FRAME: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^\s*(?P<func>[A-Za-z_][A-Za-z0-9_.]*)(?:\+0x[0-9a-f]+/0x[0-9a-f]+)?(?:\s|$)")
+        .unwrap()
+});
+
+// `[   12.345678][ T1234] ` timestamps and caller ids printk puts in front of every line
+pub(crate) fn strip_printk_prefix(line: &str) -> &str {
+    static PREFIX: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"^(?:\[\s*[0-9.]+\])?(?:\[\s*[TC]\d+\])?\s?").unwrap());
+    let prefix_len = PREFIX.find(line).map_or(0, |m| m.end());
+    &line[prefix_len..]
+}
+
+// whether a console line, without its printk prefix, starts a crash report
+pub(crate) fn is_crash_header(line: &str) -> bool {
+    HEADER.is_match(line)
+}
+
+fn crash_kind(header: &str) -> String {
+    let header = header.strip_prefix("BUG: ").unwrap_or(header);
+    for (prefix, kind) in [
+        ("WARNING", "WARNING"),
+        ("general protection fault", "general protection fault"),
+        ("kernel BUG at", "kernel BUG"),
+        ("INFO: task", "INFO: task hung"),
+        ("Kernel panic", "Kernel panic"),
+    ] {
+        if header.starts_with(prefix) {
+            return kind.to_string();
+        }
+    }
+    // `KASAN: use-after-free in foo`, `unable to handle page fault for address: ...`
+    static TOOL: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[A-Z]+: ").unwrap());
+    let from = TOOL.find(header).map_or(0, |m| m.end());
+    let end = [" in ", " Read", " Write", " for ", ":", " at "]
+        .iter()
+        .filter_map(|sep| header[from..].find(sep).map(|i| from + i))
+        .min()
+        .unwrap_or(header.len());
+    header[..end].trim().to_string()
+}
+
+// frames of the first `Call Trace:`, skipping unreliable `? foo` frames and the
+// <TASK>/<IRQ> markers
+fn call_trace(lines: &[&str]) -> Vec<String> {
+    let Some(start) = lines.iter().position(|line| line.trim() == "Call Trace:") else {
+        return Vec::new();
+    };
+
+    let mut stack = Vec::new();
+    for line in &lines[start + 1..] {
+        let line = line.trim();
+        if line.starts_with('<') && line.ends_with('>') {
+            if line.starts_with("</TASK") {
+                break;
+            }
+            continue;
+        }
+        if line.starts_with("? ") {
+            continue;
+        }
+        match FRAME.captures(line) {
+            Some(captures) => stack.push(captures["func"].to_string()),
+            None => break,
+        }
+    }
+    stack
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KASAN_LOG: &str = "\
+[   35.118203][ T3601] ==================================================================
+[   35.126377][ T3601] BUG: KASAN: use-after-free in hci_conn_drop+0x2a/0x2c0 include/net/bluetooth/hci_core.h:1230
+[   35.135028][ T3601] Read of size 8 at addr ffff88801d6b4808 by task syz-executor/3601
+[   35.143048][ T3601]
+[   35.145390][ T3601] CPU: 0 PID: 3601 Comm: syz-executor Not tainted 5.19.0-syzkaller #0
+[   35.153000][ T3601] Call Trace:
+[   35.156294][ T3601]  <TASK>
+[   35.159246][ T3601]  __dump_stack lib/dump_stack.c:88 [inline]
+[   35.162000][ T3601]  dump_stack_lvl+0xcd/0x134 lib/dump_stack.c:106
+[   35.168000][ T3601]  ? hci_conn_drop+0x2a/0x2c0
+[   35.170000][ T3601]  print_report+0x16a/0x5b0 mm/kasan/report.c:433
+[   35.175000][ T3601]  kasan_report+0xbf/0x1f0 mm/kasan/report.c:495
+[   35.180000][ T3601]  hci_conn_drop+0x2a/0x2c0 include/net/bluetooth/hci_core.h:1230
+[   35.185000][ T3601]  </TASK>
+[   35.190000][ T3601] Allocated by task 3599:
+";
+
+    #[test]
+    fn test_parse_kasan_report() {
+        let signature =
+            CrashSignature::parse(KASAN_LOG, "KASAN: use-after-free Read in hci_conn_drop")
+                .unwrap();
+        assert_eq!(signature.kind, "KASAN: use-after-free");
+        assert!(
+            signature
+                .header
+                .starts_with("BUG: KASAN: use-after-free in hci_conn_drop")
+        );
+        assert_eq!(signature.function.as_deref(), Some("hci_conn_drop"));
+        assert_eq!(
+            signature.stack,
+            vec![
+                "__dump_stack",
+                "dump_stack_lvl",
+                "print_report",
+                "kasan_report",
+                "hci_conn_drop"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_warning() {
+        let log = "\
+------------[ cut here ]------------
+WARNING: CPU: 1 PID: 4242 at mm/mempolicy.c:2155 alloc_pages_vma+0x3a0/0x420 mm/mempolicy.c:2155
+Modules linked in:
+RIP: 0010:alloc_pages_vma+0x3a0/0x420 mm/mempolicy.c:2155
+Call Trace:
+ shmem_alloc_page+0x10f/0x1e0 mm/shmem.c:1565
+ shmem_alloc_and_acct_page+0x15e/0x3e0 mm/shmem.c:1590
+ entry_SYSCALL_64_after_hwframe+0x44/0xae
+RIP: 0033:0x7f0000000000
+";
+        let signature = CrashSignature::parse(log, "WARNING in alloc_pages_vma").unwrap();
+        assert_eq!(signature.kind, "WARNING");
+        assert_eq!(signature.function.as_deref(), Some("alloc_pages_vma"));
+        assert_eq!(
+            signature.stack,
+            vec![
+                "shmem_alloc_page",
+                "shmem_alloc_and_acct_page",
+                "entry_SYSCALL_64_after_hwframe"
+            ]
+        );
+
+        assert!(CrashSignature::parse("all good\n", "").is_err());
+    }
+
+    #[test]
+    fn test_signature_matches() {
+        let expected = CrashSignature::parse(KASAN_LOG, "").unwrap();
+        // the same crash on the guest console, without printk caller ids
+        let console = KASAN_LOG.replace("[ T3601] ", "");
+        assert!(expected.matches(&CrashSignature::parse(&console, "").unwrap()));
+
+        let other = KASAN_LOG.replace("hci_conn_drop", "hci_conn_put");
+        assert!(!expected.matches(&CrashSignature::parse(&other, "").unwrap()));
+
+        let mut no_function = expected.clone();
+        no_function.function = None;
+        assert!(no_function.matches(&expected));
+    }
+
+    #[test]
+    fn test_crash_kind() {
+        assert_eq!(
+            crash_kind("BUG: unable to handle page fault for address: ffffffffffffffe8"),
+            "unable to handle page fault"
+        );
+        assert_eq!(
+            crash_kind("general protection fault, probably for non-canonical address"),
+            "general protection fault"
+        );
+        assert_eq!(
+            crash_kind("KFENCE: use-after-free read in foo+0x1/0x2"),
+            "KFENCE: use-after-free read"
+        );
+    }
+}
@@ -0,0 +1,311 @@
+use crate::parse::arch::Architecture;
+use crate::parse::report::CrashReport;
+use crate::parse::workspace::{Workspace, default_workspace};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::info;
+
+// every path of a report's workspace, all derived from workspace/<id>. what is built and run
+// belongs to one crash, the first crash's lives in workspace/<id> itself and crash n's in
+// workspace/<id>/crash-<n>:
+//
+// workspace/<id>/
+// ├── linux-<commit>.tar.gz
+// ├── linux-<commit>/     kernel source tree, with bear's compile_commands.json
+// ├── fix.diff            CrashReport.patch
+// ├── maintainers.json    get_maintainer.pl output for the fix's files
+// ├── crash-<n>/          the per crash files below, for crash n > 0
+// ├── build/              make O= output, including .config and a captured vmcore
+// ├── install/            installed uapi headers
+// ├── image/              guest disk image and console log
+// ├── failure/            preserved failed build
+// ├── build.log
+// ├── .state.json         pipeline stages already completed
+// ├── result.json         outcome of the last pipeline run
+// ├── reproducer.c
+// ├── reproducer          reproducer.c built statically against install/include
+// ├── reproducer.log      compiler diagnostics of that build
+// ├── reproducer.syz      syzkaller program, when the report has one
+// └── crash.log           syzbot's crash report, see download_crash_log
+//
+// workspace/.cache/ is shared between reports:
+// ├── linux-<commit>.tar.gz
+// ├── linux-<commit>/     pristine tree, hardlinked into the report workspaces
+// └── ccache/             compiler cache, when build.ccache is set
+#[derive(Debug, Clone)]
+pub struct Layout {
+    root: PathBuf,
+    crash_dir: PathBuf,
+    source_dir: PathBuf,
+    cache_dir: PathBuf,
+}
+
+impl Layout {
+    pub fn new(report: &CrashReport) -> Result<Layout> {
+        Layout::for_crash(report, 0)
+    }
+
+    pub fn for_crash(report: &CrashReport, crash_index: usize) -> Result<Layout> {
+        Layout::in_workspace(default_workspace(), report, crash_index)
+    }
+
+    // the layout of crash `crash_index` with the source tree of `commit` instead of the one it
+    // is reproduced on, for a build of the fix
+    pub fn for_commit(report: &CrashReport, crash_index: usize, commit: &str) -> Result<Layout> {
+        let mut layout = Layout::for_crash(report, crash_index)?;
+        layout.source_dir = layout.root.join(format!("linux-{}", commit));
+        Ok(layout)
+    }
+
+    pub fn in_workspace(
+        workspace: &Workspace,
+        report: &CrashReport,
+        crash_index: usize,
+    ) -> Result<Layout> {
+        let root = workspace.build_path(report);
+        let crash_dir = match crash_index {
+            0 => root.clone(),
+            n => root.join(format!("crash-{}", n)),
+        };
+        Ok(Layout {
+            root,
+            crash_dir,
+            source_dir: workspace.kernel_source_path(report, crash_index)?,
+            cache_dir: workspace.cache_path(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // where the files of the crash this layout was made for live
+    pub fn crash_dir(&self) -> &Path {
+        &self.crash_dir
+    }
+
+    pub fn source_dir(&self) -> PathBuf {
+        self.source_dir.clone()
+    }
+
+    pub fn source_archive(&self) -> PathBuf {
+        let mut name = self.source_dir.file_name().unwrap_or_default().to_owned();
+        name.push(".tar.gz");
+        self.root.join(name)
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    pub fn cached_archive(&self) -> PathBuf {
+        self.cache_dir
+            .join(self.source_archive().file_name().unwrap_or_default())
+    }
+
+    pub fn cached_source_dir(&self) -> PathBuf {
+        self.cache_dir
+            .join(self.source_dir.file_name().unwrap_or_default())
+    }
+
+    pub fn ccache_dir(&self) -> PathBuf {
+        self.cache_dir.join("ccache")
+    }
+
+    pub fn build_out_dir(&self) -> PathBuf {
+        self.crash_dir.join("build")
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        self.build_out_dir().join(".config")
+    }
+
+    pub fn kernel_image_path(&self, arch: Architecture) -> PathBuf {
+        self.build_out_dir().join(arch.image_path())
+    }
+
+    // bear's database of the whole build, kept up to date by make_kernel and rebuild_kernel
+    pub fn compile_commands_path(&self) -> PathBuf {
+        self.source_dir.join("compile_commands.json")
+    }
+
+    // what one build compiled, merged into compile_commands.json afterwards
+    pub fn rebuild_compile_commands_path(&self) -> PathBuf {
+        self.source_dir.join("rebuild_compile_commands.json")
+    }
+
+    pub fn install_dir(&self) -> PathBuf {
+        self.crash_dir.join("install")
+    }
+
+    // touched after every successful headers_install
+    pub fn headers_stamp(&self) -> PathBuf {
+        self.install_dir().join(".headers_installed")
+    }
+
+    // the report's inline fix patch
+    pub fn fix_patch_path(&self) -> PathBuf {
+        self.root.join("fix.diff")
+    }
+
+    // where get.sh and capture_vmcore leave the dump
+    pub fn vmcore_path(&self) -> PathBuf {
+        self.build_out_dir().join("vmcore")
+    }
+
+    pub fn reproducer_path(&self) -> PathBuf {
+        self.crash_dir.join("reproducer.c")
+    }
+
+    pub fn reproducer_binary_path(&self) -> PathBuf {
+        self.crash_dir.join("reproducer")
+    }
+
+    pub fn reproducer_log_path(&self) -> PathBuf {
+        self.crash_dir.join("reproducer.log")
+    }
+
+    pub fn crash_log_path(&self) -> PathBuf {
+        self.crash_dir.join("crash.log")
+    }
+
+    pub fn syz_reproducer_path(&self) -> PathBuf {
+        self.crash_dir.join("reproducer.syz")
+    }
+
+    pub fn image_dir(&self) -> PathBuf {
+        self.crash_dir.join("image")
+    }
+
+    pub fn failure_dir(&self) -> PathBuf {
+        self.crash_dir.join("failure")
+    }
+
+    pub fn build_log_path(&self) -> PathBuf {
+        self.crash_dir.join("build.log")
+    }
+
+    // per stage outcome of the last pipeline run, see pipeline::PipelineResult
+    pub fn result_path(&self) -> PathBuf {
+        self.crash_dir.join("result.json")
+    }
+
+    // see CrashReport::maintainers
+    pub fn maintainers_path(&self) -> PathBuf {
+        self.root.join("maintainers.json")
+    }
+
+    // pipeline checkpoint, see pipeline::Checkpoint
+    pub fn state_path(&self) -> PathBuf {
+        self.crash_dir.join(".state.json")
+    }
+
+    // make arguments placing build output and installed headers in this layout
+    pub fn make_dirs_args(&self) -> String {
+        format!(
+            "O={} INSTALL_HDR_PATH={}",
+            self.build_out_dir().display(),
+            self.install_dir().display()
+        )
+    }
+
+    // bring a workspace created before the layout was centralized up to date
+    pub async fn migrate(&self) -> Result<()> {
+        let old_reproducer = self.root.join("bug.c");
+        let reproducer = self.reproducer_path();
+
+        if fs::try_exists(&old_reproducer).await? && !fs::try_exists(&reproducer).await? {
+            info!(
+                "Migrating {} to {}",
+                old_reproducer.display(),
+                reproducer.display()
+            );
+            fs::rename(&old_reproducer, &reproducer)
+                .await
+                .with_context(|| format!("Failed to migrate {}", old_reproducer.display()))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse::parse_file;
+
+    #[test]
+    fn test_layout_paths() {
+        let crash_report =
+            parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
+        let layout = Layout::new(&crash_report).unwrap();
+        let root = layout.root().to_path_buf();
+
+        assert!(root.ends_with("workspace/0b6b2d6d6cefa8b462930e55be699efba635788f"));
+        assert_eq!(
+            layout.source_archive(),
+            root.join("linux-77076934afdcd46516caf18ed88b2f88025c9ddb.tar.gz")
+        );
+        assert_eq!(layout.config_path(), root.join("build/.config"));
+        assert_eq!(
+            layout.kernel_image_path(Architecture::Amd64),
+            root.join("build/arch/x86_64/boot/bzImage")
+        );
+        assert_eq!(layout.reproducer_path(), root.join("reproducer.c"));
+        assert_eq!(layout.vmcore_path(), root.join("build/vmcore"));
+        assert_eq!(layout.crash_dir(), root);
+        assert_eq!(
+            layout.cached_source_dir(),
+            root.parent()
+                .unwrap()
+                .join(".cache/linux-77076934afdcd46516caf18ed88b2f88025c9ddb")
+        );
+    }
+
+    #[test]
+    fn test_crash_layout_paths() {
+        let mut crash_report =
+            parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
+        crash_report.crashes.push(crash_report.crashes[0].clone());
+        let first = Layout::for_crash(&crash_report, 0).unwrap();
+        let second = Layout::for_crash(&crash_report, 1).unwrap();
+        let crash_dir = first.root().join("crash-1");
+
+        assert_eq!(second.root(), first.root());
+        assert_eq!(second.crash_dir(), crash_dir);
+        assert_eq!(second.config_path(), crash_dir.join("build/.config"));
+        assert_eq!(second.reproducer_path(), crash_dir.join("reproducer.c"));
+        assert_eq!(second.state_path(), crash_dir.join(".state.json"));
+        assert_eq!(second.crash_log_path(), crash_dir.join("crash.log"));
+        assert_ne!(second.build_out_dir(), first.build_out_dir());
+        // the report's own files and the tree of a shared commit are not duplicated
+        assert_eq!(second.fix_patch_path(), first.fix_patch_path());
+        assert_eq!(second.source_dir(), first.source_dir());
+
+        let fixed = Layout::for_commit(&crash_report, 1, "abc").unwrap();
+        assert_eq!(fixed.source_dir(), first.root().join("linux-abc"));
+        assert_eq!(fixed.build_out_dir(), second.build_out_dir());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_reproducer() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = Layout {
+            root: dir.path().to_path_buf(),
+            crash_dir: dir.path().to_path_buf(),
+            source_dir: dir.path().join("linux-abc"),
+            cache_dir: dir.path().join(".cache"),
+        };
+        std::fs::write(dir.path().join("bug.c"), "int main() {}").unwrap();
+
+        layout.migrate().await.unwrap();
+        layout.migrate().await.unwrap();
+
+        assert!(!dir.path().join("bug.c").exists());
+        assert_eq!(
+            std::fs::read_to_string(layout.reproducer_path()).unwrap(),
+            "int main() {}"
+        );
+    }
+}
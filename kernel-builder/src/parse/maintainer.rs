@@ -0,0 +1,159 @@
+use crate::kernel::nix::NixCommand;
+use crate::parse::compiler::select_compiler;
+use crate::parse::layout::Layout;
+use crate::parse::report::CrashReport;
+use crate::util::shell_quote;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::Path;
+use tokio::fs;
+use tracing::{info, warn};
+
+// a person get_maintainer.pl lists for a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Maintainer {
+    pub name: String,
+    pub email: String,
+}
+
+impl CrashReport {
+    // maintainers and reviewers of the files the fix touches, from scripts/get_maintainer.pl
+    // of the crash's source tree. the tree must have been downloaded. the result is cached in
+    // workspace/<id>/maintainers.json
+    pub async fn maintainers(&self, crash_index: usize) -> Result<Vec<Maintainer>> {
+        if self.patch_modified_files.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let layout = Layout::for_crash(self, crash_index)?;
+        let cache = layout.maintainers_path();
+        if let Some(maintainers) = load_cached(&cache).await {
+            return Ok(maintainers);
+        }
+
+        let source_dir = layout.source_dir();
+        if !fs::try_exists(source_dir.join("scripts/get_maintainer.pl")).await? {
+            anyhow::bail!(
+                "No scripts/get_maintainer.pl in {}, download the kernel source first",
+                source_dir.display()
+            );
+        }
+
+        let files: Vec<String> = self
+            .patch_modified_files
+            .iter()
+            .map(|file| shell_quote(file))
+            .collect();
+        let command = format!(
+            "perl scripts/get_maintainer.pl --no-rolestats --no-git --no-git-fallback --nol -f {}",
+            files.join(" ")
+        );
+        let compiler = select_compiler(self, crash_index)?;
+        let shell_script_path = env::current_dir()?.join("nix").join("shell.nix");
+        let nix_cmd = NixCommand::new(shell_script_path, &compiler.nix_arg(), source_dir);
+
+        info!(
+            "Looking up maintainers of {} files for report {}",
+            self.patch_modified_files.len(),
+            self.id
+        );
+        let output = nix_cmd
+            .output(&command)
+            .await
+            .context("Failed to run get_maintainer.pl")?;
+        let maintainers = parse_maintainers(&output);
+
+        if fs::try_exists(layout.root()).await? {
+            let json = serde_json::to_string_pretty(&maintainers)?;
+            if let Err(e) = fs::write(&cache, json).await {
+                warn!("Failed to cache maintainers in {}: {}", cache.display(), e);
+            }
+        }
+
+        Ok(maintainers)
+    }
+}
+
+async fn load_cached(path: &Path) -> Option<Vec<Maintainer>> {
+    let content = fs::read_to_string(path).await.ok()?;
+    match serde_json::from_str(&content) {
+        Ok(maintainers) => Some(maintainers),
+        Err(e) => {
+            warn!("Ignoring unreadable {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+// one `Name <email>` per line, names may be quoted. lines without an address (the nix-shell
+// banner) are skipped, as are duplicates
+fn parse_maintainers(output: &str) -> Vec<Maintainer> {
+    let mut maintainers: Vec<Maintainer> = Vec::new();
+    for line in output.lines() {
+        let line = line.trim();
+        let (Some(open), true) = (line.rfind('<'), line.ends_with('>')) else {
+            continue;
+        };
+        let email = line[open + 1..line.len() - 1].trim().to_string();
+        if !email.contains('@') {
+            continue;
+        }
+        let name = line[..open].trim().trim_matches('"').to_string();
+        if maintainers.iter().all(|m| m.email != email) {
+            maintainers.push(Maintainer { name, email });
+        }
+    }
+    maintainers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_maintainers() {
+        let output = "\
+Using gcc-12.2
+Alexander Viro <viro@zeniv.linux.org.uk>
+\"Christian Brauner\" <brauner@kernel.org>
+Jan Kara <jack@suse.cz>
+linux-fsdevel@vger.kernel.org
+Jan Kara <jack@suse.cz>
+";
+        assert_eq!(
+            parse_maintainers(output),
+            vec![
+                Maintainer {
+                    name: "Alexander Viro".to_string(),
+                    email: "viro@zeniv.linux.org.uk".to_string(),
+                },
+                Maintainer {
+                    name: "Christian Brauner".to_string(),
+                    email: "brauner@kernel.org".to_string(),
+                },
+                Maintainer {
+                    name: "Jan Kara".to_string(),
+                    email: "jack@suse.cz".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_cached_maintainers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("maintainers.json");
+        assert!(load_cached(&path).await.is_none());
+
+        let maintainers = vec![Maintainer {
+            name: "Jan Kara".to_string(),
+            email: "jack@suse.cz".to_string(),
+        }];
+        std::fs::write(&path, serde_json::to_string(&maintainers).unwrap()).unwrap();
+        assert_eq!(load_cached(&path).await, Some(maintainers));
+
+        std::fs::write(&path, "not json").unwrap();
+        assert!(load_cached(&path).await.is_none());
+    }
+}
@@ -1,3 +1,9 @@
-pub mod report;
+pub mod arch;
 pub mod compiler;
+pub mod crash_log;
+pub mod layout;
+pub mod maintainer;
 pub mod parse;
+pub mod report;
+pub mod syz;
+pub mod workspace;
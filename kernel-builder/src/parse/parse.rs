@@ -1,25 +1,25 @@
 use crate::parse::report::CrashReport;
+use crate::parse::workspace::default_workspace;
 use anyhow::{Context, Result};
-use std::path::PathBuf;
-use std::{env, fs};
-use tracing::info;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::{Instrument, Span, info, warn};
 
+// the free functions resolve against the default workspace, see parse::workspace
 pub fn build_path(report: &CrashReport) -> PathBuf {
-    let root = env::current_dir().unwrap();
-    let id = report.id.clone();
-    let suffix = format!("workspace/{}", id);
-    let path = root.join(&suffix);
-
-    PathBuf::from(path)
+    default_workspace().build_path(report)
 }
 
-pub fn kernel_source_path(report: &CrashReport) -> PathBuf {
-    let root = build_path(report);
-    let commit = report.crashes.first().unwrap().kernel_source_commit.clone();
-    let suffix = format!("linux-{}", commit);
-    let path = root.join(suffix);
+// source tarballs and trees shared by every report, keyed by commit
+pub fn cache_path() -> PathBuf {
+    default_workspace().cache_path()
+}
 
-    PathBuf::from(path)
+pub fn kernel_source_path(report: &CrashReport, crash_index: usize) -> Result<PathBuf> {
+    default_workspace().kernel_source_path(report, crash_index)
 }
 
 pub fn parse_file(filepath: &str) -> Result<CrashReport> {
@@ -34,6 +34,104 @@ pub fn parse_file(filepath: &str) -> Result<CrashReport> {
     Ok(report)
 }
 
+// async variant of parse_file for the runtime, deserialization runs on the blocking pool
+pub async fn parse_file_async(path: &Path) -> Result<CrashReport> {
+    let json_content = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read json file {:?}", path))?;
+
+    let path = path.to_path_buf();
+    let span = Span::current();
+    let report = tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        serde_json::from_str::<CrashReport>(&json_content)
+            .with_context(|| format!("Failed to parse json file {:?}", path))
+            .inspect(|_| {
+                info!(
+                    "Parsing crash report from file {} successfully",
+                    path.display()
+                )
+            })
+    })
+    .await??;
+
+    Ok(report)
+}
+
+// upper bound on reports read at once by parse_dir
+const PARSE_DIR_CONCURRENCY: usize = 16;
+
+// reports that parsed, plus every file that didn't and why
+pub type ParsedDir = (Vec<CrashReport>, Vec<(PathBuf, anyhow::Error)>);
+
+// loads every *.json in a directory, a broken file is logged and collected instead of failing the batch
+pub async fn parse_dir(path: &Path) -> Result<ParsedDir> {
+    let mut entries = tokio::fs::read_dir(path)
+        .await
+        .with_context(|| format!("Failed to read directory {:?}", path))?;
+
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let file = entry.path();
+        if file.extension().is_some_and(|ext| ext == "json") {
+            files.push(file);
+        }
+    }
+    files.sort();
+
+    let permits = Arc::new(Semaphore::new(PARSE_DIR_CONCURRENCY));
+    let mut tasks = JoinSet::new();
+    for (index, file) in files.into_iter().enumerate() {
+        let permits = permits.clone();
+        tasks.spawn(
+            async move {
+                let _permit = permits.acquire_owned().await;
+                let result = parse_file_async(&file).await;
+                (index, file, result)
+            }
+            .in_current_span(),
+        );
+    }
+
+    let mut results = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        results.push(joined?);
+    }
+    // keep directory order stable regardless of which task finished first
+    results.sort_by_key(|(index, _, _)| *index);
+
+    let mut reports = Vec::new();
+    let mut failures = Vec::new();
+    for (_, file, result) in results {
+        match result {
+            Ok(report) => reports.push(report),
+            Err(err) => {
+                warn!("Skipping {}: {:#}", file.display(), err);
+                failures.push((file, err));
+            }
+        }
+    }
+
+    info!(
+        "Parsed {} reports from {}, {} failed",
+        reports.len(),
+        path.display(),
+        failures.len()
+    );
+
+    Ok((reports, failures))
+}
+
+// newline separated report ids/paths, blank lines and `#` comments are skipped
+pub fn parse_report_list(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -42,17 +140,67 @@ mod tests {
     fn test_build_path() {
         let crash_report =
             parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
-        let path = build_path(&crash_report).to_string_lossy().into_owned();
-        assert_eq!(path, "/home/luvciyt/Repo/DumpMindExperimentPlatform/kernel-builder/workspace/0b6b2d6d6cefa8b462930e55be699efba635788f".to_string())
+        let path = build_path(&crash_report);
+        assert_eq!(
+            path,
+            default_workspace()
+                .root()
+                .join("0b6b2d6d6cefa8b462930e55be699efba635788f")
+        );
     }
 
     #[test]
     fn test_kernel_source_path() {
         let crash_report =
             parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
-        let path = kernel_source_path(&crash_report)
-            .to_string_lossy()
-            .into_owned();
-        assert_eq!(path, "/home/luvciyt/Repo/DumpMindExperimentPlatform/kernel-builder/workspace/0b6b2d6d6cefa8b462930e55be699efba635788f/linux-02d5e016800d082058b3d3b7c3ede136cdc6ddcb".to_string())
+        let path = kernel_source_path(&crash_report, 0).unwrap();
+        assert_eq!(
+            path,
+            build_path(&crash_report).join("linux-77076934afdcd46516caf18ed88b2f88025c9ddb")
+        );
+    }
+
+    #[test]
+    fn test_parse_report_list() {
+        let content = "# batch\n0b6b2d6d6cefa8b462930e55be699efba635788f\n\n  datasets/x.json  \n";
+        assert_eq!(
+            parse_report_list(content),
+            vec![
+                "0b6b2d6d6cefa8b462930e55be699efba635788f".to_string(),
+                "datasets/x.json".to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parse_file_async() {
+        let path = "datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json";
+        let report = parse_file_async(Path::new(path)).await.unwrap();
+        assert_eq!(report.id, parse_file(path).unwrap().id);
+
+        assert!(
+            parse_file_async(Path::new("datasets/missing.json"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parse_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::copy(
+            "datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json",
+            dir.path().join("a.json"),
+        )
+        .unwrap();
+        fs::write(dir.path().join("b.json"), "{ not json").unwrap();
+        fs::write(dir.path().join("notes.txt"), "ignored").unwrap();
+
+        let (reports, failures) = parse_dir(dir.path()).await.unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, dir.path().join("b.json"));
+
+        assert!(parse_dir(&dir.path().join("missing")).await.is_err());
     }
 }
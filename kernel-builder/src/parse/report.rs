@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
 
 // crash report struct
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -54,4 +56,343 @@ pub struct Crash {
     pub architecture: String,
     #[serde(rename = "crash-report-link")]
     pub crash_report_link: String,
+    // expected sha256 of the kernel source tarball, not part of syzbot reports
+    #[serde(rename = "kernel-source-sha256", default)]
+    pub sha256: Option<String>,
+}
+
+// which kernel tree an experiment needs: the buggy one or the fixed one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExperimentMode {
+    #[default]
+    Reproduce,
+    VerifyFix,
+}
+
+// which of the report's fix commits to use
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FixSelector {
+    Index(usize),
+    // `repo` matches any fix whose repo url contains it, e.g. "linux-stable"
+    Branch { repo: String, branch: String },
+}
+
+impl Default for FixSelector {
+    fn default() -> Self {
+        FixSelector::Index(0)
+    }
+}
+
+// "<n>" selects the n-th fix commit, "<repo>:<branch>" the fix on that branch
+impl std::str::FromStr for FixSelector {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        if let Ok(index) = s.parse::<usize>() {
+            return Ok(FixSelector::Index(index));
+        }
+        match s.split_once(':') {
+            Some((repo, branch)) if !repo.is_empty() && !branch.is_empty() => {
+                Ok(FixSelector::Branch {
+                    repo: repo.to_string(),
+                    branch: branch.to_string(),
+                })
+            }
+            _ => anyhow::bail!(
+                "Invalid fix selector {:?}, expected <n> or <repo>:<branch>",
+                s
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ReportError {
+    #[error("Report {0} has no crashes")]
+    NoCrash(String),
+    #[error("Report {id} has no crash #{index}, it only has {count}")]
+    CrashNotFound {
+        id: String,
+        index: usize,
+        count: usize,
+    },
+    #[error("Report {0} has no fix commits")]
+    NoFixCommit(String),
+    #[error("Report {id} has no fix commit matching {selector:?}")]
+    FixNotFound { id: String, selector: FixSelector },
+    #[error("Report {id} has an invalid {field} commit: {value:?}")]
+    InvalidCommit {
+        id: String,
+        field: &'static str,
+        value: String,
+    },
+    #[error("Report {id}: parent_of_fix_commit {commit} is itself a fix commit")]
+    ParentIsFix { id: String, commit: String },
+}
+
+fn is_commit_hash(s: &str) -> bool {
+    s.len() == 40 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+impl CrashReport {
+    // the crash every stage builds and reproduces, syzbot lists the most relevant one first
+    pub fn primary_crash(&self) -> Result<&Crash, ReportError> {
+        self.crash(0)
+    }
+
+    // a report can list the same bug hit with several configs and reproducers
+    pub fn crash(&self, index: usize) -> Result<&Crash, ReportError> {
+        if self.crashes.is_empty() {
+            return Err(ReportError::NoCrash(self.id.clone()));
+        }
+
+        self.crashes
+            .get(index)
+            .ok_or_else(|| ReportError::CrashNotFound {
+                id: self.id.clone(),
+                index,
+                count: self.crashes.len(),
+            })
+    }
+
+    // check that the commits the report carries are usable and consistent with each other
+    pub fn validate_commits(&self) -> Result<(), ReportError> {
+        if !self.parent_of_fix_commit.is_empty() && !is_commit_hash(&self.parent_of_fix_commit) {
+            return Err(ReportError::InvalidCommit {
+                id: self.id.clone(),
+                field: "parent_of_fix_commit",
+                value: self.parent_of_fix_commit.clone(),
+            });
+        }
+
+        for fix in &self.fix_commits {
+            if !is_commit_hash(&fix.hash) {
+                return Err(ReportError::InvalidCommit {
+                    id: self.id.clone(),
+                    field: "fix",
+                    value: fix.hash.clone(),
+                });
+            }
+            if fix.hash == self.parent_of_fix_commit {
+                return Err(ReportError::ParentIsFix {
+                    id: self.id.clone(),
+                    commit: fix.hash.clone(),
+                });
+            }
+        }
+
+        for crash in &self.crashes {
+            let commit = &crash.kernel_source_commit;
+            if !is_commit_hash(commit) {
+                return Err(ReportError::InvalidCommit {
+                    id: self.id.clone(),
+                    field: "kernel_source_commit",
+                    value: commit.clone(),
+                });
+            }
+
+            // the crash was observed on a tree that already contains the fix
+            if self.fix_commits.iter().any(|fix| &fix.hash == commit) {
+                warn!(
+                    "Report {}: kernel_source_commit {} is a fix commit, the crashing tree already contains the fix",
+                    self.id, commit
+                );
+            } else if !self.parent_of_fix_commit.is_empty() && *commit != self.parent_of_fix_commit
+            {
+                warn!(
+                    "Report {}: kernel_source_commit {} differs from parent_of_fix_commit {}",
+                    self.id, commit, self.parent_of_fix_commit
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    // pick one of the fix commits, a bug can be fixed separately on several branches
+    pub fn fix_commit(&self, selector: &FixSelector) -> Result<&FixCommit, ReportError> {
+        if self.fix_commits.is_empty() {
+            return Err(ReportError::NoFixCommit(self.id.clone()));
+        }
+
+        let fix = match selector {
+            FixSelector::Index(index) => self.fix_commits.get(*index),
+            FixSelector::Branch { repo, branch } => self
+                .fix_commits
+                .iter()
+                .find(|fix| fix.repo.contains(repo.as_str()) && fix.branch == *branch),
+        };
+
+        fix.ok_or_else(|| ReportError::FixNotFound {
+            id: self.id.clone(),
+            selector: selector.clone(),
+        })
+    }
+
+    // the tree crash `crash_index` is reproduced on, without validating the report: the parent of
+    // the fix, or the crash's kernel_source_commit when the report carries no parent commit
+    pub fn reproduce_commit(&self, crash_index: usize) -> Result<&str, ReportError> {
+        let crash = self.crash(crash_index)?;
+        if !self.parent_of_fix_commit.is_empty() {
+            return Ok(&self.parent_of_fix_commit);
+        }
+        Ok(&crash.kernel_source_commit)
+    }
+
+    // the commit of `mode` for crash `crash_index` without validating the report, see
+    // build_commit_with_fix
+    pub fn experiment_commit(
+        &self,
+        mode: ExperimentMode,
+        fix: &FixSelector,
+        crash_index: usize,
+    ) -> Result<&str, ReportError> {
+        match mode {
+            ExperimentMode::Reproduce => self.reproduce_commit(crash_index),
+            ExperimentMode::VerifyFix => {
+                self.crash(crash_index)?;
+                self.fix_commit(fix).map(|fix| fix.hash.as_str())
+            }
+        }
+    }
+
+    // commit to build for crash `crash_index` in the given experiment mode.
+    // reproducing builds the parent of the fix so that the buggy and fixed kernels only differ by
+    // the fix itself; kernel_source_commit is only used when the report carries no parent commit.
+    pub fn build_commit(
+        &self,
+        mode: ExperimentMode,
+        crash_index: usize,
+    ) -> Result<&str, ReportError> {
+        self.build_commit_with_fix(mode, &FixSelector::default(), crash_index)
+    }
+
+    // like `build_commit`, verifying the fix builds the fix commit chosen by `fix`
+    pub fn build_commit_with_fix(
+        &self,
+        mode: ExperimentMode,
+        fix: &FixSelector,
+        crash_index: usize,
+    ) -> Result<&str, ReportError> {
+        self.validate_commits()?;
+
+        let commit = self.experiment_commit(mode, fix, crash_index)?;
+        if mode == ExperimentMode::Reproduce && self.parent_of_fix_commit.is_empty() {
+            warn!(
+                "Report {} has no parent_of_fix_commit, falling back to kernel_source_commit {}",
+                self.id, commit
+            );
+        }
+        Ok(commit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse::parse_file;
+
+    #[test]
+    fn test_build_commit() {
+        let crash_report =
+            parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
+        assert_eq!(
+            crash_report
+                .build_commit(ExperimentMode::Reproduce, 0)
+                .unwrap(),
+            "77076934afdcd46516caf18ed88b2f88025c9ddb"
+        );
+        assert_eq!(
+            crash_report
+                .build_commit(ExperimentMode::VerifyFix, 0)
+                .unwrap(),
+            "68a3765c659f809dcaac20030853a054646eb739"
+        );
+    }
+
+    #[test]
+    fn test_reproduce_commit_fallback() {
+        let mut crash_report =
+            parse_file("datasets/2ebf4e2ffdaf022d2aac190c391ecb56689b6fc4.json").unwrap();
+        crash_report.parent_of_fix_commit.clear();
+        let mut second = crash_report.crashes[0].clone();
+        second.kernel_source_commit = "1".repeat(40);
+        crash_report.crashes.push(second);
+
+        assert_eq!(
+            crash_report
+                .build_commit(ExperimentMode::Reproduce, 1)
+                .unwrap(),
+            "1".repeat(40)
+        );
+        assert!(matches!(
+            crash_report.build_commit(ExperimentMode::Reproduce, 2),
+            Err(ReportError::CrashNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_parent_is_fix() {
+        let mut crash_report =
+            parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
+        crash_report.parent_of_fix_commit = crash_report.fix_commits[0].hash.clone();
+        assert!(matches!(
+            crash_report.build_commit(ExperimentMode::Reproduce, 0),
+            Err(ReportError::ParentIsFix { .. })
+        ));
+    }
+
+    #[test]
+    fn test_select_fix_commit() {
+        let crash_report =
+            parse_file("datasets/2ebf4e2ffdaf022d2aac190c391ecb56689b6fc4.json").unwrap();
+        let second = crash_report.fix_commit(&FixSelector::Index(1)).unwrap();
+        assert_eq!(
+            crash_report
+                .build_commit_with_fix(ExperimentMode::VerifyFix, &FixSelector::Index(1), 0)
+                .unwrap(),
+            second.hash
+        );
+
+        let selector = FixSelector::Branch {
+            repo: "torvalds/linux".to_string(),
+            branch: "master".to_string(),
+        };
+        assert_eq!(
+            crash_report.fix_commit(&selector).unwrap().hash,
+            crash_report.fix_commits[0].hash
+        );
+        assert!(matches!(
+            crash_report.fix_commit(&FixSelector::Index(2)),
+            Err(ReportError::FixNotFound { .. })
+        ));
+
+        assert_eq!("1".parse::<FixSelector>().unwrap(), FixSelector::Index(1));
+        assert_eq!(
+            "torvalds/linux:master".parse::<FixSelector>().unwrap(),
+            selector
+        );
+        assert!("master".parse::<FixSelector>().is_err());
+    }
+
+    #[test]
+    fn test_primary_crash() {
+        let mut crash_report =
+            parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
+        assert_eq!(
+            crash_report.primary_crash().unwrap().kernel_source_commit,
+            "02d5e016800d082058b3d3b7c3ede136cdc6ddcb"
+        );
+
+        assert!(matches!(
+            crash_report.crash(crash_report.crashes.len()),
+            Err(ReportError::CrashNotFound { .. })
+        ));
+
+        crash_report.crashes.clear();
+        assert!(matches!(
+            crash_report.primary_crash(),
+            Err(ReportError::NoCrash(_))
+        ));
+    }
 }
@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::path::Path;
+
+// one call of a syzkaller program, e.g.
+// `r0 = openat$dir(0xffffffffffffff9c, &(0x7f0000000000)='./file0\x00', 0x0, 0x0) (async)`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyzCall {
+    // the resource the call's result is bound to, `r0`
+    pub result: Option<String>,
+    // full call name including the syzkaller specialisation, `openat$dir`
+    pub name: String,
+    // the argument list as written, without the outer parentheses
+    pub args: String,
+    // call properties such as `async` or `fail_nth: 1`
+    pub props: Option<String>,
+}
+
+impl SyzCall {
+    // the kernel syscall behind the call, `openat` for `openat$dir`
+    pub fn syscall(&self) -> &str {
+        self.name.split('$').next().unwrap_or(&self.name)
+    }
+}
+
+// a syz reproducer as served by syzbot (ReproSyz): an optional options comment followed by
+// one call per line
+#[derive(Debug, Clone, Default)]
+pub struct SyzProgram {
+    // the `#{"threaded":true,...}` header, passed to syz-execprog as is
+    pub options: Option<String>,
+    pub calls: Vec<SyzCall>,
+}
+
+impl SyzProgram {
+    pub fn parse(program: &str) -> Result<SyzProgram> {
+        static CALL: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"^(?:(?P<result>r\d+) = )?(?P<name>[A-Za-z0-9_$]+)\((?P<args>.*)\)$")
+                .unwrap()
+        });
+        static PROPS: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"\) \((?P<props>[a-z_]+(?:: \d+)?(?:, [a-z_]+(?:: \d+)?)*)\)$").unwrap()
+        });
+
+        let mut parsed = SyzProgram::default();
+        for (index, line) in program.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some(comment) = line.strip_prefix('#') {
+                // syzbot puts the bug link and a note above the options, which are the first
+                // JSON object before any call
+                let comment = comment.trim();
+                if parsed.calls.is_empty() && parsed.options.is_none() && comment.starts_with('{') {
+                    parsed.options = Some(comment.to_string());
+                }
+                continue;
+            }
+
+            let (line, props) = match PROPS.captures(line) {
+                Some(captures) => {
+                    let props = captures.name("props").unwrap();
+                    // keep the call's own closing parenthesis
+                    (&line[..props.start() - 2], Some(props.as_str().to_string()))
+                }
+                None => (line, None),
+            };
+            let captures = CALL
+                .captures(line)
+                .with_context(|| format!("Line {} is not a syzkaller call: {}", index + 1, line))?;
+            parsed.calls.push(SyzCall {
+                result: captures.name("result").map(|m| m.as_str().to_string()),
+                name: captures["name"].to_string(),
+                args: captures["args"].to_string(),
+                props,
+            });
+        }
+
+        if parsed.calls.is_empty() {
+            anyhow::bail!("syzkaller program has no calls");
+        }
+        Ok(parsed)
+    }
+
+    pub async fn from_file(path: &Path) -> Result<SyzProgram> {
+        let program = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read syz reproducer {}", path.display()))?;
+        SyzProgram::parse(&program)
+            .with_context(|| format!("Failed to parse syz reproducer {}", path.display()))
+    }
+
+    // distinct kernel syscalls in call order
+    pub fn syscalls(&self) -> Vec<&str> {
+        let mut syscalls: Vec<&str> = Vec::new();
+        for call in &self.calls {
+            if !syscalls.contains(&call.syscall()) {
+                syscalls.push(call.syscall());
+            }
+        }
+        syscalls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_syz_program() {
+        let program = r#"# {"threaded":true,"repeat":true,"procs":1,"sandbox":"none"}
+r0 = openat$dir(0xffffffffffffff9c, &(0x7f0000000000)='./file0\x00', 0x0, 0x0)
+mkdirat(r0, &(0x7f0000000040)='./file1\x00', 0x1ff) (async)
+ioctl$FS_IOC_SETFLAGS(r0, 0x40086602, &(0x7f0000000080)=0x10) (fail_nth: 3, rerun: 4)
+# trailing comment
+openat(0xffffffffffffff9c, &(0x7f00000000c0)='./file1/(x)\x00', 0x0, 0x0)
+"#;
+        let parsed = SyzProgram::parse(program).unwrap();
+        assert_eq!(
+            parsed.options.as_deref(),
+            Some(r#"{"threaded":true,"repeat":true,"procs":1,"sandbox":"none"}"#)
+        );
+        assert_eq!(parsed.calls.len(), 4);
+
+        let first = &parsed.calls[0];
+        assert_eq!(first.result.as_deref(), Some("r0"));
+        assert_eq!(first.name, "openat$dir");
+        assert_eq!(first.syscall(), "openat");
+        assert_eq!(
+            first.args,
+            r"0xffffffffffffff9c, &(0x7f0000000000)='./file0\x00', 0x0, 0x0"
+        );
+        assert_eq!(first.props, None);
+
+        assert_eq!(parsed.calls[1].props.as_deref(), Some("async"));
+        assert_eq!(
+            parsed.calls[1].args,
+            r"r0, &(0x7f0000000040)='./file1\x00', 0x1ff"
+        );
+        assert_eq!(
+            parsed.calls[2].props.as_deref(),
+            Some("fail_nth: 3, rerun: 4")
+        );
+        assert_eq!(parsed.calls[3].props, None);
+
+        assert_eq!(parsed.syscalls(), vec!["openat", "mkdirat", "ioctl"]);
+    }
+
+    #[test]
+    fn test_parse_syz_program_errors() {
+        assert!(SyzProgram::parse("").is_err());
+        assert!(SyzProgram::parse("# {\"threaded\":true}\n").is_err());
+        let err = SyzProgram::parse("openat(0x0)\nnot a call\n").unwrap_err();
+        assert!(err.to_string().contains("Line 2"));
+    }
+
+    #[test]
+    fn test_parse_syzbot_header() {
+        let program = r#"# https://syzkaller.appspot.com/bug?id=0b6b2d6d6cefa8b462930e55be699efba635788f
+# See https://goo.gl/kgGztJ for information about syzkaller reproducers.
+#{"threaded":true,"repeat":true,"procs":6,"slowdown":1,"sandbox":"none","sandbox_arg":0,"tun":true}
+r0 = socket$inet6_tcp(0xa, 0x1, 0x0)
+close(r0)
+"#;
+        let parsed = SyzProgram::parse(program).unwrap();
+        assert_eq!(
+            parsed.options.as_deref(),
+            Some(
+                r#"{"threaded":true,"repeat":true,"procs":6,"slowdown":1,"sandbox":"none","sandbox_arg":0,"tun":true}"#
+            )
+        );
+        assert_eq!(parsed.syscalls(), vec!["socket", "close"]);
+
+        let no_options = SyzProgram::parse("# See https://goo.gl/kgGztJ\nclose(0x3)\n").unwrap();
+        assert_eq!(no_options.options, None);
+    }
+}
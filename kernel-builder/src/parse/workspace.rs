@@ -0,0 +1,103 @@
+use crate::config::config::{Config, WorkspaceConfig};
+use crate::parse::report::CrashReport;
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use std::env;
+use std::path::{Path, PathBuf};
+
+// set once at startup from --workspace or settings.toml, the hardcoded root until then
+static DEFAULT_WORKSPACE: OnceCell<Workspace> = OnceCell::new();
+
+// root directory holding workspace/<id> build directories and the shared .cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Workspace {
+    root: PathBuf,
+}
+
+impl Workspace {
+    // relative roots are resolved against the current directory right away, so that a later
+    // chdir (or a child process with another cwd) still sees the same paths
+    pub fn new(root: impl AsRef<Path>) -> Result<Workspace> {
+        let root = root.as_ref();
+        let root = if root.is_absolute() {
+            root.to_path_buf()
+        } else {
+            env::current_dir()
+                .context("Failed to resolve the current directory")?
+                .join(root)
+        };
+        Ok(Workspace { root })
+    }
+
+    pub fn from_config(config: &WorkspaceConfig) -> Result<Workspace> {
+        Workspace::new(&config.root)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn build_path(&self, report: &CrashReport) -> PathBuf {
+        self.root.join(&report.id)
+    }
+
+    pub fn kernel_source_path(&self, report: &CrashReport, crash_index: usize) -> Result<PathBuf> {
+        let commit = report.reproduce_commit(crash_index)?;
+        Ok(self.build_path(report).join(format!("linux-{}", commit)))
+    }
+
+    // source tarballs and trees shared by every report, keyed by commit
+    pub fn cache_path(&self) -> PathBuf {
+        self.root.join(".cache")
+    }
+}
+
+// make `workspace` the one used by the free path functions, must run before any of them
+pub fn set_default_workspace(workspace: Workspace) -> Result<()> {
+    DEFAULT_WORKSPACE
+        .set(workspace)
+        .map_err(|_| anyhow::anyhow!("The default workspace is already set"))
+}
+
+// the hardcoded workspace unless main set the configured one
+pub fn default_workspace() -> &'static Workspace {
+    DEFAULT_WORKSPACE.get_or_init(|| {
+        let config = Config::default().workspace;
+        Workspace::from_config(&config).expect("Failed to resolve the workspace root")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse::parse_file;
+
+    #[test]
+    fn test_workspace_paths() {
+        let crash_report =
+            parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
+        let workspace = Workspace::new("/srv/experiments").unwrap();
+
+        assert_eq!(
+            workspace.build_path(&crash_report),
+            PathBuf::from("/srv/experiments/0b6b2d6d6cefa8b462930e55be699efba635788f")
+        );
+        assert_eq!(
+            workspace.kernel_source_path(&crash_report, 0).unwrap(),
+            PathBuf::from(
+                "/srv/experiments/0b6b2d6d6cefa8b462930e55be699efba635788f/linux-77076934afdcd46516caf18ed88b2f88025c9ddb"
+            )
+        );
+        assert_eq!(
+            workspace.cache_path(),
+            PathBuf::from("/srv/experiments/.cache")
+        );
+    }
+
+    #[test]
+    fn test_relative_root() {
+        let workspace = Workspace::new("ws").unwrap();
+        assert!(workspace.root().is_absolute());
+        assert_eq!(workspace.root(), env::current_dir().unwrap().join("ws"));
+    }
+}
@@ -0,0 +1,761 @@
+use crate::kernel::compile::{BuildOptions, make_kernel};
+use crate::kernel::download::{
+    OverwritePolicy, download_bug, download_config, download_kernel, download_syz_reproducer,
+};
+use crate::kernel::modify::{ConfigFixReport, ConfigUnsatisfied, check_fix_config};
+use crate::parse::compiler::Compiler;
+use crate::parse::layout::Layout;
+use crate::parse::report::CrashReport;
+use crate::parse::syz::SyzProgram;
+use crate::script::script::mount;
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use serde_with::{DisplayFromStr, DurationMilliSeconds, TimestampSeconds, serde_as};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::fs;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span, error, info, info_span, warn};
+
+// every log line of a crash's pipeline carries the report id and kernel commit, so the
+// output of reports processed side by side can be told apart
+pub fn report_span(report: &CrashReport, crash_index: usize) -> Span {
+    let commit = report
+        .reproduce_commit(crash_index)
+        .map(str::to_string)
+        .unwrap_or_default();
+    info_span!("report", id = %report.id, crash = crash_index, %commit)
+}
+
+// the steps from a syzbot report to a mounted kernel build, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    DownloadKernel,
+    DownloadBug,
+    DownloadConfig,
+    FixConfig,
+    Build,
+    Mount,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 6] = [
+        Stage::DownloadKernel,
+        Stage::DownloadBug,
+        Stage::DownloadConfig,
+        Stage::FixConfig,
+        Stage::Build,
+        Stage::Mount,
+    ];
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stage::DownloadKernel => write!(f, "download-kernel"),
+            Stage::DownloadBug => write!(f, "download-bug"),
+            Stage::DownloadConfig => write!(f, "download-config"),
+            Stage::FixConfig => write!(f, "fix-config"),
+            Stage::Build => write!(f, "build"),
+            Stage::Mount => write!(f, "mount"),
+        }
+    }
+}
+
+impl std::str::FromStr for Stage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Stage::ALL
+            .into_iter()
+            .find(|stage| stage.to_string() == s)
+            .ok_or_else(|| {
+                let names: Vec<String> = Stage::ALL.iter().map(Stage::to_string).collect();
+                anyhow::anyhow!(
+                    "Unknown stage {:?}, expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+// stages completed by earlier runs, kept in the crash's .state.json (see Layout) so a restarted
+// pipeline picks up where the last one stopped. only valid for the crash and commit it was
+// written for
+#[serde_as]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    crash_index: usize,
+    commit: String,
+    #[serde_as(as = "BTreeMap<_, TimestampSeconds<i64>>")]
+    completed: BTreeMap<Stage, SystemTime>,
+}
+
+impl Checkpoint {
+    pub fn new(crash_index: usize, commit: &str) -> Checkpoint {
+        Checkpoint {
+            crash_index,
+            commit: commit.to_string(),
+            completed: BTreeMap::new(),
+        }
+    }
+
+    // the checkpoint at `path`, or an empty one if there is none for this crash
+    pub async fn load(path: &Path, crash_index: usize, commit: &str) -> Result<Checkpoint> {
+        if !fs::try_exists(path).await? {
+            return Ok(Checkpoint::new(crash_index, commit));
+        }
+
+        let content = fs::read_to_string(path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        match serde_json::from_str::<Checkpoint>(&content) {
+            Ok(checkpoint)
+                if checkpoint.crash_index == crash_index && checkpoint.commit == commit =>
+            {
+                Ok(checkpoint)
+            }
+            Ok(_) => {
+                info!(
+                    "{} belongs to another crash, starting from scratch",
+                    path.display()
+                );
+                Ok(Checkpoint::new(crash_index, commit))
+            }
+            Err(e) => {
+                warn!("Ignoring unreadable {}: {}", path.display(), e);
+                Ok(Checkpoint::new(crash_index, commit))
+            }
+        }
+    }
+
+    // write through a temporary file so a crash never leaves a truncated checkpoint
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_string_pretty(self)?)
+            .await
+            .with_context(|| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn completed_at(&self, stage: Stage) -> Option<SystemTime> {
+        self.completed.get(&stage).copied()
+    }
+
+    pub fn complete(&mut self, stage: Stage) {
+        self.completed.insert(stage, SystemTime::now());
+    }
+
+    // forget `stage` and everything after it, which was built on its output
+    pub fn invalidate_from(&mut self, stage: Stage) {
+        self.completed.retain(|done, _| *done < stage);
+    }
+}
+
+// serialized as {"status": "failed", "detail": "<error>"}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", content = "detail", rename_all = "snake_case")]
+pub enum StageStatus {
+    Succeeded,
+    // not selected, already done, or not reached because an earlier stage stopped the run
+    Skipped(String),
+    Failed(String),
+}
+
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct StageOutcome {
+    pub stage: Stage,
+    #[serde(flatten)]
+    pub status: StageStatus,
+    // zero for stages that did not run
+    #[serde_as(as = "DurationMilliSeconds<u64>")]
+    #[serde(rename = "duration_ms")]
+    pub duration: Duration,
+}
+
+// what happened to each stage of one run, in stage order. written to the crash's result.json
+#[serde_as]
+#[derive(Debug, Serialize)]
+pub struct PipelineResult {
+    pub report_id: String,
+    pub crash_index: usize,
+    pub stages: Vec<StageOutcome>,
+    // the compiler the report asks for and the one the build used, set when the build stage ran
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_compiler: Option<Compiler>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual_compiler: Option<Compiler>,
+    // set when the fix-config stage ran
+    #[serde(skip)]
+    pub config_fix: Option<ConfigFixReport>,
+}
+
+impl PipelineResult {
+    fn new(report_id: &str, crash_index: usize) -> PipelineResult {
+        PipelineResult {
+            report_id: report_id.to_string(),
+            crash_index,
+            stages: Vec::new(),
+            requested_compiler: None,
+            actual_compiler: None,
+            config_fix: None,
+        }
+    }
+
+    pub fn status(&self, stage: Stage) -> Option<&StageStatus> {
+        self.stages
+            .iter()
+            .find(|outcome| outcome.stage == stage)
+            .map(|outcome| &outcome.status)
+    }
+
+    // no stage failed
+    pub fn succeeded(&self) -> bool {
+        !self
+            .stages
+            .iter()
+            .any(|outcome| matches!(outcome.status, StageStatus::Failed(_)))
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .await
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    fn record(&mut self, stage: Stage, status: StageStatus, duration: Duration) {
+        match &status {
+            StageStatus::Succeeded => info!("Stage {} succeeded in {:?}", stage, duration),
+            StageStatus::Skipped(reason) => info!("Stage {} skipped: {}", stage, reason),
+            StageStatus::Failed(reason) => error!("Stage {} failed: {}", stage, reason),
+        }
+        self.stages.push(StageOutcome {
+            stage,
+            status,
+            duration,
+        });
+    }
+}
+
+// how a batch run shares the machine: builds are cpu and memory bound, downloads network bound
+struct StageLimits {
+    builds: Semaphore,
+    downloads: Semaphore,
+}
+
+// per report outcome of run_batch, ordered by report id
+#[derive(Debug)]
+pub struct BatchSummary {
+    pub results: Vec<(String, Result<PipelineResult>)>,
+}
+
+impl BatchSummary {
+    // reports whose pipeline ran without a failed stage
+    pub fn succeeded(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|(_, result)| result.as_ref().is_ok_and(PipelineResult::succeeded))
+            .count()
+    }
+
+    pub fn failed(&self) -> Vec<&str> {
+        self.results
+            .iter()
+            .filter(|(_, result)| !result.as_ref().is_ok_and(PipelineResult::succeeded))
+            .map(|(id, _)| id.as_str())
+            .collect()
+    }
+}
+
+// runs the selected stages for one crash of a report. a failed stage does not stop the
+// later ones, except for a config that cannot satisfy kernel.toml and cancellation.
+// stages recorded in the checkpoint are skipped unless forced
+pub struct Pipeline {
+    stages: BTreeSet<Stage>,
+    options: BuildOptions,
+    force: BTreeSet<Stage>,
+    resume: bool,
+}
+
+#[derive(Default)]
+pub struct PipelineBuilder {
+    stages: Option<BTreeSet<Stage>>,
+    options: Option<BuildOptions>,
+    force: BTreeSet<Stage>,
+    resume: Option<bool>,
+}
+
+impl PipelineBuilder {
+    // run only `stages`, all of them by default
+    pub fn stages<I: IntoIterator<Item = Stage>>(mut self, stages: I) -> Self {
+        self.stages = Some(stages.into_iter().collect());
+        self
+    }
+    // drop every selected stage after `last`
+    pub fn until(mut self, last: Stage) -> Self {
+        let stages = self.stages.get_or_insert_with(|| Stage::ALL.into());
+        stages.retain(|stage| *stage <= last);
+        self
+    }
+    pub fn skip(mut self, stage: Stage) -> Self {
+        self.stages
+            .get_or_insert_with(|| Stage::ALL.into())
+            .remove(&stage);
+        self
+    }
+    // crash index, dry run and cancellation for every stage
+    pub fn options(mut self, options: BuildOptions) -> Self {
+        self.options = Some(options);
+        self
+    }
+    // run `stage` even if the checkpoint has it, the stages after it run again as well
+    pub fn force(mut self, stage: Stage) -> Self {
+        self.force.insert(stage);
+        self
+    }
+    // skip the stages completed by an earlier run, on by default
+    pub fn resume(mut self, enable: bool) -> Self {
+        self.resume = Some(enable);
+        self
+    }
+    pub fn build(self) -> Pipeline {
+        Pipeline {
+            stages: self.stages.unwrap_or_else(|| Stage::ALL.into()),
+            options: self.options.unwrap_or_default(),
+            force: self.force,
+            resume: self.resume.unwrap_or(true),
+        }
+    }
+}
+
+// the syz program is a bonus next to the C reproducer, failing to get it only warns
+async fn fetch_syz_reproducer(
+    report: &Arc<CrashReport>,
+    crash_index: usize,
+    cancel: &CancellationToken,
+) {
+    let path = match download_syz_reproducer(report, crash_index, cancel).await {
+        Ok(Some(path)) => path,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("No syz reproducer for report {}: {:#}", report.id, e);
+            return;
+        }
+    };
+    match SyzProgram::from_file(&path).await {
+        Ok(program) => info!(
+            "Syz reproducer has {} calls ({})",
+            program.calls.len(),
+            program.syscalls().join(", ")
+        ),
+        Err(e) => warn!("{:#}", e),
+    }
+}
+
+fn status(result: Result<()>) -> StageStatus {
+    match result {
+        Ok(()) => StageStatus::Succeeded,
+        Err(e) => StageStatus::Failed(format!("{:#}", e)),
+    }
+}
+
+impl Pipeline {
+    pub fn builder() -> PipelineBuilder {
+        PipelineBuilder::default()
+    }
+
+    pub fn stages(&self) -> &BTreeSet<Stage> {
+        &self.stages
+    }
+
+    pub async fn run(&self, report: &Arc<CrashReport>) -> Result<PipelineResult> {
+        self.run_limited(report, None).await
+    }
+
+    // run the pipeline for every report, with at most `max_parallel` kernels building at once
+    // and download.max_concurrent_downloads reports downloading. a report keeps its download
+    // slot until it gets a build slot, so hundreds of them don't fill the disk with source trees
+    // waiting for a build
+    pub async fn run_batch(
+        &self,
+        reports: Vec<Arc<CrashReport>>,
+        max_parallel: usize,
+    ) -> BatchSummary {
+        let limits = StageLimits {
+            builds: Semaphore::new(max_parallel.max(1)),
+            downloads: Semaphore::new(self.options.config.download.max_concurrent_downloads),
+        };
+        let in_flight = limits.builds.available_permits() + limits.downloads.available_permits();
+
+        let mut results: Vec<(String, Result<PipelineResult>)> = futures::stream::iter(reports)
+            .map(|report| {
+                let limits = &limits;
+                let span = report_span(&report, self.options.crash_index);
+                async move {
+                    let result = self.run_limited(&report, Some(limits)).await;
+                    (report.id.clone(), result)
+                }
+                .instrument(span)
+            })
+            .buffer_unordered(in_flight)
+            .collect()
+            .await;
+        results.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let summary = BatchSummary { results };
+        info!(
+            "Batch finished: {} of {} reports succeeded",
+            summary.succeeded(),
+            summary.results.len()
+        );
+        summary
+    }
+
+    async fn run_limited(
+        &self,
+        report: &Arc<CrashReport>,
+        limits: Option<&StageLimits>,
+    ) -> Result<PipelineResult> {
+        // also fails early on a bad crash index instead of in every stage
+        let layout = Layout::for_crash(report, self.options.crash_index)?;
+        let result = self
+            .run_with_checkpoint(report, &layout.state_path(), limits)
+            .await?;
+
+        // the workspace only exists once a stage created it
+        if fs::try_exists(layout.root()).await?
+            && let Err(e) = result.save(&layout.result_path()).await
+        {
+            warn!("Failed to save the pipeline result: {:#}", e);
+        }
+        Ok(result)
+    }
+
+    async fn run_with_checkpoint(
+        &self,
+        report: &Arc<CrashReport>,
+        state_path: &Path,
+        limits: Option<&StageLimits>,
+    ) -> Result<PipelineResult> {
+        let crash_index = self.options.crash_index;
+        let cancel = &self.options.cancel;
+        let commit = self.options.build_commit(report)?;
+
+        let mut checkpoint = if self.resume {
+            Checkpoint::load(state_path, crash_index, commit).await?
+        } else {
+            Checkpoint::new(crash_index, commit)
+        };
+        if let Some(first) = self.force.first() {
+            checkpoint.invalidate_from(*first);
+        }
+        // a dry run neither builds anything nor may it claim to have
+        let record = !self.options.dry_run;
+
+        let mut result = PipelineResult::new(&report.id, crash_index);
+        let mut stopped: Option<String> = None;
+        // the download slot is only given back once the build slot is held, see run_batch
+        let mut download_permit = None;
+
+        for stage in Stage::ALL {
+            if !self.stages.contains(&stage) {
+                result.record(
+                    stage,
+                    StageStatus::Skipped("not selected".to_string()),
+                    Duration::ZERO,
+                );
+                continue;
+            }
+            if cancel.is_cancelled() && stopped.is_none() {
+                stopped = Some("cancelled".to_string());
+            }
+            if let Some(reason) = &stopped {
+                result.record(stage, StageStatus::Skipped(reason.clone()), Duration::ZERO);
+                continue;
+            }
+            if checkpoint.completed_at(stage).is_some() {
+                let reason = format!("completed by an earlier run, use --force {} to redo", stage);
+                result.record(stage, StageStatus::Skipped(reason), Duration::ZERO);
+                continue;
+            }
+
+            let _build_permit = match (limits, stage) {
+                (
+                    Some(limits),
+                    Stage::DownloadKernel | Stage::DownloadBug | Stage::DownloadConfig,
+                ) => {
+                    if download_permit.is_none() {
+                        download_permit = Some(limits.downloads.acquire().await?);
+                    }
+                    None
+                }
+                (Some(limits), Stage::Build) => {
+                    let permit = limits.builds.acquire().await?;
+                    download_permit = None;
+                    Some(permit)
+                }
+                _ => None,
+            };
+
+            // a forced download replaces what an earlier run left behind
+            let overwrite = if self.force.first().is_some_and(|first| stage >= *first) {
+                OverwritePolicy::Overwrite
+            } else {
+                OverwritePolicy::Skip
+            };
+
+            info!("Running stage {}", stage);
+            let started = Instant::now();
+            let status = match stage {
+                Stage::DownloadKernel => {
+                    status(download_kernel(report, &self.options, overwrite).await)
+                }
+                Stage::DownloadBug => {
+                    fetch_syz_reproducer(report, crash_index, cancel).await;
+                    status(download_bug(report, crash_index, overwrite, cancel).await)
+                }
+                Stage::DownloadConfig => {
+                    status(download_config(report, crash_index, overwrite, cancel).await)
+                }
+                Stage::FixConfig => match check_fix_config(report, &self.options).await {
+                    Ok(fix_report) => {
+                        info!(
+                            "fixed {} configs, added {}",
+                            fix_report.changed.len(),
+                            fix_report.added.len()
+                        );
+                        let diff = &fix_report.olddefconfig;
+                        if !diff.is_empty() {
+                            info!(
+                                "olddefconfig altered {} config symbols",
+                                diff.added.len() + diff.removed.len() + diff.changed.len()
+                            );
+                        }
+                        result.config_fix = Some(fix_report);
+                        StageStatus::Succeeded
+                    }
+                    Err(e) if e.is::<ConfigUnsatisfied>() => {
+                        // building a kernel without the requested debug options is a waste of time
+                        stopped = Some(format!("{} failed", stage));
+                        StageStatus::Failed(e.to_string())
+                    }
+                    Err(e) => StageStatus::Failed(format!("{:#}", e)),
+                },
+                Stage::Build => match make_kernel(report, &self.options).await {
+                    Ok(artifacts) => {
+                        result.requested_compiler = artifacts.requested_compiler;
+                        result.actual_compiler = artifacts.actual_compiler;
+                        StageStatus::Succeeded
+                    }
+                    Err(e) => StageStatus::Failed(format!("{:#}", e)),
+                },
+                Stage::Mount => {
+                    if self.options.dry_run {
+                        StageStatus::Skipped("dry run".to_string())
+                    } else {
+                        status(mount(report, crash_index).await)
+                    }
+                }
+            };
+
+            if record && matches!(status, StageStatus::Succeeded) {
+                checkpoint.complete(stage);
+                if let Err(e) = checkpoint.save(state_path).await {
+                    warn!("Failed to save the pipeline checkpoint: {:#}", e);
+                }
+            }
+            result.record(stage, status, started.elapsed());
+        }
+
+        if !result.succeeded() {
+            warn!("Pipeline for report {} finished with failures", report.id);
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parse::parse::parse_file;
+
+    #[test]
+    fn test_builder_stages() {
+        let pipeline = Pipeline::builder().build();
+        assert_eq!(pipeline.stages().len(), Stage::ALL.len());
+
+        let pipeline = Pipeline::builder()
+            .until(Stage::FixConfig)
+            .skip(Stage::DownloadKernel)
+            .build();
+        assert_eq!(
+            pipeline.stages().iter().copied().collect::<Vec<_>>(),
+            vec![Stage::DownloadBug, Stage::DownloadConfig, Stage::FixConfig]
+        );
+
+        let pipeline = Pipeline::builder()
+            .stages([Stage::Mount, Stage::Build])
+            .until(Stage::Build)
+            .build();
+        assert_eq!(
+            pipeline.stages().iter().copied().collect::<Vec<_>>(),
+            vec![Stage::Build]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run_records_every_stage() {
+        let report =
+            Arc::new(parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap());
+
+        let result = Pipeline::builder()
+            .stages([])
+            .build()
+            .run(&report)
+            .await
+            .unwrap();
+        assert_eq!(result.stages.len(), Stage::ALL.len());
+        assert!(result.succeeded());
+        assert_eq!(
+            result.status(Stage::Build),
+            Some(&StageStatus::Skipped("not selected".to_string()))
+        );
+
+        let options = BuildOptions {
+            crash_index: report.crashes.len(),
+            ..BuildOptions::default()
+        };
+        assert!(
+            Pipeline::builder()
+                .options(options)
+                .build()
+                .run(&report)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint_skips_download() {
+        let report =
+            Arc::new(parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap());
+        let commit = BuildOptions::default().build_commit(&report).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join(".state.json");
+
+        let mut checkpoint = Checkpoint::new(0, commit);
+        checkpoint.complete(Stage::DownloadKernel);
+        checkpoint.save(&state_path).await.unwrap();
+
+        // the stage would go to the network if it ran
+        let result = Pipeline::builder()
+            .stages([Stage::DownloadKernel])
+            .build()
+            .run_with_checkpoint(&report, &state_path, None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            result.status(Stage::DownloadKernel),
+            Some(StageStatus::Skipped(reason)) if reason.contains("earlier run")
+        ));
+
+        // a checkpoint of another crash does not count
+        let other = Checkpoint::load(&state_path, 1, commit).await.unwrap();
+        assert!(other.completed_at(Stage::DownloadKernel).is_none());
+    }
+
+    #[test]
+    fn test_checkpoint_invalidate_from() {
+        let mut checkpoint = Checkpoint::new(0, "abc");
+        for stage in Stage::ALL {
+            checkpoint.complete(stage);
+        }
+        checkpoint.invalidate_from(Stage::FixConfig);
+        assert!(checkpoint.completed_at(Stage::DownloadConfig).is_some());
+        assert!(checkpoint.completed_at(Stage::FixConfig).is_none());
+        assert!(checkpoint.completed_at(Stage::Mount).is_none());
+
+        assert_eq!("fix-config".parse::<Stage>().unwrap(), Stage::FixConfig);
+        assert!("patch".parse::<Stage>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_run_batch() {
+        // a second crash lets this report get past the crash index check
+        let mut two_crashes =
+            parse_file("datasets/0b6b2d6d6cefa8b462930e55be699efba635788f.json").unwrap();
+        two_crashes.crashes.push(two_crashes.crashes[0].clone());
+        let good = Arc::new(two_crashes);
+        let other =
+            Arc::new(parse_file("datasets/0be4824a86385f022a4f6f5104bcb9246032fdd9.json").unwrap());
+        assert_eq!(other.crashes.len(), 1);
+
+        // a crash index the other report doesn't have fails it without touching the first
+        let options = BuildOptions {
+            crash_index: 1,
+            ..BuildOptions::default()
+        };
+        let pipeline = Pipeline::builder().stages([]).options(options).build();
+        let summary = pipeline
+            .run_batch(vec![other.clone(), good.clone()], 2)
+            .await;
+
+        // sorted by id whatever order they finished in
+        let ids: Vec<&str> = summary.results.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(
+            ids,
+            [
+                "0b6b2d6d6cefa8b462930e55be699efba635788f",
+                "0be4824a86385f022a4f6f5104bcb9246032fdd9"
+            ]
+        );
+        assert_eq!(
+            summary.failed(),
+            ["0be4824a86385f022a4f6f5104bcb9246032fdd9"]
+        );
+        assert_eq!(summary.succeeded(), 1);
+    }
+
+    #[test]
+    fn test_result_json() {
+        let mut result = PipelineResult::new("abc", 0);
+        result.record(
+            Stage::DownloadKernel,
+            StageStatus::Succeeded,
+            Duration::from_millis(1500),
+        );
+        result.record(
+            Stage::Build,
+            StageStatus::Failed("make exited with 2".to_string()),
+            Duration::from_secs(60),
+        );
+
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["report_id"], "abc");
+        assert_eq!(json["stages"][0]["stage"], "download-kernel");
+        assert_eq!(json["stages"][0]["status"], "succeeded");
+        assert_eq!(json["stages"][0]["duration_ms"], 1500);
+        assert_eq!(json["stages"][1]["status"], "failed");
+        assert_eq!(json["stages"][1]["detail"], "make exited with 2");
+        assert!(json.get("actual_compiler").is_none());
+
+        result.requested_compiler = Some(Compiler::parse("gcc (GCC) 10.2.1 20210110").unwrap());
+        result.actual_compiler = Some(Compiler::parse("gcc (GCC) 10.3.0").unwrap());
+        let json = serde_json::to_value(&result).unwrap();
+        assert_eq!(json["requested_compiler"], "gcc-10.2.1");
+        assert_eq!(json["actual_compiler"], "gcc-10.3.0");
+        assert!(!result.succeeded());
+    }
+}
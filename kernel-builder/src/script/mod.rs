@@ -1 +1,3 @@
-pub mod script;
\ No newline at end of file
+pub mod preflight;
+pub mod script;
+pub mod tool;
@@ -0,0 +1,258 @@
+use crate::config::config::{AuthMethod, Config, ProxyConfig, ProxyPolicy, SSHConfig};
+use crate::kernel::nix::{NixCommand, verify_compiler_available};
+use crate::parse::compiler::Compiler;
+use crate::script::tool::{OPTIONAL_TOOLS, REQUIRED_TOOLS, require_tool};
+use anyhow::Result;
+use std::env;
+use std::fmt;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+// how long the proxy gets to accept a connection
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// outcome of one preflight probe, with a hint on how to fix it when it failed
+#[derive(Debug)]
+pub struct CheckResult {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+    pub hint: Option<String>,
+}
+
+impl CheckResult {
+    fn pass(name: impl Into<String>, detail: impl Into<String>) -> CheckResult {
+        CheckResult {
+            name: name.into(),
+            passed: true,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn fail(
+        name: impl Into<String>,
+        detail: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> CheckResult {
+        CheckResult {
+            name: name.into(),
+            passed: false,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+impl fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mark = if self.passed { "✔" } else { "✘" };
+        write!(f, "[{}] {}: {}", mark, self.name, self.detail)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n    hint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+// probe everything a run depends on: the external tools, the default nix-shell toolchain,
+// the proxy and the ssh key. every check runs even if an earlier one failed, optional tools are
+// listed but never fail
+pub async fn preflight_check() -> Result<Vec<CheckResult>> {
+    let config = Config::load()?;
+
+    let mut results: Vec<CheckResult> =
+        REQUIRED_TOOLS.iter().map(|name| check_tool(name)).collect();
+    results.extend(OPTIONAL_TOOLS.iter().map(|name| check_optional_tool(name)));
+    results.push(check_default_toolchain().await);
+    results.push(check_proxy(&config.proxy).await);
+    results.push(check_ssh_key(&config.ssh));
+
+    Ok(results)
+}
+
+fn check_tool(name: &str) -> CheckResult {
+    match require_tool(name) {
+        Ok(path) => CheckResult::pass(name, path.display().to_string()),
+        Err(e) => CheckResult::fail(name, e.to_string(), tool_hint(name)),
+    }
+}
+
+fn check_optional_tool(name: &str) -> CheckResult {
+    let mut result = check_tool(name);
+    if !result.passed {
+        result.passed = true;
+        result.detail = format!("not found, optional: {}", tool_use(name));
+    }
+    result
+}
+
+// what goes without an optional tool
+fn tool_use(name: &str) -> &'static str {
+    match name {
+        "crash" => "captured vmcores are not analyzed",
+        _ => "the stages using it fail",
+    }
+}
+
+fn tool_hint(name: &str) -> &'static str {
+    match name {
+        "nix-shell" => "install nix, see https://nixos.org/download",
+        "bear" => "install bear (e.g. `nix-env -iA nixpkgs.bear` or `apt install bear`)",
+        "qemu-system-x86_64" => "install qemu (e.g. `apt install qemu-system-x86`)",
+        "crash" => "install the crash utility (e.g. `apt install crash`)",
+        _ => "install it with the system package manager and make sure it is on PATH",
+    }
+}
+
+// the shell.nix default toolchain, builds that ask for a specific compiler are checked by
+// check_compiler
+async fn check_default_toolchain() -> CheckResult {
+    let name = "toolchain";
+    if require_tool("nix-shell").is_err() {
+        return CheckResult::fail(
+            name,
+            "not checked, nix-shell is missing",
+            tool_hint("nix-shell"),
+        );
+    }
+    let dir = match env::current_dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            return CheckResult::fail(name, e.to_string(), "run from the kernel-builder directory");
+        }
+    };
+    let shell_script = dir.join("nix").join("shell.nix");
+    if !shell_script.is_file() {
+        return CheckResult::fail(
+            name,
+            format!("{} not found", shell_script.display()),
+            "run from the kernel-builder directory",
+        );
+    }
+
+    let nix_cmd = NixCommand::new(shell_script, "gcc-default", dir);
+    match nix_cmd.output("${CC:-cc} --version").await {
+        Ok(output) => {
+            let version = output.lines().next().unwrap_or_default().trim().to_string();
+            CheckResult::pass(name, version)
+        }
+        Err(e) => CheckResult::fail(
+            name,
+            format!("{:#}", e),
+            "check that <nixpkgs> is set up (`nix-channel --update`) and nix/shell.nix evaluates",
+        ),
+    }
+}
+
+// the toolchain a report asks for, as provided by nix/shell.nix
+pub async fn check_compiler(compiler: &Compiler) -> CheckResult {
+    let name = format!("compiler {}", compiler.nix_arg());
+    let result = match env::current_dir() {
+        Ok(dir) => {
+            let shell_script_path = dir.join("nix").join("shell.nix");
+            let nix_cmd = NixCommand::new(shell_script_path, &compiler.nix_arg(), dir);
+            verify_compiler_available(&nix_cmd, compiler).await
+        }
+        Err(e) => Err(e.into()),
+    };
+    match result {
+        Ok(actual) => CheckResult::pass(name, format!("nix/shell.nix provides {}", actual)),
+        Err(e) => CheckResult::fail(
+            name,
+            format!("{:#}", e),
+            "add a nixpkgs channel that still ships this compiler to nix/shell.nix",
+        ),
+    }
+}
+
+async fn check_proxy(proxy: &ProxyConfig) -> CheckResult {
+    let name = "proxy";
+    match proxy.policy {
+        ProxyPolicy::Never => return CheckResult::pass(name, "not used (policy = never)"),
+        ProxyPolicy::Auto => {
+            return CheckResult::pass(name, "taken from HTTP_PROXY/HTTPS_PROXY (policy = auto)");
+        }
+        ProxyPolicy::Always | ProxyPolicy::SyzkallerOnly => {}
+    }
+
+    let addr = format!("{}:{}", proxy.host, proxy.port);
+    let hint = "start the proxy, point proxy.host/proxy.port (or KB_PROXY_HOST/KB_PROXY_PORT) at it, or set policy = \"never\"";
+    match tokio::time::timeout(PROXY_CONNECT_TIMEOUT, TcpStream::connect(&addr)).await {
+        Ok(Ok(_)) => CheckResult::pass(name, format!("{} is reachable", addr)),
+        Ok(Err(e)) => CheckResult::fail(name, format!("cannot connect to {}: {}", addr, e), hint),
+        Err(_) => CheckResult::fail(
+            name,
+            format!(
+                "connecting to {} timed out after {:?}",
+                addr, PROXY_CONNECT_TIMEOUT
+            ),
+            hint,
+        ),
+    }
+}
+
+fn check_ssh_key(ssh: &SSHConfig) -> CheckResult {
+    let name = "ssh key";
+    match ssh.validate() {
+        Ok(()) => {
+            let detail = match ssh.auth_method() {
+                AuthMethod::KeyFile(path) => path.display().to_string(),
+                AuthMethod::Agent => "ssh agent".to_string(),
+                AuthMethod::Password(_) => "password".to_string(),
+            };
+            CheckResult::pass(name, detail)
+        }
+        Err(e) => CheckResult::fail(
+            name,
+            e.to_string(),
+            "set ssh.key_path (or KB_SSH_KEY_PATH) to a readable key with mode 600",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(port: u16) -> ProxyConfig {
+        ProxyConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            policy: ProxyPolicy::Always,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_proxy() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let result = check_proxy(&proxy(port)).await;
+        assert!(result.passed, "{}", result);
+
+        drop(listener);
+        let result = check_proxy(&proxy(port)).await;
+        assert!(!result.passed);
+        assert!(result.hint.is_some());
+
+        let mut never = proxy(port);
+        never.policy = ProxyPolicy::Never;
+        assert!(check_proxy(&never).await.passed);
+    }
+
+    #[test]
+    fn test_check_tool() {
+        assert!(check_tool("sh").passed);
+        let result = check_tool("definitely-not-a-real-tool");
+        assert!(!result.passed);
+        assert!(result.to_string().contains("hint: install it"));
+    }
+
+    #[test]
+    fn test_check_optional_tool() {
+        let result = check_optional_tool("definitely-not-a-real-tool");
+        assert!(result.passed);
+        assert!(result.detail.starts_with("not found, optional"));
+        assert!(result.hint.is_some());
+    }
+}
@@ -1,49 +1,94 @@
-use anyhow::{Result, bail};
+use crate::config::config::Config;
+use crate::kernel::artifacts::BuildArtifacts;
+use crate::kvm::vmcore::analyze_crash_vmcore;
+use crate::parse::arch::select_architecture;
+use crate::parse::layout::Layout;
+use crate::parse::report::CrashReport;
+use crate::parse::workspace::default_workspace;
+use anyhow::{Context, Result, bail};
 use std::env;
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::process::Command;
-use crate::parse::report::CrashReport;
 
-pub async fn mount(report: &Arc<CrashReport>) -> Result<()> {
-    let id = report.id.clone();
-    let commit = report.crashes.first().unwrap().kernel_source_commit.clone();
+// absolute path of `name` in workspace.script_dir, which must exist
+fn script_path(name: &str) -> Result<PathBuf> {
+    let script_dir = Config::load()?.workspace.script_dir;
+    let script_dir = if script_dir.is_absolute() {
+        script_dir
+    } else {
+        env::current_dir()
+            .context("Failed to resolve the current directory")?
+            .join(script_dir)
+    };
+
+    let path = script_dir.join(name);
+    if !path.is_file() {
+        bail!(
+            "Script {} not found in {}, point workspace.script_dir at the directory holding it",
+            name,
+            script_dir.display()
+        );
+    }
+    Ok(path)
+}
 
-    let script_path = env::current_dir()?.join("script");
+// run script `name` for crash `crash_index` of the report. the workspace root is handed over
+// as WORK_DIR, the crash's directory as CRASH_DIR and the boot image of its build as
+// KERNEL_IMAGE, so the script does not have to guess them from its own location or the arch
+async fn run_script(name: &str, report: &CrashReport, crash_index: usize) -> Result<()> {
+    let path = script_path(name)?;
+    let commit = report.reproduce_commit(crash_index)?;
+    let layout = Layout::for_crash(report, crash_index)?;
+    let artifacts = BuildArtifacts::expected(&layout, select_architecture(report)?);
 
-    let status = Command::new("./mount.sh")
-        .arg(id)
+    let status = Command::new(&path)
+        .arg(&report.id)
         .arg(commit)
-        .current_dir(script_path)
+        .env("WORK_DIR", default_workspace().root())
+        .env("CRASH_DIR", layout.crash_dir())
+        .env("KERNEL_IMAGE", &artifacts.bzimage)
+        .current_dir(path.parent().unwrap_or(&path))
         .stdout(std::process::Stdio::inherit())
         .stderr(std::process::Stdio::inherit())
         .status()
-        .await?; // 注意这里是 .await
+        .await
+        .with_context(|| format!("Failed to run {}", path.display()))?;
 
     if !status.success() {
-        bail!("failed to mount debian.img");
+        bail!("{} exited with {}", path.display(), status);
     }
 
     Ok(())
 }
 
-pub async fn get_vmcore(report: &Arc<CrashReport>) -> Result<()> {
-    let id = report.id.clone();
-    let commit = report.crashes.first().unwrap().kernel_source_commit.clone();
+pub async fn mount(report: &Arc<CrashReport>, crash_index: usize) -> Result<()> {
+    run_script("mount.sh", report, crash_index)
+        .await
+        .context("failed to mount debian.img")
+}
+
+pub async fn get_vmcore(report: &Arc<CrashReport>, crash_index: usize) -> Result<()> {
+    run_script("get.sh", report, crash_index)
+        .await
+        .context("failed to get vmcore")?;
+    analyze_crash_vmcore(report, crash_index)
+        .await
+        .context("failed to analyze vmcore")?;
+    Ok(())
+}
 
-    let script_path = env::current_dir()?.join("script");
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    let status = Command::new("./get.sh")
-        .arg(id)
-        .arg(commit)
-        .current_dir(script_path)
-        .stdout(std::process::Stdio::inherit())
-        .stderr(std::process::Stdio::inherit())
-        .status()
-        .await?;
+    #[test]
+    fn test_script_path() {
+        let path = script_path("mount.sh").unwrap();
+        assert!(path.is_absolute());
+        assert!(path.ends_with("script/mount.sh"));
 
-    if !status.success() {
-        bail!("failed to get vmcore");
+        let err = script_path("missing.sh").unwrap_err();
+        assert!(err.to_string().contains("Script missing.sh not found in"));
     }
-
-    Ok(())
 }
@@ -0,0 +1,57 @@
+use std::env;
+use std::path::PathBuf;
+use thiserror::Error;
+
+// external tools the pipeline shells out to, checked by `doctor`
+pub const REQUIRED_TOOLS: &[&str] = &["nix-shell", "bear", "patch", "git", "qemu-system-x86_64"];
+// tools only some stages use, a missing one is reported without failing the check
+pub const OPTIONAL_TOOLS: &[&str] = &["crash"];
+
+#[derive(Debug, Error)]
+pub enum ToolError {
+    #[error("required tool '{0}' not found on PATH")]
+    NotFound(String),
+}
+
+// locate an executable on PATH
+pub fn find_tool(name: &str) -> Option<PathBuf> {
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+pub fn require_tool(name: &str) -> Result<PathBuf, ToolError> {
+    find_tool(name).ok_or_else(|| ToolError::NotFound(name.to_string()))
+}
+
+fn is_executable(path: &std::path::Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+// result of probing every required tool, in REQUIRED_TOOLS order
+pub fn check_tools() -> Vec<(&'static str, Result<PathBuf, ToolError>)> {
+    REQUIRED_TOOLS
+        .iter()
+        .map(|name| (*name, require_tool(name)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_require_tool() {
+        assert!(require_tool("sh").is_ok());
+        let err = require_tool("definitely-not-a-real-tool").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "required tool 'definitely-not-a-real-tool' not found on PATH"
+        );
+    }
+}